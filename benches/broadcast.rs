@@ -0,0 +1,56 @@
+use bytes::Bytes;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rwshell::server::{PtyEvent, encode_write_message};
+use std::hint::black_box;
+use tokio::sync::broadcast;
+
+const CHUNK_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+const VIEWER_COUNTS: [usize; 3] = [1, 5, 20];
+
+fn sample_output(size: usize) -> Bytes {
+    // Mix of printable text and escape sequences, like a busy build log
+    let pattern = b"\x1b[32mcompiling\x1b[0m crate v1.0.0 (/src/crate)\r\n";
+    pattern.iter().copied().cycle().take(size).collect::<Vec<u8>>().into()
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_write_message");
+    for size in CHUNK_SIZES {
+        let data = sample_output(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| encode_write_message(black_box(data)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast_fanout");
+    let data = sample_output(1024);
+    for viewers in VIEWER_COUNTS {
+        let (tx, _) = broadcast::channel::<PtyEvent>(1024);
+        let mut receivers: Vec<_> = (0..viewers).map(|_| tx.subscribe()).collect();
+
+        group.throughput(Throughput::Bytes((data.len() * viewers) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(viewers), &data, |b, data| {
+            b.iter(|| {
+                let frame = encode_write_message(data).unwrap();
+                tx.send(PtyEvent::Output {
+                    data: data.clone(),
+                    frame,
+                    compressed: None,
+                })
+                .unwrap();
+
+                for rx in receivers.iter_mut() {
+                    black_box(rx.try_recv().unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_broadcast);
+criterion_main!(benches);
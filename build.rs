@@ -0,0 +1,72 @@
+//! Compresses the embedded frontend assets ahead of time, so serving them to a crowd of viewers costs no
+//! CPU and as little of the host's uplink as possible.
+
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+const ASSETS_DIR: &str = "frontend";
+
+fn main() {
+    println!("cargo:rerun-if-changed={ASSETS_DIR}");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    let variants_dir = out_dir.join("precompressed");
+    fs::create_dir_all(&variants_dir).expect("create the directory of precompressed assets");
+
+    let mut files = Vec::new();
+    collect_files(Path::new(ASSETS_DIR), &mut files);
+    files.sort();
+
+    let mut table = String::from("pub static PRECOMPRESSED: &[PrecompressedAsset] = &[\n");
+    for file in files {
+        let path = file
+            .strip_prefix(ASSETS_DIR)
+            .expect("assets are found in the assets directory")
+            .to_string_lossy()
+            .replace('\\', "/");
+        let data = fs::read(&file).unwrap_or_else(|e| panic!("read {}: {e}", file.display()));
+        let hash = Sha256::digest(&data).iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gzip.write_all(&data).expect("gzip in memory");
+        let gzip = gzip.finish().expect("gzip in memory");
+        let zstd = zstd::bulk::compress(&data, 19).expect("zstd in memory");
+
+        let gzip = variant(&variants_dir, &path, "gz", &gzip, data.len());
+        let zstd = variant(&variants_dir, &path, "zst", &zstd, data.len());
+        let _ = writeln!(
+            table,
+            "    PrecompressedAsset {{ path: {path:?}, sha256: {hash:?}, gzip: {gzip}, zstd: {zstd} }},"
+        );
+    }
+    table.push_str("];\n");
+    fs::write(out_dir.join("precompressed.rs"), table).expect("write the table of precompressed assets");
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|e| panic!("read {}: {e}", dir.display()));
+    for entry in entries {
+        let path = entry.expect("read an assets directory entry").path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Write a compressed variant of the asset at `path` and return the expression that embeds it, or `None`
+/// if it isn't any smaller than the `original_len` bytes of the asset
+fn variant(dir: &Path, path: &str, extension: &str, data: &[u8], original_len: usize) -> String {
+    if data.len() >= original_len {
+        return "None".to_string();
+    }
+    let file = dir.join(format!("{}.{extension}", path.replace('/', "__")));
+    fs::write(&file, data).unwrap_or_else(|e| panic!("write {}: {e}", file.display()));
+    format!("Some(include_bytes!({:?}))", file.to_string_lossy())
+}
@@ -0,0 +1,187 @@
+//! Load testing harness for rwshell.
+//!
+//! Connects N synthetic WebSocket viewers to a session and reports the output throughput each of
+//! them observes. With `--spawn` it first starts a headless rwshell server running a high-output
+//! command, so a full measurement is a single invocation:
+//!
+//! ```bash
+//! cargo build --release --examples
+//! target/release/examples/load_test --spawn "yes" --viewers 20 --duration 10
+//! ```
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use clap::Parser;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+#[derive(Parser, Debug)]
+#[command(name = "load_test")]
+#[command(about = "Measure rwshell output throughput with many concurrent viewers")]
+struct LoadTestArgs {
+    /// Session URL to connect to
+    #[arg(long, default_value = "http://127.0.0.1:8000/s/local/")]
+    url: String,
+
+    /// Number of concurrent viewers
+    #[arg(long, default_value = "20")]
+    viewers: usize,
+
+    /// Measurement duration in seconds
+    #[arg(long, default_value = "10")]
+    duration: u64,
+
+    /// Start a headless rwshell server running this command before connecting
+    #[arg(long)]
+    spawn: Option<String>,
+
+    /// Listen address for the spawned server
+    #[arg(long, default_value = "127.0.0.1:8199")]
+    listen: String,
+}
+
+#[derive(Deserialize)]
+struct TtyMessage {
+    #[serde(rename = "Type")]
+    msg_type: String,
+    #[serde(rename = "Data")]
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct WriteMessage {
+    #[serde(rename = "Data")]
+    data: String,
+}
+
+#[derive(Default)]
+struct ViewerReport {
+    messages: u64,
+    bytes: u64,
+    first_output: Option<Duration>,
+}
+
+fn ws_url(session_url: &str) -> Result<String> {
+    let url = url::Url::parse(session_url)?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = url.path().trim_end_matches('/');
+    Ok(format!("{scheme}://{host}:{port}{path}/ws/"))
+}
+
+async fn run_viewer(ws_url: String, duration: Duration) -> Result<ViewerReport> {
+    let started = Instant::now();
+    let (ws_stream, _) = connect_async(&ws_url).await?;
+    let (_ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let mut report = ViewerReport::default();
+    let deadline = tokio::time::Instant::now() + duration;
+
+    while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, ws_receiver.next()).await {
+        let Message::Text(text) = msg? else { continue };
+        let tty_msg: TtyMessage = serde_json::from_str(&text)?;
+        if tty_msg.msg_type != "Write" {
+            continue;
+        }
+
+        let write_msg: WriteMessage = serde_json::from_slice(&general_purpose::STANDARD.decode(&tty_msg.data)?)?;
+        let output = general_purpose::STANDARD.decode(&write_msg.data)?;
+
+        report.first_output.get_or_insert_with(|| started.elapsed());
+        report.messages += 1;
+        report.bytes += output.len() as u64;
+    }
+
+    Ok(report)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = LoadTestArgs::parse();
+
+    // Optionally start a headless server next to this example binary
+    let mut server = None;
+    let session_url = match &args.spawn {
+        Some(command) => {
+            let exe = std::env::current_exe()?;
+            let bin = exe
+                .parent()
+                .and_then(|dir| dir.parent())
+                .map(|dir| dir.join("rwshell"))
+                .ok_or_else(|| anyhow::anyhow!("Cannot locate the rwshell binary"))?;
+
+            let mut parts = command.split_whitespace();
+            let program = parts.next().ok_or_else(|| anyhow::anyhow!("Empty --spawn command"))?;
+            let program_args = parts.collect::<Vec<_>>().join(" ");
+
+            server = Some(
+                tokio::process::Command::new(bin)
+                    .args(["--headless", "--listen", &args.listen, "--command", program])
+                    .arg("--args")
+                    .arg(program_args)
+                    .stdout(std::process::Stdio::null())
+                    .kill_on_drop(true)
+                    .spawn()?,
+            );
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            format!("http://{}/s/local/", args.listen)
+        }
+        None => args.url.clone(),
+    };
+
+    let ws_url = ws_url(&session_url)?;
+    let duration = Duration::from_secs(args.duration);
+    println!(
+        "Connecting {} viewers to {} for {}s",
+        args.viewers, ws_url, args.duration
+    );
+
+    let viewers: Vec<_> = (0..args.viewers)
+        .map(|_| tokio::spawn(run_viewer(ws_url.clone(), duration)))
+        .collect();
+
+    let mut total_bytes = 0;
+    let mut total_messages = 0;
+    let mut slowest = u64::MAX;
+    let mut fastest = 0;
+
+    for (index, viewer) in viewers.into_iter().enumerate() {
+        match viewer.await? {
+            Ok(report) => {
+                total_bytes += report.bytes;
+                total_messages += report.messages;
+                slowest = slowest.min(report.bytes);
+                fastest = fastest.max(report.bytes);
+                println!(
+                    "viewer {:3}: {:8} messages {:12} bytes, first output after {:?}",
+                    index, report.messages, report.bytes, report.first_output
+                );
+            }
+            Err(e) => println!("viewer {index:3}: failed: {e}"),
+        }
+    }
+
+    if slowest == u64::MAX {
+        slowest = 0;
+    }
+
+    let seconds = duration.as_secs_f64();
+    println!();
+    println!("total:      {total_messages} messages, {total_bytes} bytes");
+    println!(
+        "throughput: {:.2} MiB/s across all viewers",
+        total_bytes as f64 / seconds / 1048576.0
+    );
+    println!(
+        "per viewer: {:.2} MiB/s slowest, {:.2} MiB/s fastest",
+        slowest as f64 / seconds / 1048576.0,
+        fastest as f64 / seconds / 1048576.0
+    );
+
+    drop(server);
+    Ok(())
+}
@@ -0,0 +1,43 @@
+;; Example rwshell output plugin: masks every digit sent to viewers and logs viewers joining.
+;;
+;; Run it with `rwshell --plugin examples/plugins/mask_digits.wat`.
+(module
+  (import "rwshell" "log" (func $log (param i32 i32)))
+
+  (memory (export "memory") 2)
+  (data (i32.const 0) "viewer joined")
+
+  ;; Input is written to the second page onwards
+  (global $buffer i32 (i32.const 65536))
+
+  (func (export "rwshell_abi_version") (result i32)
+    (i32.const 1))
+
+  (func (export "rwshell_alloc") (param $len i32) (result i32)
+    (local $pages i32)
+    ;; Grow memory until the buffer fits
+    (local.set $pages
+      (i32.shr_u (i32.add (i32.add (global.get $buffer) (local.get $len)) (i32.const 65535)) (i32.const 16)))
+    (if (i32.gt_u (local.get $pages) (memory.size))
+      (then (drop (memory.grow (i32.sub (local.get $pages) (memory.size))))))
+    (global.get $buffer))
+
+  (func (export "rwshell_output") (param $ptr i32) (param $len i32) (result i64)
+    (local $i i32)
+    (local $byte i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (local.set $byte (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+        (if (i32.and (i32.ge_u (local.get $byte) (i32.const 48)) (i32.le_u (local.get $byte) (i32.const 57)))
+          (then (i32.store8 (i32.add (local.get $ptr) (local.get $i)) (i32.const 35))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    ;; The chunk was rewritten in place
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+      (i64.extend_i32_u (local.get $len))))
+
+  (func (export "rwshell_event") (param $kind i32) (param $ptr i32) (param $len i32)
+    (if (i32.eq (local.get $kind) (i32.const 1))
+      (then (call $log (i32.const 0) (i32.const 13))))))
@@ -0,0 +1,29 @@
+-- Example rwshell script: refuses `rm -rf` from viewers and reports who joins.
+--
+-- Run it with `rwshell --script examples/scripts/guard.lua`.
+
+-- What each viewer typed on the current line
+local typed = {}
+
+function on_client_join(client)
+  rwshell.log("viewer " .. client.id .. " joined from " .. client.address)
+end
+
+function on_input(client, data)
+  local line = (typed[client.id] or "") .. data
+  if line:find("rm%s+%-%a*r%a*f") or line:find("rm%s+%-%a*f%a*r") then
+    rwshell.notify("Blocked rm -rf from viewer " .. client.id)
+    typed[client.id] = ""
+    -- Cancel the line instead
+    return "\3"
+  end
+
+  if data:find("[\r\n]") then
+    line = ""
+  end
+  typed[client.id] = line:sub(-256)
+end
+
+function on_exit(code)
+  rwshell.log("session ended with exit code " .. code)
+end
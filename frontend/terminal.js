@@ -1,3 +1,16 @@
+// Longest notes the server keeps, in bytes
+const MAX_NOTES_LENGTH = 32 * 1024;
+
+// Most recent stderr output shown, in characters
+const MAX_STDERR_LENGTH = 64 * 1024;
+
+// WebSocket subprotocol that carries output and input as binary messages: a type byte, then the raw bytes
+const BINARY_PROTOCOL = "rwshell.v2";
+const BINARY_WRITE = 1;
+// The same with output compressed by the server's --compression, here only deflate
+const DEFLATE_PROTOCOL = "rwshell.v2+deflate";
+const BINARY_WRITE_COMPRESSED = 2;
+
 function base64Encode(str) {
   const utf8Bytes = new TextEncoder().encode(str);
   let binary = "";
@@ -25,6 +38,11 @@ function base64StringToArrayBuffer(base64) {
   return bytes.buffer;
 }
 
+async function inflate(data) {
+  const stream = new Blob([data]).stream().pipeThrough(new DecompressionStream("deflate"));
+  return new Uint8Array(await new Response(stream).arrayBuffer());
+}
+
 function waitForFonts() {
   return new Promise((resolve) => {
     if (document.fonts && document.fonts.ready) {
@@ -46,8 +64,13 @@ class TTYReceiver {
   constructor(wsAddress, container) {
     console.debug("Opening WS connection to", wsAddress);
 
-    // Create WebSocket connection
-    this.connection = new WebSocket(wsAddress);
+    // Create WebSocket connection, preferring the binary protocol; older servers answer with JSON only
+    const protocols =
+      typeof DecompressionStream === "undefined"
+        ? [BINARY_PROTOCOL]
+        : [DEFLATE_PROTOCOL, BINARY_PROTOCOL];
+    this.connection = new WebSocket(wsAddress, protocols);
+    this.connection.binaryType = "arraybuffer";
 
     // Create xterm terminal with better defaults for full screen
     this.terminal = new Terminal({
@@ -117,6 +140,33 @@ class TTYReceiver {
     // Initialize headless state
     this.headless = false;
 
+    // Reason sent by the server before it closes the connection
+    this.closeMessage = null;
+
+    // History pager state: whether it is open and the index of its first line
+    this.pagerOpen = false;
+    this.pagerStart = 0;
+
+    // Notes shared by the session's participants, and the pending save of local edits
+    this.notes = "";
+    this.notesSaveTimeout = null;
+
+    // Timestamped entries noted during the session, oldest first
+    this.timeline = [];
+
+    // Number of viewers connected to the session, once the server has told it
+    this.viewers = null;
+
+    // Whether the server holds back output until this viewer follows the session again
+    this.paused = false;
+
+    // Recent stderr of the program with --separate-stderr, and its decoder across messages
+    this.stderr = "";
+    this.stderrDecoder = new TextDecoder();
+
+    // Output waiting to be written, so it stays in order while compressed output is decompressed
+    this.pendingOutput = Promise.resolve();
+
     // Create and load addons for enhanced functionality (with fallbacks)
     this.fitAddon = null;
     this.clipboardAddon = null;
@@ -185,6 +235,9 @@ class TTYReceiver {
     // Handle WebSocket open
     this.connection.onopen = (evt) => {
       console.debug("WebSocket connection opened");
+      // A new connection follows the session
+      this.paused = false;
+      this.sendCapabilities();
       this.terminal.focus();
       this.updateStatusBar();
       // Fit to screen after connection
@@ -197,12 +250,26 @@ class TTYReceiver {
       this.terminal.clear();
 
       setTimeout(() => {
-        this.terminal.write("Session closed");
+        if (this.closeMessage) {
+          this.terminal.write("Session closed: " + this.closeMessage.Message);
+        } else {
+          this.terminal.write("Session closed");
+        }
       }, 1000);
     };
 
     // Handle incoming messages
     this.connection.onmessage = (ev) => {
+      if (ev.data instanceof ArrayBuffer) {
+        const frame = new Uint8Array(ev.data);
+        if (frame[0] === BINARY_WRITE) {
+          this.writeOutput(frame.subarray(1));
+        } else if (frame[0] === BINARY_WRITE_COMPRESSED) {
+          this.writeOutput(inflate(frame.subarray(1)));
+        }
+        return;
+      }
+
       try {
         const message = JSON.parse(ev.data);
         console.debug(
@@ -216,11 +283,7 @@ class TTYReceiver {
         if (message.Type === "Write") {
           const writeMsg = JSON.parse(msgData);
           const decodedData = base64StringToArrayBuffer(writeMsg.Data);
-          this.terminal.write(new Uint8Array(decodedData));
-          // Ensure terminal scrolls to bottom after new data
-          setTimeout(() => {
-            this.terminal.scrollToBottom();
-          }, 0);
+          this.writeOutput(new Uint8Array(decodedData));
         }
 
         if (message.Type === "WinSize") {
@@ -247,9 +310,76 @@ class TTYReceiver {
           const readOnlyMsg = JSON.parse(msgData);
           console.debug("Received ReadOnly state:", readOnlyMsg.ReadOnly);
           this.readonly = readOnlyMsg.ReadOnly;
+          this.canRequestWrite = readOnlyMsg.CanRequestWrite || false;
+          this.canFork = readOnlyMsg.CanFork || false;
           this.updateReadOnlyState();
         }
 
+        if (message.Type === "Clients") {
+          this.viewers = JSON.parse(msgData).Count;
+          this.updateStatusBar();
+        }
+
+        if (message.Type === "WriteAccess") {
+          this.showWriteAccess(JSON.parse(msgData).Granted);
+        }
+
+        if (message.Type === "Forked") {
+          this.openFork(JSON.parse(msgData).Url);
+        }
+
+        if (message.Type === "Close") {
+          this.closeMessage = JSON.parse(msgData);
+          console.debug("Received Close:", this.closeMessage.Reason, this.closeMessage.Message);
+        }
+
+        if (message.Type === "Keys") {
+          const keysMsg = JSON.parse(msgData);
+          this.showKeys(keysMsg.Keys);
+        }
+
+        if (message.Type === "ScreenText") {
+          const screenTextMsg = JSON.parse(msgData);
+          this.announceLines(screenTextMsg.Lines);
+        }
+
+        if (message.Type === "Confirmation") {
+          this.showConfirmation(JSON.parse(msgData));
+        }
+
+        if (message.Type === "Error") {
+          this.showError(JSON.parse(msgData).Message);
+          // A fork that failed leaves nothing to show in the tab opened for it
+          if (this.forkTab) {
+            this.forkTab.close();
+            this.forkTab = null;
+          }
+        }
+
+        if (message.Type === "Warning") {
+          this.showWarning(JSON.parse(msgData).Message);
+        }
+
+        if (message.Type === "Notes") {
+          this.showNotes(JSON.parse(msgData).Text);
+        }
+
+        if (message.Type === "Timeline") {
+          this.showTimeline(JSON.parse(msgData).Entries);
+        }
+
+        if (message.Type === "Stderr") {
+          this.showStderr(JSON.parse(msgData).Data);
+        }
+
+        if (message.Type === "Stats") {
+          this.showStats(JSON.parse(msgData));
+        }
+
+        if (message.Type === "History") {
+          this.showHistory(JSON.parse(msgData));
+        }
+
         if (message.Type === "Headless") {
           const headlessMsg = JSON.parse(msgData);
           console.debug("Received Headless state:", headlessMsg.Headless);
@@ -270,6 +400,15 @@ class TTYReceiver {
       }
 
       try {
+        if (this.connection.protocol.startsWith(BINARY_PROTOCOL)) {
+          const input = new TextEncoder().encode(data);
+          const frame = new Uint8Array(input.length + 1);
+          frame[0] = BINARY_WRITE;
+          frame.set(input, 1);
+          this.connection.send(frame);
+          return;
+        }
+
         const writeMessage = {
           Type: "Write",
           Data: base64Encode(
@@ -288,6 +427,7 @@ class TTYReceiver {
 
     // Setup clipboard and special key handling
     this.setupKeyboardHandling();
+    this.setupNotes();
 
     // Handle window resize with debounce
     let resizeTimeout;
@@ -531,6 +671,18 @@ class TTYReceiver {
       if (this.headless) {
         statusText += " (Headless)";
       }
+      if (this.paused) {
+        statusText += " (Paused, Ctrl+Shift+Space to follow)";
+      }
+      if (this.viewers !== null) {
+        statusText += ` · Viewers: ${this.viewers}`;
+      }
+      if (this.notes || this.timeline.length > 0) {
+        statusText += " · Notes: Ctrl+Shift+M";
+      }
+      if (this.stderr) {
+        statusText += " · Stderr: Ctrl+Shift+E";
+      }
       statusElement.textContent = statusText;
     }
   }
@@ -538,6 +690,74 @@ class TTYReceiver {
   setupKeyboardHandling() {
     // Unified keyboard shortcut handler
     this.terminal.attachCustomKeyEventHandler((e) => {
+      // While the history pager is open, keys page through it instead of reaching the session
+      if (this.pagerOpen) {
+        if (e.type === "keydown") {
+          this.handlePagerKey(e);
+        }
+        e.preventDefault();
+        return false;
+      }
+
+      // Shift+PageUp pages back through the session history kept by the server
+      if (e.type === "keydown" && e.shiftKey && e.code === "PageUp") {
+        e.preventDefault();
+        this.requestHistory(null);
+        return false;
+      }
+
+      // Ctrl+Shift+Space pauses the output, and follows it again, catching up on what was held back
+      if (e.type === "keydown" && e.ctrlKey && e.shiftKey && e.code === "Space") {
+        e.preventDefault();
+        this.setFollow(this.paused);
+        return false;
+      }
+
+      // Ctrl+Shift+M opens the session notes
+      if (e.type === "keydown" && e.ctrlKey && e.shiftKey && e.code === "KeyM") {
+        e.preventDefault();
+        this.toggleNotes();
+        return false;
+      }
+
+      // Ctrl+Shift+E shows the program's stderr
+      if (e.type === "keydown" && e.ctrlKey && e.shiftKey && e.code === "KeyE") {
+        e.preventDefault();
+        const panel = document.getElementById("stderr");
+        if (panel) {
+          panel.classList.toggle("visible");
+        }
+        return false;
+      }
+
+      // Ctrl+Shift+H asks the host for attention, also from read-only sessions
+      if (e.type === "keydown" && e.ctrlKey && e.shiftKey && e.code === "KeyH") {
+        e.preventDefault();
+        const text = window.prompt("Message for the host (optional):", "");
+        if (text !== null) {
+          this.sendKnock(text);
+        }
+        return false;
+      }
+
+      // Ctrl+Shift+Y asks the host for write access when the session allows it
+      if (e.type === "keydown" && e.ctrlKey && e.shiftKey && e.code === "KeyY") {
+        e.preventDefault();
+        if (this.readonly && this.canRequestWrite) {
+          this.requestWrite();
+        }
+        return false;
+      }
+
+      // Ctrl+Shift+F forks the session into a copy of its own, when the server allows it
+      if (e.type === "keydown" && e.ctrlKey && e.shiftKey && e.code === "KeyF") {
+        e.preventDefault();
+        if (this.canFork) {
+          this.forkSession();
+        }
+        return false;
+      }
+
       // Handle Ctrl/Cmd combinations
       if (e.ctrlKey || e.metaKey) {
         switch (e.code) {
@@ -575,6 +795,472 @@ class TTYReceiver {
     this.terminal.options.selectionManager = true;
   }
 
+  showKeys(keys) {
+    const overlay = document.getElementById("keys");
+    if (!overlay) {
+      return;
+    }
+
+    // Keep the most recent keys, like a screencast key display
+    this.recentKeys = (this.recentKeys || []).concat(keys).slice(-20);
+    overlay.textContent = this.recentKeys.map((key) => ([...key].length > 1 ? `[${key}]` : key)).join(" ");
+    overlay.classList.add("visible");
+
+    clearTimeout(this.keysTimeout);
+    this.keysTimeout = setTimeout(() => {
+      overlay.classList.remove("visible");
+      this.recentKeys = [];
+    }, 2000);
+  }
+
+  // Write output, or a promise of it, after the output that arrived before it
+  writeOutput(output) {
+    this.pendingOutput = this.pendingOutput
+      .then(() => output)
+      .then((data) => {
+        this.terminal.write(data);
+        // Ensure terminal scrolls to bottom after new data
+        setTimeout(() => {
+          this.terminal.scrollToBottom();
+        }, 0);
+      })
+      .catch((e) => console.error("Failed to write output:", e));
+  }
+
+  showStats(stats) {
+    const overlay = document.getElementById("stats");
+    if (!overlay) {
+      return;
+    }
+
+    const percent = (used, total) => (total > 0 ? Math.round((used / total) * 100) : 0);
+    const parts = [
+      ["load", stats.Load[0].toFixed(2), false],
+      ["mem", `${percent(stats.MemoryTotal - stats.MemoryAvailable, stats.MemoryTotal)}%`, false],
+    ];
+    if (stats.SwapTotal > 0) {
+      const swap = percent(stats.SwapTotal - stats.SwapFree, stats.SwapTotal);
+      // Swapping is what viewers most want to notice
+      parts.push(["swap", `${swap}%`, swap >= 10]);
+    }
+    if (stats.Cpu !== null && stats.Cpu !== undefined) {
+      parts.push(["cpu", `${Math.round(stats.Cpu)}%`, false]);
+    }
+
+    overlay.replaceChildren();
+    parts.forEach(([name, value, warning], index) => {
+      const part = document.createElement("span");
+      part.textContent = `${name} ${value}`;
+      if (warning) {
+        part.className = "warning";
+      }
+      overlay.append(index > 0 ? " · " : "", part);
+    });
+    overlay.classList.add("visible");
+  }
+
+  showConfirmation(confirmation) {
+    const banner = document.getElementById("confirmation");
+    if (!banner) {
+      return;
+    }
+
+    const texts = {
+      Pending: "Waiting for the host to allow",
+      Allowed: "The host allowed",
+      Refused: "The host refused",
+      Trusted: "The host now trusts your commands and allowed",
+    };
+    banner.textContent = `${texts[confirmation.Status] || confirmation.Status}: ${confirmation.Line}`;
+    banner.classList.remove("error");
+    banner.classList.add("visible");
+
+    // Keep a pending command in view until it is answered
+    clearTimeout(this.confirmationTimeout);
+    if (confirmation.Status !== "Pending") {
+      this.confirmationTimeout = setTimeout(() => banner.classList.remove("visible"), 3000);
+    }
+  }
+
+  showError(message) {
+    const banner = document.getElementById("confirmation");
+    if (!banner) {
+      return;
+    }
+
+    banner.textContent = message;
+    banner.classList.add("visible", "error");
+    clearTimeout(this.confirmationTimeout);
+    this.confirmationTimeout = setTimeout(() => banner.classList.remove("visible", "error"), 5000);
+  }
+
+  // The session is about to end, e.g. at its idle timeout; shown longer than errors so it isn't missed
+  showWarning(message) {
+    const banner = document.getElementById("confirmation");
+    if (!banner) {
+      return;
+    }
+
+    banner.textContent = message;
+    banner.classList.remove("error");
+    banner.classList.add("visible");
+    clearTimeout(this.confirmationTimeout);
+    this.confirmationTimeout = setTimeout(() => banner.classList.remove("visible"), 15000);
+  }
+
+  announceLines(lines) {
+    const log = document.getElementById("screen-text");
+    if (!log) {
+      return;
+    }
+
+    for (const line of lines) {
+      const entry = document.createElement("div");
+      entry.textContent = line;
+      log.appendChild(entry);
+    }
+
+    // Keep the log short; screen readers only announce additions
+    while (log.childElementCount > 200) {
+      log.removeChild(log.firstChild);
+    }
+  }
+
+  requestHistory(start) {
+    if (!this.connection || this.connection.readyState !== WebSocket.OPEN) {
+      return;
+    }
+
+    try {
+      const historyRequest = {
+        Type: "HistoryRequest",
+        Data: base64Encode(JSON.stringify({ Start: start, Lines: this.terminal.rows })),
+      };
+      this.connection.send(JSON.stringify(historyRequest));
+    } catch (e) {
+      console.error("Error requesting history:", e);
+    }
+  }
+
+  showHistory(page) {
+    const pager = document.getElementById("pager");
+    if (!pager) {
+      return;
+    }
+
+    this.pagerOpen = true;
+    this.pagerStart = page.Start;
+    const end = page.Start + page.Lines.length;
+    document.getElementById("pager-title").textContent =
+      `History: lines ${page.Total === 0 ? 0 : page.Start + 1}-${end} of ${page.Total}` +
+      " (PageUp/PageDown, Home/End, Esc to close)";
+    document.getElementById("pager-lines").textContent = page.Lines.join("\n");
+    pager.classList.add("visible");
+  }
+
+  handlePagerKey(e) {
+    const rows = this.terminal.rows;
+    switch (e.code) {
+      case "PageUp":
+        this.requestHistory(Math.max(0, this.pagerStart - rows));
+        break;
+      case "PageDown":
+        this.requestHistory(this.pagerStart + rows);
+        break;
+      case "Home":
+        this.requestHistory(0);
+        break;
+      case "End":
+        this.requestHistory(null);
+        break;
+      case "Escape":
+      case "KeyQ":
+        this.pagerOpen = false;
+        document.getElementById("pager").classList.remove("visible");
+        this.terminal.focus();
+        break;
+    }
+  }
+
+  sendCapabilities() {
+    try {
+      // xterm.js renders 24-bit colors
+      const capabilitiesMessage = {
+        Type: "Capabilities",
+        Data: base64Encode(JSON.stringify({ Term: "xterm-256color", Colors: "truecolor" })),
+      };
+      this.connection.send(JSON.stringify(capabilitiesMessage));
+    } catch (e) {
+      console.error("Error sending capabilities:", e);
+    }
+  }
+
+  setupNotes() {
+    const editor = document.getElementById("notes-text");
+    if (!editor) {
+      return;
+    }
+
+    // Save edits once typing pauses, rather than on every key
+    editor.addEventListener("input", () => {
+      clearTimeout(this.notesSaveTimeout);
+      this.notesSaveTimeout = setTimeout(() => {
+        this.notesSaveTimeout = null;
+        this.sendNotes(editor.value);
+      }, 500);
+    });
+    editor.addEventListener("keydown", (e) => {
+      if (e.key === "Escape" || (e.ctrlKey && e.shiftKey && e.code === "KeyM")) {
+        e.preventDefault();
+        this.toggleNotes();
+      }
+    });
+
+    const entry = document.getElementById("timeline-text");
+    if (!entry) {
+      return;
+    }
+    entry.addEventListener("keydown", (e) => {
+      if (e.key === "Enter" && entry.value.trim()) {
+        e.preventDefault();
+        this.sendNote(entry.value);
+        entry.value = "";
+      } else if (e.key === "Escape" || (e.ctrlKey && e.shiftKey && e.code === "KeyM")) {
+        e.preventDefault();
+        this.toggleNotes();
+      }
+    });
+  }
+
+  toggleNotes() {
+    const panel = document.getElementById("notes");
+    const editor = document.getElementById("notes-text");
+    if (!panel || !editor) {
+      return;
+    }
+
+    if (panel.classList.toggle("visible")) {
+      editor.readOnly = this.readonly;
+      const entry = document.getElementById("timeline-text");
+      if (entry) {
+        entry.hidden = this.readonly;
+      }
+      if (this.notesSaveTimeout === null) {
+        editor.value = this.notes;
+      }
+      editor.focus();
+    } else {
+      this.terminal.focus();
+    }
+  }
+
+  showNotes(text) {
+    this.notes = text;
+    const editor = document.getElementById("notes-text");
+    // Keep what is being typed; it is sent shortly and then comes back from the server
+    if (editor && this.notesSaveTimeout === null) {
+      editor.value = text;
+    }
+    this.updateStatusBar();
+  }
+
+  sendNotes(text) {
+    if (this.readonly || !this.connection || this.connection.readyState !== WebSocket.OPEN) {
+      return;
+    }
+    if (new TextEncoder().encode(text).length > MAX_NOTES_LENGTH) {
+      console.warn(`Notes are limited to ${MAX_NOTES_LENGTH} bytes and were not saved`);
+      return;
+    }
+
+    try {
+      const notesMessage = {
+        Type: "Notes",
+        Data: base64Encode(JSON.stringify({ Text: text })),
+      };
+      this.connection.send(JSON.stringify(notesMessage));
+    } catch (e) {
+      console.error("Error sending notes:", e);
+    }
+  }
+
+  showTimeline(entries) {
+    this.timeline.push(...entries);
+    const list = document.getElementById("timeline");
+    if (list) {
+      for (const entry of entries) {
+        const item = document.createElement("li");
+        const time = document.createElement("time");
+        time.dateTime = entry.Time;
+        time.textContent = new Date(entry.Time).toLocaleTimeString();
+        time.title = `${entry.Time} by ${entry.Author}`;
+        item.append(time, entry.Text);
+        list.append(item);
+      }
+      list.scrollTop = list.scrollHeight;
+    }
+    this.updateStatusBar();
+  }
+
+  showStderr(data) {
+    const text = this.stderrDecoder.decode(base64StringToArrayBuffer(data), { stream: true });
+    // Shown as plain text, without the colors and cursor movements of the program
+    const plain = text.replace(/\x1b(\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(\x07|\x1b\\)|.)|\r/g, "");
+    const firstOutput = !this.stderr;
+    this.stderr = (this.stderr + plain).slice(-MAX_STDERR_LENGTH);
+
+    const lines = document.getElementById("stderr-lines");
+    if (lines) {
+      lines.textContent = this.stderr;
+      lines.scrollTop = lines.scrollHeight;
+    }
+    if (firstOutput) {
+      this.updateStatusBar();
+    }
+  }
+
+  sendNote(text) {
+    if (this.readonly || !this.connection || this.connection.readyState !== WebSocket.OPEN) {
+      return;
+    }
+
+    try {
+      const noteMessage = {
+        Type: "Note",
+        Data: base64Encode(JSON.stringify({ Text: text })),
+      };
+      this.connection.send(JSON.stringify(noteMessage));
+    } catch (e) {
+      console.error("Error sending timeline entry:", e);
+    }
+  }
+
+  sendKnock(text) {
+    if (!this.connection || this.connection.readyState !== WebSocket.OPEN) {
+      return;
+    }
+
+    try {
+      const knockMessage = {
+        Type: "Knock",
+        Data: base64Encode(JSON.stringify({ Message: text })),
+      };
+      this.connection.send(JSON.stringify(knockMessage));
+    } catch (e) {
+      console.error("Error sending knock:", e);
+    }
+  }
+
+  setFollow(follow) {
+    if (!this.connection || this.connection.readyState !== WebSocket.OPEN) {
+      return;
+    }
+
+    try {
+      const followMessage = {
+        Type: "Follow",
+        Data: base64Encode(JSON.stringify({ Follow: follow })),
+      };
+      this.connection.send(JSON.stringify(followMessage));
+    } catch (e) {
+      console.error("Error sending follow message:", e);
+      return;
+    }
+    this.paused = !follow;
+    this.updateStatusBar();
+  }
+
+  requestWrite() {
+    if (!this.connection || this.connection.readyState !== WebSocket.OPEN) {
+      return;
+    }
+
+    try {
+      const requestMessage = {
+        Type: "RequestWrite",
+        Data: base64Encode("{}"),
+      };
+      this.connection.send(JSON.stringify(requestMessage));
+    } catch (e) {
+      console.error("Error requesting write access:", e);
+      return;
+    }
+
+    // Keep the request in view until the host answers
+    const banner = document.getElementById("confirmation");
+    if (banner) {
+      banner.textContent = "Waiting for the host to let you type";
+      banner.classList.remove("error");
+      banner.classList.add("visible");
+      clearTimeout(this.confirmationTimeout);
+    }
+  }
+
+  forkSession() {
+    if (!this.connection || this.connection.readyState !== WebSocket.OPEN || this.forkTab) {
+      return;
+    }
+
+    try {
+      const forkMessage = {
+        Type: "Fork",
+        Data: base64Encode("{}"),
+      };
+      this.connection.send(JSON.stringify(forkMessage));
+    } catch (e) {
+      console.error("Error forking the session:", e);
+      return;
+    }
+
+    // Open the tab right away, while the key press still allows it, and point it at the copy once it runs
+    this.forkTab = window.open("", "_blank");
+    const banner = document.getElementById("confirmation");
+    if (banner) {
+      banner.textContent = "Forking the session";
+      banner.classList.remove("error");
+      banner.classList.add("visible");
+      clearTimeout(this.confirmationTimeout);
+    }
+  }
+
+  openFork(url) {
+    if (this.forkTab && !this.forkTab.closed) {
+      this.forkTab.location.href = url;
+    } else {
+      window.open(url, "_blank");
+    }
+    this.forkTab = null;
+
+    const banner = document.getElementById("confirmation");
+    if (!banner) {
+      return;
+    }
+
+    banner.textContent = `Your copy of the session is at ${url}`;
+    banner.classList.remove("error");
+    banner.classList.add("visible");
+    clearTimeout(this.confirmationTimeout);
+    this.confirmationTimeout = setTimeout(() => banner.classList.remove("visible"), 10000);
+  }
+
+  showWriteAccess(granted) {
+    if (!granted) {
+      this.showError("The host denied write access");
+      return;
+    }
+
+    const banner = document.getElementById("confirmation");
+    if (!banner) {
+      return;
+    }
+
+    banner.textContent = "The host allowed you to type";
+    banner.classList.remove("error");
+    banner.classList.add("visible");
+    clearTimeout(this.confirmationTimeout);
+    this.confirmationTimeout = setTimeout(() => banner.classList.remove("visible"), 3000);
+  }
+
   sendTerminalResize() {
     // Only send resize messages to server if in headless mode
     if (!this.headless || !this.connection || this.connection.readyState !== WebSocket.OPEN) {
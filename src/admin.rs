@@ -0,0 +1,79 @@
+use crate::auth::AuthToken;
+use crate::fingerprint::ClientFingerprint;
+use crate::geoip::Location;
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// How a WebSocket client connected, kept for the admin API
+#[derive(Clone, Debug)]
+pub struct ClientConnection {
+    pub address: IpAddr,
+    pub fingerprint: ClientFingerprint,
+    pub connected_at: SystemTime,
+    pub readonly: bool,
+    /// Cancelled to disconnect the client
+    pub kick: CancellationToken,
+}
+
+/// A connected client as listed by `GET /api/clients`
+#[derive(Serialize)]
+pub struct ClientInfo {
+    pub id: String,
+    pub session: String,
+    pub address: IpAddr,
+    /// Short description of the client, e.g. "Firefox on Linux", after the name on its certificate if any
+    pub client: String,
+    pub user_agent: Option<String>,
+    /// Name on the client certificate it presented with `--tls-client-ca`
+    pub certificate: Option<String>,
+    /// RFC 3339 time the client connected at
+    pub connected_at: String,
+    pub readonly: bool,
+    pub location: Option<Location>,
+}
+
+impl ClientInfo {
+    pub fn new(id: &str, session: &str, connection: &ClientConnection, location: Option<Location>) -> Self {
+        Self {
+            id: id.to_string(),
+            session: session.to_string(),
+            address: connection.address,
+            client: connection.fingerprint.describe(),
+            user_agent: connection.fingerprint.user_agent.clone(),
+            certificate: connection.fingerprint.certificate.clone(),
+            connected_at: humantime::format_rfc3339_seconds(connection.connected_at).to_string(),
+            readonly: connection.readonly,
+            location,
+        }
+    }
+}
+
+/// Refuse requests without the admin token given with `--admin-token` as a bearer token, with 401. Unlike
+/// viewer tokens, it is never taken from the query or a cookie, so it doesn't end up in shared links.
+pub async fn require_admin_token(State(token): State<Arc<AuthToken>>, request: Request, next: Next) -> Response {
+    let candidate = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !candidate.is_some_and(|candidate| token.matches(candidate)) {
+        debug!("Refused a request to {} without the admin token", request.uri().path());
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "The admin token is required",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,212 @@
+use crate::fork;
+use crate::host::headless_host;
+use crate::notes::Notes;
+use crate::server::{RegisteredSession, RwShellServer, Session, SessionRegistry, broadcast_close, session_routes};
+use crate::session_id;
+use crate::timeline::Timeline;
+use crate::websocket::CloseReason;
+use axum::{
+    Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
+};
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+/// Session IDs generated for a session API request before giving up on finding a free one
+const MAX_ID_ATTEMPTS: usize = 16;
+
+/// Most lines `GET /api/sessions/{id}/lines` returns at once
+const MAX_LINES_PER_PAGE: usize = 1000;
+
+/// A session as listed by the session API
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub command: String,
+    /// Join URL of the session, with the token if one is required
+    pub url: String,
+    pub clients: usize,
+}
+
+/// Body of a request to start a session through the session API
+#[derive(Deserialize)]
+pub struct NewSessionRequest {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// ID to serve the session under instead of a generated one
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+/// Routes of the session API at `/api/sessions`, served with `--session-api`
+pub fn routes(server: Arc<RwShellServer>) -> Router {
+    Router::new()
+        .route("/api/sessions", get(list_sessions).post(create_session))
+        .route("/api/sessions/{id}", delete(end_session))
+        .route("/api/sessions/{id}/fork", post(fork_session))
+        .with_state(server)
+}
+
+/// Query of `GET /api/sessions/{id}/lines`
+#[derive(Deserialize)]
+pub struct LinesQuery {
+    /// Number of the last line already seen, 0 for all lines still kept
+    #[serde(default)]
+    pub since: u64,
+    /// Most lines to return, up to 1000
+    pub limit: Option<usize>,
+}
+
+/// Lines of session `id`'s output after `since`, as plain text, for pollers that follow it like a log.
+/// Served for every session, whether or not `--session-api` is given.
+pub(crate) async fn serve_lines(
+    Path(id): Path<String>,
+    Query(query): Query<LinesQuery>,
+    State(sessions): State<SessionRegistry>,
+) -> Response {
+    let Some(session) = sessions.get(&id) else {
+        return (StatusCode::NOT_FOUND, "No such session").into_response();
+    };
+    let limit = query.limit.unwrap_or(MAX_LINES_PER_PAGE).min(MAX_LINES_PER_PAGE);
+    Json(session.state.lines.since(query.since, limit)).into_response()
+}
+
+/// What the session API lists about session `id`
+pub(crate) async fn session_info(server: &RwShellServer, id: &str, session: &RegisteredSession) -> SessionInfo {
+    let url = server.session_url(id);
+    let auth_token = server.auth_token.as_deref();
+    SessionInfo {
+        id: id.to_string(),
+        command: session.command.clone(),
+        url: match auth_token {
+            Some(token) => token.add_to(&url),
+            None => url,
+        },
+        clients: session.state.clients.lock().await.len(),
+    }
+}
+
+/// List the sessions this server hosts
+async fn list_sessions(State(server): State<Arc<RwShellServer>>) -> Json<Vec<SessionInfo>> {
+    let mut sessions = Vec::new();
+    for (id, session) in server.sessions.all() {
+        sessions.push(session_info(&server, &id, &session).await);
+    }
+    Json(sessions)
+}
+
+/// Start a session running the command in the request, without a host terminal, and answer with its URL
+async fn create_session(State(server): State<Arc<RwShellServer>>, Json(request): Json<NewSessionRequest>) -> Response {
+    let sessions = &server.sessions;
+    let id = match request.id {
+        Some(id) => match session_id::parse_session_id(&id) {
+            Ok(id) if sessions.get(&id).is_some() => {
+                return (StatusCode::CONFLICT, format!("Session {id} already exists")).into_response();
+            }
+            Ok(id) => id,
+            Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid session ID: {e}")).into_response(),
+        },
+        None => match free_session_id(&server) {
+            Some(id) => id,
+            None => return (StatusCode::SERVICE_UNAVAILABLE, "No free session ID left").into_response(),
+        },
+    };
+    let mut cmd = CommandBuilder::new(&request.command);
+    cmd.args(&request.args);
+    let command = std::iter::once(request.command)
+        .chain(request.args)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let size = (server.args.headless_cols, server.args.headless_rows);
+    let session = match launch_session(&server, id.clone(), cmd, command, size) {
+        Ok(session) => session,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to start the session: {e:#}")).into_response(),
+    };
+
+    (StatusCode::CREATED, Json(session_info(&server, &id, &session).await)).into_response()
+}
+
+/// A generated ID no session has yet
+pub(crate) fn free_session_id(server: &RwShellServer) -> Option<String> {
+    // Short and word IDs may come up twice
+    (0..MAX_ID_ATTEMPTS)
+        .map(|_| server.ids.generate())
+        .find(|id| server.sessions.get(id).is_none())
+}
+
+/// Start session `id` running `cmd`, shown as `command`, in a `cols` by `rows` PTY without a host terminal,
+/// and serve it until its program exits or it is ended
+pub(crate) fn launch_session(
+    server: &Arc<RwShellServer>,
+    id: String,
+    cmd: CommandBuilder,
+    command: String,
+    (cols, rows): (u16, u16),
+) -> anyhow::Result<RegisteredSession> {
+    let sessions = &server.sessions;
+    let host = headless_host(cols, rows);
+    let Session {
+        state,
+        cancellation_token,
+        mut ended,
+    } = server.start_session(&id, cmd, Arc::clone(&host), Notes::default(), Timeline::default(), None)?;
+    host.start(&state, cancellation_token.clone());
+
+    info!("Started session {} running `{}`", id, command);
+    let session = RegisteredSession {
+        command,
+        router: session_routes(state.clone()),
+        state,
+        cancellation_token,
+    };
+    sessions.insert(id.clone(), session.clone());
+
+    // Forget the session once its program exits or it is ended through the API
+    let ended_sessions = sessions.clone();
+    let ended_id = id.clone();
+    tokio::spawn(async move {
+        let exit_code = ended.recv().await;
+        ended_sessions.remove(&ended_id);
+        info!("Session {} ended with code {}", ended_id, exit_code.unwrap_or(0));
+    });
+
+    Ok(session)
+}
+
+/// Start a copy of session `id`, as [`fork::fork_session`] does, and answer with its URL
+async fn fork_session(Path(id): Path<String>, State(server): State<Arc<RwShellServer>>) -> Response {
+    let Some(parent) = server.sessions.get(&id) else {
+        return (StatusCode::NOT_FOUND, "No such session").into_response();
+    };
+    match fork::fork_session(&server, &parent.state).await {
+        Ok(info) => (StatusCode::CREATED, Json(info)).into_response(),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Failed to fork the session: {e:#}"),
+        )
+            .into_response(),
+    }
+}
+
+/// End a session started through the session API, killing its program
+async fn end_session(Path(id): Path<String>, State(server): State<Arc<RwShellServer>>) -> Response {
+    if id == server.session_id {
+        return (StatusCode::CONFLICT, "The main session ends when its program exits").into_response();
+    }
+    let Some(session) = server.sessions.get(&id) else {
+        return (StatusCode::NOT_FOUND, "No such session").into_response();
+    };
+    broadcast_close(
+        &session.state.pty_tx,
+        CloseReason::ServerShutdown,
+        "The session was ended",
+    );
+    session.cancellation_token.cancel();
+    StatusCode::NO_CONTENT.into_response()
+}
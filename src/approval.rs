@@ -0,0 +1,109 @@
+use crate::host::HostMode;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::info;
+
+/// How long a viewer asking for write access waits for the host before it is denied
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Answer to the oldest viewer asking for write access, sent to the control socket by `rwshell approve`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApproveMessage {
+    #[serde(rename = "Allow")]
+    pub allow: bool,
+}
+
+/// Reply of the control socket to an [`ApproveMessage`]: the viewer that was answered, if any was waiting
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApprovedMessage {
+    #[serde(rename = "Client")]
+    pub client_id: Option<String>,
+}
+
+/// A viewer waiting for write access
+struct Pending {
+    client_id: String,
+    reply: oneshot::Sender<bool>,
+}
+
+/// Lets viewers that join read-only with `--approve-writers` ask the host for write access
+#[derive(Default)]
+pub struct ApprovalGate {
+    pending: std::sync::Mutex<VecDeque<Pending>>,
+}
+
+impl ApprovalGate {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Pending>> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget viewers that left meanwhile
+        pending.retain(|pending| !pending.reply.is_closed());
+        pending
+    }
+
+    /// Ask the host whether viewer `client_id` may type, denying it if there's no answer in time
+    pub async fn ask(&self, host: &dyn HostMode, client_id: &str) -> bool {
+        let (reply, answer) = oneshot::channel();
+        self.lock().push_back(Pending {
+            client_id: client_id.to_string(),
+            reply,
+        });
+        // Without a terminal, the host answers from another shell
+        let how = if host.headless() {
+            "run `rwshell approve` to allow or `rwshell approve --deny` to deny"
+        } else {
+            "press y to allow or n to deny"
+        };
+        host.notify(&format!("Viewer {client_id} asks to type: {how}"));
+        matches!(tokio::time::timeout(APPROVAL_TIMEOUT, answer).await, Ok(Ok(true)))
+    }
+
+    /// Whether a viewer is waiting for write access
+    pub fn waiting(&self) -> bool {
+        !self.lock().is_empty()
+    }
+
+    /// Answer the oldest viewer waiting for write access, returning its client ID
+    pub fn answer(&self, allow: bool) -> Option<String> {
+        let pending = self.lock().pop_front()?;
+        info!(
+            "Host {} write access to viewer {}",
+            if allow { "granted" } else { "denied" },
+            pending.client_id
+        );
+        let _ = pending.reply.send(allow);
+        Some(pending.client_id)
+    }
+
+    /// Take host keystrokes as the answer while a viewer is waiting: "y" allows it to type and "n" denies
+    /// it. Returns whether the keystrokes were taken.
+    pub fn host_input(&self, host: &dyn HostMode, data: &[u8]) -> bool {
+        if !self.waiting() {
+            return false;
+        }
+        let answer = data.iter().find_map(|byte| match byte {
+            b'y' | b'Y' => Some(true),
+            b'n' | b'N' => Some(false),
+            _ => None,
+        });
+        match answer {
+            Some(allow) => {
+                if let Some(client_id) = self.answer(allow) {
+                    host.notify(&answer_text(allow, &client_id));
+                }
+            }
+            None => host.notify("A viewer asks to type: press y to allow or n to deny"),
+        }
+        true
+    }
+}
+
+/// How the host's answer to viewer `client_id` is reported
+pub fn answer_text(allow: bool, client_id: &str) -> String {
+    if allow {
+        format!("Viewer {client_id} may type now")
+    } else {
+        format!("Denied write access to viewer {client_id}")
+    }
+}
@@ -1,8 +1,13 @@
+use crate::compression::Compression;
+use crate::session_id::IdStyle;
+use crate::term::ColorDepth;
+use crate::transform::OutputColors;
 use clap::Parser;
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "rwshell")]
 #[command(about = "Share your terminal over the web")]
+#[command(args_override_self = true)]
 pub struct Args {
     /// The command to run
     #[arg(long, default_value_t = get_default_shell())]
@@ -12,10 +17,19 @@ pub struct Args {
     #[arg(long, default_value = "")]
     pub args: String,
 
+    /// Environment variable for the command, as NAME=VALUE; may be repeated
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_env)]
+    pub env: Vec<(String, String)>,
+
     /// rwshell server address
     #[arg(long, default_value = "localhost:8000")]
     pub listen: String,
 
+    /// Address viewers reach the server at behind a reverse proxy, e.g. https://example.com/terminals/,
+    /// used in printed URLs and in the links and WebSocket paths of served pages
+    #[arg(long, value_name = "URL")]
+    pub base_url: Option<url::Url>,
+
     /// Print the rwshell version
     #[arg(long)]
     pub version: bool,
@@ -25,9 +39,70 @@ pub struct Args {
     pub readonly: bool,
 
     /// Don't expect an interactive terminal at stdin
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub headless: bool,
 
+    /// Start a session even inside another rwshell session, where the outer session would show the inner
+    /// one's output back to itself
+    #[arg(long)]
+    pub allow_nested: bool,
+
+    /// Send the command's stderr to viewers apart from the terminal, instead of through the PTY, so
+    /// diagnostics of a non-interactive job can be told apart from its output
+    #[arg(long, requires = "headless")]
+    pub separate_stderr: bool,
+
+    /// Run the command with pipes instead of a PTY, so its output is the same as when redirected to a
+    /// file: no colors or progress bars turned on by a terminal. Viewers still see it in a terminal.
+    #[arg(long, requires = "headless", conflicts_with = "compare")]
+    pub no_pty: bool,
+
+    /// Have the helper started with `rwshell pty-helper` listening on this socket run its command and hand
+    /// over the PTY, instead of running --command here, e.g. so this network-facing server can run as an
+    /// unprivileged user
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["no_pty", "compare", "separate_stderr", "session_api", "allow_fork", "suspend_after", "start_at", "start_on_connect"]
+    )]
+    pub pty_socket: Option<std::path::PathBuf>,
+
+    /// Once set up and the command started, drop the server's capabilities, keep it from gaining
+    /// privileges and refuse system calls it has no use for with a seccomp filter (Linux only)
+    #[arg(
+        long,
+        conflicts_with_all = ["session_api", "allow_fork", "start_at", "start_on_connect"]
+    )]
+    pub harden: bool,
+
+    /// Serve the session right away but only start the command at this time: a time of day such as
+    /// "14:30", an RFC 3339 time such as "2026-03-01T14:30:00Z", or a delay such as "15m". Viewers see a
+    /// waiting notice until then.
+    #[arg(long, value_name = "TIME", requires = "headless", conflicts_with = "no_pty", value_parser = crate::schedule::parse_start_time)]
+    pub start_at: Option<std::time::SystemTime>,
+
+    /// Serve the session right away but only start the command once the first viewer connects
+    #[arg(long, requires = "headless", conflicts_with_all = ["no_pty", "start_at"])]
+    pub start_on_connect: bool,
+
+    /// Suspend the command once no viewer has been connected for this long (e.g. "10m"), as --suspend-mode
+    /// says, until a viewer connects again
+    #[arg(long, value_name = "DURATION", requires = "headless", conflicts_with = "no_pty", value_parser = humantime::parse_duration)]
+    pub suspend_after: Option<std::time::Duration>,
+
+    /// How --suspend-after suspends the command: stop it with SIGSTOP, or restart it from scratch
+    #[arg(long, value_enum, default_value = "stop")]
+    pub suspend_mode: crate::suspend::SuspendMode,
+
+    /// End the session once its command had no input or output for this long (e.g. "30m"), warning viewers
+    /// a minute before
+    #[arg(long, value_name = "DURATION", requires = "headless", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Option<std::time::Duration>,
+
+    /// End the session once its command ran for this long (e.g. "2h"), warning viewers a minute before
+    #[arg(long, value_name = "DURATION", requires = "headless", value_parser = humantime::parse_duration)]
+    pub max_duration: Option<std::time::Duration>,
+
     /// Number of cols for the allocated pty when running headless
     #[arg(long, default_value = "80")]
     pub headless_cols: u16,
@@ -36,15 +111,623 @@ pub struct Args {
     #[arg(long, default_value = "25")]
     pub headless_rows: u16,
 
-    /// Generate a random UUID for the session URL
+    /// TERM exported to the command (default: matches --colors)
+    #[arg(long)]
+    pub term: Option<String>,
+
+    /// Colors the command is told it may use (default: detected from the host terminal, truecolor when headless)
+    #[arg(long, value_enum)]
+    pub colors: Option<ColorDepth>,
+
+    /// Convert output colors for viewers: auto (follow the least capable participant), none, 16 or 256
+    #[arg(long)]
+    pub output_colors: Option<OutputColors>,
+
+    /// Remove all escape sequences from output sent to viewers, e.g. for plain text transcripts
+    #[arg(long)]
+    pub strip_ansi: bool,
+
+    /// Follow the screen as plain text lines for screen readers, served at /s/<session>/text
+    #[arg(long)]
+    pub screen_reader: bool,
+
+    /// Show viewers the host's load and memory and the command's CPU usage, updated every few seconds
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Show the host's keystrokes to viewers, except while the program hides input such as passwords
+    #[arg(long)]
+    pub show_keys: bool,
+
+    /// Show the session URL in the host terminal title and as a clickable link, printed again on --url-key
+    #[arg(long)]
+    pub show_url: bool,
+
+    /// Print the session URL as a QR code at start, to join from a phone; with --show-url and --invites,
+    /// --url-key and --invite-key print one too
+    #[arg(long)]
+    pub qr: bool,
+
+    /// Key that prints the session URL again with --show-url, e.g. "ctrl-]"
+    #[arg(long, default_value = "ctrl-]", value_parser = parse_control_key)]
+    pub url_key: u8,
+
+    /// Mark output that follows a viewer's typing on the host terminal with a dim note naming the viewer,
+    /// turned off and on again with --writers-key
+    #[arg(long)]
+    pub show_writers: bool,
+
+    /// Key that turns the markers of --show-writers off and on, e.g. "ctrl-^"
+    #[arg(long, default_value = "ctrl-^", value_parser = parse_control_key)]
+    pub writers_key: u8,
+
+    /// Tell the host whenever a viewer connects or leaves, with its address and the number of viewers
+    #[arg(long)]
+    pub announce_viewers: bool,
+
+    /// WebAssembly plugin that transforms or observes output sent to viewers; may be repeated
+    #[arg(long = "plugin", value_name = "WASM")]
+    pub plugins: Vec<std::path::PathBuf>,
+
+    /// Lua script defining hooks for session events, e.g. to refuse viewers or filter their input
+    #[arg(long)]
+    pub script: Option<std::path::PathBuf>,
+
+    /// Send a session event to a sink, as EVENT=SINK (e.g. "failure=email:me@example.com"); may be
+    /// repeated. Events: exit, failure, viewer-join, viewer-leave, write-request, confirm. Sinks:
+    /// slack:URL, webhook:URL, email:ADDRESS, exec:COMMAND
+    #[arg(long, value_name = "EVENT=SINK", value_parser = crate::notify::parse_rule)]
+    pub notify: Vec<crate::notify::NotifyRule>,
+
+    /// Mail server that `email:` notification sinks send through, with plain SMTP
+    #[arg(long, value_name = "HOST:PORT", default_value = "localhost:25")]
+    pub smtp_server: String,
+
+    /// Sender of notification mails; defaults to rwshell@ this machine's name
+    #[arg(long, value_name = "ADDRESS")]
+    pub smtp_from: Option<String>,
+
+    /// Generate a random ID for the session URL, in the style of --session-ids
     #[arg(long)]
     pub uuid: bool,
 
+    /// Serve the session under this ID instead of "local", e.g. a name that's easy to read out
+    #[arg(
+        long,
+        visible_alias = "session-name",
+        value_name = "ID",
+        conflicts_with = "uuid",
+        value_parser = crate::session_id::parse_session_id
+    )]
+    pub session_id: Option<String>,
+
+    /// Style of the session IDs generated for --uuid and the session API: uuid, short, words or sequential
+    #[arg(long, value_name = "STYLE", value_enum, default_value = "uuid")]
+    pub session_ids: IdStyle,
+
+    /// Serve an API at /api/sessions to start and end more sessions in this server at runtime, each
+    /// running its own command under /s/{id}/, for requests carrying the --admin-token
+    #[arg(long, requires = "admin_token", conflicts_with_all = ["readonly", "capabilities"])]
+    pub session_api: bool,
+
+    /// Let viewers who can type fork the session with Ctrl+Shift+F: start a copy of its command, with its
+    /// environment and current directory, as a new session of their own to experiment in
+    #[arg(long, conflicts_with_all = ["readonly", "capabilities"])]
+    pub allow_fork: bool,
+
+    /// Register the session into a group created with `rwshell group create`, given its join URL
+    #[arg(long, value_name = "URL")]
+    pub group: Option<String>,
+
+    /// Name of the session on the group page (default: command@hostname)
+    #[arg(long, requires = "group")]
+    pub group_label: Option<String>,
+
+    /// Also push the session's output, read-only, to a relay started with `rwshell relay`, given its mirror URL
+    #[arg(long, value_name = "URL")]
+    pub mirror: Option<url::Url>,
+
+    /// Share the session through a relay started with `rwshell relay`, given its tunnel URL: viewers reach
+    /// the session at a URL of the relay, with no port forwarded to this machine
+    #[arg(long, value_name = "URL", conflicts_with = "base_url")]
+    pub public: Option<url::Url>,
+
+    /// Experimental: also broadcast the session's output over UDP multicast on the local network, for
+    /// `rwshell-client --multicast` to show (e.g. 239.255.42.42:4242). Anyone on the network can receive
+    /// it, so it can't be combined with options that restrict who may watch.
+    #[arg(
+        long,
+        value_name = "GROUP:PORT",
+        value_parser = crate::multicast::parse_group,
+        conflicts_with_all = ["auth_token", "generate_auth_token", "htpasswd", "invites", "capabilities", "allow_cidr", "deny_cidr", "tls_client_ca"]
+    )]
+    pub multicast: Option<std::net::SocketAddrV4>,
+
+    /// Hold viewer input that would run a command line matching this regular expression until the host
+    /// allows it (e.g. "rm -rf|drop table"); may be repeated
+    #[arg(long = "confirm-pattern", value_name = "REGEX")]
+    pub confirm_patterns: Vec<regex::Regex>,
+
+    /// Hold every command line a viewer types until the host accepts it, until the host trusts that
+    /// viewer, e.g. while mentoring someone on a live system
+    #[arg(long)]
+    pub training_wheels: bool,
+
+    /// Let web clients that could type join read-only and ask the host for write access, which the host
+    /// allows in its terminal or with `rwshell approve`
+    #[arg(long, conflicts_with = "readonly")]
+    pub approve_writers: bool,
+
+    /// Markdown file keeping the session's shared notes, e.g. next to a recording of the session as
+    /// "incident.notes.md" for "incident.cast"; it is read at start and rewritten on every change
+    #[arg(long, value_name = "FILE")]
+    pub notes_file: Option<std::path::PathBuf>,
+
+    /// File keeping the session's timeline, one JSON entry per line, e.g. next to a recording of the
+    /// session as "incident.timeline.jsonl" for "incident.cast"; it is read at start and each new entry
+    /// is appended to it
+    #[arg(long, value_name = "FILE")]
+    pub timeline_file: Option<std::path::PathBuf>,
+
+    /// Record the session to this file in the asciicast v2 format, with the output viewers see and each
+    /// resize of the terminal, e.g. for `rwshell export` or `rwshell render`
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<std::path::PathBuf>,
+
+    /// Also run the command on a second backend, shown side by side with the first to compare how it
+    /// behaves: "local" for another PTY here, or "ssh:[USER@]HOST"
+    #[arg(long, value_name = "BACKEND")]
+    pub compare: Option<crate::panes::Backend>,
+
+    /// With --compare, send input to both panes from the start, rather than the left one
+    #[arg(long, requires = "compare")]
+    pub sync_input: bool,
+
+    /// Key that starts a pane command with --compare or `rwshell multi`: then "s" toggles typing into every
+    /// pane, a pane number or Tab picks the pane that receives input
+    #[arg(long, default_value = "ctrl-\\", value_parser = parse_control_key)]
+    pub pane_key: u8,
+
+    /// PEM certificate chain to serve HTTPS with, instead of leaving TLS to a reverse proxy
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<std::path::PathBuf>,
+
+    /// PEM certificates of the CAs whose client certificates viewers may present over TLS, to tell the host
+    /// who they are; viewers without one still connect
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_client_ca: Option<std::path::PathBuf>,
+
+    /// Also listen for plain HTTP on this port of the --listen address, redirecting every request to HTTPS
+    #[arg(long, value_name = "PORT", requires = "tls_cert")]
+    pub http_redirect_port: Option<u16>,
+
+    /// Require this token from viewers, in the `token` query parameter of session URLs or in a cookie
+    #[arg(long, value_parser = crate::auth::parse_token)]
+    pub auth_token: Option<String>,
+
+    /// Require a random token from viewers, added to the printed session URLs
+    #[arg(long, conflicts_with = "auth_token")]
+    pub generate_auth_token: bool,
+
+    /// Require viewers to log in with HTTP Basic Auth as a user of this htpasswd file, whose passwords are
+    /// hashed with bcrypt (`htpasswd -B`) or SHA-1 (`htpasswd -s`)
+    #[arg(long, value_name = "FILE")]
+    pub htpasswd: Option<std::path::PathBuf>,
+
+    /// Serve an admin API at /api/clients to list and disconnect viewers, for requests carrying this token
+    /// in an `Authorization: Bearer` header
+    #[arg(long, value_parser = crate::auth::parse_token)]
+    pub admin_token: Option<String>,
+
+    /// Let viewers in only through single-use invite links, minted with POST /api/invites (with
+    /// --admin-token) or --invite-key; each link works for the first viewer to open it, until --invite-ttl
+    #[arg(long, conflicts_with = "capabilities")]
+    pub invites: bool,
+
+    /// Lifetime of invite links not used yet (e.g. "10m")
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    pub invite_ttl: std::time::Duration,
+
+    /// Key that prints a new invite link on the host terminal with --invites, e.g. "ctrl-_"
+    #[arg(long, default_value = "ctrl-_", value_parser = parse_control_key)]
+    pub invite_key: u8,
+
+    /// Use signed capability tokens instead of a fixed session path
+    #[arg(long)]
+    pub capabilities: bool,
+
+    /// Revoke a capability token once it is used from a second address, instead of only warning the host
+    #[arg(long)]
+    pub strict_token_binding: bool,
+
+    /// Lifetime of minted capability tokens (e.g. "8h", "30m"); tokens don't expire if unset
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub capability_ttl: Option<std::time::Duration>,
+
+    /// Let each minted capability token open at most this many WebSocket connections
+    #[arg(long, value_name = "COUNT", requires = "capabilities")]
+    pub capability_max_connects: Option<u32>,
+
+    /// Close the connections of each minted capability token once they received this much terminal
+    /// output in total (e.g. "10M")
+    #[arg(long, value_name = "SIZE", requires = "capabilities", value_parser = parse_size)]
+    pub capability_max_bytes: Option<usize>,
+
+    /// Close the connections of each minted capability token once they have been open this long in
+    /// total (e.g. "10m"), for links meant for a quick look
+    #[arg(long, value_name = "DURATION", requires = "capabilities", value_parser = humantime::parse_duration)]
+    pub capability_max_time: Option<std::time::Duration>,
+
+    /// Number of output messages buffered per client before it is considered lagging
+    #[arg(long, default_value = "1024")]
+    pub broadcast_capacity: usize,
+
+    /// Recent output kept as the scrollback of clients that join and for the session history (e.g. "2M")
+    #[arg(long, default_value = "64K", value_parser = parse_size)]
+    pub buffer_size: usize,
+
+    /// Most output held back for a viewer that paused it, beyond which it gets the current screen once it
+    /// follows the output again (e.g. "4M")
+    #[arg(long, default_value = "1M", value_parser = parse_size)]
+    pub pause_buffer: usize,
+
+    /// Most memory the buffered output of all sessions may take up together: history, lines kept for the
+    /// lines API and output held back for paused viewers. The oldest is dropped first beyond it (e.g. "256M")
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub memory_budget: Option<usize>,
+
+    /// How many times faster than it came in output held back during a pause is replayed
+    #[arg(long, value_name = "FACTOR", default_value = "4")]
+    pub catch_up_speed: u32,
+
+    /// Compress output sent to clients of the binary protocol that support it: none, deflate or zstd
+    #[arg(long, value_enum, default_value = "none")]
+    pub compression: Compression,
+
+    /// Largest incoming WebSocket message or decoded write accepted from clients (e.g. "64K")
+    #[arg(long, default_value = "64K", value_parser = parse_size)]
+    pub max_input_frame: usize,
+
+    /// Time allowed for a client to send its HTTP request headers, including the WebSocket upgrade
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub handshake_timeout: std::time::Duration,
+
+    /// Send small writes such as echoed keystrokes right away instead of batching them (TCP_NODELAY)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    /// Probe connections silent for this long and drop those whose peer is gone ("0s" to turn off)
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    pub tcp_keepalive: std::time::Duration,
+
+    /// Keep HTTP/1.1 connections open for further requests
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    pub keep_alive: bool,
+
+    /// Offer HTTP/2 to browsers over TLS; WebSocket connections still use HTTP/1.1
+    #[arg(long, requires = "tls_cert")]
+    pub http2: bool,
+
+    /// Most concurrent streams per HTTP/2 connection (default: 200)
+    #[arg(long, value_name = "N")]
+    pub http2_max_streams: Option<u32>,
+
+    /// Ping idle HTTP/2 connections this often, to notice dead ones (e.g. "30s")
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub http2_keep_alive_interval: Option<std::time::Duration>,
+
+    /// Close HTTP/2 connections whose ping isn't answered within this time
+    #[arg(long, value_name = "DURATION", default_value = "20s", value_parser = humantime::parse_duration)]
+    pub http2_keep_alive_timeout: std::time::Duration,
+
+    /// Close WebSocket connections that send nothing within this time after connecting (e.g. "30s")
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub first_message_timeout: Option<std::time::Duration>,
+
+    /// Close WebSocket connections once they have been open this long (e.g. "12h")
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub max_connection_lifetime: Option<std::time::Duration>,
+
+    /// Ping WebSocket clients this often, to notice connections whose client is gone ("0s" to turn off)
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = humantime::parse_duration)]
+    pub ping_interval: std::time::Duration,
+
+    /// Close WebSocket connections whose client doesn't answer a ping within this time
+    #[arg(long, value_name = "DURATION", default_value = "20s", value_parser = humantime::parse_duration)]
+    pub ping_timeout: std::time::Duration,
+
+    /// Session page loads and WebSocket connections allowed per client address per minute (0 for no limit)
+    #[arg(long, default_value = "120")]
+    pub rate_limit_per_ip: u32,
+
+    /// Session page loads and WebSocket connections allowed in total per minute (0 for no limit)
+    #[arg(long, default_value = "1200")]
+    pub rate_limit_global: u32,
+
+    /// Clients allowed to be connected to a session at once, counting WebSocket connections, output streams
+    /// and multiplexed channels (0 for no limit)
+    #[arg(long, value_name = "N", default_value = "0")]
+    pub max_clients: usize,
+
+    /// Clients allowed to be connected to a session at once from one client address (0 for no limit)
+    #[arg(long, value_name = "N", default_value = "0")]
+    pub max_clients_per_ip: usize,
+
+    /// Only serve clients whose address is in this network, e.g. 10.0.0.0/8 or a single address; may be repeated
+    #[arg(long, value_name = "CIDR", value_parser = crate::ip_filter::parse_cidr)]
+    pub allow_cidr: Vec<crate::ip_filter::Cidr>,
+
+    /// Refuse clients whose address is in this network, even if --allow-cidr allows it; may be repeated
+    #[arg(long, value_name = "CIDR", value_parser = crate::ip_filter::parse_cidr)]
+    pub deny_cidr: Vec<crate::ip_filter::Cidr>,
+
+    /// Let search engines index session pages, e.g. for public demos
+    #[arg(long)]
+    pub allow_indexing: bool,
+
+    /// File served as /robots.txt (default: disallow everything, or allow everything with --allow-indexing)
+    #[arg(long)]
+    pub robots_txt: Option<std::path::PathBuf>,
+
+    /// MaxMind database (GeoLite2/GeoIP2 City or Country) used to show where viewers connect from
+    #[arg(long)]
+    pub geoip_db: Option<std::path::PathBuf>,
+
+    /// Path to the server identity key (default: ~/.config/rwshell/identity_ed25519)
+    #[arg(long)]
+    pub identity_file: Option<std::path::PathBuf>,
+
+    /// Config file with default options and profiles (default: ~/.config/rwshell/config.toml)
+    #[arg(long)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// Use the options of a profile from the config file
+    #[arg(long)]
+    pub profile: Option<String>,
+
     /// Verbose logging
     #[arg(long)]
     pub verbose: bool,
+
+    #[command(subcommand)]
+    pub subcommand: Option<Subcommand>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Subcommand {
+    /// Attach this terminal to a headless session running on the same machine
+    Attach {
+        /// Session to attach to
+        #[arg(default_value = "local")]
+        session: String,
+    },
+    /// Allow the oldest viewer command held by --confirm-pattern or --training-wheels in a headless
+    /// session running on the same machine
+    Confirm {
+        /// Session the command is waiting in
+        #[arg(default_value = "local")]
+        session: String,
+        /// Refuse the command instead
+        #[arg(long, conflicts_with = "trust")]
+        refuse: bool,
+        /// Also stop holding the viewer's next commands under --training-wheels
+        #[arg(long)]
+        trust: bool,
+    },
+    /// Allow the oldest viewer asking for write access with --approve-writers in a headless session
+    /// running on the same machine
+    Approve {
+        /// Session the viewer is waiting in
+        #[arg(default_value = "local")]
+        session: String,
+        /// Deny write access instead
+        #[arg(long)]
+        deny: bool,
+    },
+    /// Act on a session running on the same machine, e.g. from a shell inside it
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Print the output of a session to stdout without joining it, e.g. to pipe it into grep or tee
+    Cat {
+        /// Session URL (e.g. http://localhost:8000/s/local/)
+        url: String,
+        /// Remove escape sequences such as colors and cursor movement
+        #[arg(long)]
+        plain: bool,
+    },
+    /// Turn an asciicast recording into a single HTML file that plays it back without a server
+    Export {
+        /// Recording in the asciicast v2 format, e.g. made with asciinema
+        cast: std::path::PathBuf,
+        /// HTML file to write
+        #[arg(long, value_name = "FILE")]
+        html: std::path::PathBuf,
+        /// Markdown notes to show below the recording (default: the recording's name ending in
+        /// .notes.md, if there is such a file)
+        #[arg(long, value_name = "FILE")]
+        notes: Option<std::path::PathBuf>,
+        /// Timeline to show as markers of the recording (default: the recording's name ending in
+        /// .timeline.jsonl, if there is such a file)
+        #[arg(long, value_name = "FILE")]
+        timeline: Option<std::path::PathBuf>,
+    },
+    /// Compare the plain text transcripts of two asciicast recordings and show where they diverge
+    Diff {
+        /// First recording
+        a: std::path::PathBuf,
+        /// Second recording
+        b: std::path::PathBuf,
+        /// Unchanged lines shown around each divergence
+        #[arg(short = 'U', long, default_value_t = 3)]
+        context: usize,
+        /// Leave text matching this regular expression out of the comparison, e.g. host names or
+        /// timestamps; can be repeated
+        #[arg(long, value_name = "REGEX")]
+        ignore: Vec<regex::Regex>,
+    },
+    /// Render an asciicast recording to an animated GIF
+    Render {
+        /// Recording in the asciicast v2 format, e.g. made with asciinema
+        cast: std::path::PathBuf,
+        /// GIF file to write
+        #[arg(long, value_name = "FILE")]
+        gif: std::path::PathBuf,
+        /// Monospace TrueType or OpenType font to draw text with (default: DejaVu Sans Mono or another
+        /// common one found on the system)
+        #[arg(long, value_name = "FILE")]
+        font: Option<std::path::PathBuf>,
+        /// Font size in pixels
+        #[arg(long, default_value_t = 16.0)]
+        font_size: f32,
+    },
+    /// Log into several machines over SSH in panes side by side, typing into all of them at once, and
+    /// share the session like any other
+    Multi {
+        /// Machines to log into, as [USER@]HOST
+        #[arg(required = true, value_parser = crate::panes::ssh_backend)]
+        hosts: Vec<crate::panes::Backend>,
+    },
+    /// Show a command running on several backends in panes of this terminal; started by --compare
+    #[command(hide = true)]
+    Panes {
+        #[arg(long = "backend", required = true)]
+        backends: Vec<crate::panes::Backend>,
+        #[arg(long)]
+        sync_input: bool,
+        /// Pane key, as a byte
+        #[arg(long)]
+        key: u8,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Set up a profile in the config file by answering a few questions
+    Init,
+    /// Install a systemd service that keeps a headless session with the given options running
+    InstallService {
+        /// Name of the session, used in the unit names
+        #[arg(long)]
+        name: String,
+        /// Install a system-wide service instead of one for the current user
+        #[arg(long)]
+        system: bool,
+        /// Let systemd open the listening socket and start the session on the first connection
+        #[arg(long)]
+        socket: bool,
+        /// Print the units instead of installing them
+        #[arg(long)]
+        print: bool,
+    },
+    /// Manage groups that gather several sessions behind one URL
+    Group {
+        #[command(subcommand)]
+        command: GroupCommand,
+    },
+    /// Check that a session can start here with the options given and those of the config file: PTY
+    /// allocation, the listen address, TLS files and relay connectivity
+    Doctor,
+    /// Republish sessions mirrored with --mirror to read-only viewers, serving on --listen until interrupted
+    Relay,
+    /// Run --command in a PTY for each server started with --pty-socket that connects to SOCKET, and hand
+    /// it the PTY, so the command and the network-facing server can run as different users
+    PtyHelper {
+        /// Socket to listen on
+        socket: std::path::PathBuf,
+        /// User to give the socket to, e.g. the one the server runs as; only it (and root) can connect
+        #[arg(long, value_name = "USER")]
+        socket_owner: Option<String>,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// Shell to generate the script for
+        shell: clap_complete::Shell,
+        /// Generate it for rwshell-client instead
+        #[arg(long)]
+        client: bool,
+    },
+    /// Print the man page
+    Manpage {
+        /// Generate it for rwshell-client instead
+        #[arg(long)]
+        client: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CtlCommand {
+    /// Add a timestamped entry to the session's timeline, e.g. "db failover started"
+    Note {
+        /// Text of the entry
+        #[arg(required = true, num_args = 1..)]
+        text: Vec<String>,
+        /// Session to add it to (default: the session this shell runs in, or "local")
+        #[arg(long, default_value_t = get_current_session())]
+        session: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum GroupCommand {
+    /// Serve a group on --listen and print its URLs, until interrupted
+    Create {
+        /// Name of the group
+        name: String,
+    },
 }
 
 fn get_default_shell() -> String {
     std::env::var("SHELL").unwrap_or_else(|_| "bash".to_string())
 }
+
+/// Session the current shell runs in, as told by the RWSHELL_SESSION variable rwshell sets
+fn get_current_session() -> String {
+    std::env::var(crate::session_env::RWSHELL_SESSION).unwrap_or_else(|_| "local".to_string())
+}
+
+/// Parse an environment variable assignment such as "EDITOR=vim"
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE: {value}")),
+    }
+}
+
+/// Parse a control key such as "ctrl-]" or "ctrl-g" into the byte the terminal sends for it
+fn parse_control_key(value: &str) -> Result<u8, String> {
+    let key = value
+        .strip_prefix("ctrl-")
+        .or_else(|| value.strip_prefix("ctrl+"))
+        .or_else(|| value.strip_prefix('^'))
+        .ok_or_else(|| format!("expected a control key such as ctrl-]: {value}"))?;
+    match key.as_bytes() {
+        [key @ (b'a'..=b'z' | b'A'..=b'Z' | b'@' | b'[' | b'\\' | b']' | b'^' | b'_')] => {
+            Ok(key.to_ascii_uppercase() & 0x1f)
+        }
+        _ => Err(format!("expected a control key such as ctrl-]: {value}")),
+    }
+}
+
+/// Parse a byte size with an optional binary suffix, e.g. "512", "64K", "2M" or "1GiB"
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, suffix) = value.split_at(split);
+
+    let number: usize = digits.parse().map_err(|_| format!("invalid size: {value}"))?;
+    let multiplier: usize = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size suffix: {value}")),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {value}"))
+}
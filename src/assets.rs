@@ -1,9 +1,41 @@
-use rust_embed::RustEmbed;
+use rust_embed::{EmbeddedFile, RustEmbed};
+use std::fmt::Write as _;
 
 #[derive(RustEmbed)]
 #[folder = "frontend/"]
 pub struct Assets;
 
+/// Compressed variants of an embedded asset, made by build.rs
+pub struct PrecompressedAsset {
+    path: &'static str,
+    /// SHA-256 of the asset the variants were made from, in hex
+    sha256: &'static str,
+    gzip: Option<&'static [u8]>,
+    zstd: Option<&'static [u8]>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/precompressed.rs"));
+
+/// Hex digits of the content hash in the hashed names of assets and their ETags
+const HASH_LEN: usize = 16;
+
+/// Content encodings assets are precompressed in, the most compact first
+#[derive(Debug, Clone, Copy)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// Name of the encoding in `Accept-Encoding` and `Content-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
 impl Assets {
     pub fn get_file(path: &str) -> Option<rust_embed::EmbeddedFile> {
         Assets::get(path)
@@ -12,4 +44,112 @@ impl Assets {
     pub fn get_content_type(path: &str) -> String {
         mime_guess::from_path(path).first_or_octet_stream().to_string()
     }
+
+    /// The asset a request for `name` under `/static/` is for: the asset of that name, or the one a hashed
+    /// name refers to. Also tells whether the name holds the asset's current hash, so the response may be
+    /// cached for good.
+    pub fn resolve(name: &str) -> Option<(String, EmbeddedFile, bool)> {
+        if let Some(file) = Assets::get(name) {
+            return Some((name.to_string(), file, false));
+        }
+        let (path, hash) = unhash_name(name)?;
+        let file = Assets::get(&path)?;
+        let current = content_hash(&file) == hash;
+        Some((path, file, current))
+    }
+
+    /// The variant of the asset at `path` in the most compact encoding `accept_encoding` allows, if build.rs
+    /// made one from the asset as it is now. Debug builds read assets from disk, which may have changed
+    /// since.
+    pub fn precompressed(path: &str, file: &EmbeddedFile, accept_encoding: &str) -> Option<(Encoding, &'static [u8])> {
+        let asset = PRECOMPRESSED.iter().find(|asset| asset.path == path)?;
+        if asset.sha256 != hex(&file.metadata.sha256_hash()) {
+            return None;
+        }
+        [(Encoding::Zstd, asset.zstd), (Encoding::Gzip, asset.gzip)]
+            .into_iter()
+            .find_map(|(encoding, data)| Some((encoding, data?)).filter(|_| accepts(accept_encoding, encoding)))
+    }
+
+    /// Whether the asset at `path` was precompressed, so its responses vary by `Accept-Encoding`
+    pub fn has_variants(path: &str) -> bool {
+        PRECOMPRESSED
+            .iter()
+            .any(|asset| asset.path == path && (asset.gzip.is_some() || asset.zstd.is_some()))
+    }
+
+    /// `page` with the links to assets under `/static/` replaced by their hashed names, which browsers may
+    /// cache until the asset changes
+    pub fn with_hashed_urls(page: &str) -> String {
+        let mut page = page.to_string();
+        for path in Assets::iter() {
+            let Some(file) = Assets::get(&path) else { continue };
+            page = page.replace(
+                &format!("/static/{path}\""),
+                &format!("/static/{}\"", hashed_name(&path, &content_hash(&file))),
+            );
+        }
+        page
+    }
+}
+
+/// The hash of `file` in its hashed name and ETag
+pub fn content_hash(file: &EmbeddedFile) -> String {
+    let mut hash = hex(&file.metadata.sha256_hash());
+    hash.truncate(HASH_LEN);
+    hash
+}
+
+/// `path` with `hash` put before its extension, e.g. `terminal.<hash>.js`
+fn hashed_name(path: &str, hash: &str) -> String {
+    let (dir, name) = path.rsplit_once('/').map_or(("", path), |(dir, name)| (dir, name));
+    let name = match name.split_once('.') {
+        Some((stem, extension)) => format!("{stem}.{hash}.{extension}"),
+        None => format!("{name}.{hash}"),
+    };
+    if dir.is_empty() { name } else { format!("{dir}/{name}") }
+}
+
+/// The path a hashed name was made from, and the hash in it
+fn unhash_name(name: &str) -> Option<(String, &str)> {
+    let (dir, file) = name.rsplit_once('/').map_or(("", name), |(dir, file)| (dir, file));
+    let (stem, rest) = file.split_once('.')?;
+    let (hash, extension) = rest
+        .split_once('.')
+        .map_or((rest, None), |(hash, ext)| (hash, Some(ext)));
+    if hash.len() != HASH_LEN || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut path = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{dir}/")
+    };
+    path.push_str(stem);
+    if let Some(extension) = extension {
+        let _ = write!(path, ".{extension}");
+    }
+    Some((path, hash))
+}
+
+/// Whether `accept_encoding` allows `encoding`, which it doesn't if it leaves it out or gives it `q=0`
+fn accepts(accept_encoding: &str, encoding: Encoding) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        name.eq_ignore_ascii_case(encoding.name())
+            && !params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            })
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
 }
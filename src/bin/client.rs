@@ -1,13 +1,26 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use base64::{Engine as _, engine::general_purpose};
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::BoxStream;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use rwshell::client_args::ClientArgs;
+use rwshell::known_hosts::{HostKeyStatus, KnownHosts, default_known_hosts_path, verify_identity};
+use rwshell::mosaic::Mosaic;
+use rwshell::mux::{ChannelHeader, MUX_PATH, OpenMessage};
+use rwshell::term::CapabilitiesMessage;
+use rwshell::websocket::{CloseMessage, Heartbeat, WarningMessage, server_path, session_websocket_url};
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 use std::sync::atomic::AtomicBool;
 use termios::{Termios, tcsetattr};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, USER_AGENT};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, client_async_tls};
 use tracing::{debug, error};
 use url::Url;
 
@@ -66,6 +79,7 @@ fn setup_global_terminal_restoration(original_termios: Termios) -> Result<()> {
     }
 
     // Set up signal handlers for various termination signals
+    #[allow(unknown_lints, function_casts_as_integer)]
     unsafe {
         libc::signal(libc::SIGINT, global_restore_terminal as usize); // Ctrl+C
         libc::signal(libc::SIGTERM, global_restore_terminal as usize); // Termination request
@@ -107,6 +121,34 @@ struct HeadlessMessage {
     headless: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChallengeMessage {
+    #[serde(rename = "Nonce")]
+    nonce: String, // base64 encoded
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IdentityMessage {
+    #[serde(rename = "PublicKey")]
+    public_key: String, // base64 encoded
+    #[serde(rename = "Session")]
+    session: String, // Session answered for, empty for the whole server
+    #[serde(rename = "ServerNonce")]
+    server_nonce: String, // base64 encoded
+    #[serde(rename = "Signature")]
+    signature: String, // base64 encoded
+}
+
+/// Serialize a protocol message of type `msg_type` carrying `payload`
+fn encode_message<T: Serialize>(msg_type: &str, payload: &T) -> rwshell::error::Result<String> {
+    let message = TtyMessage {
+        msg_type: msg_type.to_string(),
+        data: general_purpose::STANDARD.encode(serde_json::to_vec(payload)?),
+    };
+
+    Ok(serde_json::to_string(&message)?)
+}
+
 // Structure for window size (from sys/ioctl.h)
 #[repr(C)]
 struct WinSize {
@@ -163,20 +205,461 @@ fn get_terminal_size() -> Result<(u16, u16)> {
     Ok((winsize.ws_col, winsize.ws_row))
 }
 
-#[derive(Parser, Debug)]
-#[command(name = "rwshell-client")]
-#[command(about = "Connect to a rwshell session")]
-struct ClientArgs {
-    /// The session URL to connect to
-    #[arg(help = "Session URL (e.g. http://localhost:8000/s/local/)")]
-    session_url: String,
+/// Challenge the server to prove its identity for `session`, if known, and check it against the known
+/// hosts file. Messages received before the identity reply are returned so they can be processed normally.
+async fn verify_server_identity<S, R>(
+    ws_sender: &mut S,
+    ws_receiver: &mut R,
+    host: &str,
+    session: Option<&str>,
+    known_hosts: &KnownHosts,
+) -> Result<Vec<Message>>
+where
+    S: Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+    R: Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    const IDENTITY_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+    let nonce = rand::random::<[u8; 32]>();
+    let challenge_msg = ChallengeMessage {
+        nonce: general_purpose::STANDARD.encode(nonce),
+    };
+    ws_sender
+        .send(Message::Text(encode_message("Challenge", &challenge_msg)?))
+        .await?;
+
+    let mut pending = Vec::new();
+    let deadline = tokio::time::Instant::now() + IDENTITY_TIMEOUT;
+
+    loop {
+        let msg = match tokio::time::timeout_at(deadline, ws_receiver.next()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => {
+                return Err(anyhow::anyhow!(
+                    "Connection closed before the server presented its identity"
+                ));
+            }
+            Err(_) => {
+                return Err(anyhow::anyhow!(
+                    "Server did not present an identity (use --no-verify-identity to connect anyway)"
+                ));
+            }
+        };
+
+        let identity = match &msg {
+            Message::Text(text) => serde_json::from_str::<TtyMessage>(text)
+                .ok()
+                .filter(|tty_msg| tty_msg.msg_type == "Identity")
+                .and_then(|tty_msg| general_purpose::STANDARD.decode(&tty_msg.data).ok())
+                .and_then(|data| serde_json::from_slice::<IdentityMessage>(&data).ok()),
+            Message::Close(_) => {
+                return Err(anyhow::anyhow!(
+                    "Connection closed before the server presented its identity"
+                ));
+            }
+            _ => None,
+        };
+
+        let Some(identity) = identity else {
+            pending.push(msg);
+            continue;
+        };
+
+        verify_identity(
+            &identity.public_key,
+            &identity.session,
+            &identity.server_nonce,
+            &nonce,
+            &identity.signature,
+        )?;
+        if session.is_some_and(|session| session != identity.session) {
+            return Err(anyhow::anyhow!(
+                "Server answered for session {:?} instead of {:?}",
+                identity.session,
+                session.unwrap_or_default()
+            ));
+        }
+
+        match known_hosts.check(host, &identity.public_key)? {
+            HostKeyStatus::Trusted => debug!("Server identity for {} matches known hosts", host),
+            HostKeyStatus::Added => {
+                eprint!(
+                    "Permanently added '{}' (ed25519 {}) to {}\r\n",
+                    host,
+                    identity.public_key,
+                    known_hosts.path().display()
+                );
+            }
+            HostKeyStatus::Mismatch { expected } => {
+                return Err(anyhow::anyhow!(
+                    "SERVER IDENTITY HAS CHANGED for '{}': expected ed25519 {}, got {}. \
+                     Someone could be intercepting the session. Remove the entry from {} if the change is legitimate.",
+                    host,
+                    expected,
+                    identity.public_key,
+                    known_hosts.path().display()
+                ));
+            }
+        }
+
+        return Ok(pending);
+    }
+}
+
+/// Name of a server in the known hosts file
+fn host_key_name(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or("localhost"),
+        url.port_or_known_default().unwrap_or(80)
+    )
+}
+
+/// Something that happened in one of the sessions of a grid
+enum GridEvent {
+    Output(usize, Vec<u8>),
+    Resize(usize, u16, u16),
+    Closed(usize, String),
+}
+
+/// Connect to the WebSocket at `url` with TCP keepalive, so a connection dropped on the way, e.g. by a NAT,
+/// ends with an error instead of silently delivering nothing more
+async fn connect_websocket(
+    url: &Url,
+    keepalive: std::time::Duration,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, WsError> {
+    let host = match url.host() {
+        Some(url::Host::Ipv6(ip)) => ip.to_string(),
+        Some(host) => host.to_string(),
+        None => return Err(WsError::Url(UrlError::NoHostName)),
+    };
+    let port = url
+        .port_or_known_default()
+        .ok_or(WsError::Url(UrlError::UnsupportedUrlScheme))?;
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+    rwshell::serve::set_tcp_keepalive(&stream, keepalive)?;
+    let mut request = url.as_str().into_client_request()?;
+    // Lets the host tell this client apart from browsers
+    request.headers_mut().insert(
+        USER_AGENT,
+        HeaderValue::from_static(concat!("rwshell-client/", env!("CARGO_PKG_VERSION"))),
+    );
+    if let Some(authorization) = rwshell::auth::basic_authorization(url) {
+        request.headers_mut().insert(AUTHORIZATION, authorization);
+    }
+    let (ws_stream, _) = client_async_tls(request, stream).await?;
+    Ok(ws_stream)
+}
+
+/// Connect to a session only to watch it, verifying the server identity unless disabled
+async fn connect_viewer(url: &Url, args: &ClientArgs) -> Result<BoxStream<'static, Result<Message, WsError>>> {
+    rwshell::version::check_server(url).await?;
+    let ws_url = session_websocket_url(url)?;
+    debug!("Connecting to WebSocket: {}", ws_url);
+    let ws_stream = connect_websocket(&ws_url, args.tcp_keepalive).await?;
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let pending_messages = if args.no_verify_identity {
+        Vec::new()
+    } else {
+        let known_hosts = KnownHosts::new(args.known_hosts.clone().unwrap_or_else(default_known_hosts_path));
+        let session = session_id(url);
+        verify_server_identity(
+            &mut ws_sender,
+            &mut ws_receiver,
+            &host_key_name(url),
+            session.as_deref(),
+            &known_hosts,
+        )
+        .await?
+    };
+    Ok(futures_util::stream::iter(pending_messages.into_iter().map(Ok))
+        .chain(ws_receiver)
+        .boxed())
+}
+
+/// ID of the session at a session URL such as http://host:8000/s/local/
+fn session_id(url: &Url) -> Option<String> {
+    let (_, path) = url.path().rsplit_once("/s/")?;
+    path.split('/').next().filter(|id| !id.is_empty()).map(str::to_string)
+}
+
+/// Indexes of the session URLs that can share a connection, grouped by server: two or more sessions on
+/// the same address with the same token
+fn multiplexable_groups(urls: &[Url]) -> Vec<Vec<usize>> {
+    let same_server = |a: &Url, b: &Url| {
+        (
+            a.scheme(),
+            a.host_str(),
+            a.port_or_known_default(),
+            server_path(a, ""),
+            a.query(),
+        ) == (
+            b.scheme(),
+            b.host_str(),
+            b.port_or_known_default(),
+            server_path(b, ""),
+            b.query(),
+        )
+    };
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (index, url) in urls.iter().enumerate() {
+        if session_id(url).is_none() {
+            continue;
+        }
+        match groups.iter_mut().find(|indexes| same_server(&urls[indexes[0]], url)) {
+            Some(indexes) => indexes.push(index),
+            None => groups.push(vec![index]),
+        }
+    }
+    groups.retain(|indexes| indexes.len() > 1);
+    groups
+}
+
+/// Connect to `sessions` of the server of `url` over one connection, verifying the server identity
+/// unless disabled, and return a stream of messages for each. `None` if the server can't multiplex.
+async fn connect_multiplexed(
+    url: &Url,
+    sessions: &[String],
+    args: &ClientArgs,
+) -> Result<Option<Vec<BoxStream<'static, Result<Message, WsError>>>>> {
+    rwshell::version::check_server(url).await?;
+    let mut ws_url = session_websocket_url(url)?;
+    ws_url.set_path(&server_path(url, MUX_PATH));
+    debug!("Connecting to WebSocket: {}", ws_url);
+    let ws_stream = match connect_websocket(&ws_url, args.tcp_keepalive).await {
+        Ok(ws_stream) => ws_stream,
+        // Servers older than multiplexing, or serving capability links only
+        Err(WsError::Http(response)) if response.status() == 404 => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let pending_messages = if args.no_verify_identity {
+        Vec::new()
+    } else {
+        // The multiplexed endpoint answers for the server as a whole
+        let known_hosts = KnownHosts::new(args.known_hosts.clone().unwrap_or_else(default_known_hosts_path));
+        verify_server_identity(
+            &mut ws_sender,
+            &mut ws_receiver,
+            &host_key_name(url),
+            Some(""),
+            &known_hosts,
+        )
+        .await?
+    };
+
+    // Each session gets the channel of its position in `sessions`
+    let mut channels = Vec::with_capacity(sessions.len());
+    let mut streams = Vec::with_capacity(sessions.len());
+    for (channel, session) in sessions.iter().enumerate() {
+        let open_msg = OpenMessage {
+            channel: channel as u32,
+            session: session.clone(),
+        };
+        ws_sender
+            .send(Message::Text(encode_message("Open", &open_msg)?))
+            .await?;
+        let (channel_tx, channel_rx) = mpsc::unbounded_channel();
+        channels.push(channel_tx);
+        streams.push(
+            futures_util::stream::unfold(channel_rx, |mut channel_rx| async move {
+                channel_rx.recv().await.map(|msg| (msg, channel_rx))
+            })
+            .boxed(),
+        );
+    }
 
-    /// Enable verbose logging
-    #[arg(short, long)]
-    verbose: bool,
+    let ws_receiver = futures_util::stream::iter(pending_messages.into_iter().map(Ok)).chain(ws_receiver);
+    tokio::spawn(demultiplex(ws_receiver, channels));
+    Ok(Some(streams))
 }
 
-async fn run_client(session_url: String) -> Result<()> {
+/// Pass the messages of a multiplexed connection to the stream of their channel until it ends, which
+/// ends all of them
+async fn demultiplex(
+    mut ws_receiver: impl Stream<Item = Result<Message, WsError>> + Unpin,
+    channels: Vec<mpsc::UnboundedSender<Result<Message, WsError>>>,
+) {
+    while let Some(msg) = ws_receiver.next().await {
+        let text = match msg {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                debug!("Multiplexed connection failed: {}", e);
+                break;
+            }
+        };
+        let channel = serde_json::from_str::<ChannelHeader>(&text)
+            .ok()
+            .and_then(|header| header.channel);
+        if let Some(channel_tx) = channel.and_then(|channel| channels.get(channel as usize)) {
+            let _ = channel_tx.send(Ok(Message::Text(text)));
+        }
+    }
+}
+
+/// Forward what happens in session `index` to the grid until it ends
+async fn watch_session(
+    index: usize,
+    mut ws_receiver: BoxStream<'static, Result<Message, WsError>>,
+    events: mpsc::UnboundedSender<GridEvent>,
+) {
+    let reason = loop {
+        let text = match ws_receiver.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => break "disconnected".to_string(),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => break e.to_string(),
+        };
+        let Some((msg_type, data)) = serde_json::from_str::<TtyMessage>(&text)
+            .ok()
+            .and_then(|tty_msg| Some((tty_msg.msg_type, general_purpose::STANDARD.decode(&tty_msg.data).ok()?)))
+        else {
+            continue;
+        };
+
+        let event = match msg_type.as_str() {
+            "Write" => serde_json::from_slice::<WriteMessage>(&data)
+                .ok()
+                .and_then(|write_msg| general_purpose::STANDARD.decode(&write_msg.data).ok())
+                .map(|output| GridEvent::Output(index, output)),
+            "WinSize" => serde_json::from_slice::<WinSizeMessage>(&data)
+                .ok()
+                .map(|winsize_msg| GridEvent::Resize(index, winsize_msg.cols, winsize_msg.rows)),
+            "Close" => match serde_json::from_slice::<CloseMessage>(&data) {
+                Ok(close_msg) => break close_msg.message,
+                Err(_) => break "closed".to_string(),
+            },
+            _ => None,
+        };
+        if let Some(event) = event {
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    };
+    let _ = events.send(GridEvent::Closed(index, reason));
+}
+
+/// Watch several sessions tiled in this terminal until q or Ctrl+C is pressed
+async fn run_grid(args: ClientArgs) -> Result<()> {
+    const FRAME_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(100);
+
+    // Connect to every session before taking over the terminal, so failures are readable
+    let urls = args
+        .grid
+        .iter()
+        .map(|session_url| Url::parse(session_url).with_context(|| format!("Invalid session URL {session_url}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Sessions on the same server share one connection where the server allows it
+    let mut receivers: Vec<Option<BoxStream<'static, Result<Message, WsError>>>> = urls.iter().map(|_| None).collect();
+    for indexes in multiplexable_groups(&urls) {
+        let url = &urls[indexes[0]];
+        let sessions: Vec<String> = indexes.iter().filter_map(|&index| session_id(&urls[index])).collect();
+        match connect_multiplexed(url, &sessions, &args)
+            .await
+            .with_context(|| format!("Failed to connect to {url}"))?
+        {
+            Some(streams) => {
+                for (&index, stream) in indexes.iter().zip(streams) {
+                    receivers[index] = Some(stream);
+                }
+            }
+            None => debug!(
+                "{} can't multiplex, connecting to its sessions one by one",
+                host_key_name(url)
+            ),
+        }
+    }
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let mut labels = Vec::new();
+    for (index, (url, ws_receiver)) in urls.iter().zip(receivers).enumerate() {
+        let ws_receiver = match ws_receiver {
+            Some(ws_receiver) => ws_receiver,
+            None => connect_viewer(url, &args)
+                .await
+                .with_context(|| format!("Failed to connect to {url}"))?,
+        };
+        labels.push(format!("{}{}", host_key_name(url), url.path()));
+        tokio::spawn(watch_session(index, ws_receiver, event_tx.clone()));
+    }
+    drop(event_tx);
+
+    let original_termios = setup_raw_terminal()?;
+    setup_global_terminal_restoration(original_termios)?;
+    #[allow(unknown_lints, function_casts_as_integer)]
+    unsafe {
+        libc::signal(libc::SIGWINCH, sigwinch_handler as usize);
+    }
+
+    let (quit_tx, mut quit_rx) = mpsc::unbounded_channel::<()>();
+    tokio::task::spawn_blocking(move || {
+        use std::io::Read;
+        let mut buffer = [0u8; 64];
+        while let Ok(n) = std::io::stdin().read(&mut buffer) {
+            if n == 0 || buffer[..n].iter().any(|&byte| byte == b'q' || byte == 3) {
+                let _ = quit_tx.send(());
+                break;
+            }
+        }
+    });
+
+    use std::io::Write;
+    let mut stdout = std::io::stdout();
+    // Draw on the alternate screen without a cursor, leaving the terminal as it was on exit
+    stdout.write_all(b"\x1b[?1049h\x1b[?25l")?;
+
+    let mut mosaic = Mosaic::new(labels, get_terminal_size().unwrap_or((80, 24)));
+    let mut interval = tokio::time::interval(FRAME_INTERVAL);
+    let mut changed = true;
+    loop {
+        tokio::select! {
+            Some(event) = event_rx.recv() => {
+                match event {
+                    GridEvent::Output(index, output) => mosaic.output(index, &output),
+                    GridEvent::Resize(index, cols, rows) => mosaic.resize_session(index, cols, rows),
+                    GridEvent::Closed(index, reason) => mosaic.close(index, &reason),
+                }
+                changed = true;
+            }
+            _ = quit_rx.recv() => break,
+            // Redraw at most once per frame, however much output arrives
+            _ = interval.tick() => {
+                if WINDOW_SIZE_CHANGED.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                    mosaic.resize(get_terminal_size().unwrap_or((80, 24)));
+                    changed = true;
+                }
+                if changed {
+                    stdout.write_all(&mosaic.render())?;
+                    stdout.flush()?;
+                    changed = false;
+                }
+            }
+        }
+    }
+
+    stdout.write_all(b"\x1b[?25h\x1b[?1049l")?;
+    stdout.flush()?;
+    restore_terminal(&original_termios);
+    Ok(())
+}
+
+async fn run_client(args: ClientArgs) -> Result<()> {
+    let session_url = args
+        .session_url
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("A session URL is required"))?;
+
+    // Parse the session URL and make sure the server speaks a compatible protocol before taking
+    // over the terminal
+    let url = Url::parse(&session_url)?;
+    rwshell::version::check_server(&url).await?;
+
     // Set up raw terminal mode to prevent local echo
     let original_termios = setup_raw_terminal()?;
 
@@ -184,6 +667,7 @@ async fn run_client(session_url: String) -> Result<()> {
     setup_global_terminal_restoration(original_termios)?;
 
     // Set up SIGWINCH handler for terminal size changes
+    #[allow(unknown_lints, function_casts_as_integer)]
     unsafe {
         libc::signal(libc::SIGWINCH, sigwinch_handler as usize);
     }
@@ -198,9 +682,7 @@ async fn run_client(session_url: String) -> Result<()> {
     // Track server headless state
     let server_headless = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
 
-    // Parse the session URL and convert to WebSocket URL
-    let url = Url::parse(&session_url)?;
-
+    // Convert the session URL to the WebSocket URL
     let ws_scheme = if url.scheme() == "https" { "wss" } else { "ws" };
 
     // Build host with port
@@ -216,12 +698,15 @@ async fn run_client(session_url: String) -> Result<()> {
         path.push_str("/ws/");
     }
 
-    let ws_url = format!("{ws_scheme}://{host_port}{path}");
+    let mut ws_url = Url::parse(&format!("{ws_scheme}://{host_port}{path}"))?;
+    // Keep the session's token, if it requires one
+    rwshell::auth::carry_token(&url, &mut ws_url);
 
-    debug!("Connecting to WebSocket: {}", ws_url);
-
-    let (ws_stream, _) = connect_async(&ws_url).await?;
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let known_hosts = (!args.no_verify_identity)
+        .then(|| KnownHosts::new(args.known_hosts.clone().unwrap_or_else(default_known_hosts_path)));
+    let mut connection = connect_session(&url, &ws_url, &args, known_hosts.as_ref())
+        .await
+        .map_err(ConnectError::into_inner)?;
 
     // Create channels for communication between tasks
     let (stdin_tx, mut stdin_rx) = mpsc::unbounded_channel::<Vec<u8>>();
@@ -229,13 +714,10 @@ async fn run_client(session_url: String) -> Result<()> {
 
     let shutdown_flag_for_stdin = shutdown_flag.clone();
     let shutdown_flag_for_winsize = shutdown_flag.clone();
-    let shutdown_flag_for_sender = shutdown_flag.clone();
-    let shutdown_flag_for_stdout = shutdown_flag.clone();
     let server_headless_for_winsize = server_headless.clone();
-    let server_headless_for_stdout = server_headless.clone();
 
     // Task for reading stdin and sending to stdin channel
-    let stdin_task = tokio::task::spawn_blocking(move || {
+    tokio::task::spawn_blocking(move || {
         use std::io::{Read, stdin};
         let mut stdin = stdin();
         let mut buffer = [0u8; 1]; // Read one byte at a time for immediate response
@@ -274,7 +756,7 @@ async fn run_client(session_url: String) -> Result<()> {
     });
 
     // Task for monitoring window size changes
-    let winsize_task = tokio::spawn(async move {
+    tokio::spawn(async move {
         let mut last_size = (initial_cols, initial_rows);
         let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(200));
 
@@ -314,172 +796,384 @@ async fn run_client(session_url: String) -> Result<()> {
         debug!("Window size monitoring task ended");
     });
 
-    // Task for sending messages to WebSocket (combines stdin and window size messages)
-    let sender_task = tokio::spawn(async move {
+    // Forward input and output until the session ends, connecting again after drops with --reconnect
+    let mut resend_size = None;
+    loop {
+        let end = forward(
+            connection,
+            &mut stdin_rx,
+            &mut size_rx,
+            &server_headless,
+            &shutdown_flag,
+            resend_size,
+            Heartbeat::new(args.ping_interval, args.ping_timeout),
+        )
+        .await;
+        let reason = match end {
+            ConnectionEnd::Finished => break,
+            ConnectionEnd::Lost(reason) if !args.reconnect => {
+                print_status(&format!("connection lost: {reason}"));
+                break;
+            }
+            ConnectionEnd::ServerShutdown(message) if !args.reconnect => {
+                print_status(&format!("session closed: {message}"));
+                break;
+            }
+            ConnectionEnd::Lost(reason) | ConnectionEnd::ServerShutdown(reason) => reason,
+        };
+
+        match reconnect(&url, &ws_url, &args, known_hosts.as_ref(), reason, &mut stdin_rx).await {
+            Ok(Some(new_connection)) => connection = new_connection,
+            Ok(None) => break,
+            Err(e) => {
+                print_status(&format!("{e:#}"));
+                break;
+            }
+        }
+        // The server may have lost the size this terminal gave the session
+        resend_size = get_terminal_size().ok();
+    }
+
+    // Set shutdown flag to stop other tasks
+    shutdown_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+
+    // Restore terminal before exiting
+    restore_terminal(&original_termios);
+
+    Ok(())
+}
+
+/// Delay before the first reconnection attempt with --reconnect, doubled after every failure
+const MIN_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+type WsSender = futures_util::stream::SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+/// A connection to the session: where to send messages, and the messages received, starting with those
+/// that arrived while the server identity was verified
+struct Connection {
+    sender: WsSender,
+    receiver: BoxStream<'static, Result<Message, WsError>>,
+}
+
+/// Why connecting to the session failed
+enum ConnectError {
+    /// Worth trying again, e.g. while the server restarts
+    Unreachable(anyhow::Error),
+    /// Trying again won't help, e.g. the session is gone or the server identity changed
+    Refused(anyhow::Error),
+}
+
+impl ConnectError {
+    fn into_inner(self) -> anyhow::Error {
+        match self {
+            ConnectError::Unreachable(e) | ConnectError::Refused(e) => e,
+        }
+    }
+}
+
+/// How a connection to the session ended
+enum ConnectionEnd {
+    /// Ctrl+C was pressed, or the session ended for good
+    Finished,
+    /// The connection dropped, so connecting again may resume the session
+    Lost(String),
+    /// The server is shutting down, e.g. to restart, and said why
+    ServerShutdown(String),
+}
+
+/// Connect to the session, verify the server identity against `known_hosts` unless it's turned off, and
+/// describe this terminal to the server
+async fn connect_session(
+    url: &Url,
+    ws_url: &Url,
+    args: &ClientArgs,
+    known_hosts: Option<&KnownHosts>,
+) -> Result<Connection, ConnectError> {
+    debug!("Connecting to WebSocket: {}", ws_url);
+    let ws_stream = match connect_websocket(ws_url, args.tcp_keepalive).await {
+        Ok(ws_stream) => ws_stream,
+        // E.g. the session ended while the server was away
+        Err(WsError::Http(response)) if response.status().is_client_error() => {
+            return Err(ConnectError::Refused(anyhow::anyhow!(
+                "The server refused the connection: {}",
+                response.status()
+            )));
+        }
+        Err(e) => return Err(ConnectError::Unreachable(e.into())),
+    };
+    let (mut sender, mut receiver) = ws_stream.split();
+
+    // Verify the server identity before any input is sent
+    let pending_messages = match known_hosts {
+        Some(known_hosts) => verify_server_identity(
+            &mut sender,
+            &mut receiver,
+            &host_key_name(url),
+            session_id(url).as_deref(),
+            known_hosts,
+        )
+        .await
+        .map_err(|e| match e.downcast_ref::<WsError>() {
+            Some(_) => ConnectError::Unreachable(e),
+            None => ConnectError::Refused(e),
+        })?,
+        None => Vec::new(),
+    };
+
+    // Describe this terminal so the server knows which colors it can display
+    let capabilities = encode_message("Capabilities", &CapabilitiesMessage::from_env())
+        .map_err(|e| ConnectError::Refused(e.into()))?;
+    sender
+        .send(Message::Text(capabilities))
+        .await
+        .map_err(|e| ConnectError::Unreachable(e.into()))?;
+
+    Ok(Connection {
+        sender,
+        receiver: futures_util::stream::iter(pending_messages.into_iter().map(Ok))
+            .chain(receiver)
+            .boxed(),
+    })
+}
+
+/// Connect to the session again after it was lost for `reason`, waiting longer after every failed
+/// attempt. Keys typed meanwhile are dropped; `None` if Ctrl+C was pressed.
+async fn reconnect(
+    url: &Url,
+    ws_url: &Url,
+    args: &ClientArgs,
+    known_hosts: Option<&KnownHosts>,
+    mut reason: String,
+    stdin_rx: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+) -> Result<Option<Connection>> {
+    let mut delay = MIN_RECONNECT_DELAY;
+    for attempt in 1.. {
+        print_status_line(&format!(
+            "connection lost: {reason}; reconnecting in {}s (attempt {attempt})",
+            delay.as_secs()
+        ));
+        let deadline = tokio::time::Instant::now() + delay;
         loop {
             tokio::select! {
-                // Handle stdin messages
-                stdin_data = stdin_rx.recv() => {
-                    match stdin_data {
-                        Some(data) => {
-                            let encoded_data = general_purpose::STANDARD.encode(&data);
-                            let write_msg = WriteMessage {
-                                size: data.len(),
-                                data: encoded_data
-                            };
-
-                            let message = TtyMessage {
-                                msg_type: "Write".to_string(),
-                                data: general_purpose::STANDARD
-                                    .encode(serde_json::to_vec(&write_msg).unwrap()),
-                            };
-
-                            let json_str = serde_json::to_string(&message).unwrap();
-
-                            if let Err(e) = ws_sender.send(Message::Text(json_str)).await {
-                                error!("Failed to send stdin message: {}", e);
-                                break;
-                            }
-                        }
-                        None => {
-                            debug!("Stdin channel closed");
-                            break;
-                        }
-                    }
-                }
+                _ = tokio::time::sleep_until(deadline) => break,
+                data = stdin_rx.recv() => if data.is_none() {
+                    print_status("");
+                    return Ok(None);
+                },
+            }
+        }
 
-                // Handle window size change messages
-                size_data = size_rx.recv() => {
-                    match size_data {
-                        Some((cols, rows)) => {
-                            let winsize_msg = WinSizeMessage { cols, rows };
+        // The server may have been upgraded while it was away
+        let connected = match rwshell::version::check_server(url).await {
+            Ok(()) => connect_session(url, ws_url, args, known_hosts).await,
+            Err(e) => Err(ConnectError::Refused(e)),
+        };
+        match connected {
+            Ok(connection) => {
+                print_status("reconnected");
+                return Ok(Some(connection));
+            }
+            Err(ConnectError::Refused(e)) => {
+                print_status("");
+                return Err(e);
+            }
+            Err(ConnectError::Unreachable(e)) => {
+                debug!("Reconnection attempt {} failed: {:#}", attempt, e);
+                reason = e.to_string();
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+    unreachable!("reconnection attempts are unbounded")
+}
 
-                            let message = TtyMessage {
-                                msg_type: "WinSize".to_string(),
-                                data: general_purpose::STANDARD
-                                    .encode(serde_json::to_vec(&winsize_msg).unwrap()),
-                            };
+/// Replace the current line with a status line of the client
+fn print_status_line(status: &str) {
+    use std::io::Write;
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "\r\x1b[K[rwshell] {status}");
+    let _ = stdout.flush();
+}
 
-                            let json_str = serde_json::to_string(&message).unwrap();
+/// Print a status line of the client on its own line, or end the status line shown with an empty one
+fn print_status(status: &str) {
+    use std::io::Write;
+    let mut stdout = std::io::stdout();
+    let _ = if status.is_empty() {
+        write!(stdout, "\r\n")
+    } else {
+        write!(stdout, "\r\x1b[K\r\n[rwshell] {status}\r\n")
+    };
+    let _ = stdout.flush();
+}
 
-                            if let Err(e) = ws_sender.send(Message::Text(json_str)).await {
-                                error!("Failed to send window size message: {}", e);
-                                break;
-                            }
-                        }
-                        None => {
-                            debug!("Window size channel closed");
-                            break;
-                        }
-                    }
+/// Forward keystrokes and size changes to the session and its output to stdout until the connection
+/// ends. With `resend_size`, that size is sent once the server turns out to be headless.
+async fn forward(
+    connection: Connection,
+    stdin_rx: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    size_rx: &mut mpsc::UnboundedReceiver<(u16, u16)>,
+    server_headless: &AtomicBool,
+    shutdown_flag: &AtomicBool,
+    mut resend_size: Option<(u16, u16)>,
+    heartbeat: Option<Heartbeat>,
+) -> ConnectionEnd {
+    use std::io::{Write, stdout};
+    let Connection {
+        mut sender,
+        mut receiver,
+    } = connection;
+    let mut stdout = stdout();
+
+    // Pings are sent from the loop below, which owns the sender
+    let (ping_tx, mut ping_rx) = mpsc::unbounded_channel();
+    let pings = async {
+        match &heartbeat {
+            Some(heartbeat) => heartbeat.run(|| ping_tx.send(()).is_ok()).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(pings);
+
+    loop {
+        tokio::select! {
+            Some(()) = ping_rx.recv() => {
+                if let Err(e) = sender.send(Message::Ping(Vec::new())).await {
+                    return ConnectionEnd::Lost(e.to_string());
                 }
+            }
 
-                // Check shutdown flag periodically
-                _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {
-                    if shutdown_flag_for_sender.load(std::sync::atomic::Ordering::Relaxed) {
-                        break;
+            _ = &mut pings => {
+                return ConnectionEnd::Lost("the server stopped answering".to_string());
+            }
+
+            // Handle stdin messages
+            stdin_data = stdin_rx.recv() => {
+                let Some(data) = stdin_data else {
+                    debug!("Stdin channel closed");
+                    return ConnectionEnd::Finished;
+                };
+                let write_msg = WriteMessage {
+                    size: data.len(),
+                    data: general_purpose::STANDARD.encode(&data),
+                };
+                let json_str = match encode_message("Write", &write_msg) {
+                    Ok(json_str) => json_str,
+                    Err(e) => {
+                        error!("Failed to encode stdin message: {}", e);
+                        return ConnectionEnd::Finished;
                     }
+                };
+                if let Err(e) = sender.send(Message::Text(json_str)).await {
+                    return ConnectionEnd::Lost(e.to_string());
                 }
             }
-        }
-        debug!("WebSocket sender task ended");
-    });
 
-    // Task for receiving messages from WebSocket and writing to stdout
-    let stdout_task = tokio::spawn(async move {
-        use std::io::{Write, stdout};
-        let mut stdout = stdout();
-
-        while let Some(msg) = ws_receiver.next().await {
-            // Check shutdown flag
-            if shutdown_flag_for_stdout.load(std::sync::atomic::Ordering::Relaxed) {
-                break;
+            // Handle window size change messages
+            size_data = size_rx.recv() => {
+                let Some((cols, rows)) = size_data else {
+                    debug!("Window size channel closed");
+                    return ConnectionEnd::Finished;
+                };
+                if let Err(e) = send_size(&mut sender, cols, rows).await {
+                    return ConnectionEnd::Lost(e.to_string());
+                }
             }
 
-            match msg {
-                Ok(Message::Text(text)) => {
-                    if let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(&text) {
-                        if tty_msg.msg_type == "Write" {
-                            if let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) {
-                                if let Ok(write_msg) = serde_json::from_slice::<WriteMessage>(&data) {
-                                    if let Ok(output) = general_purpose::STANDARD.decode(&write_msg.data) {
-                                        // Write directly to stdout without buffering for immediate display
-                                        if let Err(e) = stdout.write_all(&output) {
-                                            error!("Failed to write to stdout: {}", e);
-                                            break;
-                                        }
-                                        if let Err(e) = stdout.flush() {
-                                            error!("Failed to flush stdout: {}", e);
-                                        }
-                                    }
-                                }
+            // Handle messages from the server
+            msg = receiver.next() => {
+                if let Some(heartbeat) = &heartbeat {
+                    heartbeat.heard();
+                }
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => {
+                        debug!("WebSocket connection closed");
+                        return ConnectionEnd::Lost("the server closed the connection".to_string());
+                    }
+                    // E.g. keepalive probes went unanswered
+                    Some(Err(e)) => {
+                        error!("WebSocket error: {:?}", e);
+                        return ConnectionEnd::Lost(e.to_string());
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(&text) else {
+                    continue;
+                };
+                let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                    continue;
+                };
+
+                if tty_msg.msg_type == "Write" {
+                    if let Ok(write_msg) = serde_json::from_slice::<WriteMessage>(&data) {
+                        if let Ok(output) = general_purpose::STANDARD.decode(&write_msg.data) {
+                            // Write directly to stdout without buffering for immediate display
+                            if let Err(e) = stdout.write_all(&output) {
+                                error!("Failed to write to stdout: {}", e);
+                                return ConnectionEnd::Finished;
                             }
-                        } else if tty_msg.msg_type == "WinSize" {
-                            // Handle window size changes from server
-                            if let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) {
-                                if let Ok(winsize_msg) = serde_json::from_slice::<serde_json::Value>(&data) {
-                                    if let (Some(cols), Some(rows)) = (
-                                        winsize_msg.get("Cols").and_then(|v| v.as_u64()),
-                                        winsize_msg.get("Rows").and_then(|v| v.as_u64()),
-                                    ) {
-                                        debug!("Received window size change from server: {}x{}", cols, rows);
-                                        // Set the actual terminal size
-                                        if let Err(e) = set_terminal_size(cols as u16, rows as u16) {
-                                            error!("Failed to set terminal size: {}", e);
-                                        }
-                                    }
-                                }
+                            if let Err(e) = stdout.flush() {
+                                error!("Failed to flush stdout: {}", e);
                             }
-                        } else if tty_msg.msg_type == "Headless" {
-                            // Handle headless state from server
-                            if let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) {
-                                if let Ok(headless_msg) = serde_json::from_slice::<HeadlessMessage>(&data) {
-                                    debug!("Received headless state from server: {}", headless_msg.headless);
-                                    server_headless_for_stdout
-                                        .store(headless_msg.headless, std::sync::atomic::Ordering::Relaxed);
-                                }
+                        }
+                    }
+                } else if tty_msg.msg_type == "WinSize" {
+                    // Handle window size changes from server
+                    if let Ok(winsize_msg) = serde_json::from_slice::<WinSizeMessage>(&data) {
+                        debug!("Received window size change from server: {}x{}", winsize_msg.cols, winsize_msg.rows);
+                        // Set the actual terminal size
+                        if let Err(e) = set_terminal_size(winsize_msg.cols, winsize_msg.rows) {
+                            error!("Failed to set terminal size: {}", e);
+                        }
+                    }
+                } else if tty_msg.msg_type == "Close" {
+                    // Server is about to close the connection, show why
+                    let Ok(close_msg) = serde_json::from_slice::<CloseMessage>(&data) else {
+                        return ConnectionEnd::Finished;
+                    };
+                    debug!("Received close from server: {:?}", close_msg.reason);
+                    if close_msg.reason.should_reconnect() {
+                        return ConnectionEnd::ServerShutdown(close_msg.message);
+                    }
+                    print_status(&format!("session closed: {}", close_msg.message));
+                    return ConnectionEnd::Finished;
+                } else if tty_msg.msg_type == "Warning" {
+                    // The session is about to end
+                    if let Ok(warning_msg) = serde_json::from_slice::<WarningMessage>(&data) {
+                        print_status(&warning_msg.message);
+                    }
+                } else if tty_msg.msg_type == "Headless" {
+                    // Handle headless state from server
+                    if let Ok(headless_msg) = serde_json::from_slice::<HeadlessMessage>(&data) {
+                        debug!("Received headless state from server: {}", headless_msg.headless);
+                        server_headless.store(headless_msg.headless, std::sync::atomic::Ordering::Relaxed);
+                        if let Some((cols, rows)) = resend_size.take().filter(|_| headless_msg.headless) {
+                            if let Err(e) = send_size(&mut sender, cols, rows).await {
+                                return ConnectionEnd::Lost(e.to_string());
                             }
                         }
                     }
                 }
-                Ok(Message::Close(_)) => {
-                    debug!("WebSocket connection closed");
-                    break;
-                }
-                Err(e) => {
-                    error!("WebSocket error: {:?}", e);
-                    break;
-                }
-                _ => {
-                    // Ignore other message types
+            }
+
+            // Check shutdown flag periodically
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {
+                if shutdown_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                    return ConnectionEnd::Finished;
                 }
             }
         }
-        debug!("Stdout forwarding task ended");
-    });
-
-    // Wait for any task to complete or shutdown flag
-    tokio::select! {
-        _ = stdin_task => {
-            debug!("Stdin task completed");
-        },
-        _ = winsize_task => {
-            debug!("Window size task completed");
-        },
-        _ = sender_task => {
-            debug!("Sender task completed");
-        },
-        _ = stdout_task => {
-            debug!("Stdout task completed");
-        },
     }
+}
 
-    // Set shutdown flag to stop other tasks
-    shutdown_flag.store(true, std::sync::atomic::Ordering::Relaxed);
-
-    // Restore terminal before exiting
-    restore_terminal(&original_termios);
-
+/// Ask a headless session to resize to `cols` x `rows`
+async fn send_size(sender: &mut WsSender, cols: u16, rows: u16) -> Result<()> {
+    let json_str = encode_message("WinSize", &WinSizeMessage { cols, rows })?;
+    sender.send(Message::Text(json_str)).await?;
     Ok(())
 }
 
@@ -490,16 +1184,35 @@ async fn main() -> Result<()> {
     // Initialize logging
     let log_level = if args.verbose { "debug" } else { "info" };
     tracing_subscriber::fmt()
-        .with_env_filter(format!("rwshell_client={log_level}"))
+        .with_env_filter(format!("rwshell_client={log_level},rwshell={log_level}"))
         .init();
 
+    // Watch several sessions at once
+    if !args.grid.is_empty() {
+        if let Err(e) = run_grid(args).await {
+            error!("Client error: {:#}", e);
+            std::process::exit(1);
+        }
+        // Don't wait for the blocked stdin reader before exiting
+        std::process::exit(0);
+    }
+
+    // Follow a session broadcast on the local network
+    if let Some(group) = args.multicast {
+        if let Err(e) = rwshell::multicast::receive(group).await {
+            error!("Client error: {:#}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     // Run client
-    if let Err(e) = run_client(args.session_url).await {
+    if let Err(e) = run_client(args).await {
         error!("Client error: {}", e);
         std::process::exit(1);
     }
-
-    Ok(())
+    // Don't wait for the blocked stdin reader before exiting
+    std::process::exit(0);
 }
 
 fn setup_raw_terminal() -> Result<Termios> {
@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::time::Instant;
+
+/// Buffers whose data counts against `--memory-budget`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferKind {
+    /// Recent output of a session, the scrollback of joining viewers
+    History,
+    /// Output kept as plain text lines for `/api/sessions/{id}/lines`
+    Lines,
+    /// Output held back for a viewer that paused it
+    Pause,
+}
+
+impl BufferKind {
+    pub const ALL: [BufferKind; 3] = [BufferKind::History, BufferKind::Lines, BufferKind::Pause];
+
+    /// Name of the buffer in `/metrics`
+    pub fn label(self) -> &'static str {
+        match self {
+            BufferKind::History => "history",
+            BufferKind::Lines => "lines",
+            BufferKind::Pause => "pause",
+        }
+    }
+}
+
+/// A buffer the budget can take data back from
+pub trait Evict: Send + Sync {
+    /// When the oldest data the buffer holds came in, if it holds any
+    fn oldest(&self) -> Option<Instant>;
+
+    /// Drop the oldest piece of data. Returns the bytes freed, or 0 if there was nothing to drop or the
+    /// buffer is busy right now.
+    fn evict_oldest(&self) -> usize;
+}
+
+/// Memory all sessions of the server may use for buffered output, with `--memory-budget`. Once a buffer
+/// takes it over the budget, the oldest data of any buffer is dropped first, until it fits again, so a
+/// busy server loses old scrollback instead of running out of memory.
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    /// Buffers data can be evicted from; output held back for paused viewers isn't, and is dropped instead
+    /// of growing past the budget
+    buffers: Mutex<Vec<Weak<dyn Evict>>>,
+    evictions: [AtomicU64; 3],
+    evicted_bytes: [AtomicU64; 3],
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            buffers: Mutex::new(Vec::new()),
+            evictions: Default::default(),
+            evicted_bytes: Default::default(),
+        }
+    }
+
+    /// Bytes the budget allows, from `--memory-budget`
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes held by all buffers
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Pieces of data evicted from buffers of `kind`, and their bytes
+    pub fn evictions(&self, kind: BufferKind) -> (u64, u64) {
+        (
+            self.evictions[kind as usize].load(Ordering::Relaxed),
+            self.evicted_bytes[kind as usize].load(Ordering::Relaxed),
+        )
+    }
+
+    fn over(&self) -> bool {
+        self.used() > self.limit
+    }
+
+    /// Let the budget evict data from `buffer`, for as long as it exists
+    pub fn register(&self, buffer: Weak<dyn Evict>) {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner()).push(buffer);
+    }
+
+    /// Evict the oldest data of all buffers until they fit in the budget again, or nothing more can be
+    /// evicted. Buffers that are busy are passed over for the next oldest. Must not be called while holding
+    /// the lock of a registered buffer.
+    pub fn enforce(&self) {
+        while self.over() {
+            let buffers: Vec<Arc<dyn Evict>> = {
+                let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+                buffers.retain(|buffer| buffer.strong_count() > 0);
+                buffers.iter().filter_map(Weak::upgrade).collect()
+            };
+            let mut oldest: Vec<_> = buffers
+                .iter()
+                .filter_map(|buffer| Some((buffer.oldest()?, buffer)))
+                .collect();
+            oldest.sort_by_key(|(at, _)| *at);
+            if !oldest.into_iter().any(|(_, buffer)| buffer.evict_oldest() > 0) {
+                return;
+            }
+        }
+    }
+}
+
+/// The pieces of data a buffer holds against the budget, oldest first, with when each came in. The
+/// buffer keeps its account under its own lock and tells it about every change.
+pub struct Account {
+    budget: Arc<MemoryBudget>,
+    kind: BufferKind,
+    pieces: VecDeque<(Instant, usize)>,
+}
+
+impl Account {
+    pub fn new(budget: Arc<MemoryBudget>, kind: BufferKind) -> Self {
+        Self {
+            budget,
+            kind,
+            pieces: VecDeque::new(),
+        }
+    }
+
+    /// Count `bytes` of new data as one piece
+    pub fn add(&mut self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        self.pieces.push_back((Instant::now(), bytes));
+        self.budget.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Stop counting the oldest `bytes`, which the buffer dropped by itself
+    pub fn release(&mut self, mut bytes: usize) {
+        while bytes > 0 {
+            let Some((_, piece)) = self.pieces.front_mut() else {
+                break;
+            };
+            let released = bytes.min(*piece);
+            *piece -= released;
+            bytes -= released;
+            self.budget.used.fetch_sub(released, Ordering::Relaxed);
+            if *piece == 0 {
+                self.pieces.pop_front();
+            }
+        }
+    }
+
+    /// Stop counting everything, which the buffer dropped by itself
+    pub fn clear(&mut self) {
+        let held: usize = self.pieces.drain(..).map(|(_, bytes)| bytes).sum();
+        self.budget.used.fetch_sub(held, Ordering::Relaxed);
+    }
+
+    /// Evict the oldest piece: stop counting it and count it as evicted. Returns its size, for the buffer
+    /// to drop that many of its oldest bytes.
+    pub fn evict_oldest(&mut self) -> usize {
+        let Some((_, bytes)) = self.pieces.pop_front() else {
+            return 0;
+        };
+        self.budget.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.count_evicted(bytes);
+        bytes
+    }
+
+    /// Evict everything, counting it as evicted
+    pub fn evict_all(&mut self) {
+        let held: usize = self.pieces.drain(..).map(|(_, bytes)| bytes).sum();
+        self.budget.used.fetch_sub(held, Ordering::Relaxed);
+        if held > 0 {
+            self.count_evicted(held);
+        }
+    }
+
+    fn count_evicted(&self, bytes: usize) {
+        self.budget.evictions[self.kind as usize].fetch_add(1, Ordering::Relaxed);
+        self.budget.evicted_bytes[self.kind as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// When the oldest piece came in
+    pub fn oldest(&self) -> Option<Instant> {
+        self.pieces.front().map(|(at, _)| *at)
+    }
+
+    /// Whether all buffers together are over the budget
+    pub fn over_budget(&self) -> bool {
+        self.budget.over()
+    }
+
+    pub fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Counts a session's history against the budget, which keeps it in a `tokio::sync::Mutex` of its own
+pub struct HistoryAccount {
+    history: Arc<tokio::sync::Mutex<Vec<u8>>>,
+    account: Mutex<Account>,
+}
+
+impl HistoryAccount {
+    /// Count `history` against `budget`, letting the budget evict from it
+    pub fn new(budget: &Arc<MemoryBudget>, history: Arc<tokio::sync::Mutex<Vec<u8>>>) -> Arc<Self> {
+        let account = Arc::new(Self {
+            history,
+            account: Mutex::new(Account::new(Arc::clone(budget), BufferKind::History)),
+        });
+        budget.register(Arc::downgrade(&account) as Weak<dyn Evict>);
+        account
+    }
+
+    /// The account, to update while holding the history's lock
+    pub fn account(&self) -> std::sync::MutexGuard<'_, Account> {
+        self.account.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Evict for HistoryAccount {
+    fn oldest(&self) -> Option<Instant> {
+        self.account().oldest()
+    }
+
+    fn evict_oldest(&self) -> usize {
+        // Whoever holds the history may be waiting on the budget; the next enforcement tries again
+        let Ok(mut history) = self.history.try_lock() else {
+            return 0;
+        };
+        let bytes = self.account().evict_oldest();
+        let end = bytes.min(history.len());
+        history.drain(..end);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(budget: &Arc<MemoryBudget>) -> (Arc<tokio::sync::Mutex<Vec<u8>>>, Arc<HistoryAccount>) {
+        let history = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+        let account = HistoryAccount::new(budget, Arc::clone(&history));
+        (history, account)
+    }
+
+    fn write(history: &tokio::sync::Mutex<Vec<u8>>, account: &HistoryAccount, data: &[u8]) {
+        history.try_lock().unwrap().extend_from_slice(data);
+        account.account().add(data.len());
+        // Pieces are ordered by when they came in
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    #[test]
+    fn the_oldest_data_of_any_buffer_is_evicted_first() {
+        let budget = Arc::new(MemoryBudget::new(250));
+        let (first, first_account) = history(&budget);
+        let (second, second_account) = history(&budget);
+        write(&first, &first_account, &[b'a'; 100]);
+        write(&second, &second_account, &[b'b'; 100]);
+        write(&first, &first_account, &[b'c'; 100]);
+        assert_eq!(budget.used(), 300);
+
+        budget.enforce();
+        assert_eq!(budget.used(), 200);
+        assert_eq!(*first.try_lock().unwrap(), [b'c'; 100]);
+        assert_eq!(second.try_lock().unwrap().len(), 100);
+        assert_eq!(budget.evictions(BufferKind::History), (1, 100));
+    }
+
+    #[test]
+    fn busy_buffers_are_passed_over() {
+        let budget = Arc::new(MemoryBudget::new(150));
+        let (first, first_account) = history(&budget);
+        let (second, second_account) = history(&budget);
+        write(&first, &first_account, &[b'a'; 100]);
+        write(&second, &second_account, &[b'b'; 100]);
+
+        let held = first.try_lock().unwrap();
+        budget.enforce();
+        assert_eq!(held.len(), 100);
+        assert!(second.try_lock().unwrap().is_empty());
+        assert_eq!(budget.used(), 100);
+        drop(held);
+
+        // With nothing left to evict but the held buffer, enforcing gives up instead of spinning
+        write(&first, &first_account, &[b'c'; 100]);
+        let held = first.try_lock().unwrap();
+        budget.enforce();
+        assert_eq!(budget.used(), 200);
+        drop(held);
+        budget.enforce();
+        assert_eq!(budget.used(), 100);
+    }
+
+    #[test]
+    fn dropped_buffers_stop_counting() {
+        let budget = Arc::new(MemoryBudget::new(1000));
+        let (history, account) = history(&budget);
+        write(&history, &account, b"output");
+        account.account().release(2);
+        assert_eq!(budget.used(), 4);
+        drop(account);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.evictions(BufferKind::History), (0, 0));
+    }
+}
@@ -0,0 +1,491 @@
+use crate::identity::ServerIdentity;
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// PASETO v4 public token header
+const TOKEN_HEADER: &str = "v4.public.";
+
+/// Length of an Ed25519 signature
+const SIGNATURE_LEN: usize = 64;
+
+/// Access mode granted by a capability token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccessMode {
+    #[serde(rename = "ro")]
+    ReadOnly,
+    #[serde(rename = "rw")]
+    ReadWrite,
+}
+
+/// Claims carried inside a capability token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capability {
+    /// Session the token grants access to
+    #[serde(rename = "sid")]
+    pub session_id: String,
+    /// Expiry time (RFC 3339), if any
+    #[serde(rename = "exp", skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    /// Whether the holder may write to the session
+    #[serde(rename = "mode")]
+    pub mode: AccessMode,
+    /// Limits on the use of the token, on top of its expiry
+    #[serde(rename = "quota", default, skip_serializing_if = "Quota::is_unlimited")]
+    pub quota: Quota,
+}
+
+/// Limits on how much a capability token may be used, counted by the server over all its connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Most WebSocket connections made with the token
+    #[serde(rename = "conn", default, skip_serializing_if = "Option::is_none")]
+    pub max_connects: Option<u32>,
+    /// Most bytes of terminal output sent to the token's connections
+    #[serde(rename = "bytes", default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Longest time the token's connections may stay open in total, in seconds
+    #[serde(rename = "secs", default, skip_serializing_if = "Option::is_none")]
+    pub max_seconds: Option<u64>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Capability {
+    pub fn new(session_id: &str, mode: AccessMode, ttl: Option<Duration>) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            expires: ttl.map(|ttl| humantime::format_rfc3339_seconds(SystemTime::now() + ttl).to_string()),
+            mode,
+            quota: Quota::default(),
+        }
+    }
+
+    pub fn readonly(&self) -> bool {
+        self.mode == AccessMode::ReadOnly
+    }
+
+    fn is_expired(&self) -> Result<bool> {
+        match &self.expires {
+            Some(expires) => {
+                let expires = humantime::parse_rfc3339(expires).context("Invalid token expiry")?;
+                Ok(SystemTime::now() >= expires)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Pre-authentication encoding from the PASETO specification
+fn pre_auth_encode(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend_from_slice(&(pieces.len() as u64).to_le_bytes());
+    for piece in pieces {
+        output.extend_from_slice(&(piece.len() as u64).to_le_bytes());
+        output.extend_from_slice(piece);
+    }
+    output
+}
+
+/// Mint a PASETO v4.public token for `capability`, signed with the server identity key
+pub fn mint(identity: &ServerIdentity, capability: &Capability) -> Result<String> {
+    let message = serde_json::to_vec(capability)?;
+    let signature = identity.sign(&pre_auth_encode(&[TOKEN_HEADER.as_bytes(), &message, b"", b""]));
+
+    let mut payload = message;
+    payload.extend_from_slice(&signature);
+
+    Ok(format!(
+        "{TOKEN_HEADER}{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(payload)
+    ))
+}
+
+/// Verify a capability token for `session_id`, returning its claims if it is valid and unexpired
+pub fn verify(identity: &ServerIdentity, session_id: &str, token: &str) -> Result<Capability> {
+    let encoded = token
+        .strip_prefix(TOKEN_HEADER)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported token version"))?;
+    if encoded.contains('.') {
+        return Err(anyhow::anyhow!("Token footers are not supported"));
+    }
+
+    let payload = general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded)
+        .context("Invalid token encoding")?;
+    if payload.len() <= SIGNATURE_LEN {
+        return Err(anyhow::anyhow!("Token too short"));
+    }
+
+    let (message, signature) = payload.split_at(payload.len() - SIGNATURE_LEN);
+    if !identity.verify(
+        &pre_auth_encode(&[TOKEN_HEADER.as_bytes(), message, b"", b""]),
+        signature,
+    ) {
+        return Err(anyhow::anyhow!("Invalid token signature"));
+    }
+
+    let capability: Capability = serde_json::from_slice(message).context("Invalid token claims")?;
+    if capability.session_id != session_id {
+        return Err(anyhow::anyhow!("Token is for another session"));
+    }
+    if capability.is_expired()? {
+        return Err(anyhow::anyhow!("Token expired"));
+    }
+
+    Ok(capability)
+}
+
+/// Outcome of using a capability token from a client address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenUse {
+    /// First use of the token, or another use from the address it is bound to
+    Bound,
+    /// The token was first used from another address
+    Moved { from: IpAddr },
+    /// The token was revoked after moving to another address
+    Revoked,
+}
+
+/// Binds capability tokens to the address they were first used from, so leaked links can be noticed
+pub struct TokenBindings {
+    /// Revoke tokens used from a second address instead of only reporting it
+    strict: bool,
+    bindings: Mutex<HashMap<String, IpAddr>>,
+    revoked: Mutex<HashSet<String>>,
+}
+
+impl TokenBindings {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            bindings: Mutex::new(HashMap::new()),
+            revoked: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn is_revoked(&self, token: &str) -> bool {
+        self.revoked.lock().unwrap_or_else(|e| e.into_inner()).contains(token)
+    }
+
+    /// Record a use of `token` from `addr`
+    pub fn record(&self, token: &str, addr: IpAddr) -> TokenUse {
+        if self.is_revoked(token) {
+            return TokenUse::Revoked;
+        }
+
+        let mut bindings = self.bindings.lock().unwrap_or_else(|e| e.into_inner());
+        let bound = *bindings.entry(token.to_string()).or_insert(addr);
+        if bound == addr {
+            return TokenUse::Bound;
+        }
+
+        if self.strict {
+            self.revoked
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(token.to_string());
+        }
+        TokenUse::Moved { from: bound }
+    }
+}
+
+/// How much a capability token with a quota has been used
+#[derive(Debug, Default)]
+struct Usage {
+    connects: u32,
+    bytes: u64,
+    /// Time the token had connections open, up to when the last of them closed
+    connected: Duration,
+    /// Connections made with the token that are open now
+    open: u32,
+    /// Since when the token has had a connection open, if it has any
+    open_since: Option<Instant>,
+}
+
+impl Usage {
+    /// Time the token has had connections open so far. Connections open at once count only once, so a
+    /// link can't be stretched by opening it in several tabs.
+    fn connected(&self, now: Instant) -> Duration {
+        self.connected
+            + self
+                .open_since
+                .map_or(Duration::ZERO, |since| now.duration_since(since))
+    }
+
+    fn exceeded(&self, quota: &Quota, now: Instant) -> Option<String> {
+        if let Some(max) = quota.max_connects.filter(|max| self.connects >= *max) {
+            return Some(format!("This session link was limited to {max} connections"));
+        }
+        if let Some(max) = quota.max_bytes.filter(|max| self.bytes >= *max) {
+            return Some(bytes_exceeded(max));
+        }
+        if let Some(max) = quota.max_seconds.filter(|max| self.connected(now).as_secs() >= *max) {
+            return Some(format!(
+                "This session link was limited to {} of viewing",
+                humantime::format_duration(Duration::from_secs(max))
+            ));
+        }
+        None
+    }
+}
+
+/// Counts the use of capability tokens against their quotas, for as long as the server runs
+#[derive(Default)]
+pub struct TokenQuotas {
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl TokenQuotas {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Usage>> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Why `token` can't be used any more under `quota`, if it is used up
+    pub fn exceeded(&self, token: &str, quota: &Quota) -> Option<String> {
+        self.lock().get(token)?.exceeded(quota, Instant::now())
+    }
+
+    /// Count a new connection made with `token`, unless it is used up. Its time counts toward the quota
+    /// until the returned connection is dropped.
+    pub fn connect(self: &Arc<Self>, token: &str, quota: &Quota) -> Result<QuotaConnection, String> {
+        let now = Instant::now();
+        let mut usage = self.lock();
+        let usage = usage.entry(token.to_string()).or_default();
+        if let Some(reason) = usage.exceeded(quota, now) {
+            return Err(reason);
+        }
+        usage.connects += 1;
+        usage.open += 1;
+        usage.open_since.get_or_insert(now);
+        // Time passes at the same rate however many connections are open, so they all share a deadline
+        let deadline = quota
+            .max_seconds
+            .map(|max| now + Duration::from_secs(max).saturating_sub(usage.connected(now)));
+        Ok(QuotaConnection {
+            quotas: Arc::clone(self),
+            token: token.to_string(),
+            quota: *quota,
+            deadline,
+        })
+    }
+
+    /// Count `bytes` of output sent to a connection made with `token`, returning why it must close if
+    /// that used up its quota
+    pub fn add_bytes(&self, token: &str, quota: &Quota, bytes: usize) -> Option<String> {
+        let max = quota.max_bytes?;
+        let mut usage = self.lock();
+        let usage = usage.entry(token.to_string()).or_default();
+        usage.bytes += bytes as u64;
+        (usage.bytes >= max).then(|| bytes_exceeded(max))
+    }
+
+    fn disconnect(&self, token: &str) {
+        let mut usage = self.lock();
+        let Some(usage) = usage.get_mut(token) else { return };
+        usage.open -= 1;
+        if usage.open == 0 {
+            if let Some(since) = usage.open_since.take() {
+                usage.connected += since.elapsed();
+            }
+        }
+    }
+}
+
+/// A connection made with a capability token, counted by [`TokenQuotas`] until dropped
+pub struct QuotaConnection {
+    quotas: Arc<TokenQuotas>,
+    token: String,
+    quota: Quota,
+    deadline: Option<Instant>,
+}
+
+impl QuotaConnection {
+    /// When the token's time runs out, if its time is limited
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Count `bytes` of output, returning why the connection must close if that used up the quota
+    pub fn add_bytes(&self, bytes: usize) -> Option<String> {
+        self.quotas.add_bytes(&self.token, &self.quota, bytes)
+    }
+
+    /// Stop counting the connection, returning why the quota is used up if it is
+    pub fn finish(self) -> Option<String> {
+        let (quotas, token, quota) = (Arc::clone(&self.quotas), self.token.clone(), self.quota);
+        drop(self);
+        quotas.exceeded(&token, &quota)
+    }
+}
+
+impl Drop for QuotaConnection {
+    fn drop(&mut self) {
+        self.quotas.disconnect(&self.token);
+    }
+}
+
+fn bytes_exceeded(max: u64) -> String {
+    format!("This session link was limited to {max} bytes of output")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> ServerIdentity {
+        let path = std::env::temp_dir().join(format!("rwshell-test-identity-{}", uuid::Uuid::new_v4()));
+        let identity = ServerIdentity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        identity
+    }
+
+    #[test]
+    fn tokens_verify_for_their_session() {
+        let identity = identity();
+        let token = mint(&identity, &Capability::new("abc", AccessMode::ReadOnly, None)).unwrap();
+        let capability = verify(&identity, "abc", &token).unwrap();
+        assert!(capability.readonly());
+        assert!(verify(&identity, "other", &token).is_err());
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let (identity, other) = (identity(), identity());
+        let token = mint(&identity, &Capability::new("abc", AccessMode::ReadOnly, None)).unwrap();
+        let mut payload = general_purpose::URL_SAFE_NO_PAD
+            .decode(token.strip_prefix(TOKEN_HEADER).unwrap())
+            .unwrap();
+
+        // Upgrading the claims to read-write breaks the signature
+        let claims = String::from_utf8(payload[..payload.len() - SIGNATURE_LEN].to_vec()).unwrap();
+        let forged = claims.replace("\"ro\"", "\"rw\"");
+        assert_ne!(forged, claims);
+        let mut forged = forged.into_bytes();
+        forged.extend_from_slice(&payload[payload.len() - SIGNATURE_LEN..]);
+        let forged = format!("{TOKEN_HEADER}{}", general_purpose::URL_SAFE_NO_PAD.encode(forged));
+        assert!(verify(&identity, "abc", &forged).is_err());
+
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        let flipped = format!("{TOKEN_HEADER}{}", general_purpose::URL_SAFE_NO_PAD.encode(payload));
+        assert!(verify(&identity, "abc", &flipped).is_err());
+
+        // Another server's key doesn't verify, and neither do other versions or footers
+        assert!(verify(&other, "abc", &token).is_err());
+        assert!(verify(&identity, "abc", &token.replacen("v4.", "v3.", 1)).is_err());
+        assert!(verify(&identity, "abc", &format!("{token}.footer")).is_err());
+        assert!(verify(&identity, "abc", TOKEN_HEADER).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let identity = identity();
+        let mut capability = Capability::new("abc", AccessMode::ReadWrite, Some(Duration::from_secs(60)));
+        let token = mint(&identity, &capability).unwrap();
+        assert!(verify(&identity, "abc", &token).is_ok());
+
+        capability.expires = Some("2000-01-01T00:00:00Z".to_string());
+        let token = mint(&identity, &capability).unwrap();
+        assert!(verify(&identity, "abc", &token).is_err());
+    }
+
+    fn quota(max_connects: Option<u32>, max_seconds: Option<u64>) -> Quota {
+        Quota {
+            max_connects,
+            max_bytes: None,
+            max_seconds,
+        }
+    }
+
+    #[test]
+    fn connections_open_at_once_share_the_time_quota() {
+        let quotas = Arc::new(TokenQuotas::default());
+        let quota = quota(None, Some(1));
+        let first = quotas.connect("token", &quota).unwrap();
+        std::thread::sleep(Duration::from_millis(600));
+        let second = quotas.connect("token", &quota).unwrap();
+        // The second connection gets what is left, not a second full allowance
+        assert_eq!(first.deadline(), second.deadline());
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(quotas.exceeded("token", &quota).is_some());
+        assert!(quotas.connect("token", &quota).is_err());
+        drop((first, second));
+        assert!(quotas.connect("token", &quota).is_err());
+    }
+
+    #[test]
+    fn parallel_connects_stop_at_the_connection_quota() {
+        let quotas = Arc::new(TokenQuotas::default());
+        let quota = quota(Some(4), None);
+        let connected = std::thread::scope(|scope| {
+            let attempts: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| quotas.connect("token", &quota)))
+                .collect();
+            attempts
+                .into_iter()
+                .map(|attempt| attempt.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(connected.iter().filter(|connection| connection.is_ok()).count(), 4);
+    }
+
+    #[test]
+    fn used_up_quotas_refuse_connections() {
+        let quotas = Arc::new(TokenQuotas::default());
+        let quota = Quota {
+            max_connects: Some(2),
+            max_bytes: Some(100),
+            max_seconds: None,
+        };
+        let connection = quotas.connect("token", &quota).unwrap();
+        assert_eq!(connection.add_bytes(60), None);
+        assert!(connection.add_bytes(40).is_some());
+        assert!(connection.finish().is_some());
+        assert!(quotas.exceeded("token", &quota).is_some());
+        assert!(quotas.connect("token", &quota).is_err());
+        // Other tokens are counted apart
+        assert!(quotas.connect("other", &quota).is_ok());
+
+        let quota = Quota {
+            max_bytes: None,
+            ..quota
+        };
+        drop(quotas.connect("third", &quota).unwrap());
+        drop(quotas.connect("third", &quota).unwrap());
+        assert!(
+            quotas
+                .connect("third", &quota)
+                .err()
+                .is_some_and(|reason| reason.contains("2 connections"))
+        );
+    }
+
+    #[test]
+    fn quotas_are_signed_into_tokens() {
+        let identity = identity();
+        let mut capability = Capability::new("abc", AccessMode::ReadOnly, None);
+        capability.quota = quota(Some(3), Some(600));
+        let token = mint(&identity, &capability).unwrap();
+        assert_eq!(verify(&identity, "abc", &token).unwrap().quota, capability.quota);
+    }
+
+    #[test]
+    fn time_counts_only_while_connected() {
+        let quotas = Arc::new(TokenQuotas::default());
+        let quota = quota(None, Some(60));
+        drop(quotas.connect("token", &quota).unwrap());
+        std::thread::sleep(Duration::from_millis(50));
+        let connection = quotas.connect("token", &quota).unwrap();
+        let left = connection.deadline().unwrap() - Instant::now();
+        assert!(left > Duration::from_millis(59_900), "{left:?}");
+    }
+}
@@ -0,0 +1,139 @@
+use crate::compression::Compression;
+use crate::session::WriteMessage;
+use crate::stderr::StderrMessage;
+use crate::transform::{OutputStage, StripAnsi};
+use crate::websocket::{
+    BINARY_WRITE, BINARY_WRITE_COMPRESSED, CloseMessage, TtyMessage, decode_binary_frame, session_websocket_url,
+};
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use futures_util::StreamExt;
+use std::io::Write;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, USER_AGENT};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::debug;
+use url::Url;
+
+/// Follow a session as a viewer that never sends input, writing its output to stdout until the
+/// session ends. With `plain`, escape sequences are removed so the output can be searched. Output the
+/// program wrote to stderr under `--separate-stderr` goes to stderr.
+pub async fn run(session_url: &str, plain: bool) -> Result<()> {
+    let url = Url::parse(session_url).with_context(|| format!("Invalid session URL {session_url}"))?;
+    crate::version::check_server(&url).await?;
+
+    let ws_url = session_websocket_url(&url)?;
+    debug!("Connecting to WebSocket: {}", ws_url);
+    // Ask for the binary protocol, compressed in whichever way the server offers. The list has no spaces,
+    // which tungstenite would keep in the names it checks the answer against
+    let mut request = ws_url.as_str().into_client_request()?;
+    request.headers_mut().insert(
+        USER_AGENT,
+        HeaderValue::from_static(concat!("rwshell-cat/", env!("CARGO_PKG_VERSION"))),
+    );
+    if let Some(authorization) = crate::auth::basic_authorization(&ws_url) {
+        request.headers_mut().insert(AUTHORIZATION, authorization);
+    }
+    let protocols = [Compression::Zstd, Compression::Deflate, Compression::None].map(Compression::protocol);
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, protocols.join(",").parse()?);
+    let (ws_stream, response) = connect_async(request)
+        .await
+        .with_context(|| format!("Failed to connect to {session_url}"))?;
+    let compression = response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(Compression::from_protocol)
+        .unwrap_or_default();
+    debug!("Receiving output with {:?} compression", compression);
+    let (_ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let mut strip = plain.then(StripAnsi::new);
+    let mut stdout = std::io::stdout().lock();
+    let mut text = Vec::new();
+
+    while let Some(msg) = ws_receiver.next().await {
+        let text_msg = match msg.context("Connection to the session failed")? {
+            Message::Text(text_msg) => text_msg,
+            Message::Binary(frame) => {
+                let output = match decode_binary_frame(&frame) {
+                    Some((BINARY_WRITE, output)) => output.to_vec(),
+                    Some((BINARY_WRITE_COMPRESSED, compressed)) => compression
+                        .decompress(compressed)
+                        .context("Failed to decompress output from the session")?,
+                    _ => continue,
+                };
+                if !write_output(&mut stdout, &mut strip, &mut text, &output)? {
+                    return Ok(());
+                }
+                continue;
+            }
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(&text_msg) else {
+            continue;
+        };
+        let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+            continue;
+        };
+
+        match tty_msg.msg_type.as_str() {
+            "Write" => {
+                let Some(output) = serde_json::from_slice::<WriteMessage>(&data)
+                    .ok()
+                    .and_then(|write_msg| general_purpose::STANDARD.decode(&write_msg.data).ok())
+                else {
+                    continue;
+                };
+                if !write_output(&mut stdout, &mut strip, &mut text, &output)? {
+                    return Ok(());
+                }
+            }
+            "Stderr" => {
+                if let Some(output) = serde_json::from_slice::<StderrMessage>(&data)
+                    .ok()
+                    .and_then(|stderr_msg| general_purpose::STANDARD.decode(&stderr_msg.data).ok())
+                {
+                    let mut stderr = std::io::stderr().lock();
+                    let _ = stderr.write_all(&output).and_then(|_| stderr.flush());
+                }
+            }
+            "Close" => {
+                if let Ok(close_msg) = serde_json::from_slice::<CloseMessage>(&data) {
+                    eprintln!("[rwshell] session closed: {}", close_msg.message);
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `output` to stdout, without escape sequences with `strip`. Returns false once the reader went
+/// away, e.g. `head` has seen enough.
+fn write_output(
+    stdout: &mut impl Write,
+    strip: &mut Option<StripAnsi>,
+    text: &mut Vec<u8>,
+    output: &[u8],
+) -> Result<bool> {
+    let output = match strip {
+        Some(strip) => {
+            text.clear();
+            strip.process(output, text);
+            &text[..]
+        }
+        None => output,
+    };
+    match stdout.write_all(output).and_then(|_| stdout.flush()) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(false),
+        Err(e) => Err(e).context("Failed to write to stdout"),
+    }
+}
@@ -0,0 +1,51 @@
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "rwshell-client")]
+#[command(about = "Connect to a rwshell session")]
+pub struct ClientArgs {
+    /// The session URL to connect to
+    #[arg(
+        help = "Session URL (e.g. http://localhost:8000/s/local/)",
+        required_unless_present_any = ["grid", "multicast"],
+        conflicts_with_all = ["grid", "multicast"]
+    )]
+    pub session_url: Option<String>,
+
+    /// Watch several sessions side by side, read-only; press q to quit
+    #[arg(long, num_args = 1.., value_name = "URL")]
+    pub grid: Vec<String>,
+
+    /// Experimental: show a session broadcast with `rwshell --multicast` on the local network, read-only
+    #[arg(long, value_name = "GROUP:PORT", value_parser = crate::multicast::parse_group, conflicts_with = "grid")]
+    pub multicast: Option<std::net::SocketAddrV4>,
+
+    /// Enable verbose logging
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Path to the known hosts file (default: ~/.config/rwshell/known_hosts)
+    #[arg(long)]
+    pub known_hosts: Option<std::path::PathBuf>,
+
+    /// Skip server identity verification (insecure)
+    #[arg(long)]
+    pub no_verify_identity: bool,
+
+    /// Connect again when the connection drops or the server restarts, waiting up to 30s between attempts,
+    /// instead of exiting
+    #[arg(long)]
+    pub reconnect: bool,
+
+    /// Probe the connection once silent for this long, to notice when it drops ("0s" to turn off)
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    pub tcp_keepalive: std::time::Duration,
+
+    /// Ping the server this often, to notice when the connection is gone ("0s" to turn off)
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = humantime::parse_duration)]
+    pub ping_interval: std::time::Duration,
+
+    /// Give up on the connection when the server doesn't answer a ping within this time
+    #[arg(long, value_name = "DURATION", default_value = "20s", value_parser = humantime::parse_duration)]
+    pub ping_timeout: std::time::Duration,
+}
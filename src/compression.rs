@@ -0,0 +1,81 @@
+use crate::websocket::{BINARY_PROTOCOL, BINARY_WRITE, BINARY_WRITE_COMPRESSED, binary_frame};
+use bytes::Bytes;
+use std::io::{Read, Write};
+
+/// Output shorter than this is sent as is; compressing an echoed keystroke only makes it larger
+const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// Compression of output sent to clients of the binary protocol that ask for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zlib streams, which browsers decompress with `DecompressionStream`
+    Deflate,
+    /// Zstandard frames, smaller and faster than deflate
+    Zstd,
+}
+
+impl Compression {
+    /// The compression negotiated with WebSocket subprotocol `protocol`, if it is a binary one
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        [Self::None, Self::Deflate, Self::Zstd]
+            .into_iter()
+            .find(|compression| compression.protocol() == protocol)
+    }
+
+    /// WebSocket subprotocol of the binary protocol with this compression, e.g. "rwshell.v2+zstd"
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Self::None => BINARY_PROTOCOL,
+            Self::Deflate => "rwshell.v2+deflate",
+            Self::Zstd => "rwshell.v2+zstd",
+        }
+    }
+
+    /// Subprotocols a server with this compression accepts, preferred first
+    pub fn protocols(self) -> Vec<&'static str> {
+        match self {
+            Self::None => vec![BINARY_PROTOCOL],
+            _ => vec![self.protocol(), BINARY_PROTOCOL],
+        }
+    }
+
+    /// The binary message carrying `output`, compressed if that makes it smaller
+    pub fn frame(self, output: &[u8]) -> Bytes {
+        if self != Self::None && output.len() >= MIN_COMPRESSED_SIZE {
+            if let Ok(compressed) = self.compress(output) {
+                if compressed.len() < output.len() {
+                    return binary_frame(BINARY_WRITE_COMPRESSED, &compressed);
+                }
+            }
+        }
+        binary_frame(BINARY_WRITE, output)
+    }
+
+    pub fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        match self {
+            Self::None => output.extend_from_slice(data),
+            Self::Deflate => {
+                flate2::read::ZlibDecoder::new(data).read_to_end(&mut output)?;
+            }
+            Self::Zstd => {
+                zstd::stream::read::Decoder::new(data)?.read_to_end(&mut output)?;
+            }
+        }
+        Ok(output)
+    }
+}
@@ -0,0 +1,203 @@
+use crate::args::{Args, Subcommand};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{CommandFactory, Parser};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Options that only make sense on the command line
+const COMMAND_LINE_ONLY: &[&str] = &["config", "profile", "version", "help"];
+
+/// Default location of the config file
+pub fn default_config_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rwshell")
+        .join("config.toml")
+}
+
+/// Options read from the config file.
+///
+/// Top-level keys apply to every session and `[profiles.<name>]` tables to sessions started with
+/// `--profile <name>`. Keys are the long command line options, e.g. `listen = "0.0.0.0:8000"`,
+/// `readonly = true` or `plugin = ["redact.wasm"]`; `env` takes a table of environment variables.
+#[derive(Debug, Default)]
+pub struct Config {
+    path: PathBuf,
+    defaults: toml::Table,
+    profiles: toml::Table,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut defaults: toml::Table =
+            toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))?;
+        let profiles = match defaults.remove("profiles") {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => bail!("`profiles` in {} must be a table", path.display()),
+            None => toml::Table::new(),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            defaults,
+            profiles,
+        })
+    }
+
+    /// Command line options for the defaults and `profile`, in order of increasing precedence
+    pub fn options(&self, profile: Option<&str>) -> Result<Vec<OsString>> {
+        let mut options = table_options(&self.defaults).with_context(|| format!("In {}", self.path.display()))?;
+
+        if let Some(name) = profile {
+            let table = match self.profiles.get(name) {
+                Some(toml::Value::Table(table)) => table,
+                Some(_) => bail!("Profile {} in {} must be a table", name, self.path.display()),
+                None => {
+                    let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                    bail!(
+                        "No profile named {} in {} (available: {})",
+                        name,
+                        self.path.display(),
+                        if names.is_empty() {
+                            "none".to_string()
+                        } else {
+                            names.join(", ")
+                        }
+                    );
+                }
+            };
+            let profile_options =
+                table_options(table).with_context(|| format!("In profile {} of {}", name, self.path.display()))?;
+            options.extend(profile_options);
+        }
+
+        Ok(options)
+    }
+}
+
+/// Turn config keys into the equivalent command line options
+fn table_options(table: &toml::Table) -> Result<Vec<OsString>> {
+    let command = Args::command();
+    let mut options = Vec::new();
+
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()))
+            .filter(|_| !COMMAND_LINE_ONLY.contains(&name.as_str()))
+            .ok_or_else(|| anyhow!("Unknown option `{key}`"))?;
+
+        let values = match value {
+            toml::Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(enabled) if !arg.get_action().takes_values() => {
+                    if enabled {
+                        options.push(format!("--{name}").into());
+                    }
+                }
+                toml::Value::Table(entries) => {
+                    for (entry, value) in entries {
+                        // An array gives the entry several values, e.g. several sinks for an event
+                        let values = match value {
+                            toml::Value::Array(values) => values,
+                            value => vec![value],
+                        };
+                        for value in values {
+                            options.push(format!("--{name}={entry}={}", scalar(&value, key)?).into());
+                        }
+                    }
+                }
+                value => options.push(format!("--{name}={}", scalar(&value, key)?).into()),
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+/// A config value as it would be written on the command line
+fn scalar(value: &toml::Value, key: &str) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Datetime(value) => Ok(value.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => bail!("Unsupported value for `{key}`"),
+    }
+}
+
+/// Parse the command line, filling in options from the config file and the selected profile.
+///
+/// Options given on the command line take precedence over the profile, which takes precedence over
+/// the top-level options of the config file.
+pub fn parse_args() -> Result<Args> {
+    let command_line: Vec<OsString> = std::env::args_os().collect();
+    let args = Args::parse_from(&command_line);
+
+    // The setup wizard reads the config file itself, and may be run to replace a broken one, as does
+    // the doctor to report what is wrong with it. Completions and man pages describe the options rather
+    // than use them, `cat` joins another session, recordings are processed offline, and panes run
+    // inside a session that already read it.
+    if matches!(
+        args.subcommand,
+        Some(
+            Subcommand::Init
+                | Subcommand::Doctor
+                | Subcommand::Cat { .. }
+                | Subcommand::Export { .. }
+                | Subcommand::Diff { .. }
+                | Subcommand::Render { .. }
+                | Subcommand::Panes { .. }
+                | Subcommand::Completions { .. }
+                | Subcommand::Manpage { .. }
+        )
+    ) {
+        return Ok(args);
+    }
+
+    let options = config_options(&args)?;
+    if options.is_empty() {
+        return Ok(args);
+    }
+    Ok(Args::parse_from(with_options(&command_line, options)))
+}
+
+/// Read the config file as [`parse_args`] does for `args`, parsed from the command line, but report
+/// invalid options as an error instead of exiting. Returns the options in effect.
+pub fn check(args: &Args) -> Result<Args> {
+    let command_line: Vec<OsString> = std::env::args_os().collect();
+    let options = config_options(args)?;
+    Args::try_parse_from(with_options(&command_line, options)).map_err(|e| {
+        // Only the first line, without the usage that follows
+        let message = e.to_string();
+        let first_line = message.lines().next().unwrap_or_default();
+        anyhow!("{}", first_line.trim_start_matches("error: "))
+    })
+}
+
+/// The options of the config file and the profile `args` selects, or none without a config file
+fn config_options(args: &Args) -> Result<Vec<OsString>> {
+    let path = args.config.clone().unwrap_or_else(default_config_path);
+    if args.config.is_none() && !path.exists() {
+        if args.profile.is_some() {
+            bail!("--profile needs a config file, but {} doesn't exist", path.display());
+        }
+        return Ok(Vec::new());
+    }
+    Config::load(&path)?.options(args.profile.as_deref())
+}
+
+/// `command_line` with `options` inserted after the program name, so the command line overrides them
+fn with_options(command_line: &[OsString], options: Vec<OsString>) -> Vec<OsString> {
+    let mut merged = command_line[..1].to_vec();
+    merged.extend(options);
+    merged.extend_from_slice(&command_line[1..]);
+    merged
+}
@@ -0,0 +1,395 @@
+use crate::host::HostMode;
+use crate::notify::NotifyEvent;
+use crate::server::AppState;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::info;
+
+/// How long a held command waits for the host before it is refused
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// What became of a viewer's command held for confirmation
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ConfirmationStatus {
+    Pending,
+    Allowed,
+    Refused,
+    /// Allowed, and the viewer's next commands no longer wait for the host under --training-wheels
+    Trusted,
+}
+
+/// Tells a viewer that the host is asked to confirm one of its commands, and the answer
+#[derive(Debug, Serialize)]
+pub struct ConfirmationMessage {
+    #[serde(rename = "Line")]
+    pub line: String,
+    #[serde(rename = "Status")]
+    pub status: ConfirmationStatus,
+}
+
+/// The host's answer to a held command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Allow,
+    Refuse,
+    /// Allow the command and stop holding the viewer's commands under --training-wheels
+    Trust,
+}
+
+impl Answer {
+    fn status(self) -> ConfirmationStatus {
+        match self {
+            Self::Allow => ConfirmationStatus::Allowed,
+            Self::Refuse => ConfirmationStatus::Refused,
+            Self::Trust => ConfirmationStatus::Trusted,
+        }
+    }
+}
+
+/// Answer to the oldest held command, sent to the control socket by `rwshell confirm`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmMessage {
+    #[serde(rename = "Allow")]
+    pub allow: bool,
+    #[serde(rename = "Trust", default)]
+    pub trust: bool,
+}
+
+impl ConfirmMessage {
+    pub fn new(answer: Answer) -> Self {
+        Self {
+            allow: answer != Answer::Refuse,
+            trust: answer == Answer::Trust,
+        }
+    }
+
+    pub fn answer(&self) -> Answer {
+        match (self.allow, self.trust) {
+            (false, _) => Answer::Refuse,
+            (true, false) => Answer::Allow,
+            (true, true) => Answer::Trust,
+        }
+    }
+}
+
+/// Reply of the control socket to a [`ConfirmMessage`]: the line that was answered, if any was waiting
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmedMessage {
+    #[serde(rename = "Line")]
+    pub line: Option<String>,
+}
+
+/// The command line a viewer is typing, as far as it can be followed from its keystrokes.
+///
+/// Text recalled from the shell history or completed by the shell isn't seen.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    text: Vec<u8>,
+    escape: Escape,
+}
+
+/// Where the line buffer is in an escape sequence, such as an arrow key
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    #[default]
+    None,
+    Start,
+    Csi,
+    Ss3,
+}
+
+impl LineBuffer {
+    /// Follow `data` up to the first Enter, returning its index if there is one
+    fn feed(&mut self, data: &[u8]) -> Option<usize> {
+        for (index, &byte) in data.iter().enumerate() {
+            match (self.escape, byte) {
+                (Escape::Start, b'[') => self.escape = Escape::Csi,
+                (Escape::Start, b'O') => self.escape = Escape::Ss3,
+                (Escape::Csi, 0x40..=0x7e) | (Escape::Start | Escape::Ss3, _) => self.escape = Escape::None,
+                (Escape::Csi, _) => {}
+                (Escape::None, 0x1b) => self.escape = Escape::Start,
+                (Escape::None, b'\r' | b'\n') => return Some(index),
+                (Escape::None, 0x7f | 0x08) => {
+                    // Remove a whole character, including its UTF-8 continuation bytes
+                    while let Some(byte) = self.text.pop() {
+                        if byte & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                }
+                // Ctrl+C and Ctrl+U abandon the line
+                (Escape::None, 0x03 | 0x15) => self.text.clear(),
+                (Escape::None, 0x00..=0x1f) => {}
+                (Escape::None, _) => self.text.push(byte),
+            }
+        }
+        None
+    }
+
+    fn line(&self) -> String {
+        String::from_utf8_lossy(&self.text).into_owned()
+    }
+}
+
+/// A viewer's command waiting for the host
+struct Pending {
+    client_id: String,
+    line: String,
+    reply: oneshot::Sender<Answer>,
+}
+
+/// Holds viewer commands matching `--confirm-pattern` until the host allows them, and with
+/// `--training-wheels` every command of the viewers the host doesn't trust yet
+pub struct ConfirmGate {
+    patterns: Vec<Regex>,
+    training_wheels: bool,
+    pending: std::sync::Mutex<VecDeque<Pending>>,
+    trusted: std::sync::Mutex<HashSet<String>>,
+}
+
+impl ConfirmGate {
+    pub fn new(patterns: Vec<Regex>, training_wheels: bool) -> Self {
+        Self {
+            patterns,
+            training_wheels,
+            pending: std::sync::Mutex::new(VecDeque::new()),
+            trusted: std::sync::Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether the commands of viewer `client_id` wait for the host under --training-wheels
+    fn in_training(&self, client_id: &str) -> bool {
+        self.training_wheels
+            && !self
+                .trusted
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(client_id)
+    }
+
+    /// Whether viewer `client_id` running `line` needs the host's answer
+    fn holds(&self, client_id: &str, line: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(line))
+            || (!line.trim().is_empty() && self.in_training(client_id))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Pending>> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget commands whose viewer left or stopped waiting
+        pending.retain(|pending| !pending.reply.is_closed());
+        pending
+    }
+
+    /// Ask the host whether viewer `client_id` may run `line`, refusing it if there's no answer in time
+    async fn ask(&self, host: &dyn HostMode, client_id: &str, line: &str) -> Answer {
+        let (reply, answer) = oneshot::channel();
+        self.lock().push_back(Pending {
+            client_id: client_id.to_string(),
+            line: line.to_string(),
+            reply,
+        });
+        // Without a terminal, the host answers from another shell
+        let how = match (host.headless(), self.in_training(client_id)) {
+            (true, false) => "run `rwshell confirm` to allow or `rwshell confirm --refuse` to refuse",
+            (true, true) => {
+                "run `rwshell confirm` to allow, `rwshell confirm --trust` to also allow the viewer's next \
+                 commands, or `rwshell confirm --refuse` to refuse"
+            }
+            (false, false) => "press y to allow or n to refuse",
+            (false, true) => "press y to allow, t to also allow the viewer's next commands, or n to refuse",
+        };
+        host.notify(&format!("Viewer {client_id} wants to run `{line}`: {how}"));
+        match tokio::time::timeout(CONFIRM_TIMEOUT, answer).await {
+            Ok(Ok(answer)) => answer,
+            _ => Answer::Refuse,
+        }
+    }
+
+    /// Whether a viewer's command is waiting for the host
+    pub fn waiting(&self) -> bool {
+        !self.lock().is_empty()
+    }
+
+    /// Answer the oldest waiting command, returning its line
+    pub fn answer(&self, answer: Answer) -> Option<String> {
+        let pending = self.lock().pop_front()?;
+        info!(
+            "Host {} `{}` from viewer {}",
+            match answer {
+                Answer::Allow => "allowed",
+                Answer::Refuse => "refused",
+                Answer::Trust => "allowed and trusted",
+            },
+            pending.line,
+            pending.client_id
+        );
+        if answer == Answer::Trust {
+            self.trusted
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(pending.client_id.clone());
+        }
+        let _ = pending.reply.send(answer);
+        Some(pending.line)
+    }
+
+    /// Forget that viewer `client_id` was trusted, once it leaves
+    pub fn forget(&self, client_id: &str) {
+        self.trusted.lock().unwrap_or_else(|e| e.into_inner()).remove(client_id);
+    }
+
+    /// Take host keystrokes as the answer while a command is waiting: "y" allows it, "t" also trusts its
+    /// viewer and "n" refuses it. Returns whether the keystrokes were taken.
+    pub fn host_input(&self, host: &dyn HostMode, data: &[u8]) -> bool {
+        if !self.waiting() {
+            return false;
+        }
+        let answer = data.iter().find_map(|byte| match byte {
+            b'y' | b'Y' => Some(Answer::Allow),
+            b't' | b'T' if self.training_wheels => Some(Answer::Trust),
+            b'n' | b'N' => Some(Answer::Refuse),
+            _ => None,
+        });
+        match answer {
+            Some(answer) => {
+                if let Some(line) = self.answer(answer) {
+                    host.notify(&format!("{} `{line}`", answer_text(answer)));
+                }
+            }
+            None => host.notify("A viewer's command is waiting: press y to allow or n to refuse"),
+        }
+        true
+    }
+}
+
+/// How the host's answer to a command is reported, before the command
+pub fn answer_text(answer: Answer) -> &'static str {
+    match answer {
+        Answer::Allow => "Allowed",
+        Answer::Refuse => "Refused",
+        Answer::Trust => "Trusted the viewer and allowed",
+    }
+}
+
+/// Write `data` typed by viewer `client_id` to the PTY. With a confirmation gate, the Enter that would
+/// run a line matching one of its patterns, or any line of a viewer in training, is held until the host
+/// answers; the input after a refused line is dropped. `status` is told about each held line.
+pub async fn write_input(
+    state: &AppState,
+    client_id: &str,
+    line: &mut LineBuffer,
+    mut data: &[u8],
+    status: impl Fn(ConfirmationMessage),
+) -> std::io::Result<()> {
+    if let Some(limits) = &state.limits {
+        limits.touch();
+    }
+    let Some(gate) = &state.confirm else {
+        return state.pty_writer.lock().await.write_all(data).await;
+    };
+
+    while let Some(enter) = line.feed(data) {
+        let text = line.line();
+        if gate.holds(client_id, &text) {
+            state.pty_writer.lock().await.write_all(&data[..enter]).await?;
+            status(ConfirmationMessage {
+                line: text.clone(),
+                status: ConfirmationStatus::Pending,
+            });
+            if let Some(notifier) = &state.notifier {
+                notifier.notify(
+                    NotifyEvent::Confirm,
+                    format!("Viewer {client_id} wants to run `{text}`"),
+                );
+            }
+            let answer = gate.ask(state.host.as_ref(), client_id, &text).await;
+            status(ConfirmationMessage {
+                line: text,
+                status: answer.status(),
+            });
+            if answer == Answer::Refuse {
+                // The line stays typed, so pressing Enter again asks again
+                return Ok(());
+            }
+            state.pty_writer.lock().await.write_all(&data[enter..=enter]).await?;
+        } else {
+            state.pty_writer.lock().await.write_all(&data[..=enter]).await?;
+        }
+        line.text.clear();
+        data = &data[enter + 1..];
+    }
+    state.pty_writer.lock().await.write_all(data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` one after another, returning the index of the Enter in the last one, if any
+    fn feed(line: &mut LineBuffer, chunks: &[&[u8]]) -> Option<usize> {
+        chunks.iter().map(|chunk| line.feed(chunk)).last().flatten()
+    }
+
+    #[test]
+    fn follows_typed_text_up_to_enter() {
+        let mut line = LineBuffer::default();
+        assert_eq!(line.feed(b"ls -l"), None);
+        assert_eq!(line.feed(b" /tmp\rnext"), Some(5));
+        assert_eq!(line.line(), "ls -l /tmp");
+        assert_eq!(LineBuffer::default().feed(b"\n"), Some(0));
+    }
+
+    #[test]
+    fn backspace_removes_whole_characters() {
+        let mut line = LineBuffer::default();
+        line.feed("rm -rf caf\u{e9}".as_bytes());
+        line.feed(b"\x7f\x7f\x08");
+        assert_eq!(line.line(), "rm -rf c");
+        // Past the start of the line
+        line.feed(&[0x7f; 20]);
+        assert_eq!(line.line(), "");
+    }
+
+    #[test]
+    fn ctrl_c_and_ctrl_u_abandon_the_line() {
+        let mut line = LineBuffer::default();
+        line.feed(b"drop table users\x03");
+        assert_eq!(line.line(), "");
+        line.feed(b"rm -rf /\x15ls");
+        assert_eq!(line.line(), "ls");
+    }
+
+    #[test]
+    fn skips_escape_sequences_split_across_chunks() {
+        let mut line = LineBuffer::default();
+        // Up arrow, as CSI and as SS3, then a CSI with parameters
+        assert_eq!(
+            feed(&mut line, &[b"a\x1b", b"[Ab\x1b", b"O", b"Ac\x1b[1;", b"5Dd"]),
+            None
+        );
+        assert_eq!(line.line(), "abcd");
+        // Alt+x is ESC x, which is skipped with the ESC
+        line.feed(b"\x1bxe\t");
+        assert_eq!(line.line(), "abcde");
+        // An Enter inside an escape sequence doesn't end the line
+        assert_eq!(line.feed(b"\x1b[\r"), None);
+        assert_eq!(line.feed(b"A\r"), Some(1));
+    }
+
+    #[test]
+    fn holds_matching_lines_and_every_line_in_training() {
+        let gate = ConfirmGate::new(vec![Regex::new("rm -rf").unwrap()], false);
+        assert!(gate.holds("a", "sudo rm -rf /"));
+        assert!(!gate.holds("a", "ls"));
+
+        let gate = ConfirmGate::new(Vec::new(), true);
+        assert!(gate.holds("a", "ls"));
+        assert!(!gate.holds("a", "  "));
+        gate.trusted.lock().unwrap().insert("a".to_string());
+        assert!(!gate.holds("a", "ls"));
+        assert!(gate.holds("b", "ls"));
+    }
+}
@@ -0,0 +1,451 @@
+use crate::approval::{ApproveMessage, ApprovedMessage};
+use crate::confirm::{Answer, ConfirmMessage, ConfirmedMessage};
+use crate::host::{get_terminal_size, restore_terminal, setup_raw_terminal};
+use crate::server::{
+    AppState, ClientStats, PtyEvent, apply_resize, broadcast_headless, broadcast_keys, encode_message,
+    encode_write_message, is_valid_terminal_size, record_write_error, refresh_viewer_colors, resync_frames,
+};
+use crate::session::{WinSizeMessage, WriteMessage};
+use crate::term::CapabilitiesMessage;
+use crate::timeline::{NoteMessage, NotedMessage, TimelineEntry};
+use crate::websocket::{CloseMessage, CloseReason, TtyMessage};
+use anyhow::Context;
+use base64::{Engine as _, engine::general_purpose};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// Key that detaches an attached terminal (Ctrl+])
+const DETACH_KEY: u8 = 0x1d;
+
+/// Client id of the attached terminal in session statistics
+const LOCAL_TERMINAL_ID: &str = "local-terminal";
+
+/// Directory holding the control sockets of this user's sessions
+fn control_dir() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("rwshell"),
+        // SAFETY: getuid has no preconditions
+        None => std::env::temp_dir().join(format!("rwshell-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// Path of the control socket for `session_id`
+pub fn control_socket_path(session_id: &str) -> PathBuf {
+    control_dir().join(format!("{session_id}.sock"))
+}
+
+/// Accept `rwshell attach` connections for the session until `shutdown` is cancelled.
+///
+/// The socket speaks the WebSocket protocol as newline-delimited JSON. While a terminal is
+/// attached it acts as the interactive host: its input goes to the PTY and its size wins.
+pub fn start_control_socket(
+    state: AppState,
+    attached: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+) -> std::io::Result<PathBuf> {
+    let dir = control_dir();
+    std::fs::create_dir_all(&dir)?;
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+
+    let path = control_socket_path(&state.session_id);
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("another session is serving {}", path.display()),
+        ));
+    }
+    // A previous run may have left its socket behind
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    let socket_path = path.clone();
+    let supervisor = state.supervisor.clone();
+    supervisor.spawn("control-socket", async move {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let connection = serve_control_connection(
+                            stream,
+                            state.clone(),
+                            Arc::clone(&attached),
+                            shutdown.clone(),
+                        );
+                        state.supervisor.spawn_client("attach", LOCAL_TERMINAL_ID, connection);
+                    }
+                    Err(e) => debug!("Failed to accept control connection: {}", e),
+                },
+            }
+        }
+        let _ = std::fs::remove_file(&socket_path);
+    });
+
+    Ok(path)
+}
+
+async fn write_line(writer: &mut (impl AsyncWrite + Unpin), frame: &str) -> std::io::Result<()> {
+    writer.write_all(frame.as_bytes()).await?;
+    writer.write_all(b"\n").await
+}
+
+/// Serve a connection to the control socket: an answer from `rwshell confirm` or `rwshell approve`, a
+/// timeline entry from `rwshell ctl note`, or else an attached terminal, which starts by sending its size
+async fn serve_control_connection(
+    stream: UnixStream,
+    state: AppState,
+    attached: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let Ok(Some(first_line)) = lines.next_line().await else {
+        return;
+    };
+
+    if let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(&first_line) {
+        if tty_msg.msg_type == "Confirm" {
+            let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                return;
+            };
+            let Ok(confirm_msg) = serde_json::from_slice::<ConfirmMessage>(&data) else {
+                return;
+            };
+            let line = state
+                .confirm
+                .as_ref()
+                .and_then(|gate| gate.answer(confirm_msg.answer()));
+            if let Ok(frame) = encode_message("Confirmed", &ConfirmedMessage { line }) {
+                let _ = write_line(&mut writer, &frame).await;
+            }
+            return;
+        }
+        if tty_msg.msg_type == "Approve" {
+            let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                return;
+            };
+            let Ok(approve_msg) = serde_json::from_slice::<ApproveMessage>(&data) else {
+                return;
+            };
+            let client_id = state.approval.as_ref().and_then(|gate| gate.answer(approve_msg.allow));
+            if let Ok(frame) = encode_message("Approved", &ApprovedMessage { client_id }) {
+                let _ = write_line(&mut writer, &frame).await;
+            }
+            return;
+        }
+        if tty_msg.msg_type == "Note" {
+            let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                return;
+            };
+            let Ok(note_msg) = serde_json::from_slice::<NoteMessage>(&data) else {
+                return;
+            };
+            let noted = match state.timeline.append(&note_msg.text, "host", &state.pty_tx) {
+                Ok(entry) => NotedMessage {
+                    entry: Some(entry),
+                    error: None,
+                },
+                Err(message) => NotedMessage {
+                    entry: None,
+                    error: Some(message),
+                },
+            };
+            if let Ok(frame) = encode_message("Noted", &noted) {
+                let _ = write_line(&mut writer, &frame).await;
+            }
+            return;
+        }
+    }
+
+    serve_attached_terminal(lines, writer, &first_line, state, attached, shutdown).await;
+}
+
+async fn serve_attached_terminal(
+    mut lines: Lines<BufReader<OwnedReadHalf>>,
+    mut writer: OwnedWriteHalf,
+    first_line: &str,
+    state: AppState,
+    attached: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+) {
+    if attached.swap(true, Ordering::SeqCst) {
+        let close_msg = CloseMessage {
+            reason: CloseReason::AlreadyAttached,
+            message: "Another terminal is already attached".to_string(),
+        };
+        if let Ok(frame) = encode_message("Close", &close_msg) {
+            let _ = write_line(&mut writer, &frame).await;
+        }
+        // Let the terminal hang up first so it reads the Close instead of a broken pipe
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while let Ok(Some(_)) = lines.next_line().await {}
+        })
+        .await;
+        return;
+    }
+
+    debug!("Local terminal attached to session {}", state.session_id);
+    state
+        .clients
+        .lock()
+        .await
+        .insert(LOCAL_TERMINAL_ID.to_string(), ClientStats::default());
+    broadcast_headless(&state.pty_tx, false);
+    let mut pty_rx = state.pty_tx.subscribe();
+
+    // Start from the current screen, like a client that fell behind
+    let mut synced = match resync_frames(&state).await {
+        Ok(frames) => {
+            let mut synced = true;
+            for frame in frames {
+                synced &= write_line(&mut writer, &frame).await.is_ok();
+            }
+            synced
+        }
+        Err(e) => {
+            debug!("Failed to encode initial screen for attached terminal: {}", e);
+            false
+        }
+    };
+
+    handle_attached_input(&state, first_line).await;
+    while synced {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => handle_attached_input(&state, &line).await,
+                _ => break,
+            },
+            event = pty_rx.recv() => match event {
+                Ok(PtyEvent::Output { frame, .. } | PtyEvent::Message(frame) | PtyEvent::ScreenText { frame, .. }) => {
+                    synced = write_line(&mut writer, &frame).await.is_ok();
+                }
+                Ok(PtyEvent::Close { frame, .. }) => {
+                    let _ = write_line(&mut writer, &frame).await;
+                    break;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let Ok(frames) = resync_frames(&state).await else { break };
+                    for frame in frames {
+                        synced &= write_line(&mut writer, &frame).await.is_ok();
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+
+    state.clients.lock().await.remove(LOCAL_TERMINAL_ID);
+    refresh_viewer_colors(&state).await;
+    attached.store(false, Ordering::SeqCst);
+    broadcast_headless(&state.pty_tx, true);
+    debug!("Local terminal detached from session {}", state.session_id);
+}
+
+async fn handle_attached_input(state: &AppState, line: &str) {
+    let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(line) else {
+        return;
+    };
+    let Ok(data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+        return;
+    };
+
+    if tty_msg.msg_type == "Write" {
+        let Ok(write_msg) = serde_json::from_slice::<WriteMessage>(&data) else {
+            return;
+        };
+        let Ok(input) = general_purpose::STANDARD.decode(&write_msg.data) else {
+            return;
+        };
+        // While a viewer's command waits for confirmation, keystrokes answer it
+        if let Some(gate) = &state.confirm {
+            if gate.host_input(state.host.as_ref(), &input) {
+                return;
+            }
+        }
+        // While a viewer asks for write access, keystrokes answer it
+        if let Some(gate) = &state.approval {
+            if gate.host_input(state.host.as_ref(), &input) {
+                return;
+            }
+        }
+        broadcast_keys(state, &input).await;
+        if let Some(limits) = &state.limits {
+            limits.touch();
+        }
+        if let Err(e) = state.pty_writer.lock().await.write_all(&input).await {
+            record_write_error(state, LOCAL_TERMINAL_ID, &e).await;
+        }
+    } else if tty_msg.msg_type == "WinSize" {
+        let Ok(winsize_msg) = serde_json::from_slice::<WinSizeMessage>(&data) else {
+            return;
+        };
+        if is_valid_terminal_size(winsize_msg.cols, winsize_msg.rows) {
+            apply_resize(
+                winsize_msg.cols,
+                winsize_msg.rows,
+                &state.pty_master,
+                &state.current_size,
+                &state.pty_tx,
+            )
+            .await;
+        }
+    } else if tty_msg.msg_type == "Capabilities" {
+        let Ok(capabilities) = serde_json::from_slice::<CapabilitiesMessage>(&data) else {
+            return;
+        };
+        if let Some(stats) = state.clients.lock().await.get_mut(LOCAL_TERMINAL_ID) {
+            stats.term = Some(capabilities.sanitized_term());
+            stats.colors = Some(capabilities.colors);
+        }
+        refresh_viewer_colors(state).await;
+    }
+}
+
+/// Attach this terminal to the headless session `session_id` running on this machine
+pub async fn attach(session_id: &str) -> anyhow::Result<()> {
+    let path = control_socket_path(session_id);
+    let stream = UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("No headless session {session_id} found at {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let original_termios = setup_raw_terminal().context("Input not a tty")?;
+    eprint!("[rwshell] Attached to session {session_id}, press Ctrl+] to detach\r\n");
+
+    let result = run_attached(reader, &mut writer).await;
+
+    restore_terminal(&original_termios);
+    match result {
+        Ok(Some(message)) => eprintln!("\r\n[rwshell] {message}"),
+        Ok(None) => eprintln!("\r\n[rwshell] Detached from session {session_id}"),
+        Err(e) => eprintln!("\r\n[rwshell] Connection to session lost: {e}"),
+    }
+    Ok(())
+}
+
+/// Answer the oldest viewer command waiting for confirmation in the headless session `session_id`
+/// running on this machine, returning its line, or `None` if none is waiting
+pub async fn confirm(session_id: &str, answer: Answer) -> anyhow::Result<Option<String>> {
+    let path = control_socket_path(session_id);
+    let stream = UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("No headless session {session_id} found at {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    write_line(&mut writer, &encode_message("Confirm", &ConfirmMessage::new(answer))?).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let line = lines.next_line().await?.context("The session closed the connection")?;
+    let tty_msg: TtyMessage = serde_json::from_str(&line)?;
+    let confirmed: ConfirmedMessage = serde_json::from_slice(&general_purpose::STANDARD.decode(&tty_msg.data)?)?;
+    Ok(confirmed.line)
+}
+
+/// Answer the oldest viewer asking for write access in the headless session `session_id` running on this
+/// machine, returning its client ID, or `None` if none is waiting
+pub async fn approve(session_id: &str, allow: bool) -> anyhow::Result<Option<String>> {
+    let path = control_socket_path(session_id);
+    let stream = UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("No headless session {session_id} found at {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    write_line(&mut writer, &encode_message("Approve", &ApproveMessage { allow })?).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let line = lines.next_line().await?.context("The session closed the connection")?;
+    let tty_msg: TtyMessage = serde_json::from_str(&line)?;
+    let approved: ApprovedMessage = serde_json::from_slice(&general_purpose::STANDARD.decode(&tty_msg.data)?)?;
+    Ok(approved.client_id)
+}
+
+/// Add `text` to the timeline of session `session_id` running on this machine, returning the new entry
+pub async fn note(session_id: &str, text: &str) -> anyhow::Result<TimelineEntry> {
+    let path = control_socket_path(session_id);
+    let stream = UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("No session {session_id} found at {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let note_msg = NoteMessage { text: text.to_string() };
+    write_line(&mut writer, &encode_message("Note", &note_msg)?).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let line = lines.next_line().await?.context("The session closed the connection")?;
+    let tty_msg: TtyMessage = serde_json::from_str(&line)?;
+    let noted: NotedMessage = serde_json::from_slice(&general_purpose::STANDARD.decode(&tty_msg.data)?)?;
+    match (noted.entry, noted.error) {
+        (Some(entry), _) => Ok(entry),
+        (None, error) => Err(anyhow::anyhow!(
+            error.unwrap_or_else(|| "The entry was refused".to_string())
+        )),
+    }
+}
+
+/// Relay the local terminal until the user detaches (`None`) or the session closes (its message)
+async fn run_attached(
+    reader: tokio::net::unix::OwnedReadHalf,
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+) -> anyhow::Result<Option<String>> {
+    let mut lines = BufReader::new(reader).lines();
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut window_changes = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())?;
+    let mut input = [0u8; 1024];
+
+    let (cols, rows) = get_terminal_size();
+    write_line(writer, &encode_message("WinSize", &WinSizeMessage { cols, rows })?).await?;
+    write_line(
+        writer,
+        &encode_message("Capabilities", &CapabilitiesMessage::from_env())?,
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+            read = stdin.read(&mut input) => {
+                let n = read?;
+                if n == 0 {
+                    return Ok(None);
+                }
+
+                let (data, detach) = match input[..n].iter().position(|&b| b == DETACH_KEY) {
+                    Some(index) => (&input[..index], true),
+                    None => (&input[..n], false),
+                };
+                if !data.is_empty() {
+                    write_line(writer, &encode_write_message(data)?).await?;
+                }
+                if detach {
+                    return Ok(None);
+                }
+            }
+            _ = window_changes.recv() => {
+                let (cols, rows) = get_terminal_size();
+                write_line(writer, &encode_message("WinSize", &WinSizeMessage { cols, rows })?).await?;
+            }
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Err(anyhow::anyhow!("session ended"));
+                };
+                let tty_msg: TtyMessage = serde_json::from_str(&line)?;
+                let data = general_purpose::STANDARD.decode(&tty_msg.data)?;
+
+                if tty_msg.msg_type == "Write" {
+                    let write_msg: WriteMessage = serde_json::from_slice(&data)?;
+                    stdout.write_all(&general_purpose::STANDARD.decode(&write_msg.data)?).await?;
+                    stdout.flush().await?;
+                } else if tty_msg.msg_type == "Close" {
+                    let close_msg: CloseMessage = serde_json::from_slice(&data)?;
+                    return Ok(Some(close_msg.message));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::recording::Recording;
+use regex::Regex;
+use similar::{Algorithm, DiffTag};
+use std::path::Path;
+
+/// Compare the plain text transcripts of the recordings at `a` and `b` and print where they diverge,
+/// with `context` unchanged lines around each divergence. Text matching any of `ignore` is left out of
+/// the comparison, e.g. host names or timestamps.
+///
+/// Returns whether the transcripts differ.
+pub fn run(a: &Path, b: &Path, context: usize, ignore: &[Regex]) -> anyhow::Result<bool> {
+    let lines_a = Recording::load(a)?.transcript();
+    let lines_b = Recording::load(b)?.transcript();
+    let masked_a = mask(&lines_a, ignore);
+    let masked_b = mask(&lines_b, ignore);
+
+    // Patience diff lines up runs of unique lines, such as commands, rather than blank lines
+    let ops = similar::capture_diff_slices(Algorithm::Patience, &masked_a, &masked_b);
+    let groups = similar::group_diff_ops(ops, context);
+    if groups.is_empty() {
+        println!("Transcripts match ({} lines)", lines_a.len());
+        return Ok(false);
+    }
+
+    println!("--- {}", a.display());
+    println!("+++ {}", b.display());
+    for group in &groups {
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            continue;
+        };
+        let (old, new) = (
+            first.old_range().start..last.old_range().end,
+            first.new_range().start..last.new_range().end,
+        );
+        println!(
+            "@@ -{},{} +{},{} @@",
+            old.start + 1,
+            old.len(),
+            new.start + 1,
+            new.len()
+        );
+        for op in group {
+            let (tag, old, new) = op.as_tag_tuple();
+            match tag {
+                DiffTag::Equal => old.for_each(|i| println!(" {}", lines_a[i])),
+                DiffTag::Delete => old.for_each(|i| println!("-{}", lines_a[i])),
+                DiffTag::Insert => new.for_each(|i| println!("+{}", lines_b[i])),
+                DiffTag::Replace => {
+                    old.for_each(|i| println!("-{}", lines_a[i]));
+                    new.for_each(|i| println!("+{}", lines_b[i]));
+                }
+            }
+        }
+    }
+    let divergences = groups.iter().flatten().filter(|op| op.tag() != DiffTag::Equal).count();
+    eprintln!(
+        "{} divergence{} between {} and {}",
+        divergences,
+        if divergences == 1 { "" } else { "s" },
+        a.display(),
+        b.display()
+    );
+    Ok(true)
+}
+
+/// `lines` with the text matching any of `ignore` replaced, so it doesn't count as a difference
+fn mask(lines: &[String], ignore: &[Regex]) -> Vec<String> {
+    lines
+        .iter()
+        .map(|line| {
+            ignore.iter().fold(line.clone(), |line, pattern| {
+                pattern.replace_all(&line, "\u{2026}").into_owned()
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,203 @@
+use crate::args::Args;
+use crate::config::{self, default_config_path};
+use crate::tls;
+use portable_pty::{PtySize, native_pty_system};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use url::Url;
+
+/// How long connecting to a relay or group may take before it counts as unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Failed,
+    /// Not configured, so there was nothing to check
+    Skipped,
+}
+
+/// A line of the report
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Check that a session can start with `args`, read from the command line, and the options of the
+/// config file, printing a report. Returns whether no check failed.
+pub async fn run(args: &Args) -> bool {
+    let (config, args) = check_config(args);
+    let mut checks = vec![config, check_pty(), check_command(&args.command)];
+    checks.push(check_bind("Listen", &args.listen));
+    if let Some(port) = args.http_redirect_port {
+        let host = args
+            .listen
+            .rsplit_once(':')
+            .map_or(args.listen.as_str(), |(host, _)| host);
+        checks.push(check_bind("Redirect", &format!("{host}:{port}")));
+    }
+    checks.push(check_tls(&args));
+    checks.push(match args.mirror.as_ref().or(args.public.as_ref()) {
+        Some(url) => check_reachable("Relay", url).await,
+        None => Check::new("Relay", Status::Skipped, "no --mirror or --public"),
+    });
+    checks.push(match args.group.as_deref().map(Url::parse) {
+        Some(Ok(url)) => check_reachable("Group", &url).await,
+        Some(Err(e)) => Check::new("Group", Status::Failed, format!("invalid --group URL: {e}")),
+        None => Check::new("Group", Status::Skipped, "no --group"),
+    });
+
+    for check in &checks {
+        let status = match check.status {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Failed => "FAIL",
+            Status::Skipped => "-",
+        };
+        println!("{:<5} {:<9} {}", status, check.name, check.detail);
+    }
+    let failed = checks.iter().filter(|check| check.status == Status::Failed).count();
+    if failed == 0 {
+        println!("\nNo problems found");
+    } else {
+        println!("\n{failed} check{} failed", if failed == 1 { "" } else { "s" });
+    }
+    failed == 0
+}
+
+/// Read the config file, going on with the command line alone if it is broken
+fn check_config(args: &Args) -> (Check, Args) {
+    let path = args.config.clone().unwrap_or_else(default_config_path);
+    match config::check(args) {
+        Ok(merged) if args.config.is_none() && !path.exists() => (
+            Check::new(
+                "Config",
+                Status::Skipped,
+                format!("no config file at {}", path.display()),
+            ),
+            merged,
+        ),
+        Ok(merged) => {
+            let detail = match &args.profile {
+                Some(profile) => format!("{}, profile {}", path.display(), profile),
+                None => path.display().to_string(),
+            };
+            (Check::new("Config", Status::Ok, detail), merged)
+        }
+        Err(e) => (Check::new("Config", Status::Failed, format!("{e:#}")), args.clone()),
+    }
+}
+
+/// Open a PTY like sessions do
+fn check_pty() -> Check {
+    let size = PtySize {
+        rows: 24,
+        cols: 80,
+        pixel_width: 0,
+        pixel_height: 0,
+    };
+    match native_pty_system().openpty(size) {
+        Ok(_) => Check::new("PTY", Status::Ok, "allocated a terminal"),
+        Err(e) => Check::new(
+            "PTY",
+            Status::Failed,
+            format!("{e:#}; check that /dev/ptmx exists and /dev/pts is mounted"),
+        ),
+    }
+}
+
+/// Find the command in the PATH, as the PTY would when starting it
+fn check_command(command: &str) -> Check {
+    let found = if command.contains('/') {
+        Path::new(command).is_file().then(|| command.to_string())
+    } else {
+        std::env::var_os("PATH").and_then(|path| {
+            std::env::split_paths(&path)
+                .map(|dir| dir.join(command))
+                .find(|candidate| candidate.is_file())
+                .map(|candidate| candidate.display().to_string())
+        })
+    };
+    match found {
+        Some(path) => Check::new("Command", Status::Ok, path),
+        None => Check::new("Command", Status::Failed, format!("{command} not found")),
+    }
+}
+
+/// Bind `address` and let it go again
+fn check_bind(name: &'static str, address: &str) -> Check {
+    match std::net::TcpListener::bind(address) {
+        Ok(listener) => {
+            let local = listener
+                .local_addr()
+                .map(|addr| addr.ip().is_loopback())
+                .unwrap_or(false);
+            if local {
+                Check::new(name, Status::Ok, format!("{address}, only reachable from this machine"))
+            } else {
+                Check::new(name, Status::Ok, address)
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::AddrInUse => Check::new(
+            name,
+            Status::Failed,
+            format!("{address} is already in use, e.g. by another rwshell; pick another with --listen"),
+        ),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Check::new(
+            name,
+            Status::Failed,
+            format!("{address}: {e}; ports below 1024 need privileges"),
+        ),
+        Err(e) => Check::new(name, Status::Failed, format!("{address}: {e}")),
+    }
+}
+
+/// Load the certificate and key given with `--tls-cert` and `--tls-key`
+fn check_tls(args: &Args) -> Check {
+    match (tls::acceptor(args), &args.tls_cert) {
+        (Ok(Some(_)), Some(cert)) => Check::new("TLS", Status::Ok, format!("{} matches its key", cert.display())),
+        (Ok(_), _) if is_local(&args.listen) => Check::new("TLS", Status::Skipped, "serving plain HTTP"),
+        (Ok(_), _) => Check::new(
+            "TLS",
+            Status::Warning,
+            "serving plain HTTP to other machines; use --tls-cert or a reverse proxy that terminates TLS",
+        ),
+        (Err(e), _) => Check::new("TLS", Status::Failed, format!("{e:#}")),
+    }
+}
+
+/// Whether `listen` only accepts connections from this machine
+fn is_local(listen: &str) -> bool {
+    listen.starts_with("localhost:") || listen.starts_with("127.") || listen.starts_with("[::1]:")
+}
+
+/// Open a TCP connection to the server at `url`. The URL may carry a token, so only its host is shown.
+async fn check_reachable(name: &'static str, url: &Url) -> Check {
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Check::new(name, Status::Failed, "the URL has no host");
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Check::new(name, Status::Ok, format!("{host}:{port} is reachable")),
+        Ok(Err(e)) => Check::new(name, Status::Failed, format!("{host}:{port}: {e}")),
+        Err(_) => Check::new(
+            name,
+            Status::Failed,
+            format!("{host}:{port} didn't answer within {}s", CONNECT_TIMEOUT.as_secs()),
+        ),
+    }
+}
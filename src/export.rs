@@ -0,0 +1,136 @@
+use crate::assets::Assets;
+use crate::history::row_html;
+use crate::notes;
+use crate::recording::{Event, Recording};
+use crate::timeline;
+use anyhow::Context;
+use serde::Serialize;
+use std::path::Path;
+
+/// Shortest time between two frames of an exported recording, in seconds
+const FRAME_INTERVAL: f64 = 1.0 / 30.0;
+
+/// A change to the screen at some point of the recording
+#[derive(Serialize)]
+struct Frame {
+    /// Seconds from the start
+    time: f64,
+    /// New size of the screen, if it changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<(u16, u16)>,
+    /// Rows that changed, as their index and HTML
+    rows: Vec<(u16, String)>,
+}
+
+#[derive(Serialize)]
+struct Player {
+    duration: f64,
+    frames: Vec<Frame>,
+    /// Seconds from the start and label of each marker
+    markers: Vec<(f64, String)>,
+}
+
+/// Write the recording at `cast` to `out` as a single HTML file that plays it back in any browser, with
+/// the session notes in `notes` below it and the entries of the timeline in `timeline` as markers, or
+/// else those found next to the recording
+pub fn html(cast: &Path, out: &Path, notes: Option<&Path>, timeline: Option<&Path>) -> anyhow::Result<()> {
+    let mut recording = Recording::load(cast)?;
+    let entries = match timeline {
+        Some(path) => {
+            if !path.exists() {
+                anyhow::bail!("No timeline found at {}", path.display());
+            }
+            timeline::read_entries(path)?
+        }
+        None => timeline::read_entries(&timeline::file_for_recording(cast))?,
+    };
+    recording.mark_timeline(&entries);
+    let title = recording.title.clone().unwrap_or_else(|| {
+        cast.file_stem()
+            .map_or_else(|| "recording".to_string(), |name| name.to_string_lossy().into_owned())
+    });
+
+    // The screens are rendered here, so the page needs no terminal emulator of its own
+    let player = Player {
+        duration: recording.duration(),
+        frames: render_frames(&recording),
+        markers: recording.markers.clone(),
+    };
+    // Keep the data from closing the script element it is embedded in
+    let data = serde_json::to_string(&player)?.replace("</", "<\\/");
+
+    let notes = match notes {
+        Some(path) => {
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read notes from {}", path.display()))?
+        }
+        None => std::fs::read_to_string(notes::file_for_recording(cast)).unwrap_or_default(),
+    };
+
+    let template = Assets::get_file("player.html").context("The player template is missing")?;
+    let page = String::from_utf8_lossy(&template.data)
+        .replace("__Title__", &html_escape(&title))
+        .replace("__Notes__", &html_escape(notes.trim_end()))
+        .replace("__Recording__", &data);
+    std::fs::write(out, page).with_context(|| format!("Failed to write {}", out.display()))?;
+    Ok(())
+}
+
+/// Replay the recording, and keep the rows that changed at most every [`FRAME_INTERVAL`]
+fn render_frames(recording: &Recording) -> Vec<Frame> {
+    let (mut cols, mut rows) = (recording.width, recording.height);
+    let mut parser = vt100::Parser::new(rows, cols, 0);
+    let mut shown: Vec<String> = vec![String::new(); rows as usize];
+    let mut frames = vec![Frame {
+        time: 0.0,
+        size: Some((cols, rows)),
+        rows: Vec::new(),
+    }];
+    let mut resized = false;
+
+    let mut events = recording.events.iter().peekable();
+    while let Some((time, event)) = events.next() {
+        match event {
+            Event::Output(data) => parser.process(data.as_bytes()),
+            Event::Resize(new_cols, new_rows) => {
+                (cols, rows) = (*new_cols, *new_rows);
+                parser.screen_mut().set_size(rows, cols);
+                shown.resize(rows as usize, String::new());
+                resized = true;
+            }
+        }
+
+        // Let output arriving in quick succession make up a single frame
+        let last_frame = frames.last().map_or(0.0, |frame| frame.time);
+        let next_time = events.peek().map(|(next, _)| *next);
+        if next_time.is_some_and(|next| next - last_frame < FRAME_INTERVAL) {
+            continue;
+        }
+
+        let screen = parser.screen();
+        let mut changed = Vec::new();
+        for row in 0..rows {
+            let html = row_html(screen, row, cols);
+            if resized || shown[row as usize] != html {
+                shown[row as usize] = html.clone();
+                changed.push((row, html));
+            }
+        }
+        if changed.is_empty() && !resized {
+            continue;
+        }
+        frames.push(Frame {
+            time: *time,
+            size: resized.then_some((cols, rows)),
+            rows: changed,
+        });
+        resized = false;
+    }
+    frames
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,94 @@
+use crate::tls::ClientCertificate;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts};
+use std::convert::Infallible;
+
+/// Longest description of a client that isn't a browser, e.g. an odd user agent
+const MAX_AGENT_LEN: usize = 60;
+
+/// What a client tells about itself when it connects: its `User-Agent` and, with `--tls-client-ca`, the
+/// name on the certificate it presented, so the host can tell its viewers apart
+#[derive(Clone, Debug, Default)]
+pub struct ClientFingerprint {
+    pub user_agent: Option<String>,
+    pub certificate: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientFingerprint {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            user_agent: parts
+                .headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            certificate: parts
+                .extensions
+                .get::<ClientCertificate>()
+                .map(|certificate| certificate.0.clone()),
+        })
+    }
+}
+
+impl ClientFingerprint {
+    /// A short description for the host, e.g. "alice (Firefox on Linux)" or "curl/8.5.0"
+    pub fn describe(&self) -> String {
+        let agent = self
+            .user_agent
+            .as_deref()
+            .map_or_else(|| "unknown client".to_string(), describe_user_agent);
+        match &self.certificate {
+            Some(name) => format!("{} ({agent})", printable(name)),
+            None => agent,
+        }
+    }
+}
+
+/// The browser and system of a browser's user agent, e.g. "Firefox on Linux"; for other clients, the
+/// first product of it, e.g. "curl/8.5.0" or "rwshell-client/1.0.0"
+fn describe_user_agent(user_agent: &str) -> String {
+    let has = |token: &str| user_agent.contains(token);
+    let browser = if has("Edg/") {
+        Some("Edge")
+    } else if has("OPR/") {
+        Some("Opera")
+    } else if has("Firefox/") || has("FxiOS/") {
+        Some("Firefox")
+    } else if has("Chrome/") || has("CriOS/") {
+        Some("Chrome")
+    } else if has("Safari/") && has("Version/") {
+        Some("Safari")
+    } else {
+        None
+    };
+    let system = if has("Android") {
+        Some("Android")
+    } else if has("iPhone") || has("iPad") {
+        Some("iOS")
+    } else if has("Windows") {
+        Some("Windows")
+    } else if has("Mac OS X") {
+        Some("macOS")
+    } else if has("CrOS") {
+        Some("ChromeOS")
+    } else if has("Linux") {
+        Some("Linux")
+    } else {
+        None
+    };
+    match (browser, system) {
+        (Some(browser), Some(system)) => format!("{browser} on {system}"),
+        (Some(browser), None) => browser.to_string(),
+        _ => {
+            let product = user_agent.split_whitespace().next().unwrap_or("unknown client");
+            printable(product).chars().take(MAX_AGENT_LEN).collect()
+        }
+    }
+}
+
+/// `text` without control characters, which could move the cursor or change colors in the host's terminal
+fn printable(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+}
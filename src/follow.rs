@@ -0,0 +1,234 @@
+use crate::budget::{Account, BufferKind, MemoryBudget};
+use crate::server::PtyEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest wait between two replayed events, however long the session was quiet between them
+const MAX_REPLAY_GAP: Duration = Duration::from_millis(250);
+
+/// Sent by a viewer to pause the session's output, `false`, or to follow it again, `true`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowMessage {
+    #[serde(rename = "Follow")]
+    pub follow: bool,
+}
+
+/// What a connection sends next while it catches up after a pause
+pub enum Replayed {
+    /// An event held back during the pause
+    Event(PtyEvent),
+    /// More came in than the buffer holds, so the viewer gets the current screen instead
+    Resync,
+}
+
+/// Output held back from a viewer that paused, to replay faster than it came in once the viewer follows
+/// the session again, until it is live
+pub struct FollowBuffer {
+    /// Most bytes held back, from `--pause-buffer`
+    limit: usize,
+    /// How much faster than live the held back events are replayed, from `--catch-up-speed`
+    speed: u32,
+    paused: bool,
+    /// Events held back and when they came in
+    events: VecDeque<(Instant, PtyEvent)>,
+    bytes: usize,
+    /// The events overflowed the buffer and were dropped; later ones are too, until the viewer resumes
+    overflowed: bool,
+    /// When the next held back event is due, while replaying
+    next_due: Option<Instant>,
+    /// Each held back event as a piece of data, if they count against a budget
+    account: Option<Account>,
+}
+
+impl FollowBuffer {
+    /// A buffer holding back up to `limit` bytes, and counting them against `budget` if given
+    pub fn new(limit: usize, speed: u32, budget: Option<Arc<MemoryBudget>>) -> Self {
+        Self {
+            limit,
+            speed: speed.max(1),
+            paused: false,
+            events: VecDeque::new(),
+            bytes: 0,
+            overflowed: false,
+            next_due: None,
+            account: budget.map(|budget| Account::new(budget, BufferKind::Pause)),
+        }
+    }
+
+    /// Pause, or follow the session again and start replaying what was held back
+    pub fn set_follow(&mut self, follow: bool) {
+        self.paused = !follow;
+        self.next_due = (follow && (self.overflowed || !self.events.is_empty())).then(Instant::now);
+    }
+
+    /// Whether `event` has to wait behind the held back ones. Closing the connection doesn't.
+    pub fn holds_back(&self, event: &PtyEvent) -> bool {
+        (self.paused || self.next_due.is_some()) && !matches!(event, PtyEvent::Close { .. })
+    }
+
+    /// Hold back `event` until it is replayed. Held back events are dropped for the current screen once
+    /// they are over the limit, or over the budget when nothing older is left to evict.
+    pub fn push(&mut self, event: PtyEvent) {
+        if self.overflowed {
+            return;
+        }
+        self.bytes += size(&event);
+        if self.bytes > self.limit {
+            if let Some(account) = &mut self.account {
+                account.clear();
+            }
+            self.overflow();
+            return;
+        }
+        if let Some(account) = &mut self.account {
+            account.add(size(&event));
+            if account.over_budget() {
+                account.budget().enforce();
+            }
+            if account.over_budget() {
+                account.evict_all();
+                self.overflow();
+                return;
+            }
+        }
+        self.events.push_back((Instant::now(), event));
+    }
+
+    fn overflow(&mut self) {
+        self.events.clear();
+        self.bytes = 0;
+        self.overflowed = true;
+    }
+
+    /// Whether held back events are being replayed
+    pub fn replaying(&self) -> bool {
+        !self.paused && self.next_due.is_some()
+    }
+
+    /// When the next held back event is due
+    pub fn next_due(&self) -> Instant {
+        self.next_due.unwrap_or_else(Instant::now)
+    }
+
+    /// The next held back event, once it is due. The wait before the one after it is the time between them
+    /// as they came in, divided by the speed. Once none is left, the viewer is live again.
+    pub fn pop(&mut self) -> Option<Replayed> {
+        let due = self.next_due?;
+        if self.overflowed {
+            self.overflowed = false;
+            self.next_due = None;
+            return Some(Replayed::Resync);
+        }
+        let Some((at, event)) = self.events.pop_front() else {
+            self.next_due = None;
+            return None;
+        };
+        self.bytes = self.bytes.saturating_sub(size(&event));
+        if let Some(account) = &mut self.account {
+            account.release(size(&event));
+        }
+        self.next_due = self
+            .events
+            .front()
+            .map(|(next, _)| due + (next.duration_since(at) / self.speed).min(MAX_REPLAY_GAP));
+        Some(Replayed::Event(event))
+    }
+}
+
+/// Bytes an event takes up in the buffer: its output, or its message
+fn size(event: &PtyEvent) -> usize {
+    match event {
+        PtyEvent::Output { data, .. } => data.len(),
+        PtyEvent::Message(frame) | PtyEvent::ScreenText { frame, .. } | PtyEvent::Close { frame, .. } => frame.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::CloseReason;
+
+    fn output(text: &str) -> PtyEvent {
+        PtyEvent::Output {
+            data: bytes::Bytes::copy_from_slice(text.as_bytes()),
+            frame: text.into(),
+            compressed: None,
+        }
+    }
+
+    fn replayed(buffer: &mut FollowBuffer) -> Vec<String> {
+        std::iter::from_fn(|| buffer.pop())
+            .map(|replayed| match replayed {
+                Replayed::Event(PtyEvent::Output { data, .. }) => String::from_utf8_lossy(&data).into_owned(),
+                Replayed::Event(_) => "event".to_string(),
+                Replayed::Resync => "resync".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn paused_output_is_replayed_in_order() {
+        let mut buffer = FollowBuffer::new(1024, 1000, None);
+        assert!(!buffer.holds_back(&output("live")));
+
+        buffer.set_follow(false);
+        assert!(buffer.holds_back(&output("a")));
+        buffer.push(output("a"));
+        buffer.push(output("b"));
+        // Nothing is replayed while paused
+        assert!(buffer.pop().is_none());
+
+        buffer.set_follow(true);
+        assert!(buffer.replaying());
+        assert_eq!(replayed(&mut buffer), ["a", "b"]);
+        assert!(!buffer.replaying());
+        assert!(!buffer.holds_back(&output("live")));
+    }
+
+    #[test]
+    fn closing_is_never_held_back() {
+        let mut buffer = FollowBuffer::new(1024, 1, None);
+        buffer.set_follow(false);
+        let close = PtyEvent::Close {
+            reason: CloseReason::ServerShutdown,
+            frame: "{}".into(),
+        };
+        assert!(!buffer.holds_back(&close));
+    }
+
+    #[test]
+    fn overflowing_the_limit_resyncs_instead() {
+        let mut buffer = FollowBuffer::new(10, 1, None);
+        buffer.set_follow(false);
+        buffer.push(output("12345"));
+        buffer.push(output("67890"));
+        buffer.push(output("x"));
+        buffer.push(output("later"));
+        buffer.set_follow(true);
+        assert_eq!(replayed(&mut buffer), ["resync"]);
+    }
+
+    #[test]
+    fn held_back_output_counts_against_the_budget() {
+        let budget = Arc::new(MemoryBudget::new(8));
+        let mut buffer = FollowBuffer::new(1024, 1000, Some(Arc::clone(&budget)));
+        buffer.set_follow(false);
+        buffer.push(output("abcd"));
+        assert_eq!(budget.used(), 4);
+        buffer.set_follow(true);
+        assert_eq!(replayed(&mut buffer), ["abcd"]);
+        assert_eq!(budget.used(), 0);
+
+        // Nothing older can be evicted, so the paused viewer's output is dropped for a resync
+        buffer.set_follow(false);
+        buffer.push(output("abcd"));
+        buffer.push(output("efghi"));
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.evictions(BufferKind::Pause), (1, 9));
+        buffer.set_follow(true);
+        assert_eq!(replayed(&mut buffer), ["resync"]);
+    }
+}
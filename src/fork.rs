@@ -0,0 +1,63 @@
+use crate::api::{self, SessionInfo};
+use crate::server::{AppState, RwShellServer};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use tracing::info;
+
+/// Sent to a viewer that forked the session, with the URL of its copy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForkedMessage {
+    #[serde(rename = "Url")]
+    pub url: String,
+}
+
+/// Lets viewers with write access fork a session, with `--allow-fork`
+pub struct Forker {
+    server: Weak<RwShellServer>,
+}
+
+impl Forker {
+    pub(crate) fn new(server: Weak<RwShellServer>) -> Self {
+        Self { server }
+    }
+
+    /// Start a copy of the session of `parent`, as [`fork_session`] does
+    pub(crate) async fn fork(&self, parent: &AppState) -> anyhow::Result<SessionInfo> {
+        let server = self.server.upgrade().context("The server is shutting down")?;
+        fork_session(&server, parent).await
+    }
+}
+
+/// Start a new session running the command of `parent` with the same environment, in the directory its
+/// command is in now, at the size of its terminal. The copy has no viewers and lives until its command
+/// exits, independently of `parent`.
+pub(crate) async fn fork_session(server: &Arc<RwShellServer>, parent: &AppState) -> anyhow::Result<SessionInfo> {
+    let id = api::free_session_id(server).context("No free session ID left")?;
+    let mut cmd = (*parent.command).clone();
+    if let Some(cwd) = working_directory(parent) {
+        cmd.cwd(cwd);
+    }
+    let command = cmd
+        .get_argv()
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let size = *parent.current_size.lock().await;
+
+    let session = api::launch_session(server, id.clone(), cmd, command, size)?;
+    info!("Forked session {} as {}", parent.session_id, id);
+    Ok(api::session_info(server, &id, &session).await)
+}
+
+/// The current directory of the session's command, e.g. where its shell was `cd`ed to; only known on Linux
+fn working_directory(state: &AppState) -> Option<PathBuf> {
+    let pid = state.child_pid.load(Ordering::Relaxed);
+    if pid == 0 {
+        return None;
+    }
+    std::fs::read_link(format!("/proc/{pid}/cwd")).ok()
+}
@@ -0,0 +1,27 @@
+use crate::args::Args;
+use crate::client_args::ClientArgs;
+use clap::CommandFactory;
+use std::io::Write;
+
+/// Command line definition of rwshell, or of rwshell-client if `client` is set
+fn command(client: bool) -> clap::Command {
+    if client { ClientArgs::command() } else { Args::command() }
+}
+
+/// Print a completion script for `shell` to stdout
+pub fn completions(shell: clap_complete::Shell, client: bool) -> std::io::Result<()> {
+    let mut command = command(client);
+    let name = command.get_name().to_string();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, name, &mut script);
+    std::io::stdout().write_all(&script)
+}
+
+/// Print the man page in roff format to stdout
+pub fn manpage(client: bool) -> std::io::Result<()> {
+    let mut page = Vec::new();
+    clap_mangen::Man::new(command(client))
+        .source(format!("rwshell {}", env!("CARGO_PKG_VERSION")))
+        .render(&mut page)?;
+    std::io::stdout().write_all(&page)
+}
@@ -0,0 +1,70 @@
+use anyhow::Context;
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::debug;
+
+/// Where a client address is located, as far as the database knows
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    /// ISO 3166-1 country code
+    pub country: Option<String>,
+    /// City name in English
+    pub city: Option<String>,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.city, &self.country) {
+            (Some(city), Some(country)) => write!(f, "{city}, {country}"),
+            (Some(place), None) | (None, Some(place)) => f.write_str(place),
+            (None, None) => f.write_str("unknown location"),
+        }
+    }
+}
+
+/// Resolves client addresses against a local MaxMind database (GeoLite2/GeoIP2 City or Country)
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let reader =
+            Reader::open_readfile(path).with_context(|| format!("Failed to open GeoIP database {}", path.display()))?;
+        debug!(
+            "Loaded GeoIP database {} ({})",
+            path.display(),
+            reader.metadata.database_type
+        );
+        Ok(Self { reader })
+    }
+
+    /// Look up `ip`; private and unlisted addresses have no location
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+
+        let record: geoip2::City = match self.reader.lookup(ip) {
+            Ok(record) => record,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return None,
+            Err(e) => {
+                debug!("GeoIP lookup of {} failed: {}", ip, e);
+                return None;
+            }
+        };
+
+        let country = record.country.and_then(|country| country.iso_code).map(str::to_string);
+        let city = record
+            .city
+            .and_then(|city| city.names)
+            .and_then(|names| names.get("en").map(|name| name.to_string()));
+        if country.is_none() && city.is_none() {
+            return None;
+        }
+        Some(Location { country, city })
+    }
+}
@@ -0,0 +1,238 @@
+use crate::args::Args;
+use crate::assets::Assets;
+use crate::capability::{self, AccessMode, Capability};
+use crate::identity::{ServerIdentity, default_identity_path};
+use crate::serve::{ServeOptions, serve};
+use crate::supervisor::Supervisor;
+use crate::tls;
+use anyhow::Context;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// How often sessions renew their membership
+const REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Members that haven't renewed their membership for this long are dropped, e.g. after a crash
+const MEMBER_TTL: Duration = Duration::from_secs(60);
+
+/// A session registered into a group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    /// Random ID chosen by the session, which identifies it when it renews its membership or leaves
+    pub id: String,
+    /// Name shown on the group page
+    pub label: String,
+    /// URL viewers open to join the session
+    pub url: String,
+}
+
+struct Member {
+    member: GroupMember,
+    last_seen: Instant,
+}
+
+#[derive(Clone)]
+struct GroupState {
+    /// Name of the group, which capability tokens are minted for
+    name: Arc<str>,
+    /// Path the group is reached under, from `--base-url`
+    base_path: Arc<str>,
+    identity: Arc<ServerIdentity>,
+    members: Arc<Mutex<HashMap<String, Member>>>,
+}
+
+impl GroupState {
+    /// Verify a group token from the request path, requiring read-write access if `write` is set
+    fn authorize(&self, token: &str, write: bool) -> Result<(), StatusCode> {
+        match capability::verify(&self.identity, &group_session_id(&self.name), token) {
+            Ok(capability) if write && capability.readonly() => Err(StatusCode::FORBIDDEN),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                debug!("Rejected group token: {}", e);
+                Err(StatusCode::NOT_FOUND)
+            }
+        }
+    }
+
+    /// Current members, after dropping those that stopped renewing their membership
+    fn members(&self) -> Vec<GroupMember> {
+        let mut members = self.members.lock().unwrap_or_else(|e| e.into_inner());
+        members.retain(|_, member| member.last_seen.elapsed() < MEMBER_TTL);
+        let mut list: Vec<GroupMember> = members.values().map(|member| member.member.clone()).collect();
+        list.sort_by(|a, b| a.label.cmp(&b.label).then_with(|| a.id.cmp(&b.id)));
+        list
+    }
+}
+
+/// Identifier capability tokens of a group are minted for, kept apart from session IDs
+fn group_session_id(name: &str) -> String {
+    format!("group/{name}")
+}
+
+/// Serve the group `name` until interrupted: a page listing the sessions registered into the group,
+/// and the endpoints sessions started with `--group` register through.
+///
+/// Two links are printed: a read-only one for the group page, and a read-write one that also lets
+/// sessions join.
+pub async fn create(args: &Args, name: &str) -> anyhow::Result<()> {
+    let identity_path = args.identity_file.clone().unwrap_or_else(default_identity_path);
+    let identity = Arc::new(ServerIdentity::load_or_generate(&identity_path)?);
+    let ttl = args.capability_ttl;
+    let view_token = capability::mint(
+        &identity,
+        &Capability::new(&group_session_id(name), AccessMode::ReadOnly, ttl),
+    )?;
+    let join_token = capability::mint(
+        &identity,
+        &Capability::new(&group_session_id(name), AccessMode::ReadWrite, ttl),
+    )?;
+
+    let state = GroupState {
+        name: name.into(),
+        base_path: tls::base_path(args).into(),
+        identity,
+        members: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let app = Router::new()
+        .route("/g/{token}/", get(serve_group_page))
+        .route("/g/{token}/sessions", get(list_members).post(register_member))
+        .route("/g/{token}/sessions/{id}", delete(remove_member))
+        .with_state(state);
+
+    let options = ServeOptions::new(args, tls::acceptor(args)?);
+    let listener = TcpListener::bind(&args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    tls::spawn_http_redirect(args).await?;
+    println!("group {name}: {}", tls::page_url(args, &format!("/g/{view_token}/")));
+    println!(
+        "add sessions with: rwshell --group {}",
+        tls::page_url(args, &format!("/g/{join_token}/"))
+    );
+
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    serve(listener, app, options, shutdown).await?;
+    Ok(())
+}
+
+async fn serve_group_page(Path(token): Path<String>, State(state): State<GroupState>) -> Response {
+    if let Err(status) = state.authorize(&token, false) {
+        return status.into_response();
+    }
+    match Assets::get_file("group.html") {
+        Some(template) => {
+            let template_str = String::from_utf8_lossy(&template.data);
+            let rendered = template_str
+                .replace("__GroupName__", &html_escape(&state.name))
+                .replace("__MembersPath__", &format!("{}/g/{token}/sessions", state.base_path));
+            Html(rendered).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn list_members(Path(token): Path<String>, State(state): State<GroupState>) -> Response {
+    if let Err(status) = state.authorize(&token, false) {
+        return status.into_response();
+    }
+    Json(state.members()).into_response()
+}
+
+async fn register_member(
+    Path(token): Path<String>,
+    State(state): State<GroupState>,
+    Json(member): Json<GroupMember>,
+) -> StatusCode {
+    if let Err(status) = state.authorize(&token, true) {
+        return status;
+    }
+    if !(member.url.starts_with("http://") || member.url.starts_with("https://")) {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let mut members = state.members.lock().unwrap_or_else(|e| e.into_inner());
+    if !members.contains_key(&member.id) {
+        info!("Session {} joined group {}", member.label, state.name);
+    }
+    members.insert(
+        member.id.clone(),
+        Member {
+            member,
+            last_seen: Instant::now(),
+        },
+    );
+    StatusCode::NO_CONTENT
+}
+
+async fn remove_member(Path((token, id)): Path<(String, String)>, State(state): State<GroupState>) -> StatusCode {
+    if let Err(status) = state.authorize(&token, true) {
+        return status;
+    }
+    let removed = state.members.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+    if let Some(member) = removed {
+        info!("Session {} left group {}", member.member.label, state.name);
+    }
+    StatusCode::NO_CONTENT
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Keep `member` registered in the group at `group_url` while the session runs, and remove it once
+/// `shutdown` is cancelled
+pub fn join(supervisor: &Supervisor, group_url: &str, member: GroupMember, shutdown: CancellationToken) {
+    let members_url = format!("{}/sessions", group_url.trim_end_matches('/'));
+    supervisor.spawn("group", async move {
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        let mut registered = false;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            let result = client
+                .post(&members_url)
+                .json(&member)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) if !registered => {
+                    info!("Joined the session group");
+                    registered = true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to register with the session group: {}", e);
+                    registered = false;
+                }
+            }
+        }
+
+        if registered {
+            let leave = client.delete(format!("{members_url}/{}", member.id)).send();
+            if let Err(e) = tokio::time::timeout(Duration::from_millis(100), leave).await {
+                debug!("Timed out leaving the session group: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,307 @@
+use anyhow::{Context, bail};
+use std::mem::offset_of;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tracing::info;
+
+/// `AUDIT_ARCH_*` value the kernel reports in `seccomp_data` for system calls of this build's architecture
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Set in the number of x32 system calls, which x86_64 kernels accept too
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls the server never makes, which would let an attacker who took it over reach into other
+/// processes, the kernel or the machine's configuration
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kcmp,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_adjtimex,
+    libc::SYS_clock_adjtime,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+/// `clone` flags creating namespaces, with which an unprivileged process gets capabilities of its own
+const CLONE_NAMESPACES: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET;
+
+/// Restrict the server process once it is set up, with `--harden`: every thread gives up its capabilities,
+/// nothing it runs from now on can gain privileges, e.g. through setuid programs, and a seccomp filter
+/// refuses system calls the server has no use for, such as `ptrace`, `mount` or creating namespaces.
+///
+/// The session's command, started before, keeps its privileges; commands started afterwards, e.g. when a
+/// session restarts, inherit the restrictions.
+pub fn apply() -> anyhow::Result<()> {
+    drop_capabilities().context("Failed to drop capabilities")?;
+
+    // SAFETY: only sets a flag of the calling thread, which the filter below then extends to every thread
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set no_new_privs");
+    }
+
+    let mut filter = seccomp_filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: the program points into `filter`, which outlives the call; the kernel copies it
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    // With TSYNC, a positive result is a thread the filter couldn't be applied to
+    if result != 0 {
+        if result < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to install the seccomp filter");
+        }
+        bail!("Failed to install the seccomp filter in thread {result}");
+    }
+
+    info!("Hardened the server: capabilities dropped, no new privileges and a seccomp filter");
+    Ok(())
+}
+
+/// Highest capability number the kernel knows, read before signal handlers need it
+static LAST_CAPABILITY: AtomicU32 = AtomicU32::new(40);
+
+/// `_LINUX_CAPABILITY_VERSION_3`, whose sets take two 32-bit words each
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Capabilities are per thread, and the runtime's threads already run: signal each thread that still has
+/// some to drop them itself, until none is left
+fn drop_capabilities() -> anyhow::Result<()> {
+    if let Ok(last) = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap") {
+        if let Ok(last) = last.trim().parse() {
+            LAST_CAPABILITY.store(last, Ordering::Relaxed);
+        }
+    }
+
+    let signal = libc::SIGRTMIN();
+    // SAFETY: the handler only makes system calls and restores errno, so it is async-signal-safe
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_drop_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    // SAFETY: getpid and gettid can't fail
+    let (pid, own_tid) = unsafe { (libc::getpid(), libc::gettid()) };
+    for _ in 0..100 {
+        let pending = threads_with_capabilities()?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        for tid in pending {
+            if tid == own_tid {
+                drop_thread_capabilities();
+            } else {
+                // SAFETY: a thread that exited in the meantime only makes it fail with ESRCH
+                unsafe {
+                    libc::tgkill(pid, tid, signal);
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    bail!("some threads kept their capabilities")
+}
+
+extern "C" fn on_drop_signal(_: libc::c_int) {
+    // SAFETY: errno is the calling thread's own
+    unsafe {
+        let errno = *libc::__errno_location();
+        drop_thread_capabilities();
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Drop the calling thread's capabilities, also from its bounding set when it may. Only makes system
+/// calls, so it can run in a signal handler.
+fn drop_thread_capabilities() {
+    // SAFETY: prctl and capset only change the calling thread's capabilities; failures leave them as they
+    // were, which the caller notices
+    unsafe {
+        for capability in 0..=LAST_CAPABILITY.load(Ordering::Relaxed) {
+            libc::prctl(libc::PR_CAPBSET_DROP, capability as libc::c_ulong, 0, 0, 0);
+        }
+        // The version and the thread, 0 for the calling one
+        let header: [u32; 2] = [CAPABILITY_VERSION_3, 0];
+        // The effective, permitted and inheritable sets, twice for the two words of each, all empty
+        let data = [0u32; 6];
+        libc::syscall(libc::SYS_capset, header.as_ptr(), data.as_ptr());
+    }
+}
+
+/// Threads of this process with effective, permitted, inheritable or ambient capabilities left
+fn threads_with_capabilities() -> anyhow::Result<Vec<libc::pid_t>> {
+    let mut pending = Vec::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let Ok(tid) = entry.file_name().to_string_lossy().parse() else {
+            continue;
+        };
+        // The thread may have exited since
+        let Ok(status) = std::fs::read_to_string(entry.path().join("status")) else {
+            continue;
+        };
+        let has_capabilities = status.lines().any(|line| {
+            ["CapInh:", "CapPrm:", "CapEff:", "CapAmb:"].iter().any(|field| {
+                line.strip_prefix(field)
+                    .is_some_and(|mask| u64::from_str_radix(mask.trim(), 16).is_ok_and(|mask| mask != 0))
+            })
+        });
+        if has_capabilities {
+            pending.push(tid);
+        }
+    }
+    Ok(pending)
+}
+
+/// Where a conditional jump of the filter goes
+#[derive(Clone, Copy)]
+enum Target {
+    Next,
+    CheckClone,
+    NoSuchCall,
+    Deny,
+}
+
+/// The seccomp program: system calls of other architectures, the denied ones and `clone` creating
+/// namespaces fail with EPERM; `clone3`, whose flags can't be inspected, fails with ENOSYS so the C
+/// library falls back to `clone`
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let load = |offset: usize| {
+        (
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            offset as u32,
+            Target::Next,
+            Target::Next,
+        )
+    };
+    let jump_if =
+        |value: u32, target: Target| (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, value, target, Target::Next);
+
+    let mut body = vec![
+        load(offset_of!(libc::seccomp_data, arch)),
+        (
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            AUDIT_ARCH,
+            Target::Next,
+            Target::Deny,
+        ),
+        load(offset_of!(libc::seccomp_data, nr)),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    body.push((
+        libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+        X32_SYSCALL_BIT,
+        Target::Deny,
+        Target::Next,
+    ));
+    body.push(jump_if(libc::SYS_clone3 as u32, Target::NoSuchCall));
+    body.push(jump_if(libc::SYS_clone as u32, Target::CheckClone));
+    body.extend(
+        DENIED_SYSCALLS
+            .iter()
+            .map(|&syscall| jump_if(syscall as u32, Target::Deny)),
+    );
+
+    // After the body come: allowing the call, checking the flags of `clone` (3 instructions), ENOSYS
+    // and EPERM
+    let position = |target| match target {
+        Target::Next => body.len(),
+        Target::CheckClone => body.len() + 1,
+        Target::NoSuchCall => body.len() + 4,
+        Target::Deny => body.len() + 5,
+    };
+    let instruction = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let ret = |value: u32| instruction(libc::BPF_RET | libc::BPF_K, value, 0, 0);
+    let errno = |errno: libc::c_int| ret(libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA));
+
+    let mut program: Vec<libc::sock_filter> = body
+        .iter()
+        .enumerate()
+        .map(|(index, &(code, k, jump_true, jump_false))| {
+            let offset = |target| match target {
+                Target::Next => 0,
+                target => (position(target) - index - 1) as u8,
+            };
+            instruction(code, k, offset(jump_true), offset(jump_false))
+        })
+        .collect();
+    program.push(ret(libc::SECCOMP_RET_ALLOW));
+    // The flags are the first argument; its lower half comes first on little-endian machines
+    let (code, k, _, _) = load(offset_of!(libc::seccomp_data, args));
+    program.push(instruction(code, k, 0, 0));
+    // Past the next two instructions to EPERM
+    program.push(instruction(
+        libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+        CLONE_NAMESPACES as u32,
+        2,
+        0,
+    ));
+    program.push(ret(libc::SECCOMP_RET_ALLOW));
+    program.push(errno(libc::ENOSYS));
+    program.push(errno(libc::EPERM));
+    program
+}
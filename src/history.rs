@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+
+/// Most lines of scrollback rebuilt from the history buffer
+const MAX_SCROLLBACK_LINES: usize = 10_000;
+
+/// Most lines sent in one page of history
+const MAX_PAGE_LINES: usize = 500;
+
+/// Sequences that switch to and from the alternate screen used by pagers, editors and tmux
+const ALTERNATE_SCREEN_ENTER: [&[u8]; 3] = [b"\x1b[?1049h", b"\x1b[?1047h", b"\x1b[?47h"];
+const ALTERNATE_SCREEN_LEAVE: [&[u8]; 3] = [b"\x1b[?1049l", b"\x1b[?1047l", b"\x1b[?47l"];
+
+/// A client asking for a page of the session's history, without affecting the live terminal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequestMessage {
+    /// Index of the first line wanted, or the last page if absent
+    #[serde(rename = "Start", default)]
+    pub start: Option<usize>,
+    #[serde(rename = "Lines")]
+    pub lines: usize,
+}
+
+/// A page of the session's history as plain text lines
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    /// Index of the first line of the page
+    #[serde(rename = "Start")]
+    pub start: usize,
+    /// Number of lines in the whole history
+    #[serde(rename = "Total")]
+    pub total: usize,
+    #[serde(rename = "Lines")]
+    pub lines: Vec<String>,
+}
+
+/// Output that was shown on the main screen, leaving out what full screen programs drew on the
+/// alternate screen
+pub fn main_screen_output(output: &[u8]) -> Vec<u8> {
+    let mut main = Vec::with_capacity(output.len());
+    let mut alternate = false;
+    let mut rest = output;
+    while !rest.is_empty() {
+        let switch = if alternate {
+            ALTERNATE_SCREEN_LEAVE.iter().find(|seq| rest.starts_with(seq))
+        } else {
+            ALTERNATE_SCREEN_ENTER.iter().find(|seq| rest.starts_with(seq))
+        };
+        if let Some(seq) = switch {
+            alternate = !alternate;
+            rest = &rest[seq.len()..];
+            continue;
+        }
+        if !alternate {
+            main.push(rest[0]);
+        }
+        rest = &rest[1..];
+    }
+    main
+}
+
+/// Replay `output` on a terminal of `cols` x `rows` and turn every row it left on the main screen and in
+/// the scrollback into a line, oldest first. `lines_of` adds the lines of the given rows of the screen,
+/// scrolled back to each screenful in turn.
+fn replay<T>(
+    output: &[u8],
+    (cols, rows): (u16, u16),
+    mut lines_of: impl FnMut(&vt100::Screen, std::ops::Range<u16>, &mut Vec<T>),
+) -> Vec<T> {
+    let mut parser = vt100::Parser::new(rows, cols, MAX_SCROLLBACK_LINES);
+    parser.process(&main_screen_output(output));
+
+    // The scrollback can only be read a screenful at a time, by scrolling back
+    parser.screen_mut().set_scrollback(usize::MAX);
+    let depth = parser.screen().scrollback();
+    let mut lines = Vec::with_capacity(depth + rows as usize);
+    let mut offset = depth;
+    loop {
+        parser.screen_mut().set_scrollback(offset);
+        let first = depth - offset;
+        lines_of(parser.screen(), (lines.len() - first) as u16..rows, &mut lines);
+        if offset == 0 {
+            break;
+        }
+        offset = offset.saturating_sub(rows as usize);
+    }
+    lines
+}
+
+/// Every line `output` left on the main screen and in the scrollback of a terminal of `cols` x `rows`,
+/// oldest first and without trailing blanks
+pub fn render_lines(output: &[u8], size: (u16, u16)) -> Vec<String> {
+    let (cols, _) = size;
+    let mut lines = replay(output, size, |screen, rows, lines| {
+        lines.extend(rows.map(|row| screen.contents_between(row, 0, row, cols).trim_end().to_string()));
+    });
+
+    // Rows below the last output are empty
+    while lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    lines
+}
+
+/// The lines `output` left in the scrollback of a terminal of `cols` x `rows`, above its main screen,
+/// oldest first, with the escape sequences that reproduce their colors and text attributes
+pub fn scrollback_formatted(output: &[u8], size: (u16, u16)) -> Vec<Vec<u8>> {
+    let (cols, rows) = size;
+    let mut lines = replay(output, size, |screen, rows, lines| {
+        lines.extend(screen.rows_formatted(0, cols).skip(rows.start as usize));
+    });
+    lines.truncate(lines.len().saturating_sub(rows as usize));
+    lines
+}
+
+/// The lines of [`render_lines`] as a standalone HTML page that keeps colors and text attributes
+pub fn render_html(output: &[u8], size: (u16, u16)) -> String {
+    let (cols, _) = size;
+    let mut lines = replay(output, size, |screen, rows, lines| {
+        lines.extend(rows.map(|row| row_html(screen, row, cols)));
+    });
+
+    while lines.last().is_some_and(|html| html.is_empty()) {
+        lines.pop();
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"UTF-8\" />\n<title>rwshell session history</title>\n\
+         </head>\n<body style=\"margin: 0; background: #000000; color: #ffffff\">\n\
+         <pre style=\"margin: 0; padding: 8px; font-family: monospace\">{}</pre>\n</body>\n</html>\n",
+        lines.join("\n")
+    )
+}
+
+/// Row `row` of the visible screen as HTML with inline styles, without trailing unstyled blanks
+pub(crate) fn row_html(screen: &vt100::Screen, row: u16, cols: u16) -> String {
+    let mut html = String::new();
+    let mut open_style: Option<String> = None;
+    for col in 0..cols {
+        let Some(cell) = screen.cell(row, col) else {
+            continue;
+        };
+        if cell.is_wide_continuation() {
+            continue;
+        }
+        let style = cell_style(cell);
+        if style != open_style {
+            if open_style.is_some() {
+                html.push_str("</span>");
+            }
+            if let Some(style) = &style {
+                html.push_str(&format!("<span style=\"{style}\">"));
+            }
+            open_style = style;
+        }
+        let contents = if cell.has_contents() { cell.contents() } else { " " };
+        html.push_str(&html_escape(contents));
+    }
+    if open_style.is_some() {
+        html.push_str("</span>");
+    } else {
+        html.truncate(html.trim_end_matches(' ').len());
+    }
+    html
+}
+
+/// Inline CSS for the attributes of `cell`, if it has any
+fn cell_style(cell: &vt100::Cell) -> Option<String> {
+    let (mut fg, mut bg) = (css_color(cell.fgcolor()), css_color(cell.bgcolor()));
+    if cell.inverse() {
+        (fg, bg) = (
+            Some(bg.unwrap_or_else(|| "#000000".to_string())),
+            Some(fg.unwrap_or_else(|| "#ffffff".to_string())),
+        );
+    }
+
+    let mut style = Vec::new();
+    if let Some(fg) = fg {
+        style.push(format!("color: {fg}"));
+    }
+    if let Some(bg) = bg {
+        style.push(format!("background: {bg}"));
+    }
+    if cell.bold() {
+        style.push("font-weight: bold".to_string());
+    }
+    if cell.dim() {
+        style.push("opacity: 0.6".to_string());
+    }
+    if cell.italic() {
+        style.push("font-style: italic".to_string());
+    }
+    if cell.underline() {
+        style.push("text-decoration: underline".to_string());
+    }
+    (!style.is_empty()).then(|| style.join("; "))
+}
+
+/// CSS color of a terminal color, or `None` for the default color
+fn css_color(color: vt100::Color) -> Option<String> {
+    color_rgb(color).map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"))
+}
+
+/// RGB value of a terminal color in the xterm palette, or `None` for the default color
+pub(crate) fn color_rgb(color: vt100::Color) -> Option<[u8; 3]> {
+    const PALETTE: [[u8; 3]; 16] = [
+        [0x00, 0x00, 0x00],
+        [0xcd, 0x00, 0x00],
+        [0x00, 0xcd, 0x00],
+        [0xcd, 0xcd, 0x00],
+        [0x00, 0x00, 0xee],
+        [0xcd, 0x00, 0xcd],
+        [0x00, 0xcd, 0xcd],
+        [0xe5, 0xe5, 0xe5],
+        [0x7f, 0x7f, 0x7f],
+        [0xff, 0x00, 0x00],
+        [0x00, 0xff, 0x00],
+        [0xff, 0xff, 0x00],
+        [0x5c, 0x5c, 0xff],
+        [0xff, 0x00, 0xff],
+        [0x00, 0xff, 0xff],
+        [0xff, 0xff, 0xff],
+    ];
+    match color {
+        vt100::Color::Default => None,
+        vt100::Color::Idx(idx @ 0..=15) => Some(PALETTE[idx as usize]),
+        // 6x6x6 color cube
+        vt100::Color::Idx(idx @ 16..=231) => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+            let idx = idx - 16;
+            Some([level(idx / 36), level(idx / 6 % 6), level(idx % 6)])
+        }
+        // Grayscale ramp
+        vt100::Color::Idx(idx) => {
+            let gray = 8 + (idx - 232) * 10;
+            Some([gray, gray, gray])
+        }
+        vt100::Color::Rgb(r, g, b) => Some([r, g, b]),
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The page of `lines` a history request asks for
+pub fn page(lines: &[String], request: &HistoryRequestMessage) -> HistoryMessage {
+    let count = request.lines.clamp(1, MAX_PAGE_LINES);
+    let total = lines.len();
+    let start = request
+        .start
+        .unwrap_or(total.saturating_sub(count))
+        .min(total.saturating_sub(count));
+    HistoryMessage {
+        start,
+        total,
+        lines: lines[start..(start + count).min(total)].to_vec(),
+    }
+}
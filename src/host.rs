@@ -0,0 +1,548 @@
+use crate::args::Args;
+use crate::control::start_control_socket;
+use crate::invite::Invites;
+use crate::qr;
+use crate::server::{AppState, apply_resize, broadcast_keys, is_valid_terminal_size, token_in_links};
+use async_trait::async_trait;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use terminal_size::{Height, Width, terminal_size};
+use termios::{TCSANOW, Termios, tcsetattr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Time without typing from a viewer after which its next input is marked again, with `--show-writers`
+const WRITER_BURST_GAP: Duration = Duration::from_secs(2);
+
+/// How the machine running rwshell takes part in the session
+#[async_trait]
+pub trait HostMode: Send + Sync {
+    /// Whether web clients, rather than the host terminal, decide the terminal size
+    fn headless(&self) -> bool;
+
+    /// Terminal size the PTY starts with
+    fn initial_size(&self) -> (u16, u16);
+
+    /// Prepare the host terminal before the session starts
+    fn prepare(&self);
+
+    /// Start the background tasks this mode needs for the session
+    fn start(&self, state: &AppState, shutdown: CancellationToken);
+
+    /// Show PTY output on the host
+    async fn echo(&self, data: &[u8]);
+
+    /// Tell the host that a viewer is asking for attention
+    fn notify_knock(&self, client_id: &str, message: &str);
+
+    /// Tell the host about something that needs its attention, such as a leaked session link
+    fn notify(&self, notice: &str);
+
+    /// Tell the host that viewer `client_id` typed into the program
+    fn remote_input(&self, client_id: &str);
+
+    /// Resolves when the host asks rwshell to stop
+    async fn interrupted(&self);
+
+    /// Undo `prepare` before exiting
+    fn restore(&self);
+}
+
+/// A URL viewers open to join the session
+#[derive(Debug, Clone)]
+pub struct SessionLink {
+    /// Kind of access the URL grants, e.g. "read-only session"
+    pub label: &'static str,
+    pub url: String,
+}
+
+impl SessionLink {
+    /// The link as terminals print it, clickable where OSC 8 hyperlinks are supported
+    pub fn hyperlink(&self) -> String {
+        format!("{}: \x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", self.label, self.url, self.url)
+    }
+}
+
+impl std::fmt::Display for SessionLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.label, self.url)
+    }
+}
+
+/// Pick the host mode selected on the command line
+pub fn host_mode(args: &Args, links: &[SessionLink], invites: Option<Arc<Invites>>) -> Arc<dyn HostMode> {
+    if args.headless {
+        headless_host(args.headless_cols, args.headless_rows)
+    } else {
+        Arc::new(InteractiveHost {
+            links: if args.show_url { links.into() } else { Arc::default() },
+            url_key: args.show_url.then_some(args.url_key),
+            writers: args.show_writers.then(|| Arc::new(WriterMarks::new(args.writers_key))),
+            invites: invites.map(|invites| (invites, args.invite_key)),
+            qr: args.qr,
+            links_qr: args.qr && token_in_links(args),
+            ..Default::default()
+        })
+    }
+}
+
+/// A host without a terminal, for a PTY of `cols` x `rows`
+pub fn headless_host(cols: u16, rows: u16) -> Arc<dyn HostMode> {
+    Arc::new(HeadlessHost {
+        cols,
+        rows,
+        attached: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+/// The session runs in the terminal rwshell was started from, which owns input and size
+#[derive(Default)]
+pub struct InteractiveHost {
+    original_termios: std::sync::Mutex<Option<Termios>>,
+    stdout: Mutex<Option<tokio::io::Stdout>>,
+    /// Session URLs shown in the title and printed again on `url_key`, with `--show-url`
+    links: Arc<[SessionLink]>,
+    url_key: Option<u8>,
+    /// Marks output that follows a viewer's typing, with `--show-writers`
+    writers: Option<Arc<WriterMarks>>,
+    /// Invite links and the key that prints a new one, with `--invites`
+    invites: Option<(Arc<Invites>, u8)>,
+    /// Print invite links as QR codes too, with `--qr`
+    qr: bool,
+    /// Print session links as QR codes too, with `--qr` unless they only work with an invite
+    links_qr: bool,
+}
+
+#[async_trait]
+impl HostMode for InteractiveHost {
+    fn headless(&self) -> bool {
+        false
+    }
+
+    fn initial_size(&self) -> (u16, u16) {
+        get_terminal_size()
+    }
+
+    fn prepare(&self) {
+        match setup_raw_terminal() {
+            Ok(termios) => *self.original_termios.lock().unwrap_or_else(|e| e.into_inner()) = Some(termios),
+            Err(e) => debug!("Failed to set raw terminal mode: {}. Continuing anyway.", e),
+        }
+
+        if !self.links.is_empty() {
+            // Save the current title so it comes back on exit
+            let _ = std::io::stderr().write_all(b"\x1b[22;0t");
+            show_url_in_title(&self.links);
+        }
+    }
+
+    fn start(&self, state: &AppState, shutdown: CancellationToken) {
+        // For `rwshell ctl` and `rwshell confirm` from other shells; this terminal stays attached, so
+        // `rwshell attach` is refused
+        match start_control_socket(state.clone(), Arc::new(AtomicBool::new(true)), shutdown.clone()) {
+            Ok(path) => debug!("Control socket listening on {}", path.display()),
+            Err(e) => debug!("Failed to start control socket, `rwshell ctl` is unavailable: {}", e),
+        }
+
+        // Follow the size of the host terminal
+        let state_resize = state.clone();
+        let token_size = shutdown;
+        state.supervisor.spawn("terminal-size", async move {
+            let mut last_size = *state_resize.current_size.lock().await;
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(500));
+
+            loop {
+                tokio::select! {
+                    _ = token_size.cancelled() => {
+                        debug!("Terminal size monitoring task cancelled");
+                        break;
+                    }
+                    _ = interval.tick() => {
+                        let current_size = get_terminal_size();
+                        if current_size == last_size {
+                            continue;
+                        }
+
+                        debug!("Terminal size changed: {}x{} -> {}x{}",
+                               last_size.0, last_size.1, current_size.0, current_size.1);
+
+                        // Validate the new terminal size before applying it
+                        if !is_valid_terminal_size(current_size.0, current_size.1) {
+                            debug!("Ignoring invalid terminal size from host terminal: {}x{}",
+                                   current_size.0, current_size.1);
+                            continue;
+                        }
+
+                        apply_resize(
+                            current_size.0,
+                            current_size.1,
+                            &state_resize.pty_master,
+                            &state_resize.current_size,
+                            &state_resize.pty_tx,
+                        )
+                        .await;
+                        last_size = current_size;
+                    }
+                }
+            }
+        });
+
+        // Forward host keystrokes to the PTY
+        let state_stdin = state.clone();
+        let links = Arc::clone(&self.links);
+        let url_key = self.url_key;
+        let writers = self.writers.clone();
+        let invites = self.invites.clone();
+        let (qr, links_qr) = (self.qr, self.links_qr);
+        state.supervisor.spawn("stdin", async move {
+            let mut stdin = tokio::io::stdin();
+            let mut buffer = [0u8; 1024];
+
+            loop {
+                match stdin.read(&mut buffer).await {
+                    Ok(n) if n > 0 => {
+                        let mut data = &buffer[..n];
+                        let filtered: Vec<u8>;
+                        if let Some(key) = url_key.filter(|key| data.contains(key)) {
+                            print_links(&links, links_qr);
+                            filtered = data.iter().copied().filter(|&byte| byte != key).collect();
+                            data = &filtered;
+                            if data.is_empty() {
+                                continue;
+                            }
+                        }
+                        let uninvited: Vec<u8>;
+                        if let Some((invites, key)) = invites.as_ref().filter(|(_, key)| data.contains(key)) {
+                            print_invite(invites, qr);
+                            uninvited = data.iter().copied().filter(|&byte| byte != *key).collect();
+                            data = &uninvited;
+                            if data.is_empty() {
+                                continue;
+                            }
+                        }
+                        let unmarked: Vec<u8>;
+                        if let Some(writers) = writers.as_ref().filter(|writers| data.contains(&writers.key)) {
+                            let notice = if writers.toggle() {
+                                "Marking output that follows viewers' typing"
+                            } else {
+                                "No longer marking output that follows viewers' typing"
+                            };
+                            state_stdin.host.notify(notice);
+                            unmarked = data.iter().copied().filter(|&byte| byte != writers.key).collect();
+                            data = &unmarked;
+                            if data.is_empty() {
+                                continue;
+                            }
+                        }
+                        if let Some(writers) = &writers {
+                            writers.host_input();
+                        }
+                        // While a viewer's command waits for confirmation, keystrokes answer it
+                        if let Some(gate) = &state_stdin.confirm {
+                            if gate.host_input(state_stdin.host.as_ref(), data) {
+                                continue;
+                            }
+                        }
+                        // While a viewer asks for write access, keystrokes answer it
+                        if let Some(gate) = &state_stdin.approval {
+                            if gate.host_input(state_stdin.host.as_ref(), data) {
+                                continue;
+                            }
+                        }
+                        // Check the echo mode before the program can change it in response to this input
+                        broadcast_keys(&state_stdin, data).await;
+                        if let Err(e) = state_stdin.pty_writer.lock().await.write_all(data).await {
+                            warn!("Failed to write host input to the program: {}", e);
+                        }
+                    }
+                    Ok(_) => {
+                        eprintln!("Stdin reached EOF");
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("Error reading from stdin: {e}");
+                        break;
+                    }
+                }
+            }
+            eprintln!("Stdin reader task ended");
+        });
+    }
+
+    async fn echo(&self, data: &[u8]) {
+        let mut stdout = self.stdout.lock().await;
+        let stdout = stdout.get_or_insert_with(tokio::io::stdout);
+        if let Some(marker) = self.writers.as_ref().and_then(|writers| writers.take_marker()) {
+            let _ = stdout.write_all(marker.as_bytes()).await;
+        }
+        let _ = stdout.write_all(data).await;
+        let _ = stdout.flush().await;
+    }
+
+    fn notify_knock(&self, _client_id: &str, message: &str) {
+        if message.is_empty() {
+            self.notify("A viewer is asking for your attention");
+        } else {
+            self.notify(&format!("A viewer is asking for your attention: {message}"));
+        }
+    }
+
+    fn notify(&self, notice: &str) {
+        let notice = format!("\x07\r\n[rwshell] {notice}\r\n");
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(notice.as_bytes());
+        let _ = stderr.flush();
+    }
+
+    fn remote_input(&self, client_id: &str) {
+        if let Some(writers) = &self.writers {
+            writers.remote_input(client_id);
+        }
+    }
+
+    async fn interrupted(&self) {
+        // Ctrl+C reaches the shared program through the raw terminal instead
+        std::future::pending().await
+    }
+
+    fn restore(&self) {
+        if let Some(ref termios) = *self.original_termios.lock().unwrap_or_else(|e| e.into_inner()) {
+            restore_terminal(termios);
+        }
+        if !self.links.is_empty() {
+            // Bring back the title saved in `prepare`
+            let _ = std::io::stderr().write_all(b"\x1b[23;0t");
+        }
+    }
+}
+
+/// The session runs without a host terminal; web clients decide the terminal size until a local
+/// terminal is attached with `rwshell attach`
+pub struct HeadlessHost {
+    cols: u16,
+    rows: u16,
+    attached: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl HostMode for HeadlessHost {
+    fn headless(&self) -> bool {
+        !self.attached.load(Ordering::SeqCst)
+    }
+
+    fn initial_size(&self) -> (u16, u16) {
+        (self.cols, self.rows)
+    }
+
+    fn prepare(&self) {}
+
+    fn start(&self, state: &AppState, shutdown: CancellationToken) {
+        // Let a local terminal take over the host role later
+        match start_control_socket(state.clone(), Arc::clone(&self.attached), shutdown.clone()) {
+            Ok(path) => debug!("Control socket listening on {}", path.display()),
+            Err(e) => warn!("Failed to start control socket, `rwshell attach` is unavailable: {}", e),
+        }
+
+        // Apply client resize requests that were held back by rate limiting
+        let state = state.clone();
+        let supervisor = state.supervisor.clone();
+        supervisor.spawn("pending-resize", async move {
+            const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+            const MIN_RESIZE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        debug!("Pending resize processor cancelled");
+                        break;
+                    }
+                    _ = interval.tick() => {
+                        // Check if we have a pending resize and enough time has passed
+                        let pending = *state.pending_resize.lock().await;
+
+                        if let Some((cols, rows)) = pending {
+                            let now = std::time::Instant::now();
+                            let last_time = *state.last_resize_time.lock().await;
+
+                            if now.duration_since(last_time) >= MIN_RESIZE_INTERVAL {
+                                *state.pending_resize.lock().await = None;
+                                *state.last_resize_time.lock().await = now;
+
+                                debug!("Processing pending resize: {}x{}", cols, rows);
+                                apply_resize(cols, rows, &state.pty_master, &state.current_size, &state.pty_tx).await;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    async fn echo(&self, _data: &[u8]) {}
+
+    fn notify_knock(&self, client_id: &str, message: &str) {
+        info!("Viewer {} knocked: {}", client_id, message);
+    }
+
+    fn notify(&self, notice: &str) {
+        warn!("{}", notice);
+    }
+
+    fn remote_input(&self, _client_id: &str) {}
+
+    async fn interrupted(&self) {
+        let _ = tokio::signal::ctrl_c().await;
+        debug!("Received Ctrl+C in headless mode, shutting down server");
+    }
+
+    fn restore(&self) {}
+}
+
+/// Marks the output that follows a viewer's typing on the host terminal, so it doesn't seem to appear by
+/// itself. Each burst of input from a viewer is marked once, before the output that comes next.
+struct WriterMarks {
+    enabled: AtomicBool,
+    /// Key that turns the markers off and on
+    key: u8,
+    /// The viewer that typed last and when, until the host types
+    last: std::sync::Mutex<Option<(String, Instant)>>,
+    /// Marker to show before the next output
+    pending: std::sync::Mutex<Option<String>>,
+}
+
+impl WriterMarks {
+    fn new(key: u8) -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            key,
+            last: std::sync::Mutex::default(),
+            pending: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Turn the markers off if they are on, or on again. Returns whether they are on.
+    fn toggle(&self) -> bool {
+        let enabled = !self.enabled.fetch_xor(true, Ordering::SeqCst);
+        if !enabled {
+            *self.pending.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        enabled
+    }
+
+    fn remote_input(&self, client_id: &str) {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let same_burst =
+            matches!(&*last, Some((id, at)) if id == client_id && now.duration_since(*at) < WRITER_BURST_GAP);
+        *last = Some((client_id.to_string(), now));
+        if !same_burst {
+            let name: String = client_id.chars().take(8).collect();
+            *self.pending.lock().unwrap_or_else(|e| e.into_inner()) = Some(format!("\x1b[2m[viewer {name}]\x1b[22m"));
+        }
+    }
+
+    /// The host typed, so the next input from a viewer starts a new burst
+    fn host_input(&self) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        *self.pending.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// The marker to show before the output being echoed, if a burst of input from a viewer preceded it
+    fn take_marker(&self) -> Option<String> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+/// Put the session URL in the terminal title, which the shared program may replace
+fn show_url_in_title(links: &[SessionLink]) {
+    if let Some(link) = links.first() {
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\x1b]0;rwshell: {}\x07", link.url);
+        let _ = stderr.flush();
+    }
+}
+
+/// Print a new invite link, for the host to pass on to one viewer
+fn print_invite(invites: &Invites, qr: bool) {
+    let link = SessionLink {
+        label: "invite for one viewer",
+        url: invites.mint(None).url,
+    };
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(
+        stderr,
+        "\r\n[rwshell] {}, valid for {}\r\n",
+        link.hyperlink(),
+        humantime::format_duration(invites.ttl())
+    );
+    if qr {
+        print_qr(&mut stderr, &link);
+    }
+    let _ = stderr.flush();
+}
+
+/// Print the session URLs again, as they scroll away once the program starts
+fn print_links(links: &[SessionLink], qr: bool) {
+    let mut stderr = std::io::stderr().lock();
+    let _ = stderr.write_all(b"\r\n");
+    for link in links {
+        let _ = write!(stderr, "[rwshell] {}\r\n", link.hyperlink());
+        if qr {
+            print_qr(&mut stderr, link);
+        }
+    }
+    let _ = stderr.flush();
+    drop(stderr);
+    show_url_in_title(links);
+}
+
+/// Print `link` as a QR code to the host terminal, which is in raw mode
+fn print_qr(out: &mut impl Write, link: &SessionLink) {
+    match qr::render(&link.url, "\r\n") {
+        Ok(code) => {
+            let _ = out.write_all(code.as_bytes());
+        }
+        Err(e) => debug!("Failed to print the {} as a QR code: {:#}", link.label, e),
+    }
+}
+
+pub(crate) fn get_terminal_size() -> (u16, u16) {
+    if let Some((Width(w), Height(h))) = terminal_size() {
+        (w, h)
+    } else {
+        // Fallback to default size if unable to detect
+        (80, 25)
+    }
+}
+
+pub(crate) fn setup_raw_terminal() -> Result<Termios, std::io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let stdin_fd = std::io::stdin().as_raw_fd();
+    let original_termios = Termios::from_fd(stdin_fd)?;
+    let mut raw_termios = original_termios;
+
+    // Set raw mode
+    termios::cfmakeraw(&mut raw_termios);
+
+    // Apply the raw terminal settings
+    tcsetattr(stdin_fd, TCSANOW, &raw_termios)?;
+
+    Ok(original_termios)
+}
+
+pub(crate) fn restore_terminal(original_termios: &Termios) {
+    use std::os::unix::io::AsRawFd;
+
+    let stdin_fd = std::io::stdin().as_raw_fd();
+    let _ = tcsetattr(stdin_fd, TCSANOW, original_termios);
+}
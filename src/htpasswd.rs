@@ -0,0 +1,228 @@
+use anyhow::{Context, bail};
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error};
+
+/// Credentials remembered as verified, above which the cache starts over
+const MAX_VERIFIED: usize = 1024;
+
+/// How a password in an htpasswd file is hashed
+enum PasswordHash {
+    /// `$2y$...`, from `htpasswd -B`
+    Bcrypt(String),
+    /// `{SHA}` and the base64 SHA-1 digest, from `htpasswd -s`
+    Sha1([u8; 20]),
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            return Some(Self::Bcrypt(hash.to_string()));
+        }
+        let digest = general_purpose::STANDARD.decode(hash.strip_prefix("{SHA}")?).ok()?;
+        Some(Self::Sha1(digest.try_into().ok()?))
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Self::Sha1(digest) => {
+                // Constant time, like `AuthToken::matches`
+                let candidate = Sha1::digest(password.as_bytes());
+                digest
+                    .iter()
+                    .zip(candidate.iter())
+                    .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+            }
+        }
+    }
+}
+
+/// Users and password hashes of an htpasswd file given with `--htpasswd`, which every request to the
+/// session must authenticate as with HTTP Basic Auth
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+    /// SHA-1 digests of `user:password` pairs that were verified, since checking a bcrypt hash takes a
+    /// while and a page load makes several requests
+    verified: Mutex<HashSet<[u8; 20]>>,
+}
+
+impl Htpasswd {
+    /// Read the file, which has a `user:hash` line per user, hashed with bcrypt or SHA-1
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&content, &path.display().to_string())
+    }
+
+    /// Parse the content of the file named `name`
+    fn parse(content: &str, name: &str) -> anyhow::Result<Self> {
+        let mut users = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                bail!("{}:{}: expected user:hash", name, number + 1);
+            };
+            let Some(hash) = PasswordHash::parse(hash) else {
+                bail!(
+                    "{}:{}: the password of {} isn't hashed with bcrypt or SHA-1; add it again with `htpasswd -B`",
+                    name,
+                    number + 1,
+                    user
+                );
+            };
+            users.insert(user.to_string(), hash);
+        }
+        if users.is_empty() {
+            bail!("{} has no users", name);
+        }
+        Ok(Self {
+            users,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Whether `credentials`, as `user:password`, match a user of the file
+    fn verify(&self, credentials: &str) -> bool {
+        let key: [u8; 20] = Sha1::digest(credentials.as_bytes()).into();
+        if self.verified.lock().unwrap_or_else(|e| e.into_inner()).contains(&key) {
+            return true;
+        }
+        let Some((user, password)) = credentials.split_once(':') else {
+            return false;
+        };
+        let Some(hash) = self.users.get(user) else {
+            return false;
+        };
+        if !hash.verify(password) {
+            return false;
+        }
+        let mut verified = self.verified.lock().unwrap_or_else(|e| e.into_inner());
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(key);
+        true
+    }
+}
+
+/// `user:password` given with Basic Auth in `request`, if any
+fn basic_credentials(request: &Request) -> Option<String> {
+    let value = request.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    String::from_utf8(general_purpose::STANDARD.decode(encoded.trim()).ok()?).ok()
+}
+
+/// Refuse requests without the credentials of a user of the htpasswd file with 401, which makes browsers
+/// ask for them. Browsers then send them with every request to the server, the WebSocket upgrade included.
+pub async fn require_password(State(htpasswd): State<Option<Arc<Htpasswd>>>, request: Request, next: Next) -> Response {
+    let Some(htpasswd) = htpasswd else {
+        return next.run(request).await;
+    };
+
+    if let Some(credentials) = basic_credentials(&request) {
+        // Checking a bcrypt hash keeps a thread busy for a while
+        let checker = Arc::clone(&htpasswd);
+        match tokio::task::spawn_blocking(move || checker.verify(&credentials)).await {
+            Ok(true) => return next.run(request).await,
+            Ok(false) => debug!("Refused a request to {} with wrong credentials", request.uri().path()),
+            Err(e) => error!("Failed to check credentials: {}", e),
+        }
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, r#"Basic realm="rwshell", charset="UTF-8""#)],
+        "A user name and password are required",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `{SHA}` hash of "secret", as `htpasswd -s` writes it
+    const SECRET_SHA1: &str = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+
+    fn parse(content: &str) -> anyhow::Result<Htpasswd> {
+        Htpasswd::parse(content, "users.htpasswd")
+    }
+
+    fn parse_error(content: &str) -> String {
+        match parse(content) {
+            Ok(_) => panic!("{content:?} was accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn verifies_sha1_and_bcrypt_passwords() {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let htpasswd = parse(&format!("alice:{SECRET_SHA1}\nbob:{bcrypt}\n")).unwrap();
+        assert!(htpasswd.verify("alice:secret"));
+        assert!(!htpasswd.verify("alice:Secret"));
+        assert!(htpasswd.verify("bob:hunter2"));
+        assert!(!htpasswd.verify("bob:hunter3"));
+        // Verified credentials are remembered, wrong ones still aren't accepted
+        assert!(htpasswd.verify("bob:hunter2"));
+        assert!(!htpasswd.verify("bob:"));
+    }
+
+    #[test]
+    fn refuses_unknown_users_and_credentials_without_a_password() {
+        let htpasswd = parse(&format!("alice:{SECRET_SHA1}")).unwrap();
+        assert!(!htpasswd.verify("mallory:secret"));
+        assert!(!htpasswd.verify("alice"));
+        assert!(!htpasswd.verify(""));
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let htpasswd = parse(&format!("# users\n\n   \n  alice:{SECRET_SHA1}  \n")).unwrap();
+        assert!(htpasswd.verify("alice:secret"));
+    }
+
+    #[test]
+    fn rejects_malformed_lines_with_their_line_number() {
+        let error = parse_error(&format!("alice:{SECRET_SHA1}\nbob\n"));
+        assert_eq!(error, "users.htpasswd:2: expected user:hash");
+
+        // MD5, from plain `htpasswd`
+        let error = parse_error("carol:$apr1$salt$hash");
+        assert!(
+            error.starts_with("users.htpasswd:1: the password of carol isn't hashed"),
+            "{error}"
+        );
+        // Crypt, from `htpasswd -d`
+        assert!(parse("dave:rl0P1xuoTSXYQ").is_err());
+        // Not base64, or not a SHA-1 digest
+        assert!(parse("erin:{SHA}not base64!").is_err());
+        assert!(parse("frank:{SHA}c2hvcnQ=").is_err());
+        assert!(parse("grace:").is_err());
+    }
+
+    #[test]
+    fn rejects_files_without_users() {
+        let error = parse_error("# nobody yet\n");
+        assert_eq!(error, "users.htpasswd has no users");
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// Domain separation prefix for identity challenge signatures
+const CHALLENGE_CONTEXT: &[u8] = b"rwshell-identity-v1\0";
+
+/// Build the byte string that is signed in response to a client challenge: the session answered for, a
+/// nonce chosen by the server and the client's nonce, each prefixed with its length. The session is empty
+/// for the multiplexed endpoint, which answers for the server as a whole.
+pub fn challenge_payload(session: &str, server_nonce: &[u8], client_nonce: &[u8]) -> Vec<u8> {
+    let mut payload =
+        Vec::with_capacity(CHALLENGE_CONTEXT.len() + 12 + session.len() + server_nonce.len() + client_nonce.len());
+    payload.extend_from_slice(CHALLENGE_CONTEXT);
+    for part in [session.as_bytes(), server_nonce, client_nonce] {
+        payload.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        payload.extend_from_slice(part);
+    }
+    payload
+}
+
+/// Default location of the server identity key
+pub fn default_identity_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rwshell")
+        .join("identity_ed25519")
+}
+
+/// Persistent Ed25519 identity of a rwshell server
+pub struct ServerIdentity {
+    signing_key: SigningKey,
+}
+
+impl ServerIdentity {
+    /// Load the identity key from `path`, generating and saving a new one if it doesn't exist
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let encoded = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read identity key {}", path.display()))?;
+            let bytes = general_purpose::STANDARD
+                .decode(encoded.trim())
+                .with_context(|| format!("Invalid identity key encoding in {}", path.display()))?;
+            let secret: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid identity key length in {}", path.display()))?;
+
+            debug!("Loaded server identity from {}", path.display());
+            return Ok(Self {
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+
+        let signing_key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create identity directory {}", parent.display()))?;
+        }
+        write_private_file(path, &general_purpose::STANDARD.encode(signing_key.to_bytes()))
+            .with_context(|| format!("Failed to write identity key {}", path.display()))?;
+
+        info!("Generated new server identity at {}", path.display());
+        Ok(Self { signing_key })
+    }
+
+    /// Base64 encoded public key
+    pub fn public_key(&self) -> String {
+        general_purpose::STANDARD.encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Answer a client supplied challenge nonce for `session`, returning a nonce of the server's own and
+    /// the signature, both base64 encoded
+    pub fn sign_challenge(&self, session: &str, client_nonce: &[u8]) -> (String, String) {
+        let server_nonce = rand::random::<[u8; 32]>();
+        let signature = self.sign(&challenge_payload(session, &server_nonce, client_nonce));
+        (
+            general_purpose::STANDARD.encode(server_nonce),
+            general_purpose::STANDARD.encode(signature),
+        )
+    }
+
+    /// Sign an arbitrary message with the identity key
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.signing_key.sign(message).to_bytes()
+    }
+
+    /// Verify a signature made with the identity key
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return false;
+        };
+        self.signing_key.verifying_key().verify(message, &signature).is_ok()
+    }
+}
+
+fn write_private_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.write_all(b"\n")
+}
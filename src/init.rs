@@ -0,0 +1,165 @@
+use crate::args::Args;
+use crate::config::default_config_path;
+use crate::systemd::{self, ServiceUnit};
+use anyhow::{Context, Result};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// Ask about a shared terminal setup and write it to the config file as a profile, optionally with
+/// a systemd unit running it
+pub fn run(args: &Args) -> Result<()> {
+    let mut prompt = Prompt::new();
+    println!("This sets up a profile in the rwshell config file. Press Enter to accept the [default].\n");
+
+    let config_path = args.config.clone().unwrap_or_else(default_config_path);
+    let mut config = read_config(&config_path)?;
+
+    let name = prompt.ask("Profile name", "shared")?;
+    let exists = matches!(config.get("profiles"), Some(toml::Value::Table(profiles)) if profiles.contains_key(&name));
+    if exists && !prompt.confirm(&format!("Replace the existing profile {name}?"), false)? {
+        println!("Nothing was changed.");
+        return Ok(());
+    }
+    let mut profile = toml::Table::new();
+
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "bash".to_string());
+    profile.insert("command".into(), prompt.ask("Command to share", &shell)?.into());
+
+    println!("\nUse 0.0.0.0:<port> to accept viewers from other machines.");
+    let listen = prompt.ask("Listen address", "localhost:8000")?;
+    let local = listen.starts_with("localhost:") || listen.starts_with("127.") || listen.starts_with("[::1]:");
+    profile.insert("listen".into(), listen.into());
+
+    let headless = prompt.confirm("Run without a terminal on this machine, e.g. as a service?", false)?;
+    if headless {
+        profile.insert("headless".into(), true.into());
+    }
+    if prompt.confirm("Keep viewers read-only?", false)? {
+        profile.insert("readonly".into(), true.into());
+    }
+
+    println!("\nSigned capability links replace the guessable /s/local/ URL with a read-write and a read-only link.");
+    if prompt.confirm("Use capability links?", !local)? {
+        profile.insert("capabilities".into(), true.into());
+        let ttl = loop {
+            let ttl = prompt.ask("Link lifetime, e.g. 8h (empty for links that don't expire)", "")?;
+            match humantime::parse_duration(&ttl) {
+                _ if ttl.is_empty() => break None,
+                Ok(_) => break Some(ttl),
+                Err(e) => println!("Invalid duration: {e}"),
+            }
+        };
+        if let Some(ttl) = ttl {
+            profile.insert("capability-ttl".into(), ttl.into());
+        }
+    }
+
+    if !local {
+        println!(
+            "\nrwshell serves plain HTTP. For viewers on other networks, put it behind a reverse proxy that \
+             terminates TLS, such as Caddy or nginx."
+        );
+    }
+
+    let profiles = config
+        .entry("profiles")
+        .or_insert_with(|| toml::Table::new().into())
+        .as_table_mut()
+        .with_context(|| format!("`profiles` in {} must be a table", config_path.display()))?;
+    profiles.insert(name.clone(), profile.into());
+    write_file(&config_path, &toml::to_string(&config)?)?;
+    println!("\nWrote profile {} to {}", name, config_path.display());
+    match &args.config {
+        Some(path) => println!("Start it with: rwshell --config {} --profile {}", path.display(), name),
+        None => println!("Start it with: rwshell --profile {name}"),
+    }
+
+    if headless && prompt.confirm("\nCreate a systemd user service for it?", false)? {
+        let exe = std::env::current_exe().context("Failed to find the rwshell executable")?;
+        let unit_args = [
+            "--config".to_string(),
+            config_path.to_string_lossy().into_owned(),
+            "--profile".to_string(),
+            name.clone(),
+        ];
+        let service = systemd::service_name(&name);
+        let unit_path = systemd::user_unit_dir().join(&service);
+        let unit = ServiceUnit {
+            description: &name,
+            exe: &exe,
+            args: &unit_args,
+            working_directory: None,
+            system: false,
+            socket_activated: false,
+        };
+        write_file(&unit_path, &unit.render())?;
+        println!("Wrote {}", unit_path.display());
+        println!("Start it with: systemctl --user daemon-reload && systemctl --user enable --now {service}");
+    }
+
+    Ok(())
+}
+
+/// The existing config file, or an empty one
+fn read_config(path: &Path) -> Result<toml::Table> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(toml::Table::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read config file {}", path.display())),
+    }
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Questions asked on the terminal; at the end of input every question takes its default
+struct Prompt {
+    input: std::io::StdinLock<'static>,
+}
+
+impl Prompt {
+    fn new() -> Self {
+        Self {
+            input: std::io::stdin().lock(),
+        }
+    }
+
+    fn read_line(&mut self, question: &str) -> Result<String> {
+        print!("{question} ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            println!();
+        }
+        Ok(line.trim().to_string())
+    }
+
+    fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        let answer = if default.is_empty() {
+            self.read_line(&format!("{question}:"))?
+        } else {
+            self.read_line(&format!("{question} [{default}]:"))?
+        };
+        Ok(if answer.is_empty() { default.to_string() } else { answer })
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let hint = if default { "[Y/n]" } else { "[y/N]" };
+        loop {
+            match self
+                .read_line(&format!("{question} {hint}"))?
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => println!("Please answer y or n."),
+            }
+        }
+    }
+}
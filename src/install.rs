@@ -0,0 +1,114 @@
+use crate::args::Args;
+use crate::config::default_config_path;
+use crate::systemd::{self, ServiceUnit};
+use anyhow::{Context, Result, bail};
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+/// Options of `rwshell install-service`
+pub struct InstallOptions<'a> {
+    pub name: &'a str,
+    pub system: bool,
+    pub socket: bool,
+    pub print: bool,
+}
+
+/// Install and start a systemd service running a headless session with the options rwshell was
+/// started with, optionally activated by a systemd socket
+pub fn install_service(args: &Args, options: &InstallOptions<'_>) -> Result<()> {
+    let name = options.name;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("Invalid service name {name:?}: use letters, digits, '-' and '_'");
+    }
+
+    let exe = std::env::current_exe().context("Failed to find the rwshell executable")?;
+    let working_directory = std::env::current_dir().context("Failed to get the current directory")?;
+    let session_args = session_args(args);
+
+    let service = ServiceUnit {
+        description: name,
+        exe: &exe,
+        args: &session_args,
+        working_directory: Some(&working_directory),
+        system: options.system,
+        socket_activated: options.socket,
+    }
+    .render();
+    let socket = if options.socket {
+        let listen = args
+            .listen
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve listen address {}", args.listen))?
+            .next()
+            .with_context(|| format!("Listen address {} has no addresses", args.listen))?;
+        Some(systemd::socket_unit(name, listen))
+    } else {
+        None
+    };
+
+    let service_name = systemd::service_name(name);
+    let socket_name = systemd::socket_name(name);
+    if options.print {
+        println!("# {service_name}\n{service}");
+        if let Some(socket) = &socket {
+            println!("# {socket_name}\n{socket}");
+        }
+        return Ok(());
+    }
+
+    let dir = if options.system {
+        systemd::system_unit_dir()
+    } else {
+        systemd::user_unit_dir()
+    };
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    write_unit(&dir.join(&service_name), &service)?;
+    if let Some(socket) = &socket {
+        write_unit(&dir.join(&socket_name), socket)?;
+    }
+
+    let started = if socket.is_some() { &socket_name } else { &service_name };
+    systemd::systemctl(options.system, &["daemon-reload"])?;
+    systemd::systemctl(options.system, &["enable", "--now", started])?;
+    println!("Started {started}");
+
+    let manager = if options.system { "" } else { " --user" };
+    println!("Follow it with: journalctl{manager} -u {service_name} -f");
+    if !options.system {
+        println!("To keep it running while you are logged out: loginctl enable-linger");
+    }
+    Ok(())
+}
+
+/// Options for the session: those given before the subcommand, always headless, and with the config
+/// file pinned so the service reads the same one
+fn session_args(args: &Args) -> Vec<String> {
+    let command_line: Vec<String> = std::env::args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let end = command_line
+        .iter()
+        .position(|arg| arg == "install-service")
+        .unwrap_or(command_line.len());
+    let mut session_args: Vec<String> = command_line[..end]
+        .iter()
+        .filter(|arg| *arg != "--headless")
+        .cloned()
+        .collect();
+    session_args.insert(0, "--headless".to_string());
+
+    if args.config.is_none() {
+        let config = default_config_path();
+        if config.exists() {
+            session_args.splice(0..0, ["--config".to_string(), config.to_string_lossy().into_owned()]);
+        }
+    }
+    session_args
+}
+
+fn write_unit(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use base64::{Engine as _, engine::general_purpose};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info};
+
+/// Query parameter carrying the code of an invite link
+pub const INVITE_PARAM: &str = "invite";
+
+/// Marks a request that redeemed an invite, which `require_token` then lets in like one with the token
+#[derive(Clone, Copy, Debug)]
+pub struct Invited;
+
+/// An invite link minted for one viewer, as returned by `POST /api/invites`
+#[derive(Serialize)]
+pub struct Invite {
+    pub url: String,
+    /// RFC 3339 time the link stops working at if it isn't used before
+    pub expires_at: String,
+}
+
+/// Single-use invite links to the main session, with `--invites`. Each link lets in the first viewer
+/// that opens it, by giving its browser the session's token cookie, and stops working after that or
+/// once its lifetime is over.
+pub struct Invites {
+    /// Session URL the invite codes are added to
+    base_url: String,
+    /// Lifetime of invites minted without one of their own
+    ttl: Duration,
+    /// Codes not used yet and when they expire
+    pending: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl Invites {
+    pub fn new(base_url: String, ttl: Duration) -> Self {
+        Self {
+            base_url,
+            ttl,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lifetime of invites minted without one of their own, from `--invite-ttl`
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// A new invite link, valid for `ttl` or the default lifetime
+    pub fn mint(&self, ttl: Option<Duration>) -> Invite {
+        let code = general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 24]>());
+        let now = SystemTime::now();
+        let expires_at = now + ttl.unwrap_or(self.ttl);
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, expires_at| *expires_at > now);
+        pending.insert(code.clone(), expires_at);
+
+        Invite {
+            url: format!("{}?{INVITE_PARAM}={code}", self.base_url),
+            expires_at: humantime::format_rfc3339_seconds(expires_at).to_string(),
+        }
+    }
+
+    /// Use up the invite `code`. Returns whether it was valid: minted here, unused and unexpired.
+    fn redeem(&self, code: &str) -> bool {
+        let expires_at = self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(code);
+        expires_at.is_some_and(|expires_at| SystemTime::now() < expires_at)
+    }
+}
+
+/// Invite code given in the query of `request`, if any
+fn query_invite(request: &Request) -> Option<String> {
+    let query = request.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == INVITE_PARAM)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Redeem the invite in the query of a request, marking the request as [`Invited`] if it was valid. Used or
+/// expired invites are ignored, so a viewer that got in can still reload the page with its cookie.
+pub async fn redeem_invite(State(invites): State<Option<Arc<Invites>>>, mut request: Request, next: Next) -> Response {
+    if let (Some(invites), Some(code)) = (invites, query_invite(&request)) {
+        if invites.redeem(&code) {
+            info!("An invite link was used for {}", request.uri().path());
+            request.extensions_mut().insert(Invited);
+        } else {
+            debug!("Ignored a used, expired or unknown invite for {}", request.uri().path());
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(invite: &Invite) -> String {
+        let (_, code) = invite.url.split_once(&format!("?{INVITE_PARAM}=")).unwrap();
+        code.to_string()
+    }
+
+    #[test]
+    fn invites_let_in_one_viewer() {
+        let invites = Invites::new("http://localhost:8000/s/abc/".to_string(), Duration::from_secs(600));
+        let invite = invites.mint(None);
+        assert!(invite.url.starts_with("http://localhost:8000/s/abc/?invite="));
+        let code = code(&invite);
+        assert!(invites.redeem(&code));
+        assert!(!invites.redeem(&code));
+    }
+
+    #[test]
+    fn expired_and_unknown_invites_are_refused() {
+        let invites = Invites::new("http://localhost:8000/s/abc/".to_string(), Duration::from_secs(600));
+        let expired = code(&invites.mint(Some(Duration::ZERO)));
+        assert!(!invites.redeem(&expired));
+
+        let valid = code(&invites.mint(None));
+        assert!(!invites.redeem(""));
+        assert!(!invites.redeem(&valid[1..]));
+        // Guessing doesn't use up the real invite
+        assert!(invites.redeem(&valid));
+    }
+
+    #[test]
+    fn invites_are_read_from_the_query() {
+        let request = Request::builder()
+            .uri("/s/abc/?theme=dark&invite=a-b_c")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(query_invite(&request).as_deref(), Some("a-b_c"));
+        let request = Request::builder()
+            .uri("/s/abc/")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(query_invite(&request), None);
+    }
+}
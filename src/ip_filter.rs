@@ -0,0 +1,179 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::debug;
+
+/// A network given with `--allow-cidr` or `--deny-cidr`, e.g. `10.0.0.0/8`, or a single address. Bits of
+/// the address past the prefix are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_bits(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_bits(network.into(), ip.into(), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` of the `width` bits of `a` and `b` are the same
+fn prefix_bits(a: u128, b: u128, width: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = u32::from(width - prefix_len);
+    a >> shift == b >> shift
+}
+
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Parse a network given as `ADDRESS/PREFIX`, or a single address. The prefix of an IPv4-mapped IPv6
+/// address counts IPv6 bits, e.g. `::ffff:10.0.0.0/104` is `10.0.0.0/8`.
+pub fn parse_cidr(value: &str) -> Result<Cidr, String> {
+    let (address, prefix_len) = match value.split_once('/') {
+        Some((address, prefix_len)) => (address, Some(prefix_len)),
+        None => (value, None),
+    };
+    let address = address
+        .parse::<IpAddr>()
+        .map_err(|_| format!("{address:?} is not an IP address"))?;
+    let network = canonical(address);
+    let (min, width) = match (address, network) {
+        (IpAddr::V6(_), IpAddr::V4(_)) => (96, 128),
+        (_, IpAddr::V4(_)) => (0, 32),
+        (_, IpAddr::V6(_)) => (0, 128),
+    };
+    let prefix_len = match prefix_len {
+        Some(prefix_len) => match prefix_len.parse::<u8>() {
+            Ok(prefix_len) if (min..=width).contains(&prefix_len) => prefix_len - min,
+            _ => return Err(format!("the prefix length must be a number from {min} to {width}")),
+        },
+        None => width - min,
+    };
+    Ok(Cidr { network, prefix_len })
+}
+
+/// Client addresses allowed to reach the server, from `--allow-cidr` and `--deny-cidr`
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// `None` when neither list is given, so every address is allowed
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Option<Self> {
+        (!allow.is_empty() || !deny.is_empty()).then_some(Self { allow, deny })
+    }
+
+    /// Denied networks win over allowed ones; with allowed networks, other addresses are denied
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Middleware answering `403 Forbidden` to clients whose address the filter doesn't allow, before any
+/// other check. Requests without a known client address are refused too.
+pub async fn filter_ip(State(filter): State<Arc<IpFilter>>, request: Request, next: Next) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    if ip.is_some_and(|ip| filter.allows(ip)) {
+        return next.run(request).await;
+    }
+    debug!("Refused a request for {} from {:?}", request.uri().path(), ip);
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ipv4_networks_and_addresses() {
+        let cidr = parse_cidr("10.1.2.3/8").unwrap();
+        assert!(cidr.contains(ip("10.200.0.1")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+
+        let single = parse_cidr("192.168.1.5").unwrap();
+        assert!(single.contains(ip("192.168.1.5")));
+        assert!(!single.contains(ip("192.168.1.6")));
+
+        assert!(parse_cidr("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn parses_ipv6_networks() {
+        let cidr = parse_cidr("2001:db8::/32").unwrap();
+        assert!(cidr.contains(ip("2001:db8:1::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+        assert!(!cidr.contains(ip("10.0.0.1")));
+
+        assert!(parse_cidr("::1").unwrap().contains(ip("::1")));
+        assert!(parse_cidr("fe80::/10").unwrap().contains(ip("fe80::1234")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_as_ipv4() {
+        // IPv4 clients of a dual-stack listener
+        let cidr = parse_cidr("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("::ffff:11.1.2.3")));
+
+        let mapped = parse_cidr("::ffff:10.0.0.0/104").unwrap();
+        assert_eq!(mapped, cidr);
+        assert!(mapped.contains(ip("10.9.9.9")));
+        assert_eq!(parse_cidr("::ffff:10.1.2.3").unwrap(), parse_cidr("10.1.2.3").unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_networks() {
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("2001:db8::/129").is_err());
+        assert!(parse_cidr("::ffff:10.0.0.0/8").is_err());
+        assert!(parse_cidr("10.0.0.0/").is_err());
+        assert!(parse_cidr("10.0.0.0/-1").is_err());
+        assert!(parse_cidr("example.com/24").is_err());
+        assert!(parse_cidr("").is_err());
+    }
+
+    #[test]
+    fn denied_networks_win_over_allowed_ones() {
+        let filter = IpFilter::new(
+            vec![parse_cidr("10.0.0.0/8").unwrap()],
+            vec![parse_cidr("10.0.0.0/24").unwrap()],
+        )
+        .unwrap();
+        assert!(filter.allows(ip("10.1.0.1")));
+        assert!(!filter.allows(ip("10.0.0.1")));
+        assert!(!filter.allows(ip("::ffff:10.0.0.1")));
+        assert!(!filter.allows(ip("192.168.0.1")));
+        assert!(IpFilter::new(Vec::new(), Vec::new()).is_none());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::identity::challenge_payload;
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use std::path::{Path, PathBuf};
+
+/// Outcome of checking a server identity against the known hosts file
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    /// The host is known and presented the pinned key
+    Trusted,
+    /// The host was not known before and its key has been recorded
+    Added,
+    /// The host is known but presented a different key
+    Mismatch { expected: String },
+}
+
+/// Default location of the client known hosts file
+pub fn default_known_hosts_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rwshell")
+        .join("known_hosts")
+}
+
+/// Verify that `signature` is a valid signature by `public_key` of the answer for `session` to the challenge
+/// `client_nonce`, with the base64 encoded `server_nonce`
+pub fn verify_identity(
+    public_key: &str,
+    session: &str,
+    server_nonce: &str,
+    client_nonce: &[u8],
+    signature: &str,
+) -> Result<()> {
+    let key_bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(public_key)
+        .context("Invalid public key encoding")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid public key length"))?;
+    let signature_bytes: [u8; 64] = general_purpose::STANDARD
+        .decode(signature)
+        .context("Invalid signature encoding")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid signature length"))?;
+
+    let server_nonce = general_purpose::STANDARD
+        .decode(server_nonce)
+        .context("Invalid server nonce encoding")?;
+
+    let verifying_key = VerifyingKey::from_bytes(&key_bytes).context("Invalid public key")?;
+    verifying_key
+        .verify(
+            &challenge_payload(session, &server_nonce, client_nonce),
+            &Signature::from_bytes(&signature_bytes),
+        )
+        .context("Server identity signature verification failed")
+}
+
+/// known_hosts-style store of pinned server keys, one `host ed25519 key` entry per line
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+impl KnownHosts {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Look up the pinned key for `host`
+    pub fn lookup(&self, host: &str) -> Result<Option<String>> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        };
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            if let (Some(entry_host), Some("ed25519"), Some(key)) = (fields.next(), fields.next(), fields.next()) {
+                if entry_host == host {
+                    return Ok(Some(key.to_string()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Check `public_key` for `host`, pinning it on first use
+    pub fn check(&self, host: &str, public_key: &str) -> Result<HostKeyStatus> {
+        match self.lookup(host)? {
+            Some(expected) if expected == public_key => Ok(HostKeyStatus::Trusted),
+            Some(expected) => Ok(HostKeyStatus::Mismatch { expected }),
+            None => {
+                self.add(host, public_key)?;
+                Ok(HostKeyStatus::Added)
+            }
+        }
+    }
+
+    fn add(&self, host: &str, public_key: &str) -> Result<()> {
+        use std::io::Write;
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        writeln!(file, "{host} ed25519 {public_key}")
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::ServerIdentity;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rwshell-test-{name}-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn identity() -> ServerIdentity {
+        let path = temp_path("identity");
+        let identity = ServerIdentity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        identity
+    }
+
+    #[test]
+    fn challenge_answers_verify_only_for_their_session_and_nonce() {
+        let identity = identity();
+        let (server_nonce, signature) = identity.sign_challenge("abc", b"client nonce");
+        let public_key = identity.public_key();
+
+        assert!(verify_identity(&public_key, "abc", &server_nonce, b"client nonce", &signature).is_ok());
+        // An answer relayed from another session or for another challenge doesn't pass
+        assert!(verify_identity(&public_key, "other", &server_nonce, b"client nonce", &signature).is_err());
+        assert!(verify_identity(&public_key, "", &server_nonce, b"client nonce", &signature).is_err());
+        assert!(verify_identity(&public_key, "abc", &server_nonce, b"other nonce", &signature).is_err());
+        let (other_nonce, _) = identity.sign_challenge("abc", b"client nonce");
+        assert!(verify_identity(&public_key, "abc", &other_nonce, b"client nonce", &signature).is_err());
+    }
+
+    #[test]
+    fn challenge_answers_verify_only_with_the_signing_key() {
+        let (identity, impostor) = (identity(), identity());
+        let (server_nonce, signature) = impostor.sign_challenge("abc", b"client nonce");
+        assert!(
+            verify_identity(
+                &identity.public_key(),
+                "abc",
+                &server_nonce,
+                b"client nonce",
+                &signature
+            )
+            .is_err()
+        );
+        assert!(
+            verify_identity(
+                &identity.public_key(),
+                "abc",
+                &server_nonce,
+                b"client nonce",
+                "not base64!"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn keys_are_pinned_on_first_use() {
+        let path = temp_path("known_hosts");
+        let known_hosts = KnownHosts::new(&path);
+        let (key, other_key) = (identity().public_key(), identity().public_key());
+
+        assert_eq!(known_hosts.check("host:8000", &key).unwrap(), HostKeyStatus::Added);
+        assert_eq!(known_hosts.check("host:8000", &key).unwrap(), HostKeyStatus::Trusted);
+        assert_eq!(
+            known_hosts.check("host:8000", &other_key).unwrap(),
+            HostKeyStatus::Mismatch { expected: key.clone() }
+        );
+        // A mismatch leaves the pinned key in place
+        assert_eq!(known_hosts.lookup("host:8000").unwrap(), Some(key));
+        assert_eq!(
+            known_hosts.check("other:8000", &other_key).unwrap(),
+            HostKeyStatus::Added
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
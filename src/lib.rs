@@ -1,7 +1,75 @@
+pub mod admin;
+pub mod api;
+pub mod approval;
 pub mod args;
 pub mod assets;
+pub mod auth;
+pub mod budget;
+pub mod capability;
+pub mod cat;
+pub mod client_args;
+pub mod compression;
+pub mod config;
+pub mod confirm;
+pub mod control;
+pub mod diff;
+pub mod doctor;
 pub mod error;
+pub mod export;
+pub mod fingerprint;
+pub mod follow;
+pub mod fork;
+pub mod generate;
+pub mod geoip;
+pub mod group;
+pub mod harden;
+pub mod history;
+pub mod host;
+pub mod htpasswd;
+pub mod identity;
+pub mod init;
+pub mod install;
+pub mod invite;
+pub mod ip_filter;
+pub mod known_hosts;
+pub mod limits;
+pub mod line_log;
+pub mod metrics;
+pub mod mirror;
+pub mod mosaic;
+pub mod multicast;
+pub mod mux;
+pub mod notes;
+pub mod notify;
+pub mod panes;
+pub mod plugin;
 pub mod pty;
+pub mod pty_helper;
+pub mod qr;
+pub mod rate_limit;
+pub mod recording;
+pub mod relay;
+pub mod render;
+pub mod schedule;
+pub mod screen;
+pub mod screen_reader;
+pub mod script;
+pub mod serve;
 pub mod server;
 pub mod session;
+pub mod session_env;
+pub mod session_id;
+pub mod showkeys;
+pub mod stats;
+pub mod stderr;
+pub mod subscription;
+pub mod supervisor;
+pub mod suspend;
+pub mod systemd;
+pub mod term;
+pub mod timeline;
+pub mod tls;
+pub mod transform;
+pub mod tunnel;
+pub mod version;
 pub mod websocket;
@@ -0,0 +1,127 @@
+use crate::server::{AppState, PtyEvent, broadcast_close, encode_message};
+use crate::websocket::{CloseReason, WarningMessage};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// How long before a limit ends the session its viewers are warned, at most; shorter limits are warned
+/// about halfway through
+const WARNING_LEAD: Duration = Duration::from_secs(60);
+
+/// How often the limits are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ends a session whose command saw no input or output for `--idle-timeout`, or ran for `--max-duration`
+pub struct SessionLimits {
+    idle_timeout: Option<Duration>,
+    max_duration: Option<Duration>,
+    last_activity: std::sync::Mutex<Instant>,
+}
+
+impl SessionLimits {
+    /// `None` without either limit
+    pub fn new(idle_timeout: Option<Duration>, max_duration: Option<Duration>) -> Option<Self> {
+        (idle_timeout.is_some() || max_duration.is_some()).then(|| Self {
+            idle_timeout,
+            max_duration,
+            last_activity: std::sync::Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Note input to or output from the command
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_activity.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
+
+    /// Enforce the limits from now on, as the command just started: warn viewers ahead of each, and end the
+    /// session through `shutdown` once one is reached
+    pub fn start(self: &Arc<Self>, state: &AppState, shutdown: CancellationToken) {
+        let limits = Arc::clone(self);
+        let state = state.clone();
+        state.supervisor.clone().spawn("limits", async move {
+            limits.touch();
+            let started = Instant::now();
+            let mut warned_idle = false;
+            let mut warned_max = false;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+                    _ = shutdown.cancelled() => return,
+                }
+
+                if let Some(max_duration) = limits.max_duration {
+                    let elapsed = started.elapsed();
+                    if elapsed >= max_duration {
+                        let message = format!("The session reached its maximum duration of {}", describe(max_duration));
+                        end(&state, CloseReason::TimeLimit, &message, &shutdown);
+                        return;
+                    }
+                    if !warned_max && elapsed >= max_duration - warning_lead(max_duration) {
+                        warned_max = true;
+                        let left = describe(max_duration - elapsed);
+                        warn(
+                            &state,
+                            &format!("The session reaches its maximum duration and ends in {left}"),
+                        );
+                    }
+                }
+
+                if let Some(idle_timeout) = limits.idle_timeout {
+                    let idle_for = limits.idle_for();
+                    if idle_for >= idle_timeout {
+                        let message = format!("The session was idle for {}", describe(idle_timeout));
+                        end(&state, CloseReason::IdleTimeout, &message, &shutdown);
+                        return;
+                    }
+                    if idle_for < idle_timeout - warning_lead(idle_timeout) {
+                        warned_idle = false;
+                    } else if !warned_idle {
+                        warned_idle = true;
+                        let left = describe(idle_timeout - idle_for);
+                        warn(
+                            &state,
+                            &format!("The session is idle and ends in {left} unless something happens"),
+                        );
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn warning_lead(limit: Duration) -> Duration {
+    WARNING_LEAD.min(limit / 2)
+}
+
+/// `duration` rounded to whole seconds, e.g. "1m 30s"
+fn describe(duration: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(duration.as_secs_f64().round() as u64)).to_string()
+}
+
+/// Send a `Warning` to every client of the session
+fn warn(state: &AppState, message: &str) {
+    info!("{}", message);
+    match encode_message(
+        "Warning",
+        &WarningMessage {
+            message: message.to_string(),
+        },
+    ) {
+        Ok(frame) => {
+            let _ = state.pty_tx.send(PtyEvent::Message(frame));
+        }
+        Err(e) => error!("Failed to encode warning: {}", e),
+    }
+}
+
+/// Close every client for `reason` and end the session, which terminates the command
+fn end(state: &AppState, reason: CloseReason, message: &str, shutdown: &CancellationToken) {
+    info!("{}, ending it", message);
+    broadcast_close(&state.pty_tx, reason, message);
+    shutdown.cancel();
+}
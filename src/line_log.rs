@@ -0,0 +1,179 @@
+use crate::budget::{Account, BufferKind, Evict, MemoryBudget};
+use crate::transform::{OutputStage, StripAnsi};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use tokio::time::Instant;
+
+/// Most lines kept for `/api/sessions/{id}/lines`, the oldest dropped first
+const MAX_LINES: usize = 10_000;
+
+/// Most text kept over all lines, in bytes
+const MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Longest line kept, in bytes; the rest of a longer one is dropped
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+/// A line of output, numbered in the order lines were completed, from 1
+#[derive(Debug, Clone, Serialize)]
+pub struct Line {
+    pub seq: u64,
+    pub text: String,
+}
+
+/// Lines after the `since` of a request to `/api/sessions/{id}/lines`
+#[derive(Debug, Serialize)]
+pub struct LinesPage {
+    pub lines: Vec<Line>,
+    /// Number of the last line returned, or `since` without any: the `since` of the next request
+    pub last: u64,
+    /// Lines after `since` that were dropped before they were asked for
+    pub missed: u64,
+}
+
+/// The session's output as plain text lines, for pollers that follow it like a log without emulating a
+/// terminal. Output goes through the same escape sequence removal as `--strip-ansi`; a carriage return
+/// followed by more text starts the line over, as progress bars redraw it, and a line is complete at its
+/// line feed.
+pub struct LineLog {
+    assembler: Mutex<LineAssembler>,
+    /// Budget the lines count against, with `--memory-budget`
+    budget: Option<Arc<MemoryBudget>>,
+}
+
+struct LineAssembler {
+    strip: StripAnsi,
+    /// The line being written
+    current: Vec<u8>,
+    /// A carriage return came last, so more text replaces the line
+    carriage_return: bool,
+    lines: VecDeque<Line>,
+    bytes: usize,
+    /// Number of the last completed line
+    last: u64,
+    /// Each line as a piece of data, if the lines count against a budget
+    account: Option<Account>,
+}
+
+impl LineLog {
+    /// An empty log, whose lines count against `budget` if given
+    pub fn new(budget: Option<&Arc<MemoryBudget>>) -> Arc<Self> {
+        let log = Arc::new(Self {
+            assembler: Mutex::new(LineAssembler {
+                strip: StripAnsi::new(),
+                current: Vec::new(),
+                carriage_return: false,
+                lines: VecDeque::new(),
+                bytes: 0,
+                last: 0,
+                account: budget.map(|budget| Account::new(Arc::clone(budget), BufferKind::Lines)),
+            }),
+            budget: budget.cloned(),
+        });
+        if let Some(budget) = budget {
+            budget.register(Arc::downgrade(&log) as Weak<dyn Evict>);
+        }
+        log
+    }
+
+    fn assembler(&self) -> MutexGuard<'_, LineAssembler> {
+        self.assembler.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Add output of the session, as sent to viewers
+    pub fn feed(&self, data: &[u8]) {
+        {
+            let mut log = self.assembler();
+            let mut text = Vec::with_capacity(data.len());
+            log.strip.process(data, &mut text);
+            for byte in text {
+                log.push_byte(byte);
+            }
+        }
+        if let Some(budget) = &self.budget {
+            budget.enforce();
+        }
+    }
+
+    /// Up to `limit` lines numbered after `since`, oldest first
+    pub fn since(&self, since: u64, limit: usize) -> LinesPage {
+        let log = self.assembler();
+        let first = log.lines.front().map_or(log.last + 1, |line| line.seq);
+        let lines: Vec<Line> = log
+            .lines
+            .iter()
+            .skip_while(|line| line.seq <= since)
+            .take(limit)
+            .cloned()
+            .collect();
+        LinesPage {
+            last: lines.last().map_or(since, |line| line.seq),
+            missed: first.saturating_sub(since + 1),
+            lines,
+        }
+    }
+}
+
+impl LineAssembler {
+    fn push_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.complete_line(),
+            b'\r' => self.carriage_return = true,
+            0x08 => {
+                // Back over a whole character, continuation bytes included
+                while let Some(byte) = self.current.pop() {
+                    if byte & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                if std::mem::take(&mut self.carriage_return) {
+                    self.current.clear();
+                }
+                if self.current.len() < MAX_LINE_LEN {
+                    self.current.push(byte);
+                }
+            }
+        }
+    }
+
+    fn complete_line(&mut self) {
+        self.carriage_return = false;
+        let text = String::from_utf8_lossy(&self.current).trim_end().to_string();
+        self.current.clear();
+        self.last += 1;
+        self.bytes += text.len();
+        if let Some(account) = &mut self.account {
+            account.add(line_size(&text));
+        }
+        self.lines.push_back(Line { seq: self.last, text });
+        while self.lines.len() > MAX_LINES || self.bytes > MAX_BYTES {
+            let Some(line) = self.lines.pop_front() else { break };
+            self.bytes -= line.text.len();
+            if let Some(account) = &mut self.account {
+                account.release(line_size(&line.text));
+            }
+        }
+    }
+}
+
+impl Evict for LineLog {
+    fn oldest(&self) -> Option<Instant> {
+        self.assembler().account.as_ref()?.oldest()
+    }
+
+    fn evict_oldest(&self) -> usize {
+        let mut log = self.assembler();
+        let Some(line) = log.lines.pop_front() else {
+            return 0;
+        };
+        log.bytes -= line.text.len();
+        log.account.as_mut().map_or(0, Account::evict_oldest)
+    }
+}
+
+/// Memory a line takes up against the budget, which counts even empty lines as a piece of their own
+fn line_size(text: &str) -> usize {
+    std::mem::size_of::<Line>() + text.len()
+}
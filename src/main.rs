@@ -1,20 +1,18 @@
 use anyhow::Result;
-use clap::Parser;
-use tracing::debug;
+use std::sync::Arc;
+use tracing::{debug, warn};
 
-mod args;
-mod assets;
-mod server;
-
-use args::Args;
-use server::RwShellServer;
+use rwshell::args::{CtlCommand, GroupCommand, Subcommand};
+use rwshell::confirm::{Answer, answer_text};
+use rwshell::install::{InstallOptions, install_service};
+use rwshell::server::RwShellServer;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Parse command line arguments
-    let args = Args::parse();
+    // Parse command line arguments, with defaults from the config file
+    let args = rwshell::config::parse_args()?;
 
     // Initialize logging
     let log_level = if args.verbose { "debug" } else { "info" };
@@ -29,6 +27,192 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    // Take over a headless session from this terminal
+    if let Some(Subcommand::Attach { session }) = &args.subcommand {
+        if let Err(e) = rwshell::control::attach(session).await {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+        // Don't wait for the blocked stdin reader before exiting
+        std::process::exit(0);
+    }
+
+    // Answer a viewer command waiting for confirmation
+    if let Some(Subcommand::Confirm { session, refuse, trust }) = &args.subcommand {
+        let answer = match (refuse, trust) {
+            (true, _) => Answer::Refuse,
+            (false, true) => Answer::Trust,
+            (false, false) => Answer::Allow,
+        };
+        match rwshell::control::confirm(session, answer).await {
+            Ok(Some(line)) => println!("{} `{}`", answer_text(answer), line),
+            Ok(None) => {
+                eprintln!("No command is waiting for confirmation in session {session}");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Answer a viewer asking for write access
+    if let Some(Subcommand::Approve { session, deny }) = &args.subcommand {
+        match rwshell::control::approve(session, !deny).await {
+            Ok(Some(client_id)) => println!("{}", rwshell::approval::answer_text(!deny, &client_id)),
+            Ok(None) => {
+                eprintln!("No viewer is asking for write access in session {session}");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Add to the timeline of a session
+    if let Some(Subcommand::Ctl {
+        command: CtlCommand::Note { text, session },
+    }) = &args.subcommand
+    {
+        match rwshell::control::note(session, &text.join(" ")).await {
+            Ok(entry) => println!("{} {}", entry.time, entry.text),
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Panes of a session started with --compare
+    if let Some(Subcommand::Panes {
+        backends,
+        sync_input,
+        key,
+        command,
+    }) = &args.subcommand
+    {
+        if let Err(e) = rwshell::panes::run(backends, command, *sync_input, *key).await {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+        // Don't wait for the blocked stdin reader before exiting
+        std::process::exit(0);
+    }
+
+    // Follow another session's output
+    if let Some(Subcommand::Cat { url, plain }) = &args.subcommand {
+        if let Err(e) = rwshell::cat::run(url, *plain).await {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Serve a group of sessions
+    if let Some(Subcommand::Group {
+        command: GroupCommand::Create { name },
+    }) = &args.subcommand
+    {
+        return rwshell::group::create(&args, name).await;
+    }
+
+    // Republish mirrored sessions
+    if let Some(Subcommand::Relay) = &args.subcommand {
+        return rwshell::relay::run(&args).await;
+    }
+
+    // Run commands for an unprivileged server
+    if let Some(Subcommand::PtyHelper { socket, socket_owner }) = &args.subcommand {
+        return rwshell::pty_helper::run(&args, socket, socket_owner.as_deref()).await;
+    }
+
+    // Recordings
+    if let Some(Subcommand::Export {
+        cast,
+        html,
+        notes,
+        timeline,
+    }) = &args.subcommand
+    {
+        rwshell::export::html(cast, html, notes.as_deref(), timeline.as_deref())?;
+        return Ok(());
+    }
+    if let Some(Subcommand::Diff { a, b, context, ignore }) = &args.subcommand {
+        // Exit with 1 when the transcripts differ, like diff
+        if rwshell::diff::run(a, b, *context, ignore)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(Subcommand::Render {
+        cast,
+        gif,
+        font,
+        font_size,
+    }) = &args.subcommand
+    {
+        rwshell::render::gif(cast, gif, font.as_deref(), *font_size)?;
+        return Ok(());
+    }
+
+    // Pre-flight checks
+    if let Some(Subcommand::Doctor) = &args.subcommand {
+        if !rwshell::doctor::run(&args).await {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Interactive setup
+    if let Some(Subcommand::Init) = &args.subcommand {
+        return rwshell::init::run(&args);
+    }
+
+    // Packaging artifacts
+    if let Some(Subcommand::Completions { shell, client }) = &args.subcommand {
+        rwshell::generate::completions(*shell, *client)?;
+        return Ok(());
+    }
+    if let Some(Subcommand::Manpage { client }) = &args.subcommand {
+        rwshell::generate::manpage(*client)?;
+        return Ok(());
+    }
+
+    if let Some(Subcommand::InstallService {
+        name,
+        system,
+        socket,
+        print,
+    }) = &args.subcommand
+    {
+        let options = InstallOptions {
+            name,
+            system: *system,
+            socket: *socket,
+            print: *print,
+        };
+        return install_service(&args, &options);
+    }
+
+    // Sharing a terminal from inside a session sends the inner session's output back through the outer one
+    if let Some(session) = rwshell::session_env::enclosing_session().filter(|_| !args.allow_nested) {
+        if !args.headless {
+            eprintln!(
+                "Error: this shell already runs in rwshell session {session}; exit it first, or start a nested \
+                 session with --allow-nested"
+            );
+            std::process::exit(1);
+        }
+        // Without a terminal of its own, a session started from inside another doesn't feed back into it
+        warn!("Starting a session inside rwshell session {}", session);
+    }
+
     // Check if stdin is a terminal (unless running headless)
     if !args.headless && !atty::is(atty::Stream::Stdin) {
         eprintln!("Input not a tty");
@@ -38,7 +222,7 @@ async fn main() -> Result<()> {
     // Server mode - start a new sharing session
     debug!("Starting rwshell server");
 
-    let server = RwShellServer::new(args).await?;
+    let server = Arc::new(RwShellServer::new(args).await?);
     server.run().await?;
 
     println!("rwshell finished");
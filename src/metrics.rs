@@ -0,0 +1,183 @@
+use crate::budget::{BufferKind, MemoryBudget};
+use crate::server::AppState;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Content type of the Prometheus text format served at `/metrics`
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Liveness of a session's command, for alerting rules on `/metrics`
+pub struct CommandMetrics {
+    running: AtomicBool,
+    /// Times the command was started, the first one included
+    starts: AtomicU64,
+    last_exit_code: Mutex<Option<u32>>,
+    /// When the command last wrote output, or when the session started before it did
+    last_output: Mutex<Instant>,
+}
+
+impl Default for CommandMetrics {
+    fn default() -> Self {
+        Self {
+            running: AtomicBool::new(false),
+            starts: AtomicU64::new(0),
+            last_exit_code: Mutex::new(None),
+            last_output: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl CommandMetrics {
+    /// Note that the command started, or started again after `--suspend-mode restart`
+    pub fn started(&self) {
+        self.starts.fetch_add(1, Ordering::Relaxed);
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    /// Note that the command was ended while nobody watched, with `--suspend-mode restart`
+    pub fn stopped(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Note that the command exited with `exit_code`
+    pub fn exited(&self, exit_code: u32) {
+        self.stopped();
+        *self.last_exit_code.lock().unwrap_or_else(|e| e.into_inner()) = Some(exit_code);
+    }
+
+    /// Note that the command wrote output
+    pub fn output(&self) {
+        *self.last_output.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+}
+
+/// Exit codes of the commands of every session, counted over the life of the server, so those of
+/// sessions that ended are still reported
+#[derive(Default)]
+pub struct ExitCounts(Mutex<BTreeMap<u32, u64>>);
+
+impl ExitCounts {
+    pub fn record(&self, exit_code: u32) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(exit_code)
+            .or_default() += 1;
+    }
+}
+
+/// The metrics of `sessions`, the exit codes counted in `exits` and the use of the memory budget if there
+/// is one, in the Prometheus text format
+pub fn render(sessions: &[(String, AppState)], exits: &ExitCounts, budget: Option<&MemoryBudget>) -> String {
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&AppState) -> Option<String>| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (id, state) in sessions {
+            if let Some(value) = value(state) {
+                let _ = writeln!(out, "{name}{{session=\"{}\"}} {value}", escape_label(id));
+            }
+        }
+    };
+
+    family(
+        "rwshell_command_up",
+        "gauge",
+        "Whether the command of the session is running",
+        &|state| Some(u8::from(state.metrics.running.load(Ordering::Relaxed)).to_string()),
+    );
+    family(
+        "rwshell_command_suspended",
+        "gauge",
+        "Whether the command is suspended with --suspend-after while nobody watches",
+        &|state| {
+            let suspended = state.suspender.as_ref().is_some_and(|suspender| suspender.suspended());
+            Some(u8::from(suspended).to_string())
+        },
+    );
+    family(
+        "rwshell_command_restarts_total",
+        "counter",
+        "Times the command was started again after it was ended",
+        &|state| {
+            Some(
+                state
+                    .metrics
+                    .starts
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(1)
+                    .to_string(),
+            )
+        },
+    );
+    family(
+        "rwshell_command_last_exit_code",
+        "gauge",
+        "Exit code of the command when it last exited",
+        &|state| {
+            let exit_code = *state.metrics.last_exit_code.lock().unwrap_or_else(|e| e.into_inner());
+            exit_code.map(|exit_code| exit_code.to_string())
+        },
+    );
+    family(
+        "rwshell_output_idle_seconds",
+        "gauge",
+        "Seconds since the command last wrote output, or since the session started if it hasn't",
+        &|state| {
+            let last_output = *state.metrics.last_output.lock().unwrap_or_else(|e| e.into_inner());
+            Some(format!("{:.3}", last_output.elapsed().as_secs_f64()))
+        },
+    );
+
+    let name = "rwshell_command_exits_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Commands of all sessions that exited, by exit code\n# TYPE {name} counter"
+    );
+    for (exit_code, count) in exits.0.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        let _ = writeln!(out, "{name}{{code=\"{exit_code}\"}} {count}");
+    }
+
+    if let Some(budget) = budget {
+        let _ = writeln!(
+            out,
+            "# HELP rwshell_memory_budget_bytes Memory the buffered output of all sessions may use\n\
+             # TYPE rwshell_memory_budget_bytes gauge\nrwshell_memory_budget_bytes {}",
+            budget.limit()
+        );
+        let _ = writeln!(
+            out,
+            "# HELP rwshell_memory_used_bytes Memory the buffered output of all sessions uses\n\
+             # TYPE rwshell_memory_used_bytes gauge\nrwshell_memory_used_bytes {}",
+            budget.used()
+        );
+        let evictions = BufferKind::ALL.map(|kind| (kind.label(), budget.evictions(kind)));
+        let name = "rwshell_memory_evictions_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Pieces of buffered output dropped to stay within the memory budget\n\
+             # TYPE {name} counter"
+        );
+        for (buffer, (count, _)) in evictions {
+            let _ = writeln!(out, "{name}{{buffer=\"{buffer}\"}} {count}");
+        }
+        let name = "rwshell_memory_evicted_bytes_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Bytes of buffered output dropped to stay within the memory budget\n\
+             # TYPE {name} counter"
+        );
+        for (buffer, (_, bytes)) in evictions {
+            let _ = writeln!(out, "{name}{{buffer=\"{buffer}\"}} {bytes}");
+        }
+    }
+    out
+}
+
+/// `value` as a label value, with backslashes, quotes and line feeds escaped
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
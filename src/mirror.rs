@@ -0,0 +1,103 @@
+use crate::server::{AppState, PtyEvent, resync_frames};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use url::Url;
+
+/// Delay before the first reconnection attempt, doubled after every failure
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How a connection to the relay ended
+enum MirrorEnd {
+    /// The session ended and the relay was told so
+    SessionClosed,
+    /// The connection failed and should be retried
+    Disconnected,
+}
+
+/// Push the session's output to the relay at `url`, reconnecting until `shutdown` is cancelled.
+///
+/// The relay receives what a read-only viewer would, and nothing it sends back reaches the session.
+pub fn start(state: &AppState, url: Url, shutdown: CancellationToken) {
+    let state_mirror = state.clone();
+    state.supervisor.spawn("mirror", async move {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            let end = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                end = mirror(&state_mirror, &url) => end,
+            };
+            match end {
+                Ok(MirrorEnd::SessionClosed) => break,
+                Ok(MirrorEnd::Disconnected) => delay = MIN_RECONNECT_DELAY,
+                // The URL carries the relay's token, so only its host is logged
+                Err(e) => warn!(
+                    "Failed to mirror the session to {}: {}",
+                    url.host_str().unwrap_or("relay"),
+                    e
+                ),
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+/// Mirror the session over one connection to the relay
+async fn mirror(state: &AppState, url: &Url) -> anyhow::Result<MirrorEnd> {
+    let (ws_stream, _) = connect_async(url.as_str()).await?;
+    let (mut sender, mut receiver) = ws_stream.split();
+    info!("Mirroring the session to {}", url.host_str().unwrap_or("relay"));
+
+    // Subscribe before taking the snapshot so no output falls in between
+    let mut pty_rx = state.pty_tx.subscribe();
+    for frame in resync_frames(state).await? {
+        sender.send(Message::Text(frame.as_str().to_owned())).await?;
+    }
+
+    loop {
+        let event = tokio::select! {
+            event = pty_rx.recv() => event,
+            // The relay sends nothing but control frames; notice when it goes away
+            message = receiver.next() => match message {
+                Some(Ok(Message::Close(_))) | None => {
+                    warn!("The relay closed the mirror connection");
+                    return Ok(MirrorEnd::Disconnected);
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            },
+        };
+
+        let (frame, close) = match event {
+            Ok(PtyEvent::Output { frame, .. }) | Ok(PtyEvent::Message(frame)) => (frame, false),
+            Ok(PtyEvent::ScreenText { .. }) => continue,
+            Ok(PtyEvent::Close { frame, .. }) => (frame, true),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                debug!("Mirror missed {} messages, resynchronizing", missed);
+                for frame in resync_frames(state).await? {
+                    sender.send(Message::Text(frame.as_str().to_owned())).await?;
+                }
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(MirrorEnd::SessionClosed),
+        };
+
+        sender.send(Message::Text(frame.as_str().to_owned())).await?;
+        if close {
+            let _ = sender.send(Message::Close(None)).await;
+            return Ok(MirrorEnd::SessionClosed);
+        }
+    }
+}
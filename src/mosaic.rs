@@ -0,0 +1,227 @@
+use std::io::Write;
+
+/// Size a session's screen starts with until the server reports its real size
+const DEFAULT_SESSION_SIZE: (u16, u16) = (80, 24);
+
+/// A session shown in one tile of the mosaic
+struct Tile {
+    label: String,
+    parser: vt100::Parser,
+    /// Why the session ended, once it has
+    closed: Option<String>,
+}
+
+/// Position and size of a tile on the terminal, in cells; the first row holds the label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rect {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+/// Several sessions tiled in one terminal, each following its own screen.
+///
+/// Sessions keep their own size; a tile smaller than its session shows the left columns and the rows
+/// around the cursor.
+pub struct Mosaic {
+    tiles: Vec<Tile>,
+    /// Size of the terminal the mosaic is drawn on, as (cols, rows)
+    size: (u16, u16),
+    /// Whether the terminal must be cleared before the next frame, as tiles moved
+    clear: bool,
+    /// Tile whose cursor is shown, e.g. the one receiving input; the cursor is left hidden if unset
+    cursor: Option<usize>,
+}
+
+impl Mosaic {
+    pub fn new(labels: Vec<String>, size: (u16, u16)) -> Self {
+        let (cols, rows) = DEFAULT_SESSION_SIZE;
+        let tiles = labels
+            .into_iter()
+            .map(|label| Tile {
+                label,
+                parser: vt100::Parser::new(rows, cols, 0),
+                closed: None,
+            })
+            .collect();
+        Self {
+            tiles,
+            size,
+            clear: true,
+            cursor: None,
+        }
+    }
+
+    /// Change the label of session `index`
+    pub fn set_label(&mut self, index: usize, label: String) {
+        if let Some(tile) = self.tiles.get_mut(index) {
+            tile.label = label;
+        }
+    }
+
+    /// Show the cursor of session `index` at its place in the tile, or no cursor
+    pub fn show_cursor(&mut self, index: Option<usize>) {
+        self.cursor = index;
+    }
+
+    /// Size of each tile below its label, as (cols, rows); a session of that size fits its tile exactly
+    pub fn tile_sizes(&self) -> Vec<(u16, u16)> {
+        layout(self.tiles.len(), self.size)
+            .iter()
+            .map(|rect| (rect.width, rect.height.saturating_sub(1)))
+            .collect()
+    }
+
+    /// Follow a change of the terminal the mosaic is drawn on
+    pub fn resize(&mut self, size: (u16, u16)) {
+        self.size = size;
+        self.clear = true;
+    }
+
+    /// Follow a change of the terminal size of session `index`
+    pub fn resize_session(&mut self, index: usize, cols: u16, rows: u16) {
+        if let Some(tile) = self.tiles.get_mut(index) {
+            tile.parser.screen_mut().set_size(rows, cols);
+        }
+    }
+
+    /// Apply output of session `index`
+    pub fn output(&mut self, index: usize, data: &[u8]) {
+        if let Some(tile) = self.tiles.get_mut(index) {
+            tile.parser.process(data);
+        }
+    }
+
+    /// Mark session `index` as ended for `reason`
+    pub fn close(&mut self, index: usize, reason: &str) {
+        if let Some(tile) = self.tiles.get_mut(index) {
+            tile.closed = Some(reason.to_string());
+        }
+    }
+
+    /// Whether every session has ended
+    pub fn all_closed(&self) -> bool {
+        self.tiles.iter().all(|tile| tile.closed.is_some())
+    }
+
+    /// Draw every tile, as one synchronized update of the whole terminal
+    pub fn render(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Begin a synchronized update so the terminal doesn't show half-drawn frames
+        out.extend_from_slice(b"\x1b[?2026h\x1b[m");
+        if std::mem::take(&mut self.clear) {
+            out.extend_from_slice(b"\x1b[2J");
+        }
+
+        let rects = layout(self.tiles.len(), self.size);
+        let mut cursor = None;
+        for (index, (tile, rect)) in self.tiles.iter().zip(&rects).enumerate() {
+            let position = render_tile(&mut out, tile, *rect);
+            if self.cursor == Some(index) {
+                cursor = position;
+            }
+        }
+
+        // Separate tiles that share a row with a vertical line
+        for rect in &rects {
+            if rect.x == 0 {
+                continue;
+            }
+            for row in rect.y..rect.y + rect.height {
+                let _ = write!(out, "\x1b[{};{}H\u{2502}", row + 1, rect.x);
+            }
+        }
+
+        match cursor {
+            Some((row, col)) => {
+                let _ = write!(out, "\x1b[{};{}H\x1b[?25h", row + 1, col + 1);
+            }
+            None if self.cursor.is_some() => out.extend_from_slice(b"\x1b[?25l"),
+            None => {}
+        }
+
+        out.extend_from_slice(b"\x1b[?2026l");
+        out
+    }
+}
+
+/// Draw `tile` into `rect`, returning where its cursor is on the terminal if it is visible in the tile
+fn render_tile(out: &mut Vec<u8>, tile: &Tile, rect: Rect) -> Option<(u16, u16)> {
+    if rect.width == 0 || rect.height == 0 {
+        return None;
+    }
+
+    let label = match &tile.closed {
+        Some(reason) => format!(" {} ({})", tile.label, reason),
+        None => format!(" {}", tile.label),
+    };
+    let label: String = label.chars().take(rect.width as usize).collect();
+    let padding = (rect.width as usize).saturating_sub(label.chars().count());
+    let _ = write!(
+        out,
+        "\x1b[{};{}H\x1b[7m{}{}\x1b[m",
+        rect.y + 1,
+        rect.x + 1,
+        label,
+        " ".repeat(padding)
+    );
+
+    let screen = tile.parser.screen();
+    let (rows, cols) = screen.size();
+    let body = rect.height - 1;
+    // Keep the cursor in view when the session has more rows than the tile
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    let first = (cursor_row + 1).min(rows).saturating_sub(body);
+
+    let mut lines = screen.rows_formatted(0, cols.min(rect.width)).skip(first as usize);
+    for line in 0..body {
+        // Erase the old contents of the line without moving the cursor, then draw the new ones
+        let _ = write!(out, "\x1b[{};{}H\x1b[{}X", rect.y + 2 + line, rect.x + 1, rect.width);
+        if let Some(row) = lines.next() {
+            out.extend_from_slice(&row);
+            out.extend_from_slice(b"\x1b[m");
+        }
+    }
+
+    let visible = tile.closed.is_none() && !screen.hide_cursor() && cursor_row >= first && cursor_col < rect.width;
+    visible.then(|| (rect.y + 1 + cursor_row - first, rect.x + cursor_col))
+}
+
+/// Split a terminal of `size` into `count` tiles, in rows of roughly equal tiles with a column between
+/// neighbours
+fn layout(count: usize, (cols, rows): (u16, u16)) -> Vec<Rect> {
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let grid_cols = (count as f64).sqrt().ceil() as u16;
+    let grid_rows = count.div_ceil(grid_cols as usize) as u16;
+    let height = rows / grid_rows;
+
+    let mut rects = Vec::with_capacity(count);
+    for index in 0..count as u16 {
+        let (grid_row, grid_col) = (index / grid_cols, index % grid_cols);
+        // The last row may have fewer tiles, which share its width
+        let in_row = (count as u16 - grid_row * grid_cols).min(grid_cols);
+        let width = cols.saturating_sub(in_row - 1) / in_row;
+        let x = grid_col * (width + 1);
+        let y = grid_row * height;
+        rects.push(Rect {
+            x,
+            y,
+            // The last tile of a row and the last row take what rounding left over
+            width: if grid_col + 1 == in_row {
+                cols.saturating_sub(x)
+            } else {
+                width
+            },
+            height: if grid_row + 1 == grid_rows {
+                rows.saturating_sub(y)
+            } else {
+                height
+            },
+        });
+    }
+    rects
+}
@@ -0,0 +1,261 @@
+use crate::server::{AppState, PtyEvent};
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Marks rwshell multicast packets
+const MAGIC: &[u8; 4] = b"RWMC";
+
+/// Version of the packet format
+const VERSION: u8 = 1;
+
+/// Largest payload per packet, small enough to avoid IP fragmentation on common LANs
+const MAX_PAYLOAD: usize = 1200;
+
+/// How often the whole screen is sent, for receivers that joined late or lost packets
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Kind of a multicast packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Kind {
+    /// A piece of output; `seq` numbers output packets in order
+    Output = 0,
+    /// One of `count` pieces of a screen snapshot reflecting output up to `seq`
+    Keyframe = 1,
+}
+
+/// Header of every packet: magic, version, kind, stream ID, sequence number, then for keyframes the
+/// keyframe ID, piece index and piece count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    kind: Kind,
+    /// Random per session, so a restarted session isn't mixed up with the previous one
+    stream: u32,
+    seq: u32,
+    keyframe: u32,
+    index: u16,
+    count: u16,
+}
+
+const HEADER_LEN: usize = 4 + 1 + 1 + 4 + 4 + 4 + 2 + 2;
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        packet.extend_from_slice(MAGIC);
+        packet.push(VERSION);
+        packet.push(self.kind as u8);
+        packet.extend_from_slice(&self.stream.to_be_bytes());
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&self.keyframe.to_be_bytes());
+        packet.extend_from_slice(&self.index.to_be_bytes());
+        packet.extend_from_slice(&self.count.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LEN || &packet[..4] != MAGIC || packet[4] != VERSION {
+            return None;
+        }
+        let kind = match packet[5] {
+            0 => Kind::Output,
+            1 => Kind::Keyframe,
+            _ => return None,
+        };
+        let u32_at = |at: usize| u32::from_be_bytes(packet[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_be_bytes(packet[at..at + 2].try_into().unwrap());
+        let header = Header {
+            kind,
+            stream: u32_at(6),
+            seq: u32_at(10),
+            keyframe: u32_at(14),
+            index: u16_at(18),
+            count: u16_at(20),
+        };
+        Some((header, &packet[HEADER_LEN..]))
+    }
+}
+
+/// Parse a multicast group address such as "239.255.42.42:4242"
+pub fn parse_group(value: &str) -> Result<SocketAddrV4, String> {
+    let addr: SocketAddrV4 = value
+        .parse()
+        .map_err(|_| format!("expected an IPv4 multicast address and port: {value}"))?;
+    if !addr.ip().is_multicast() {
+        return Err(format!("not a multicast address: {}", addr.ip()));
+    }
+    Ok(addr)
+}
+
+/// Broadcast the session's output to the multicast `group` on the local network until `shutdown`
+/// is cancelled. Packets don't leave the LAN, and receivers only ever read.
+pub fn start(state: &AppState, group: SocketAddrV4, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).context("Failed to open a UDP socket")?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+
+    let mut pty_rx = state.pty_tx.subscribe();
+    let current_size = state.current_size.clone();
+    state.supervisor.spawn("multicast", async move {
+        info!("Broadcasting output to multicast group {}", group);
+        let target = SocketAddr::V4(group);
+        let stream = rand::random::<u32>();
+        let mut seq = 0u32;
+        let mut keyframe = 0u32;
+        let (cols, rows) = *current_size.lock().await;
+        let mut screen = vt100::Parser::new(rows, cols, 0);
+        let mut interval = tokio::time::interval(KEYFRAME_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {
+                    let (cols, rows) = *current_size.lock().await;
+                    if screen.screen().size() != (rows, cols) {
+                        screen.screen_mut().set_size(rows, cols);
+                    }
+                    keyframe = keyframe.wrapping_add(1);
+                    let mut snapshot = b"\x1bc".to_vec();
+                    snapshot.extend_from_slice(&screen.screen().state_formatted());
+
+                    let pieces: Vec<&[u8]> = snapshot.chunks(MAX_PAYLOAD).collect();
+                    for (index, piece) in pieces.iter().enumerate() {
+                        let header = Header {
+                            kind: Kind::Keyframe,
+                            stream,
+                            seq,
+                            keyframe,
+                            index: index as u16,
+                            count: pieces.len() as u16,
+                        };
+                        send(&socket, target, &header.encode(piece)).await;
+                    }
+                }
+                event = pty_rx.recv() => match event {
+                    Ok(PtyEvent::Output { data, .. }) => {
+                        let (cols, rows) = *current_size.lock().await;
+                        if screen.screen().size() != (rows, cols) {
+                            screen.screen_mut().set_size(rows, cols);
+                        }
+                        screen.process(&data);
+                        for piece in data.chunks(MAX_PAYLOAD) {
+                            seq = seq.wrapping_add(1);
+                            let header = Header { kind: Kind::Output, stream, seq, keyframe: 0, index: 0, count: 1 };
+                            send(&socket, target, &header.encode(piece)).await;
+                        }
+                    }
+                    Ok(PtyEvent::Close { .. }) | Err(broadcast::error::RecvError::Closed) => break,
+                    Ok(_) => {}
+                    // Receivers notice the gap and wait for the next keyframe
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Multicast missed {} messages", missed);
+                        seq = seq.wrapping_add(1);
+                    }
+                },
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn send(socket: &UdpSocket, target: SocketAddr, packet: &[u8]) {
+    if let Err(e) = socket.send_to(packet, target).await {
+        debug!("Failed to send multicast packet: {}", e);
+    }
+}
+
+/// Pieces of a keyframe received so far
+struct PendingKeyframe {
+    id: u32,
+    seq: u32,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+/// Show a session broadcast to the multicast `group` on stdout until interrupted
+pub async fn receive(group: SocketAddrV4) -> anyhow::Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Let several receivers on one machine listen to the same group
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    eprintln!("Waiting for the session on {group}...");
+    let mut stdout = std::io::stdout();
+    let mut buffer = vec![0u8; 65536];
+    // Stream and sequence number of the last output shown, once a keyframe has been received
+    let mut synced: Option<(u32, u32)> = None;
+    let mut pending: Option<PendingKeyframe> = None;
+
+    loop {
+        let len = tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            received = socket.recv_from(&mut buffer) => received?.0,
+        };
+        let Some((header, payload)) = Header::decode(&buffer[..len]) else {
+            continue;
+        };
+
+        match header.kind {
+            Kind::Output => {
+                let Some((stream, seq)) = synced else {
+                    continue;
+                };
+                if header.stream != stream || header.seq.wrapping_sub(seq) as i32 <= 0 {
+                    continue;
+                }
+                if header.seq != seq.wrapping_add(1) {
+                    warn!("Lost multicast packets, waiting for the next keyframe");
+                    synced = None;
+                    continue;
+                }
+                stdout.write_all(payload)?;
+                stdout.flush()?;
+                synced = Some((stream, header.seq));
+            }
+            Kind::Keyframe => {
+                // Keyframes are only needed to get in sync
+                if synced.is_some_and(|(stream, _)| stream == header.stream) || header.count == 0 {
+                    continue;
+                }
+                if pending.as_ref().is_none_or(|keyframe| keyframe.id != header.keyframe) {
+                    pending = Some(PendingKeyframe {
+                        id: header.keyframe,
+                        seq: header.seq,
+                        pieces: vec![None; header.count as usize],
+                    });
+                }
+                let Some(keyframe) = pending.as_mut() else {
+                    continue;
+                };
+                if let Some(piece) = keyframe.pieces.get_mut(header.index as usize) {
+                    *piece = Some(payload.to_vec());
+                }
+                if keyframe.pieces.iter().all(Option::is_some) {
+                    for piece in keyframe.pieces.iter().flatten() {
+                        stdout.write_all(piece)?;
+                    }
+                    stdout.flush()?;
+                    synced = Some((header.stream, keyframe.seq));
+                    pending = None;
+                    debug!("Synchronized with multicast stream {:08x}", header.stream);
+                }
+            }
+        }
+    }
+
+    // Leave the terminal usable
+    stdout.write_all(b"\x1b[m\x1b[?25h\r\n")?;
+    stdout.flush()?;
+    Ok(())
+}
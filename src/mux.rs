@@ -0,0 +1,182 @@
+use crate::server::{AppState, ClientStats, PtyEvent, broadcast_clients, encode_message, resync_frames};
+use crate::websocket::{CloseMessage, CloseReason};
+use axum::extract::ws::{Message, Utf8Bytes};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// Path of the WebSocket that follows several sessions of a server over one connection
+pub const MUX_PATH: &str = "/mux/";
+
+/// Most sessions one multiplexed connection may follow at once
+pub const MAX_CHANNELS: usize = 64;
+
+/// Largest message accepted from a multiplexed client, which only opens and closes channels
+pub const MAX_MUX_MESSAGE: usize = 4096;
+
+/// Messages queued for a multiplexed connection before its sessions wait for it
+pub const MUX_QUEUE: usize = 1024;
+
+/// Sent by a client to follow session `Session` on channel `Channel`, read-only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenMessage {
+    #[serde(rename = "Channel")]
+    pub channel: u32,
+    #[serde(rename = "Session")]
+    pub session: String,
+}
+
+/// Sent by a client to stop following the session on channel `Channel`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseChannelMessage {
+    #[serde(rename = "Channel")]
+    pub channel: u32,
+}
+
+/// The channel of a message from the server. Messages of a session are its usual messages with the
+/// channel added next to their `Type` and `Data`; replies to the connection itself have none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelHeader {
+    #[serde(rename = "Channel", default)]
+    pub channel: Option<u32>,
+}
+
+/// Why a connection with `channels` open can't open `channel` to a session, if it can't. `exists` tells
+/// whether the session it asked for does.
+pub fn open_refusal(exists: bool, channels: &HashMap<u32, CancellationToken>, channel: u32) -> Option<&'static str> {
+    if !exists {
+        Some("No such session")
+    } else if channels.contains_key(&channel) {
+        Some("Channel already open")
+    } else if channels.len() >= MAX_CHANNELS {
+        Some("Too many channels")
+    } else {
+        None
+    }
+}
+
+/// `frame`, a serialized protocol message, with `channel` added to it
+pub fn channel_frame(channel: u32, frame: &str) -> Utf8Bytes {
+    let fields = frame.strip_prefix('{').unwrap_or(frame);
+    format!("{{\"Channel\":{channel},{fields}").into()
+}
+
+/// The `Close` message telling a client that `channel` ended because of `reason`
+pub fn channel_close_frame(channel: u32, reason: CloseReason, message: &str) -> Option<Utf8Bytes> {
+    let close_msg = CloseMessage {
+        reason,
+        message: message.to_string(),
+    };
+    match encode_message("Close", &close_msg) {
+        Ok(frame) => Some(channel_frame(channel, &frame)),
+        Err(e) => {
+            error!("Failed to encode close message: {}", e);
+            None
+        }
+    }
+}
+
+/// Send the messages of the session of `state` to `out` on `channel`, starting with its size and screen,
+/// until the session ends, the client goes away or `shutdown` is cancelled. The connection counts as
+/// one of the session's clients meanwhile.
+pub async fn forward_session(channel: u32, state: AppState, out: mpsc::Sender<Message>, shutdown: CancellationToken) {
+    let client_id = Uuid::new_v4().to_string();
+    state
+        .clients
+        .lock()
+        .await
+        .insert(client_id.clone(), ClientStats::default());
+    debug!("Multiplexed client {} follows session {}", client_id, state.session_id);
+
+    let mut pty_rx = state.pty_tx.subscribe();
+    broadcast_clients(&state).await;
+    let mut resync = true;
+    loop {
+        if std::mem::take(&mut resync) {
+            let frames = match resync_frames(&state).await {
+                Ok(frames) => frames,
+                Err(e) => {
+                    error!("Failed to encode resync messages: {}", e);
+                    break;
+                }
+            };
+            let mut sent = true;
+            for frame in frames {
+                sent = sent && out.send(Message::Text(channel_frame(channel, &frame))).await.is_ok();
+            }
+            if !sent {
+                break;
+            }
+        }
+
+        let event = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            event = pty_rx.recv() => event,
+        };
+        let (frame, close) = match event {
+            Ok(PtyEvent::Output { frame, .. } | PtyEvent::Message(frame)) => (frame, false),
+            Ok(PtyEvent::ScreenText { .. }) => continue,
+            Ok(PtyEvent::Close { frame, .. }) => (frame, true),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("Multiplexed client {} lagged behind by {} messages", client_id, skipped);
+                if let Some(stats) = state.clients.lock().await.get_mut(&client_id) {
+                    stats.lagged_messages += skipped;
+                    stats.resyncs += 1;
+                }
+                resync = true;
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                if let Some(frame) = channel_close_frame(channel, CloseReason::SessionNotFound, "Session ended") {
+                    let _ = out.send(Message::Text(frame)).await;
+                }
+                break;
+            }
+        };
+        if out.send(Message::Text(channel_frame(channel, &frame))).await.is_err() || close {
+            break;
+        }
+    }
+
+    state.clients.lock().await.remove(&client_id);
+    broadcast_clients(&state).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::TtyMessage;
+
+    #[test]
+    fn channels_are_refused_for_unknown_sessions_reuse_and_beyond_the_limit() {
+        let mut channels = HashMap::new();
+        assert_eq!(open_refusal(false, &channels, 1), Some("No such session"));
+        assert_eq!(open_refusal(true, &channels, 1), None);
+
+        channels.insert(1, CancellationToken::new());
+        assert_eq!(open_refusal(true, &channels, 1), Some("Channel already open"));
+        for channel in 2..=MAX_CHANNELS as u32 {
+            assert_eq!(open_refusal(true, &channels, channel), None);
+            channels.insert(channel, CancellationToken::new());
+        }
+        assert_eq!(open_refusal(true, &channels, 0), Some("Too many channels"));
+    }
+
+    #[test]
+    fn channel_frames_keep_the_message_and_add_the_channel() {
+        let frame = encode_message("WinSize", &serde_json::json!({"Cols": 80})).unwrap();
+        let channeled = channel_frame(7, &frame);
+        let header: ChannelHeader = serde_json::from_str(&channeled).unwrap();
+        assert_eq!(header.channel, Some(7));
+        let message: TtyMessage = serde_json::from_str(&channeled).unwrap();
+        assert_eq!(message.msg_type, "WinSize");
+
+        let close = channel_close_frame(3, CloseReason::SessionNotFound, "Session ended").unwrap();
+        let header: ChannelHeader = serde_json::from_str(&close).unwrap();
+        assert_eq!(header.channel, Some(3));
+        assert_eq!(serde_json::from_str::<TtyMessage>(&close).unwrap().msg_type, "Close");
+    }
+}
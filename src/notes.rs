@@ -0,0 +1,80 @@
+use crate::server::{PtyEvent, encode_message};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Longest notes a session keeps, in bytes; encoded in a message, they still fit in the default
+/// `--max-input-frame`
+pub const MAX_NOTES_LENGTH: usize = 32 * 1024;
+
+/// The notes of a session, sent to viewers when they change and by writers to change them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotesMessage {
+    #[serde(rename = "Text")]
+    pub text: String,
+}
+
+/// Markdown notes shared next to the terminal, such as an incident summary. With `--notes-file` they
+/// start from the file's contents and are saved to it on every change.
+#[derive(Default)]
+pub struct Notes {
+    text: std::sync::Mutex<String>,
+    file: Option<PathBuf>,
+}
+
+impl Notes {
+    /// Notes kept in `file`, starting from its contents if it exists
+    pub fn load(file: &Path) -> anyhow::Result<Self> {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read notes from {}", file.display())),
+        };
+        Ok(Self {
+            text: std::sync::Mutex::new(text),
+            file: Some(file.to_path_buf()),
+        })
+    }
+
+    pub fn text(&self) -> String {
+        self.text.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the notes with `text`, save them and send them to every viewer. Fails if `text` is
+    /// longer than [`MAX_NOTES_LENGTH`].
+    pub fn set(&self, text: String, pty_tx: &broadcast::Sender<PtyEvent>) -> Result<(), String> {
+        if text.len() > MAX_NOTES_LENGTH {
+            return Err(format!("Notes are limited to {MAX_NOTES_LENGTH} bytes"));
+        }
+        {
+            let mut current = self.text.lock().unwrap_or_else(|e| e.into_inner());
+            if *current == text {
+                return Ok(());
+            }
+            current.clone_from(&text);
+            // Saved while locked, so concurrent edits reach the file in the order they were made
+            if let Some(file) = &self.file {
+                if let Err(e) = std::fs::write(file, &text) {
+                    warn!("Failed to save notes to {}: {}", file.display(), e);
+                }
+            }
+        }
+        debug!("Notes changed ({} bytes)", text.len());
+
+        match encode_message("Notes", &NotesMessage { text }) {
+            Ok(frame) => {
+                let _ = pty_tx.send(PtyEvent::Message(frame));
+            }
+            Err(e) => warn!("Failed to encode notes: {}", e),
+        }
+        Ok(())
+    }
+}
+
+/// Notes file that `rwshell export` shows next to the recording at `cast`: "incident.cast" goes with
+/// "incident.notes.md"
+pub fn file_for_recording(cast: &Path) -> PathBuf {
+    cast.with_extension("notes.md")
+}
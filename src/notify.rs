@@ -0,0 +1,297 @@
+use crate::session_env::{RWSHELL_SESSION, RWSHELL_URL};
+use anyhow::{Context, bail};
+use serde::Serialize;
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+use url::Url;
+
+/// How long a sink may take to deliver a notification before it is given up
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Session events that can be sent to sinks with `--notify`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyEvent {
+    /// The shared program exited by itself, whatever its exit code
+    Exit,
+    /// The shared program exited by itself with a code other than 0
+    Failure,
+    /// A viewer connected
+    ViewerJoin,
+    /// A viewer left
+    ViewerLeave,
+    /// A read-only viewer asks to type with --approve-writers
+    WriteRequest,
+    /// A viewer command is held for confirmation with --confirm-pattern or --training-wheels
+    Confirm,
+}
+
+impl NotifyEvent {
+    /// The event as named on the command line, e.g. "viewer-join"
+    pub fn name(self) -> String {
+        clap::ValueEnum::to_possible_value(&self).map_or_else(String::new, |value| value.get_name().to_string())
+    }
+}
+
+/// Where notifications go
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// A Slack incoming webhook, posted a message
+    Slack(Url),
+    /// Any URL, posted the notification as JSON
+    Webhook(Url),
+    /// An email address, sent a mail through `--smtp-server`
+    Email(String),
+    /// A shell command, run with the notification in RWSHELL_* environment variables
+    Exec(String),
+}
+
+/// An event and the sink its notifications go to, given as `EVENT=SINK` with `--notify`
+#[derive(Debug, Clone)]
+pub struct NotifyRule {
+    pub event: NotifyEvent,
+    pub sink: Sink,
+}
+
+/// Parse a `--notify` rule such as "failure=email:oncall@example.com"
+pub fn parse_rule(rule: &str) -> Result<NotifyRule, String> {
+    let (event, sink) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("expected EVENT=SINK, e.g. failure=email:me@example.com, not {rule:?}"))?;
+    let event = <NotifyEvent as clap::ValueEnum>::from_str(event, true).map_err(|_| {
+        format!("unknown event {event:?}: use exit, failure, viewer-join, viewer-leave, write-request or confirm")
+    })?;
+    let (kind, target) = sink
+        .split_once(':')
+        .ok_or_else(|| format!("expected slack:URL, webhook:URL, email:ADDRESS or exec:COMMAND, not {sink:?}"))?;
+    let web_url = |target: &str| match Url::parse(target) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(url),
+        Ok(_) => Err(format!("{target} is not an http or https URL")),
+        Err(e) => Err(format!("invalid URL {target:?}: {e}")),
+    };
+    let sink = match kind {
+        "slack" => Sink::Slack(web_url(target)?),
+        "webhook" => Sink::Webhook(web_url(target)?),
+        "email" if target.contains('@') && !target.contains(['\r', '\n', '<', '>']) => Sink::Email(target.to_string()),
+        "email" => return Err(format!("invalid email address {target:?}")),
+        "exec" if !target.trim().is_empty() => Sink::Exec(target.to_string()),
+        "exec" => return Err("exec: needs a command".to_string()),
+        _ => return Err(format!("unknown sink {kind:?}: use slack, webhook, email or exec")),
+    };
+    Ok(NotifyRule { event, sink })
+}
+
+/// Mail server and sender used by `email:` sinks
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    /// Host and port of a relay accepting plain SMTP, e.g. the local mail server
+    pub server: String,
+    pub from: String,
+}
+
+/// What is sent about an event
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub event: NotifyEvent,
+    pub session: String,
+    /// Address viewers open the session at, without any token
+    pub url: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u32>,
+    /// RFC 3339 time of the event
+    pub time: String,
+}
+
+/// Sends notifications about a session's events to the sinks given for them with `--notify`
+pub struct Notifier {
+    rules: Vec<NotifyRule>,
+    session_id: String,
+    url: String,
+    /// Machine the session runs on, shown in chat messages and mails
+    hostname: String,
+    smtp: SmtpSettings,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(rules: Vec<NotifyRule>, session_id: &str, url: &str, hostname: &str, smtp: SmtpSettings) -> Self {
+        Self {
+            rules,
+            session_id: session_id.to_string(),
+            url: url.to_string(),
+            hostname: hostname.to_string(),
+            smtp,
+            http: reqwest::Client::builder()
+                .timeout(SINK_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether any sink wants `event`
+    pub fn wants(&self, event: NotifyEvent) -> bool {
+        self.rules.iter().any(|rule| rule.event == event)
+    }
+
+    /// Send `message` about `event` in the background
+    pub fn notify(self: &std::sync::Arc<Self>, event: NotifyEvent, message: String) {
+        if !self.wants(event) {
+            return;
+        }
+        let notifier = std::sync::Arc::clone(self);
+        tokio::spawn(async move { notifier.deliver(event, message, None).await });
+    }
+
+    /// Send `message` about `event` to its sinks, returning once all of them are done or gave up, e.g.
+    /// before the server exits
+    pub async fn deliver(&self, event: NotifyEvent, message: String, exit_code: Option<u32>) {
+        let notification = Notification {
+            event,
+            session: self.session_id.clone(),
+            url: self.url.clone(),
+            message,
+            exit_code,
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        };
+        let deliveries = self.rules.iter().filter(|rule| rule.event == event).map(|rule| async {
+            let result = tokio::time::timeout(SINK_TIMEOUT, self.send(&rule.sink, &notification))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+            match result {
+                Ok(()) => debug!("Sent the {} notification to {}", event.name(), sink_name(&rule.sink)),
+                Err(e) => warn!(
+                    "Failed to send the {} notification to {}: {:#}",
+                    event.name(),
+                    sink_name(&rule.sink),
+                    e
+                ),
+            }
+        });
+        futures_util::future::join_all(deliveries).await;
+    }
+
+    async fn send(&self, sink: &Sink, notification: &Notification) -> anyhow::Result<()> {
+        match sink {
+            Sink::Slack(url) => {
+                let text = format!(
+                    "*rwshell* session `{}` on {}: {}\n{}",
+                    notification.session, self.hostname, notification.message, notification.url
+                );
+                self.post(url, &serde_json::json!({ "text": text })).await
+            }
+            Sink::Webhook(url) => self.post(url, notification).await,
+            Sink::Email(to) => send_mail(&self.smtp, &self.hostname, to, notification).await,
+            Sink::Exec(command) => run_command(command, notification).await,
+        }
+    }
+
+    async fn post<T: Serialize>(&self, url: &Url, body: &T) -> anyhow::Result<()> {
+        let response = self.http.post(url.as_str()).json(body).send().await?;
+        if !response.status().is_success() {
+            bail!("the server answered {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+/// A sink as logged; URLs of webhooks are secrets, so only their host is shown
+fn sink_name(sink: &Sink) -> String {
+    match sink {
+        Sink::Slack(url) => format!("Slack at {}", url.host_str().unwrap_or("?")),
+        Sink::Webhook(url) => format!("the webhook at {}", url.host_str().unwrap_or("?")),
+        Sink::Email(to) => to.clone(),
+        Sink::Exec(_) => "the notify command".to_string(),
+    }
+}
+
+/// Run `command` with `sh -c`, passing the notification in RWSHELL_EVENT, RWSHELL_MESSAGE,
+/// RWSHELL_EXIT_CODE, RWSHELL_SESSION and RWSHELL_URL
+async fn run_command(command: &str, notification: &Notification) -> anyhow::Result<()> {
+    let mut cmd = tokio::process::Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("RWSHELL_EVENT", notification.event.name())
+        .env("RWSHELL_MESSAGE", &notification.message)
+        .env(RWSHELL_SESSION, &notification.session)
+        .env(RWSHELL_URL, &notification.url)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if let Some(code) = notification.exit_code {
+        cmd.env("RWSHELL_EXIT_CODE", code.to_string());
+    }
+    let status = cmd.status().await.context("Failed to run the command")?;
+    if !status.success() {
+        bail!("the command exited with {status}");
+    }
+    Ok(())
+}
+
+/// Send the notification to `to` over plain SMTP through the relay in `smtp`
+async fn send_mail(smtp: &SmtpSettings, hostname: &str, to: &str, notification: &Notification) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(&smtp.server)
+        .await
+        .with_context(|| format!("Failed to connect to the mail server {}", smtp.server))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    expect_reply(&mut reader, 220).await?;
+    for (command, code) in [
+        (format!("EHLO {hostname}"), 250),
+        (format!("MAIL FROM:<{}>", smtp.from), 250),
+        (format!("RCPT TO:<{to}>"), 250),
+        ("DATA".to_string(), 354),
+    ] {
+        writer.write_all(format!("{command}\r\n").as_bytes()).await?;
+        expect_reply(&mut reader, code).await?;
+    }
+
+    let subject = notification.message.replace(['\r', '\n'], " ");
+    let mut mail = format!(
+        "From: {}\r\nTo: {}\r\nSubject: [rwshell] {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        smtp.from,
+        to,
+        subject,
+        httpdate::fmt_http_date(SystemTime::now()),
+    );
+    let body = format!(
+        "{}\n\nSession: {} on {}\nURL: {}\nTime: {}\n",
+        notification.message, notification.session, hostname, notification.url, notification.time
+    );
+    for line in body.lines() {
+        // Lines starting with a dot get another, so none ends the message early
+        if line.starts_with('.') {
+            mail.push('.');
+        }
+        mail.push_str(line);
+        mail.push_str("\r\n");
+    }
+    mail.push_str(".\r\n");
+    writer.write_all(mail.as_bytes()).await?;
+    expect_reply(&mut reader, 250).await?;
+
+    writer.write_all(b"QUIT\r\n").await?;
+    Ok(())
+}
+
+/// Read an SMTP reply, which may span several lines, and fail unless it has `code`
+async fn expect_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R, code: u16) -> anyhow::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("the mail server closed the connection");
+        }
+        let line = line.trim_end();
+        // "250-..." continues the reply, "250 ..." ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if !line.starts_with(&code.to_string()) {
+            bail!("the mail server answered {line:?}");
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,309 @@
+use crate::host::{get_terminal_size, restore_terminal, setup_raw_terminal};
+use crate::mosaic::Mosaic;
+use crate::pty::AsyncPty;
+use portable_pty::{Child, CommandBuilder, MasterPty, PtySize, native_pty_system};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+
+/// Shortest time between two redraws of the panes
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Where the command of a pane runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// A PTY on this machine
+    Local,
+    /// A PTY on another machine, reached with `ssh -t`
+    Ssh(String),
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    /// Parse "local" or "ssh:[USER@]HOST"
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("ssh:") {
+            _ if value == "local" => Ok(Self::Local),
+            Some(host) if !host.is_empty() && !host.starts_with('-') => Ok(Self::Ssh(host.to_string())),
+            _ => Err(format!("expected local or ssh:[USER@]HOST: {value}")),
+        }
+    }
+}
+
+/// Parse "[USER@]HOST" as a machine reached over SSH
+pub fn ssh_backend(value: &str) -> Result<Backend, String> {
+    format!("ssh:{value}").parse()
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::Ssh(host) => write!(f, "ssh:{host}"),
+        }
+    }
+}
+
+impl Backend {
+    /// Name of the backend in the label of its pane
+    fn label(&self) -> &str {
+        match self {
+            Self::Local => "local",
+            Self::Ssh(host) => host,
+        }
+    }
+
+    /// `command` to run on this backend; over SSH, no command starts a login shell
+    fn command(&self, command: &[String]) -> CommandBuilder {
+        let argv: Vec<&str> = match self {
+            Self::Local => command.iter().map(String::as_str).collect(),
+            Self::Ssh(host) => ["ssh", "-t", host.as_str(), "--"]
+                .into_iter()
+                .chain(command.iter().map(String::as_str))
+                .collect(),
+        };
+        let mut cmd = CommandBuilder::new(argv[0]);
+        cmd.args(&argv[1..]);
+        cmd
+    }
+}
+
+/// The command that shows `command` running on each of `backends` in panes side by side, run in the
+/// session's PTY by a child rwshell. Input goes to the first pane, or to all of them with `sync_input`;
+/// `key` followed by another key switches between the two.
+pub fn command_builder(
+    backends: &[Backend],
+    command: &[String],
+    sync_input: bool,
+    key: u8,
+) -> std::io::Result<CommandBuilder> {
+    let mut cmd = CommandBuilder::new(std::env::current_exe()?);
+    cmd.arg("panes");
+    for backend in backends {
+        cmd.arg("--backend");
+        cmd.arg(backend.to_string());
+    }
+    if sync_input {
+        cmd.arg("--sync-input");
+    }
+    cmd.arg("--key");
+    cmd.arg(key.to_string());
+    cmd.arg("--");
+    cmd.args(command);
+    Ok(cmd)
+}
+
+/// A command running in one of the panes
+struct Pane {
+    backend: Backend,
+    master: Box<dyn MasterPty + Send>,
+    pty: Arc<AsyncPty>,
+    closed: bool,
+}
+
+enum PaneEvent {
+    Output(usize, Vec<u8>),
+    Closed(usize, String),
+}
+
+/// Which panes receive input, changed with the pane key
+struct InputTarget {
+    focus: usize,
+    sync: bool,
+    /// Whether the pane key was pressed and the next key is a pane command
+    pending: bool,
+}
+
+/// Run `command` on each of `backends` in panes tiled on this terminal until all of them exit.
+///
+/// After `key`, "s" toggles typing into every pane, a digit or Tab moves input to one pane, and `key`
+/// again sends the key itself.
+pub async fn run(backends: &[Backend], command: &[String], sync_input: bool, key: u8) -> anyhow::Result<()> {
+    if command.is_empty() && backends.contains(&Backend::Local) {
+        anyhow::bail!("No command to run in the local panes");
+    }
+    let labels = backends.iter().map(|backend| backend.label().to_string()).collect();
+    let mut mosaic = Mosaic::new(labels, get_terminal_size());
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let mut panes = Vec::with_capacity(backends.len());
+    let mut children: Vec<Box<dyn Child + Send + Sync>> = Vec::with_capacity(backends.len());
+    for (index, (backend, (cols, rows))) in backends.iter().zip(mosaic.tile_sizes()).enumerate() {
+        let pair = native_pty_system().openpty(pane_size(cols, rows))?;
+        mosaic.resize_session(index, cols.max(1), rows.max(1));
+        children.push(pair.slave.spawn_command(backend.command(command))?);
+        let pty = Arc::new(AsyncPty::new(pair.master.as_ref())?);
+        tokio::spawn(read_pane(index, Arc::clone(&pty), event_tx.clone()));
+        panes.push(Pane {
+            backend: backend.clone(),
+            master: pair.master,
+            pty,
+            closed: false,
+        });
+    }
+    drop(event_tx);
+
+    let original_termios = setup_raw_terminal()?;
+    let (input_tx, mut input_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::task::spawn_blocking(move || {
+        use std::io::Read;
+        let mut buffer = [0u8; 1024];
+        while let Ok(n) = std::io::stdin().read(&mut buffer) {
+            if n == 0 || input_tx.send(buffer[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut window_changes = signal(SignalKind::window_change())?;
+
+    let mut stdout = std::io::stdout();
+    stdout.write_all(b"\x1b[?1049h")?;
+
+    let mut target = InputTarget {
+        focus: 0,
+        sync: sync_input,
+        pending: false,
+    };
+    show_target(&mut mosaic, &panes, &target);
+    let mut interval = tokio::time::interval(FRAME_INTERVAL);
+    let mut changed = true;
+    while !mosaic.all_closed() {
+        tokio::select! {
+            Some(event) = event_rx.recv() => {
+                match event {
+                    PaneEvent::Output(index, data) => mosaic.output(index, &data),
+                    PaneEvent::Closed(index, reason) => {
+                        let status = tokio::task::block_in_place(|| children[index].wait());
+                        let reason = match status {
+                            Ok(status) if status.success() => reason,
+                            Ok(status) => format!("exit {}", status.exit_code()),
+                            Err(_) => reason,
+                        };
+                        panes[index].closed = true;
+                        mosaic.close(index, &reason);
+                        show_target(&mut mosaic, &panes, &target);
+                    }
+                }
+                changed = true;
+            }
+            Some(data) = input_rx.recv() => {
+                for (indexes, data) in route_input(&mut target, panes.len(), key, &data) {
+                    for index in indexes {
+                        let pane = &panes[index];
+                        if !pane.closed {
+                            let _ = pane.pty.write_all(&data).await;
+                        }
+                    }
+                }
+                show_target(&mut mosaic, &panes, &target);
+                changed = true;
+            }
+            _ = window_changes.recv() => {
+                mosaic.resize(get_terminal_size());
+                for (index, (pane, (cols, rows))) in panes.iter().zip(mosaic.tile_sizes()).enumerate() {
+                    let _ = pane.master.resize(pane_size(cols, rows));
+                    mosaic.resize_session(index, cols.max(1), rows.max(1));
+                }
+                changed = true;
+            }
+            // Redraw at most once per frame, however much output arrives
+            _ = interval.tick() => {
+                if std::mem::take(&mut changed) {
+                    stdout.write_all(&mosaic.render())?;
+                    stdout.flush()?;
+                }
+            }
+        }
+    }
+
+    stdout.write_all(b"\x1b[?25h\x1b[?1049l")?;
+    stdout.flush()?;
+    restore_terminal(&original_termios);
+    Ok(())
+}
+
+fn pane_size(cols: u16, rows: u16) -> PtySize {
+    PtySize {
+        rows: rows.max(1),
+        cols: cols.max(1),
+        pixel_width: 0,
+        pixel_height: 0,
+    }
+}
+
+async fn read_pane(index: usize, pty: Arc<AsyncPty>, event_tx: mpsc::UnboundedSender<PaneEvent>) {
+    let mut buffer = [0u8; 8192];
+    let reason = loop {
+        match pty.read(&mut buffer).await {
+            Ok(0) => break "exited".to_string(),
+            Ok(n) => {
+                if event_tx.send(PaneEvent::Output(index, buffer[..n].to_vec())).is_err() {
+                    return;
+                }
+            }
+            Err(e) => break e.to_string(),
+        }
+    };
+    let _ = event_tx.send(PaneEvent::Closed(index, reason));
+}
+
+/// Split `data` typed on the terminal into the parts each set of panes receives, following pane
+/// commands along the way
+fn route_input(target: &mut InputTarget, count: usize, key: u8, data: &[u8]) -> Vec<(Vec<usize>, Vec<u8>)> {
+    let mut routed = Vec::new();
+    let mut forward = Vec::new();
+    for &byte in data {
+        if !std::mem::take(&mut target.pending) {
+            if byte == key {
+                target.pending = true;
+            } else {
+                forward.push(byte);
+            }
+            continue;
+        }
+
+        // A pane command applies to the input that follows it
+        let before = (target.focus, target.sync);
+        match byte {
+            _ if byte == key => forward.push(byte),
+            b's' => target.sync = !target.sync,
+            b'\t' | b'n' => {
+                target.focus = (target.focus + 1) % count;
+                target.sync = false;
+            }
+            b'1'..=b'9' if ((byte - b'1') as usize) < count => {
+                target.focus = (byte - b'1') as usize;
+                target.sync = false;
+            }
+            _ => {}
+        }
+        if before != (target.focus, target.sync) && !forward.is_empty() {
+            routed.push((targets(before, count), std::mem::take(&mut forward)));
+        }
+    }
+    if !forward.is_empty() {
+        routed.push((targets((target.focus, target.sync), count), forward));
+    }
+    routed
+}
+
+/// Panes receiving input when `focus` has it, or all of them with `sync`
+fn targets((focus, sync): (usize, bool), count: usize) -> Vec<usize> {
+    if sync { (0..count).collect() } else { vec![focus] }
+}
+
+/// Mark the panes receiving input in their labels, and show the cursor of the focused one
+fn show_target(mosaic: &mut Mosaic, panes: &[Pane], target: &InputTarget) {
+    let receiving = targets((target.focus, target.sync), panes.len());
+    for (index, pane) in panes.iter().enumerate() {
+        let mut label = format!("{} {}", index + 1, pane.backend.label());
+        if receiving.contains(&index) && !pane.closed {
+            label.push_str(if target.sync { " [input: all]" } else { " [input]" });
+        }
+        mosaic.set_label(index, label);
+    }
+    mosaic.show_cursor(Some(target.focus));
+}
@@ -0,0 +1,321 @@
+use crate::host::HostMode;
+use crate::transform::OutputStage;
+use anyhow::Context;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, info};
+use wasmtime::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+/// Version of the guest ABI, described in the "Output Plugins" section of the README
+pub const ABI_VERSION: i32 = 1;
+
+/// Fuel a plugin may use per call, roughly the number of WebAssembly instructions
+const FUEL_PER_CALL: u64 = 5_000_000;
+
+/// Most linear memory a plugin may grow to, in bytes
+const MAX_MEMORY: usize = 64 << 20;
+
+/// Most elements a plugin's tables may hold
+const MAX_TABLE_ELEMENTS: usize = 10_000;
+
+/// Longest message accepted from `log` and `alert`
+const MAX_MESSAGE_LENGTH: usize = 1024;
+
+/// Session events passed to `rwshell_event`
+#[derive(Debug, Clone, Copy)]
+pub enum PluginEvent<'a> {
+    /// Kind 1: a viewer connected; the payload is its client id
+    ClientJoined(&'a str),
+    /// Kind 2: a viewer disconnected; the payload is its client id
+    ClientLeft(&'a str),
+    /// Kind 3: the shared program exited; the payload is its exit code
+    Exit(u32),
+}
+
+impl PluginEvent<'_> {
+    fn kind(&self) -> i32 {
+        match self {
+            Self::ClientJoined(_) => 1,
+            Self::ClientLeft(_) => 2,
+            Self::Exit(_) => 3,
+        }
+    }
+
+    fn payload(&self) -> String {
+        match self {
+            Self::ClientJoined(client_id) | Self::ClientLeft(client_id) => client_id.to_string(),
+            Self::Exit(code) => code.to_string(),
+        }
+    }
+}
+
+/// Data available to host functions called by a plugin
+struct PluginContext {
+    name: String,
+    host: Arc<dyn HostMode>,
+    limits: StoreLimits,
+}
+
+/// Exports of an instantiated plugin
+struct Instance {
+    store: Store<PluginContext>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    output: Option<TypedFunc<(i32, i32), i64>>,
+    event: Option<TypedFunc<(i32, i32, i32), ()>>,
+}
+
+impl Instance {
+    /// Copy `data` into a buffer allocated by the guest
+    fn write_input(&mut self, data: &[u8]) -> anyhow::Result<(i32, i32)> {
+        let len = i32::try_from(data.len()).context("input too large")?;
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, data)
+            .context("rwshell_alloc returned a buffer outside of memory")?;
+        Ok((ptr, len))
+    }
+
+    fn process(&mut self, output: TypedFunc<(i32, i32), i64>, data: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let (ptr, len) = self.write_input(data)?;
+        let result = output.call(&mut self.store, (ptr, len))?;
+        if result < 0 {
+            return Ok(None);
+        }
+
+        let start = (result >> 32) as usize;
+        let len = (result & 0xffff_ffff) as usize;
+        let transformed = self
+            .memory
+            .data(&self.store)
+            .get(start..start + len)
+            .context("rwshell_output returned a buffer outside of memory")?;
+        Ok(Some(transformed.to_vec()))
+    }
+
+    fn event(&mut self, event: TypedFunc<(i32, i32, i32), ()>, kind: i32, payload: &str) -> anyhow::Result<()> {
+        let (ptr, len) = self.write_input(payload.as_bytes())?;
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        event.call(&mut self.store, (kind, ptr, len))?;
+        Ok(())
+    }
+}
+
+/// A loaded output plugin
+pub struct Plugin {
+    name: String,
+    instance: Mutex<Instance>,
+    transforms: bool,
+    /// Set once the plugin failed; it is skipped from then on
+    failed: AtomicBool,
+}
+
+impl Plugin {
+    fn load(
+        engine: &Engine,
+        linker: &Linker<PluginContext>,
+        path: &Path,
+        host: Arc<dyn HostMode>,
+    ) -> anyhow::Result<Self> {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let module = Module::from_file(engine, path)?;
+
+        let mut store = Store::new(
+            engine,
+            PluginContext {
+                name: name.clone(),
+                host,
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(MAX_MEMORY)
+                    .table_elements(MAX_TABLE_ELEMENTS)
+                    .instances(1)
+                    .build(),
+            },
+        );
+        store.limiter(|context| &mut context.limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+
+        let version = instance
+            .get_typed_func::<(), i32>(&mut store, "rwshell_abi_version")?
+            .call(&mut store, ())?;
+        if version != ABI_VERSION {
+            anyhow::bail!("plugin uses ABI version {version}, but only version {ABI_VERSION} is supported");
+        }
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("plugin does not export its memory")?;
+        let alloc = instance.get_typed_func(&mut store, "rwshell_alloc")?;
+        let output = match instance.get_export(&mut store, "rwshell_output") {
+            Some(_) => Some(instance.get_typed_func(&mut store, "rwshell_output")?),
+            None => None,
+        };
+        let event = match instance.get_export(&mut store, "rwshell_event") {
+            Some(_) => Some(instance.get_typed_func(&mut store, "rwshell_event")?),
+            None => None,
+        };
+
+        Ok(Self {
+            name,
+            transforms: output.is_some(),
+            instance: Mutex::new(Instance {
+                store,
+                memory,
+                alloc,
+                output,
+                event,
+            }),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Disable the plugin after it failed
+    fn fail(&self, instance: &Instance, e: anyhow::Error) {
+        if !self.failed.swap(true, Ordering::Relaxed) {
+            error!("Plugin {} failed and was disabled: {:#}", self.name, e);
+            instance
+                .store
+                .data()
+                .host
+                .notify(&format!("Plugin {} failed and was disabled", self.name));
+        }
+    }
+
+    /// Run `data` through the plugin; `None` leaves it unchanged. Plugins run on the calling thread, which
+    /// the runtime hands its other tasks to meanwhile.
+    fn process(&self, data: &[u8]) -> Option<Vec<u8>> {
+        tokio::task::block_in_place(|| self.process_blocking(data))
+    }
+
+    fn process_blocking(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut instance = self.instance.lock().unwrap_or_else(|e| e.into_inner());
+        let output = instance.output.clone()?;
+        match instance.process(output, data) {
+            Ok(transformed) => transformed,
+            Err(e) => {
+                self.fail(&instance, e);
+                None
+            }
+        }
+    }
+
+    fn event(&self, event: PluginEvent<'_>) {
+        if self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+        tokio::task::block_in_place(|| self.event_blocking(event));
+    }
+
+    fn event_blocking(&self, event: PluginEvent<'_>) {
+        let mut instance = self.instance.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(callback) = instance.event.clone() else {
+            return;
+        };
+        if let Err(e) = instance.event(callback, event.kind(), &event.payload()) {
+            self.fail(&instance, e);
+        }
+    }
+}
+
+/// Output stage running PTY output through a plugin
+pub struct PluginStage(Arc<Plugin>);
+
+impl OutputStage for PluginStage {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        match self.0.process(input) {
+            Some(transformed) => output.extend_from_slice(&transformed),
+            None => output.extend_from_slice(input),
+        }
+    }
+
+    fn is_passthrough(&self) -> bool {
+        !self.0.transforms || self.0.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// The plugins loaded for a session, in the order they were given
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Arc<Plugin>>,
+}
+
+impl Plugins {
+    /// Compile and instantiate the plugins at `paths`
+    pub fn load(paths: &[impl AsRef<Path>], host: &Arc<dyn HostMode>) -> anyhow::Result<Self> {
+        if paths.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let linker = host_functions(&engine)?;
+
+        let mut plugins = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let plugin = Plugin::load(&engine, &linker, path, Arc::clone(host))
+                .with_context(|| format!("Failed to load plugin {}", path.display()))?;
+            debug!("Loaded plugin {} from {}", plugin.name, path.display());
+            plugins.push(Arc::new(plugin));
+        }
+        Ok(Self { plugins })
+    }
+
+    /// Output stages for the plugins, in order
+    pub fn stages(&self) -> impl Iterator<Item = PluginStage> + '_ {
+        self.plugins.iter().map(|plugin| PluginStage(Arc::clone(plugin)))
+    }
+
+    /// Tell every plugin about `event`
+    pub fn event(&self, event: PluginEvent<'_>) {
+        for plugin in &self.plugins {
+            plugin.event(event);
+        }
+    }
+}
+
+/// The `rwshell` import module offered to plugins
+fn host_functions(engine: &Engine) -> anyhow::Result<Linker<PluginContext>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "rwshell",
+        "log",
+        |mut caller: Caller<'_, PluginContext>, ptr: i32, len: i32| {
+            let message = read_message(&mut caller, ptr, len);
+            info!("Plugin {}: {}", caller.data().name, message);
+        },
+    )?;
+    linker.func_wrap(
+        "rwshell",
+        "alert",
+        |mut caller: Caller<'_, PluginContext>, ptr: i32, len: i32| {
+            let message = read_message(&mut caller, ptr, len);
+            let context = caller.data();
+            context.host.notify(&format!("Plugin {}: {}", context.name, message));
+        },
+    )?;
+    Ok(linker)
+}
+
+/// Read a message passed by a plugin, keeping only printable characters
+fn read_message(caller: &mut Caller<'_, PluginContext>, ptr: i32, len: i32) -> String {
+    let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+        return String::new();
+    };
+    let start = ptr as u32 as usize;
+    let len = (len as u32 as usize).min(MAX_MESSAGE_LENGTH);
+    let bytes = memory.data(&caller).get(start..start + len).unwrap_or_default();
+    String::from_utf8_lossy(bytes)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
@@ -1,6 +1,10 @@
 use crate::error::{Result, RwShellError};
 use async_trait::async_trait;
-use portable_pty::{Child, CommandBuilder, PtySize, native_pty_system};
+use portable_pty::{Child, CommandBuilder, MasterPty, PtySize, native_pty_system};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::{Command, Stdio};
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
 use tokio::sync::broadcast;
 use tracing::info;
 
@@ -78,3 +82,129 @@ impl PtyHandler for PtyMaster {
         Ok(())
     }
 }
+
+/// Non-blocking handle to a PTY master, or to a pipe of a command run without one, driven by the tokio
+/// reactor instead of a dedicated thread
+pub struct AsyncPty {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl AsyncPty {
+    /// Duplicate the file descriptor of `master` and switch it to non-blocking mode
+    pub fn new(master: &dyn MasterPty) -> std::io::Result<Self> {
+        let raw_fd = master
+            .as_raw_fd()
+            .ok_or_else(|| std::io::Error::other("PTY master has no file descriptor"))?;
+
+        // SAFETY: `raw_fd` is a valid descriptor owned by `master`; the duplicate is owned by us
+        let fd = unsafe {
+            let dup_fd = libc::fcntl(raw_fd, libc::F_DUPFD_CLOEXEC, 0);
+            if dup_fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(dup_fd)
+        };
+        Self::from_fd(fd)
+    }
+
+    /// Take over `fd`, such as a pipe to or from a command run without a PTY, in non-blocking mode
+    pub fn from_fd(fd: OwnedFd) -> std::io::Result<Self> {
+        // SAFETY: `fd` is a valid open descriptor
+        unsafe {
+            let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self {
+            fd: AsyncFd::with_interest(fd, Interest::READABLE | Interest::WRITABLE)?,
+        })
+    }
+
+    /// Read output from the PTY, returning 0 once the child side has been closed
+    pub async fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+            let result = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+
+            match result {
+                // Linux reports EIO once the last process on the PTY slave has gone away
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Ok(0),
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Whether the program on the PTY has input echo turned on; it is off at password prompts
+    pub fn echo_enabled(&self) -> std::io::Result<bool> {
+        let termios = termios::Termios::from_fd(self.fd.as_raw_fd())?;
+        Ok(termios.c_lflag & termios::ECHO != 0)
+    }
+
+    /// Write all of `data` to the PTY input
+    pub async fn write_all(&self, mut data: &[u8]) -> std::io::Result<()> {
+        while !data.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            // SAFETY: `data` is valid for reads of `data.len()` bytes
+            let result = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) };
+                if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+
+            match result {
+                Ok(Ok(0)) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(Ok(n)) => data = &data[n..],
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run `cmd` with pipes instead of a terminal, for `--no-pty`: returns the child, its stdin, and its stdout
+/// and stderr merged into one pipe
+pub fn spawn_piped(cmd: &CommandBuilder) -> std::io::Result<(Box<dyn Child + Send + Sync>, AsyncPty, AsyncPty)> {
+    let argv = cmd.get_argv();
+    let program = argv
+        .first()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "No command to run"))?;
+    let mut command = Command::new(program);
+    command.args(&argv[1..]).env_clear().envs(cmd.iter_full_env_as_str());
+    if let Some(cwd) = cmd.get_cwd() {
+        command.current_dir(cwd);
+    }
+
+    let (output, output_writer) = std::io::pipe()?;
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(output_writer.try_clone()?)
+        .stderr(output_writer)
+        .spawn()?;
+    // The command holds on to its copies of the writer until it is dropped on return, after which the
+    // output ends once the child and the processes it started have closed theirs
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| std::io::Error::other("The command has no stdin"))?;
+
+    Ok((
+        Box::new(child),
+        AsyncPty::from_fd(OwnedFd::from(stdin))?,
+        AsyncPty::from_fd(OwnedFd::from(output))?,
+    ))
+}
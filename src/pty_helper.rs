@@ -0,0 +1,451 @@
+use crate::args::Args;
+use anyhow::{Context, Result, bail};
+use portable_pty::{Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtySize, native_pty_system};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// Largest message line accepted on the helper socket
+const MAX_LINE: usize = 64 * 1024;
+
+/// Sent by the server to the helper, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Request {
+    /// Start the helper's command in a new PTY of this size, with these variables on top of its environment
+    Spawn {
+        cols: u16,
+        rows: u16,
+        env: Vec<(String, String)>,
+    },
+    /// Terminate the command; closing the connection does too
+    Kill,
+}
+
+/// Sent by the helper to the server, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+    /// The command runs; the PTY master comes with this message
+    Spawned { pid: Option<u32> },
+    /// The command couldn't be started
+    Failed { error: String },
+    /// The command exited
+    Exited { exit_code: u32 },
+}
+
+/// Variables the server may set for the helper's command: the terminal type and the session variables.
+/// Anything else, e.g. `LD_PRELOAD`, stays out of the helper's reach.
+fn allowed_env(name: &str) -> bool {
+    matches!(name, "TERM" | "COLORTERM") || name.starts_with("RWSHELL")
+}
+
+/// Serve `rwshell pty-helper`: listen on `socket` and, for every server that connects, start the command
+/// given with `--command` in a new PTY and hand the server the PTY master, until interrupted
+pub async fn run(args: &Args, socket: &Path, owner: Option<&str>) -> Result<()> {
+    let (listener, server_uid) = bind(socket, owner)?;
+    let mut cmd = CommandBuilder::new(&args.command);
+    for arg in args.args.split_whitespace() {
+        cmd.arg(arg);
+    }
+    for (name, value) in &args.env {
+        cmd.env(name, value);
+    }
+    info!(
+        "Starting `{}` for servers connecting to {}",
+        args.command,
+        socket.display()
+    );
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        // The socket's permissions keep others out; its peer's credentials make sure of it
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == server_uid => {}
+            Ok(cred) => {
+                warn!("Refused a connection from UID {}, expected {}", cred.uid(), server_uid);
+                continue;
+            }
+            Err(e) => {
+                warn!("Refused a connection whose credentials are unknown: {}", e);
+                continue;
+            }
+        }
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let cmd = cmd.clone();
+        // The connection blocks on the command until it exits
+        std::thread::spawn(move || {
+            if let Err(e) = serve(stream, cmd) {
+                warn!("PTY helper connection failed: {:#}", e);
+            }
+        });
+    }
+
+    let _ = std::fs::remove_file(socket);
+    Ok(())
+}
+
+/// Listen on `socket`, which only its owner may connect to: `owner` if given, e.g. the unprivileged user
+/// the server runs as. Returns the listener and the UID of the owner.
+fn bind(socket: &Path, owner: Option<&str>) -> Result<(tokio::net::UnixListener, libc::uid_t)> {
+    // A socket left over from a helper that didn't exit cleanly
+    if std::fs::symlink_metadata(socket).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(socket).with_context(|| format!("Failed to remove {}", socket.display()))?;
+    }
+    // Created accessible to its owner only, so no one else can connect before its permissions are set
+    // SAFETY: umask only swaps the process's file mode creation mask
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = tokio::net::UnixListener::bind(socket);
+    // SAFETY: as above
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    // SAFETY: geteuid can't fail
+    let mut uid = unsafe { libc::geteuid() };
+    if let Some(owner) = owner {
+        let name = CString::new(owner).context("Invalid user name")?;
+        // SAFETY: `name` is a valid C string; the entry is only read before the next getpwnam call
+        let (owner_uid, gid) = unsafe {
+            let entry = libc::getpwnam(name.as_ptr());
+            if entry.is_null() {
+                bail!("No user named {owner}");
+            }
+            ((*entry).pw_uid, (*entry).pw_gid)
+        };
+        std::os::unix::fs::chown(socket, Some(owner_uid), Some(gid))
+            .with_context(|| format!("Failed to hand {} to {owner}", socket.display()))?;
+        uid = owner_uid;
+    }
+    Ok((listener, uid))
+}
+
+/// Start the command for the server connected on `stream` and report when it exits
+fn serve(stream: UnixStream, mut cmd: CommandBuilder) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let Some(Request::Spawn { cols, rows, env }) = read_message(&mut reader)? else {
+        bail!("Expected a spawn request");
+    };
+    for (name, value) in env {
+        if allowed_env(&name) {
+            cmd.env(name, value);
+        } else {
+            debug!("Ignoring variable {} from the server", name);
+        }
+    }
+
+    let spawned = native_pty_system()
+        .openpty(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .and_then(|pair| Ok((pair.slave.spawn_command(cmd)?, pair.master)));
+    let (mut child, master) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            send_message(
+                &stream,
+                &Reply::Failed {
+                    error: format!("{e:#}"),
+                },
+                None,
+            )?;
+            return Err(e);
+        }
+    };
+    let pid = child.process_id();
+    let master_fd = master.as_raw_fd().context("The PTY master has no file descriptor")?;
+    send_message(&stream, &Reply::Spawned { pid }, Some(master_fd))?;
+    // The server holds the only copy now, so the output ends for it once the command exits
+    drop(master);
+    info!("Started the command with PID {} for a server", pid.unwrap_or(0));
+
+    // Terminate the command when asked to, or when the server goes away
+    let mut killer = child.clone_killer();
+    std::thread::spawn(move || {
+        while let Ok(Some(request)) = read_message::<Request>(&mut reader) {
+            if matches!(request, Request::Kill) {
+                break;
+            }
+        }
+        let _ = killer.kill();
+    });
+
+    let exit_code = child.wait().map_or(1, |status| status.exit_code());
+    info!(
+        "The command with PID {} exited with code {}",
+        pid.unwrap_or(0),
+        exit_code
+    );
+    // The server may be gone already
+    let _ = send_message(&stream, &Reply::Exited { exit_code }, None);
+    Ok(())
+}
+
+/// Connect to the helper listening on `socket` and have it start its command in a PTY of `size`, with the
+/// terminal and session variables of `cmd`. Returns the PTY master and a handle on the command.
+pub fn spawn(
+    socket: &Path,
+    (cols, rows): (u16, u16),
+    cmd: &CommandBuilder,
+) -> Result<(Box<dyn MasterPty + Send>, Box<dyn Child + Send + Sync>)> {
+    let stream = UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to the PTY helper at {}", socket.display()))?;
+    let env = cmd
+        .iter_extra_env_as_str()
+        .filter(|(name, _)| allowed_env(name))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    send_message(&stream, &Request::Spawn { cols, rows, env }, None)?;
+
+    // What came with the descriptor may already go beyond the first reply
+    let (received, fd) = receive_with_fd(&stream)?;
+    let mut reader = BufReader::new(std::io::Cursor::new(received).chain(stream.try_clone()?));
+    match read_message::<Reply>(&mut reader)?.context("The PTY helper closed the connection")? {
+        Reply::Spawned { pid } => {
+            let fd = fd.context("The PTY helper sent no PTY")?;
+            let child = RemoteChild {
+                pid,
+                connection: Arc::new(stream),
+                reader,
+            };
+            Ok((Box::new(RemoteMaster { fd }), Box::new(child)))
+        }
+        Reply::Failed { error } => bail!("The PTY helper failed to start the command: {error}"),
+        Reply::Exited { .. } => bail!("Unexpected reply from the PTY helper"),
+    }
+}
+
+fn send_message<T: Serialize>(stream: &UnixStream, message: &T, fd: Option<RawFd>) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    match fd {
+        Some(fd) => send_with_fd(stream, &line, fd)?,
+        None => (&*stream).write_all(&line)?,
+    }
+    Ok(())
+}
+
+/// The next message on `reader`, or `None` once the peer closed the connection
+fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = Vec::new();
+    let read = reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_slice(&line).context("Invalid message on the PTY helper socket")?,
+    ))
+}
+
+/// Send `data` along with a copy of `fd`
+fn send_with_fd(stream: &UnixStream, data: &[u8], fd: RawFd) -> std::io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: an all-zero msghdr is valid; the pointers set below outlive the sendmsg call
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    // SAFETY: `control` has room for one header carrying one descriptor, as CMSG_SPACE computed
+    let sent = unsafe {
+        let header = libc::CMSG_FIRSTHDR(&msg);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(header).cast::<RawFd>(), fd);
+        libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // The message is tiny, but write whatever didn't go out with the descriptor
+    (&*stream).write_all(&data[sent as usize..])
+}
+
+/// Receive the start of a message, and the descriptor that came with it, if any
+fn receive_with_fd(stream: &UnixStream) -> std::io::Result<(Vec<u8>, Option<OwnedFd>)> {
+    let mut data = vec![0u8; MAX_LINE];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: an all-zero msghdr is valid; the pointers set below outlive the recvmsg call
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    // SAFETY: `msg` describes valid buffers
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "The PTY helper closed the connection",
+        ));
+    }
+    data.truncate(received as usize);
+
+    let mut fd = None;
+    // SAFETY: the headers are those recvmsg filled in, within `control`
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&msg);
+        if !header.is_null() && (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+            let raw = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<RawFd>());
+            fd = Some(OwnedFd::from_raw_fd(raw));
+        }
+    }
+    Ok((data, fd))
+}
+
+/// A PTY master received from the helper
+struct RemoteMaster {
+    fd: OwnedFd,
+}
+
+impl RemoteMaster {
+    fn duplicate(&self) -> anyhow::Result<File> {
+        Ok(File::from(self.fd.try_clone()?))
+    }
+}
+
+impl MasterPty for RemoteMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let winsize = libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: size.pixel_width,
+            ws_ypixel: size.pixel_height,
+        };
+        // SAFETY: TIOCSWINSZ reads a winsize, which `winsize` is
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        // SAFETY: an all-zero winsize is valid, and TIOCGWINSZ only writes to it
+        let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(PtySize {
+            rows: winsize.ws_row,
+            cols: winsize.ws_col,
+            pixel_width: winsize.ws_xpixel,
+            pixel_height: winsize.ws_ypixel,
+        })
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(self.duplicate()?))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.duplicate()?))
+    }
+
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        // SAFETY: tcgetpgrp only queries the descriptor
+        let pgrp = unsafe { libc::tcgetpgrp(self.fd.as_raw_fd()) };
+        (pgrp > 0).then_some(pgrp)
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.fd.as_raw_fd())
+    }
+
+    fn tty_name(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The command the helper started, known through its connection
+#[derive(Debug)]
+struct RemoteChild {
+    pid: Option<u32>,
+    connection: Arc<UnixStream>,
+    reader: BufReader<std::io::Chain<std::io::Cursor<Vec<u8>>, UnixStream>>,
+}
+
+/// Asks the helper to terminate the command
+#[derive(Debug)]
+struct RemoteKiller {
+    connection: Arc<UnixStream>,
+}
+
+impl ChildKiller for RemoteKiller {
+    fn kill(&mut self) -> std::io::Result<()> {
+        send_message(&self.connection, &Request::Kill, None).map_err(std::io::Error::other)
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(RemoteKiller {
+            connection: Arc::clone(&self.connection),
+        })
+    }
+}
+
+impl ChildKiller for RemoteChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        self.clone_killer().kill()
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(RemoteKiller {
+            connection: Arc::clone(&self.connection),
+        })
+    }
+}
+
+impl Child for RemoteChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        // Only `wait` learns how the command exited
+        Ok(None)
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        loop {
+            match read_message::<Reply>(&mut self.reader).map_err(std::io::Error::other)? {
+                Some(Reply::Exited { exit_code }) => return Ok(ExitStatus::with_exit_code(exit_code)),
+                Some(_) => continue,
+                None => return Err(std::io::Error::other("The PTY helper closed the connection")),
+            }
+        }
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        self.pid
+    }
+}
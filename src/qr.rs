@@ -0,0 +1,16 @@
+use anyhow::Context;
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+
+/// `url` as a QR code drawn with Unicode half blocks, two rows of modules per line, with `--qr`. Light
+/// modules are drawn and dark ones left blank, like `qrencode -t utf8`, so the code scans from terminals
+/// with a dark background. Lines end with `line_end`, "\r\n" while the terminal is in raw mode.
+pub fn render(url: &str, line_end: &str) -> anyhow::Result<String> {
+    let code = QrCode::new(url.as_bytes()).context("The URL is too long for a QR code")?;
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    Ok(image.lines().map(|line| format!("{line}{line_end}")).collect())
+}
@@ -0,0 +1,371 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Number of tracked clients above which idle ones are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Token bucket allowing `limit` requests per minute, with bursts of up to `limit`
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the client has been throttled since it was last allowed through
+    throttled: bool,
+}
+
+impl Bucket {
+    fn new(limit: u32, now: Instant) -> Self {
+        Self {
+            tokens: limit as f64,
+            updated: now,
+            throttled: false,
+        }
+    }
+
+    fn refill(&mut self, limit: u32, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit as f64 / 60.0).min(limit as f64);
+        self.updated = now;
+    }
+
+    /// Take a token, or return how long until one is available
+    fn take(&mut self, limit: u32, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.throttled = false;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / limit as f64))
+        }
+    }
+
+    /// How long until a token is available, if none is, without taking one
+    fn wait(&self, limit: u32, now: Instant) -> Option<Duration> {
+        let mut bucket = *self;
+        bucket.refill(limit, now);
+        (bucket.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / limit as f64))
+    }
+
+    fn is_full(&self, limit: u32, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(limit, now);
+        bucket.tokens >= limit as f64
+    }
+}
+
+/// Limits how often session pages can be loaded and WebSockets opened, overall and per client address
+pub struct RateLimiter {
+    /// Requests per minute allowed from one client address, 0 for no limit
+    per_ip: u32,
+    /// Requests per minute allowed in total, 0 for no limit
+    global: u32,
+    global_bucket: Mutex<Bucket>,
+    clients: Mutex<HashMap<IpAddr, Bucket>>,
+    /// When each client address last knocked, see [`RateLimiter::knock`]
+    knocks: Mutex<HashMap<IpAddr, Instant>>,
+    /// When each client address last had a page of history rendered, see [`RateLimiter::history_request`]
+    history_requests: Mutex<HashMap<IpAddr, Instant>>,
+    throttled: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: u32, global: u32) -> Self {
+        Self {
+            per_ip,
+            global,
+            global_bucket: Mutex::new(Bucket::new(global, Instant::now())),
+            clients: Mutex::new(HashMap::new()),
+            knocks: Mutex::new(HashMap::new()),
+            history_requests: Mutex::new(HashMap::new()),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of requests rejected so far
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Count a knock from `ip`, or return false if the client address knocked less than `interval` ago.
+    /// Knocks are limited by address rather than by connection, so reconnecting doesn't lift the limit.
+    pub fn knock(&self, ip: IpAddr, interval: Duration) -> bool {
+        at_most_once(&self.knocks, ip, interval)
+    }
+
+    /// Count a history request from `ip`, or return false if the client address made one less than
+    /// `interval` ago. Each request replays the whole history, so viewers can't have it done without limit.
+    pub fn history_request(&self, ip: IpAddr, interval: Duration) -> bool {
+        at_most_once(&self.history_requests, ip, interval)
+    }
+
+    /// Admit a request from `ip`, or return how long the client should wait
+    fn check(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let now = Instant::now();
+
+        if let (Some(ip), true) = (ip, self.per_ip > 0) {
+            let key = client_key(ip);
+            let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+            if clients.len() >= MAX_TRACKED_CLIENTS {
+                clients.retain(|_, bucket| !bucket.is_full(self.per_ip, now));
+            }
+
+            let bucket = clients.entry(key).or_insert_with(|| Bucket::new(self.per_ip, now));
+            if let Err(retry_after) = bucket.take(self.per_ip, now) {
+                // Log once per burst so scanning shows up without flooding the log
+                if !bucket.throttled {
+                    bucket.throttled = true;
+                    warn!("Rate limiting requests from {}", key);
+                }
+                return Err(retry_after);
+            }
+        }
+
+        if self.global > 0 {
+            let mut bucket = self.global_bucket.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(retry_after) = bucket.take(self.global, now) {
+                if !bucket.throttled {
+                    bucket.throttled = true;
+                    warn!("Rate limiting all requests: more than {} per minute", self.global);
+                }
+                return Err(retry_after);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How long the client at `ip` should wait if it used up its limit or all clients did, without
+    /// counting a request
+    fn wait(&self, ip: Option<IpAddr>) -> Option<Duration> {
+        let now = Instant::now();
+        let per_ip = ip.filter(|_| self.per_ip > 0).and_then(|ip| {
+            let clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+            clients.get(&client_key(ip))?.wait(self.per_ip, now)
+        });
+        if per_ip.is_some() || self.global == 0 {
+            return per_ip;
+        }
+        let bucket = self.global_bucket.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.wait(self.global, now)
+    }
+}
+
+/// Record an event from `ip` in `last`, or return false if the client address had one less than
+/// `interval` ago
+fn at_most_once(last: &Mutex<HashMap<IpAddr, Instant>>, ip: IpAddr, interval: Duration) -> bool {
+    let now = Instant::now();
+    let key = client_key(ip);
+    let mut last = last.lock().unwrap_or_else(|e| e.into_inner());
+    if last.len() >= MAX_TRACKED_CLIENTS {
+        last.retain(|_, at| now.duration_since(*at) < interval);
+    }
+    if last.get(&key).is_some_and(|at| now.duration_since(*at) < interval) {
+        return false;
+    }
+    last.insert(key, now);
+    true
+}
+
+/// Address clients are limited by; IPv6 clients usually control a whole /64, so they share one limit
+pub(crate) fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = u128::from(v6) & !((1u128 << 64) - 1);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+        },
+    }
+}
+
+/// Middleware rejecting page loads, WebSocket upgrades and misses beyond the configured rates.
+///
+/// Static files served don't count since every page load fetches them, but static file requests that
+/// fail, for a wrong session ID, token or password, do, and a client over its limit gets none, so they
+/// can't be used to guess those without limit.
+pub async fn rate_limit(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    if request.uri().path().contains("/static/") {
+        if let Some(retry_after) = limiter.wait(ip) {
+            return too_many_requests(&limiter, retry_after);
+        }
+        let response = next.run(request).await;
+        if response.status().is_client_error() {
+            let _ = limiter.check(ip);
+        }
+        return response;
+    }
+
+    match limiter.check(ip) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => too_many_requests(&limiter, retry_after),
+    }
+}
+
+fn too_many_requests(limiter: &RateLimiter, retry_after: Duration) -> Response {
+    limiter.throttled.fetch_add(1, Ordering::Relaxed);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+        "Too many requests",
+    )
+        .into_response()
+}
+
+/// Caps the WebSocket clients connected to a session at once, overall and per client address, so a
+/// misbehaving viewer can't use up the session's broadcast subscribers
+pub struct ConnectionLimits {
+    /// Clients connected at once, 0 for no limit
+    max_clients: usize,
+    /// Clients connected at once from one client address, 0 for no limit
+    max_per_ip: usize,
+    connected: Mutex<ConnectedClients>,
+    rejected: AtomicU64,
+}
+
+#[derive(Default)]
+struct ConnectedClients {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Why a connection was turned away by [`ConnectionLimits`]
+pub enum ConnectionRejected {
+    /// The session has `--max-clients` clients
+    Full,
+    /// The client's address has `--max-clients-per-ip` clients
+    TooManyFromAddress,
+}
+
+impl ConnectionRejected {
+    /// Why the connection was turned away, for the client
+    pub fn message(&self) -> &'static str {
+        match self {
+            ConnectionRejected::Full => "The session has as many viewers as it allows, try again later",
+            ConnectionRejected::TooManyFromAddress => "Too many connections to this session from your address",
+        }
+    }
+}
+
+impl IntoResponse for ConnectionRejected {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ConnectionRejected::Full => StatusCode::SERVICE_UNAVAILABLE,
+            ConnectionRejected::TooManyFromAddress => StatusCode::TOO_MANY_REQUESTS,
+        };
+        (status, self.message()).into_response()
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(max_clients: usize, max_per_ip: usize) -> Self {
+        Self {
+            max_clients,
+            max_per_ip,
+            connected: Mutex::new(ConnectedClients::default()),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of connections turned away so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Admit a connection from `ip`, which counts against the limits until the returned slot is dropped
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionSlot, ConnectionRejected> {
+        let key = client_key(ip);
+        let mut connected = self.connected.lock().unwrap_or_else(|e| e.into_inner());
+        let from_ip = connected.per_ip.get(&key).copied().unwrap_or(0);
+        let rejected = if self.max_clients > 0 && connected.total >= self.max_clients {
+            warn!(
+                "Refused a connection from {}: the session is full ({} clients)",
+                key, connected.total
+            );
+            Some(ConnectionRejected::Full)
+        } else if self.max_per_ip > 0 && from_ip >= self.max_per_ip {
+            warn!(
+                "Refused a connection from {}: too many connections from it ({})",
+                key, from_ip
+            );
+            Some(ConnectionRejected::TooManyFromAddress)
+        } else {
+            None
+        };
+        if let Some(rejected) = rejected {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(rejected);
+        }
+
+        connected.total += 1;
+        *connected.per_ip.entry(key).or_default() += 1;
+        Ok(ConnectionSlot {
+            limits: Arc::clone(self),
+            key,
+        })
+    }
+}
+
+/// A connection counted by [`ConnectionLimits`], until dropped
+pub struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    key: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connected = self.limits.connected.lock().unwrap_or_else(|e| e.into_inner());
+        connected.total -= 1;
+        if let Some(count) = connected.per_ip.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                connected.per_ip.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knocks_are_limited_per_address() {
+        let limiter = RateLimiter::new(0, 0);
+        let interval = Duration::from_secs(60);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(limiter.knock(a, interval));
+        assert!(!limiter.knock(a, interval));
+        assert!(!limiter.knock("::ffff:192.0.2.1".parse().unwrap(), interval));
+        assert!(limiter.knock(b, interval));
+        assert!(limiter.knock(a, Duration::ZERO));
+    }
+
+    #[test]
+    fn knocks_share_an_ipv6_prefix() {
+        let limiter = RateLimiter::new(0, 0);
+        let interval = Duration::from_secs(60);
+        assert!(limiter.knock("2001:db8::1".parse().unwrap(), interval));
+        assert!(!limiter.knock("2001:db8::2".parse().unwrap(), interval));
+        assert!(limiter.knock("2001:db8:0:1::1".parse().unwrap(), interval));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::history;
+use crate::server::{AppState, PtyEvent, decode_winsize};
+use crate::timeline::TimelineEntry;
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Something that happened in a recording
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Output written to the terminal
+    Output(String),
+    /// The terminal was resized to `cols` x `rows`
+    Resize(u16, u16),
+}
+
+/// A terminal recording in the asciicast v2 format written by asciinema
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub width: u16,
+    pub height: u16,
+    pub title: Option<String>,
+    /// When the recording started, in seconds since the Unix epoch
+    pub timestamp: Option<u64>,
+    /// Events with their time in seconds from the start, in order
+    pub events: Vec<(f64, Event)>,
+    /// Markers with their time in seconds from the start and their label, in order
+    pub markers: Vec<(f64, String)>,
+    /// Pauses shortened to the idle time limit, as the time they ended in the original recording and
+    /// the seconds left out up to then
+    shortened: Vec<(f64, f64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    #[serde(default)]
+    width: u16,
+    #[serde(default)]
+    height: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_time_limit: Option<f64>,
+}
+
+impl Recording {
+    /// Read the recording at `path`, shortening pauses to the idle time limit it asks for
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header_line = lines
+            .next()
+            .transpose()?
+            .with_context(|| format!("{} is empty", path.display()))?;
+        let header: Header = serde_json::from_str(&header_line)
+            .with_context(|| format!("{} is not an asciicast recording", path.display()))?;
+        if header.version != 2 {
+            bail!(
+                "{} is an asciicast v{} recording; only v2 is supported",
+                path.display(),
+                header.version
+            );
+        }
+        if header.width == 0 || header.height == 0 {
+            bail!("{} doesn't give the terminal size", path.display());
+        }
+
+        let mut events = Vec::new();
+        let mut markers = Vec::new();
+        let mut shortened = Vec::new();
+        let mut skipped = 0.0;
+        let mut previous = 0.0;
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (time, code, data): (f64, String, String) = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid event on line {} of {}", number + 2, path.display()))?;
+
+            if let Some(limit) = header.idle_time_limit {
+                if time - previous > limit {
+                    skipped += time - previous - limit;
+                    shortened.push((time, skipped));
+                }
+            }
+            previous = time;
+
+            let event = match code.as_str() {
+                "o" => Event::Output(data),
+                "r" => match parse_size(&data) {
+                    Some((cols, rows)) => Event::Resize(cols, rows),
+                    None => continue,
+                },
+                "m" => {
+                    markers.push((time - skipped, data));
+                    continue;
+                }
+                // Input and unknown events don't change the screen
+                _ => continue,
+            };
+            events.push((time - skipped, event));
+        }
+
+        Ok(Self {
+            width: header.width,
+            height: header.height,
+            title: header.title,
+            timestamp: header.timestamp,
+            events,
+            markers,
+            shortened,
+        })
+    }
+
+    /// Add the timeline entries made while the recording ran as markers, placed by the time the
+    /// recording started
+    pub fn mark_timeline(&mut self, entries: &[TimelineEntry]) {
+        let Some(start) = self.timestamp else {
+            return;
+        };
+        let end = self.duration() + self.shortened.last().map_or(0.0, |(_, skipped)| *skipped);
+        for entry in entries {
+            let Some(time) = entry.system_time() else {
+                continue;
+            };
+            let Ok(since_epoch) = time.duration_since(std::time::UNIX_EPOCH) else {
+                continue;
+            };
+            let offset = since_epoch.as_secs_f64() - start as f64;
+            if !(0.0..=end).contains(&offset) {
+                continue;
+            }
+            // Time left out of pauses before the entry was made, up to the pause it was made in
+            let skipped = self
+                .shortened
+                .iter()
+                .take_while(|(ended, _)| *ended <= offset)
+                .last()
+                .map_or(0.0, |(_, skipped)| *skipped);
+            let pause = self
+                .shortened
+                .iter()
+                .find(|(ended, _)| *ended > offset)
+                .map_or(0.0, |(ended, total)| (total - skipped - (ended - offset)).max(0.0));
+            self.markers.push((offset - skipped - pause, entry.text.clone()));
+        }
+        self.markers.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+
+    /// Plain text lines the recording left on the main screen and in the scrollback, oldest first
+    pub fn transcript(&self) -> Vec<String> {
+        let output: Vec<u8> = self
+            .events
+            .iter()
+            .filter_map(|(_, event)| match event {
+                Event::Output(data) => Some(data.as_bytes()),
+                Event::Resize(..) => None,
+            })
+            .flatten()
+            .copied()
+            .collect();
+        // Lines written after the terminal was widened would wrap at the size it started with
+        history::render_lines(&output, self.max_size())
+    }
+
+    /// The largest size the terminal had during the recording
+    pub fn max_size(&self) -> (u16, u16) {
+        self.events
+            .iter()
+            .fold((self.width, self.height), |size, (_, event)| match event {
+                Event::Resize(cols, rows) => (size.0.max(*cols), size.1.max(*rows)),
+                Event::Output(_) => size,
+            })
+    }
+
+    /// Length of the recording in seconds
+    pub fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |(time, _)| *time)
+    }
+}
+
+/// Record the session to `path` in the asciicast v2 format with `--record`: the output viewers see and
+/// each change of the terminal size as a resize event, so the recording plays back at the sizes it had.
+/// Nothing is buffered against `--memory-budget`: each event is written through as it comes in, and a
+/// recording that falls behind misses output instead of queueing it.
+pub fn start(state: &AppState, path: &Path, title: &str, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = LineWriter::new(file);
+    let mut pty_rx = state.pty_tx.subscribe();
+    let current_size = state.current_size.clone();
+    let title = title.to_string();
+    let path = path.to_path_buf();
+    state.supervisor.spawn("recording", async move {
+        let (width, height) = *current_size.lock().await;
+        let header = Header {
+            version: 2,
+            width,
+            height,
+            title: Some(title),
+            timestamp: SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|since_epoch| since_epoch.as_secs()),
+            idle_time_limit: None,
+        };
+        if let Err(e) = serde_json::to_string(&header)
+            .map_err(std::io::Error::from)
+            .and_then(|header| writeln!(out, "{header}"))
+        {
+            warn!("Failed to record to {}: {}", path.display(), e);
+            return;
+        }
+        let started = Instant::now();
+        let mut write = |code: &str, data: &str| -> std::io::Result<()> {
+            let event = serde_json::to_string(&(started.elapsed().as_secs_f64(), code, data))?;
+            writeln!(out, "{event}")
+        };
+        // Output that ends inside a UTF-8 sequence, kept until the rest arrives: at most 3 bytes
+        let mut partial = Vec::new();
+        info!("Recording the session to {}", path.display());
+
+        loop {
+            let event = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                event = pty_rx.recv() => event,
+            };
+            let result = match event {
+                Ok(PtyEvent::Output { data, .. }) => {
+                    partial.extend_from_slice(&data);
+                    let text = take_text(&mut partial);
+                    if text.is_empty() { Ok(()) } else { write("o", &text) }
+                }
+                Ok(PtyEvent::Message(frame)) => match decode_winsize(&frame) {
+                    Some((cols, rows)) => write("r", &format!("{cols}x{rows}")),
+                    None => Ok(()),
+                },
+                Ok(_) => Ok(()),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("The recording missed {} messages", missed);
+                    Ok(())
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(e) = result {
+                warn!("Stopped recording to {}: {}", path.display(), e);
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Take the text from `partial`, leaving a UTF-8 sequence it ends inside for the next output
+fn take_text(partial: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(partial) {
+        Ok(_) => partial.len(),
+        // An incomplete sequence at the end; invalid bytes are replaced instead
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => partial.len(),
+    };
+    let rest = partial.split_off(complete);
+    let text = String::from_utf8_lossy(partial).into_owned();
+    *partial = rest;
+    text
+}
+
+/// Parse the size of a resize event, e.g. "80x24"
+fn parse_size(data: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = data.split_once('x')?;
+    let (cols, rows) = (cols.parse().ok()?, rows.parse().ok()?);
+    (cols > 0 && rows > 0).then_some((cols, rows))
+}
@@ -0,0 +1,446 @@
+use crate::args::Args;
+use crate::capability::{self, AccessMode, Capability};
+use crate::identity::{ServerIdentity, default_identity_path};
+use crate::serve::{ServeOptions, serve};
+use crate::server::{
+    MAX_HISTORY_SIZE, ReadOnlyMessage, encode_message, encode_write_message, render_session_page, serve_404,
+    serve_static_file,
+};
+use crate::session::WriteMessage;
+use crate::tls;
+use crate::tunnel::{self, Mux, TUNNEL_PATH, TunnelOpened};
+use crate::websocket::{BINARY_PROTOCOL, TtyMessage};
+use anyhow::Context;
+use axum::Router;
+use axum::extract::ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Path, Query, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, get};
+use base64::{Engine as _, engine::general_purpose};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+/// Identifier capability tokens of the relay are minted for
+const RELAY_SESSION_ID: &str = "relay";
+
+/// Messages buffered per viewer before it is resynchronized
+const VIEWER_BUFFER: usize = 1024;
+
+/// Largest message accepted from viewers, which have nothing to send
+const MAX_VIEWER_MESSAGE: usize = 4096;
+
+/// How long the ID of a tunnel whose session disconnected is kept for it to reconnect with
+const TUNNEL_RESERVATION: Duration = Duration::from_secs(10 * 60);
+
+/// What a viewer needs to catch up with the mirrored session
+#[derive(Default)]
+struct Screen {
+    /// Latest `WinSize` message
+    size: Option<Utf8Bytes>,
+    /// Recent output, since the last terminal reset
+    history: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct RelayState {
+    identity: Arc<ServerIdentity>,
+    /// Path the relay is reached under, from `--base-url`
+    base_path: Arc<str>,
+    frames: broadcast::Sender<Utf8Bytes>,
+    screen: Arc<Mutex<Screen>>,
+    /// URL tunneled sessions are reached under, followed by their tunnel ID
+    tunnel_url: Arc<str>,
+    /// Tunnels of sessions shared with `--public`, by tunnel ID
+    tunnels: Arc<Mutex<HashMap<String, Tunnel>>>,
+}
+
+/// A tunnel ID the relay gave a session, kept for it while it reconnects
+struct Tunnel {
+    /// Secret only the session got, which it reclaims the ID with
+    secret: String,
+    /// Connection to the session, while it is connected
+    mux: Option<Arc<Mux>>,
+    /// When the session disconnected, if it is not connected
+    released: Option<Instant>,
+}
+
+/// Query of a session opening a tunnel
+#[derive(Deserialize)]
+struct TunnelQuery {
+    /// Tunnel the session had before reconnecting
+    id: Option<String>,
+    /// Secret of that tunnel, from its `TunnelOpened`
+    secret: Option<String>,
+}
+
+/// Path of a request to a tunneled session
+#[derive(Deserialize)]
+struct TunnelPath {
+    id: String,
+}
+
+impl RelayState {
+    /// Verify a relay token from the request path, requiring read-write access if `write` is set
+    fn authorize(&self, token: &str, write: bool) -> bool {
+        match capability::verify(&self.identity, RELAY_SESSION_ID, token) {
+            Ok(capability) => !(write && capability.readonly()),
+            Err(e) => {
+                debug!("Rejected relay token: {}", e);
+                false
+            }
+        }
+    }
+
+    fn screen(&self) -> std::sync::MutexGuard<'_, Screen> {
+        self.screen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn tunnels(&self) -> std::sync::MutexGuard<'_, HashMap<String, Tunnel>> {
+        self.tunnels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Give a session the tunnel ID it asks for, or a new one, with the secret of the tunnel. An ID that
+    /// is taken, or kept for a session that disconnected, only goes to a session with its secret.
+    fn claim_tunnel(&self, query: TunnelQuery) -> Option<(String, String)> {
+        let mut tunnels = self.tunnels();
+        tunnels.retain(|_, tunnel| {
+            tunnel
+                .released
+                .is_none_or(|released| released.elapsed() < TUNNEL_RESERVATION)
+        });
+        let id = tunnel::tunnel_id(query.id);
+        match tunnels.get(&id) {
+            Some(tunnel) => {
+                let reclaimed = query
+                    .secret
+                    .is_some_and(|secret| secrets_match(&tunnel.secret, &secret));
+                reclaimed.then(|| (id, tunnel.secret.clone()))
+            }
+            None => {
+                let secret = tunnel::tunnel_secret();
+                let tunnel = Tunnel {
+                    secret: secret.clone(),
+                    mux: None,
+                    released: Some(Instant::now()),
+                };
+                tunnels.insert(id.clone(), tunnel);
+                Some((id, secret))
+            }
+        }
+    }
+
+    /// Messages that bring a viewer up to date: the size, then a terminal reset and the recent output
+    fn resync_frames(&self) -> crate::error::Result<Vec<Utf8Bytes>> {
+        let screen = self.screen();
+        let mut snapshot = b"\x1bc".to_vec();
+        snapshot.extend_from_slice(&screen.history);
+
+        let mut frames: Vec<Utf8Bytes> = screen.size.iter().cloned().collect();
+        frames.push(encode_write_message(&snapshot)?);
+        Ok(frames)
+    }
+
+    /// Remember what a message from the mirrored session changes for viewers that join later
+    fn record(&self, frame: &str) {
+        let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(frame) else {
+            return;
+        };
+        let mut screen = self.screen();
+        match tty_msg.msg_type.as_str() {
+            "WinSize" => screen.size = Some(frame.into()),
+            "Write" => {
+                let Some(output) = general_purpose::STANDARD
+                    .decode(&tty_msg.data)
+                    .ok()
+                    .and_then(|data| serde_json::from_slice::<WriteMessage>(&data).ok())
+                    .and_then(|write_msg| general_purpose::STANDARD.decode(&write_msg.data).ok())
+                else {
+                    return;
+                };
+                // A reset starts a new snapshot, e.g. when the session reconnects
+                if output.starts_with(b"\x1bc") {
+                    screen.history.clear();
+                }
+                screen.history.extend_from_slice(&output);
+                if screen.history.len() > MAX_HISTORY_SIZE {
+                    let start = screen.history.len() - MAX_HISTORY_SIZE;
+                    screen.history.drain(0..start);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Republish a session mirrored with `--mirror` to read-only viewers, and pass viewers on to sessions
+/// shared with `--public` through a tunnel, until interrupted.
+///
+/// Three links are printed: the page for viewers, the URL sessions mirror to, and the one they open
+/// tunnels at.
+pub async fn run(args: &Args) -> anyhow::Result<()> {
+    let identity_path = args.identity_file.clone().unwrap_or_else(default_identity_path);
+    let identity = Arc::new(ServerIdentity::load_or_generate(&identity_path)?);
+    let ttl = args.capability_ttl;
+    let view_token = capability::mint(&identity, &Capability::new(RELAY_SESSION_ID, AccessMode::ReadOnly, ttl))?;
+    let publish_token = capability::mint(
+        &identity,
+        &Capability::new(RELAY_SESSION_ID, AccessMode::ReadWrite, ttl),
+    )?;
+
+    let state = RelayState {
+        identity,
+        base_path: tls::base_path(args).into(),
+        frames: broadcast::channel(VIEWER_BUFFER).0,
+        screen: Arc::new(Mutex::new(Screen::default())),
+        tunnel_url: tls::page_url(args, TUNNEL_PATH).into(),
+        tunnels: Arc::default(),
+    };
+    let app = Router::new()
+        .route("/r/{token}/", get(serve_relay_page))
+        .route("/r/{token}/static/{*file}", get(serve_relay_static_file))
+        .route("/r/{token}/ws/", get(handle_viewer))
+        .route("/r/{token}/publish", get(handle_publisher))
+        .route("/r/{token}/tunnel", get(handle_tunnel))
+        .route("/t/{id}/", any(serve_tunneled))
+        .route("/t/{id}/{*path}", any(serve_tunneled))
+        .fallback(serve_404)
+        .with_state(state);
+
+    let options = ServeOptions::new(args, tls::acceptor(args)?);
+    let listener = TcpListener::bind(&args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    tls::spawn_http_redirect(args).await?;
+    println!(
+        "relay for viewers: {}",
+        tls::page_url(args, &format!("/r/{view_token}/"))
+    );
+    println!(
+        "mirror sessions with: rwshell --mirror {}",
+        tls::websocket_url(args, &format!("/r/{publish_token}/publish"))
+    );
+    println!(
+        "share sessions publicly with: rwshell --public {}",
+        tls::websocket_url(args, &format!("/r/{publish_token}/tunnel"))
+    );
+
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    serve(listener, app, options, shutdown).await?;
+    Ok(())
+}
+
+async fn serve_relay_page(Path(token): Path<String>, State(state): State<RelayState>) -> Response {
+    if !state.authorize(&token, false) {
+        return serve_404().await;
+    }
+    render_session_page(&format!("{}/r/{token}", state.base_path)).into_response()
+}
+
+async fn serve_relay_static_file(
+    Path((token, file)): Path<(String, String)>,
+    State(state): State<RelayState>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.authorize(&token, false) {
+        return serve_404().await;
+    }
+    serve_static_file(Path(file), headers).await
+}
+
+async fn handle_publisher(
+    ws: WebSocketUpgrade,
+    Path(token): Path<String>,
+    State(state): State<RelayState>,
+) -> Response {
+    if !state.authorize(&token, true) {
+        return serve_404().await;
+    }
+    ws.on_upgrade(move |socket| publish(socket, state))
+}
+
+/// Receive the output of a mirrored session and pass it on to viewers
+async fn publish(mut socket: WebSocket, state: RelayState) {
+    info!("A session started mirroring to the relay");
+    while let Some(message) = socket.recv().await {
+        match message {
+            Ok(Message::Text(frame)) => {
+                state.record(&frame);
+                let _ = state.frames.send(frame);
+            }
+            Ok(Message::Close(_)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Mirror connection failed: {}", e);
+                break;
+            }
+        }
+    }
+    info!("The session stopped mirroring to the relay");
+}
+
+async fn handle_viewer(ws: WebSocketUpgrade, Path(token): Path<String>, State(state): State<RelayState>) -> Response {
+    if !state.authorize(&token, false) {
+        return serve_404().await;
+    }
+    // Viewers asking for the binary protocol are accepted, and get the JSON messages it still allows
+    ws.max_message_size(MAX_VIEWER_MESSAGE)
+        .protocols([BINARY_PROTOCOL])
+        .on_upgrade(move |socket| watch(socket, state))
+}
+
+/// Send the mirrored session to a viewer, ignoring anything it sends
+async fn watch(mut socket: WebSocket, state: RelayState) {
+    let mut frames_rx = state.frames.subscribe();
+    let Ok(mut initial) = state.resync_frames() else {
+        return;
+    };
+    if let Ok(frame) = encode_message(
+        "ReadOnly",
+        &ReadOnlyMessage {
+            readonly: true,
+            can_request_write: false,
+            can_fork: false,
+        },
+    ) {
+        initial.insert(0, frame);
+    }
+    for frame in initial {
+        if socket.send(Message::Text(frame)).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let frames = tokio::select! {
+            frame = frames_rx.recv() => match frame {
+                Ok(frame) => vec![frame],
+                Err(broadcast::error::RecvError::Lagged(_)) => match state.resync_frames() {
+                    Ok(frames) => frames,
+                    Err(_) => break,
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        for frame in frames {
+            if socket.send(Message::Text(frame)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_tunnel(
+    ws: WebSocketUpgrade,
+    Path(token): Path<String>,
+    Query(query): Query<TunnelQuery>,
+    State(state): State<RelayState>,
+) -> Response {
+    if !state.authorize(&token, true) {
+        return serve_404().await;
+    }
+    let Some((id, secret)) = state.claim_tunnel(query) else {
+        debug!("Refused a session asking for a tunnel in use without its secret");
+        return (StatusCode::CONFLICT, "The tunnel is in use").into_response();
+    };
+    ws.on_upgrade(move |socket| carry_tunnel(socket, state, id, secret))
+}
+
+/// Pass viewers' connections to a session over its tunnel, until it disconnects. A session reconnecting
+/// with the ID and secret it had gets the tunnel back, even before the relay noticed its old connection
+/// is gone.
+async fn carry_tunnel(mut socket: WebSocket, state: RelayState, id: String, secret: String) {
+    let opened = TunnelOpened {
+        url: format!("{}/{id}/", state.tunnel_url),
+        id: id.clone(),
+        secret: secret.clone(),
+    };
+    let Ok(opened) = serde_json::to_string(&opened) else {
+        return;
+    };
+    if socket.send(Message::Text(opened.into())).await.is_err() {
+        return;
+    }
+
+    let (mux, mut outgoing_rx) = Mux::new();
+    let tunnel = Tunnel {
+        secret,
+        mux: Some(Arc::clone(&mux)),
+        released: None,
+    };
+    state.tunnels().insert(id.clone(), tunnel);
+    info!("A session opened tunnel {}", id);
+    loop {
+        tokio::select! {
+            Some(frame) = outgoing_rx.recv() => {
+                if socket.send(Message::Binary(frame)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Binary(frame))) => {
+                    // Only the relay opens streams
+                    let _ = mux.dispatch(frame);
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    if let Some(tunnel) = state.tunnels().get_mut(&id) {
+        if tunnel.mux.as_ref().is_some_and(|current| Arc::ptr_eq(current, &mux)) {
+            tunnel.mux = None;
+            tunnel.released = Some(Instant::now());
+            info!("The session of tunnel {} disconnected", id);
+        }
+    }
+    mux.close();
+}
+
+/// Pass a request for a tunneled session on to it
+async fn serve_tunneled(
+    Path(TunnelPath { id }): Path<TunnelPath>,
+    State(state): State<RelayState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+) -> Response {
+    let Some(mux) = state.tunnels().get(&id).and_then(|tunnel| tunnel.mux.clone()) else {
+        return serve_404().await;
+    };
+    // The session serves from its root, like behind a reverse proxy that strips its --base-url path
+    let path = request.uri().path_and_query().map_or("", |path| path.as_str());
+    let Some(Ok(uri)) = path
+        .strip_prefix(&format!("{TUNNEL_PATH}/{id}"))
+        .map(|path| path.parse())
+    else {
+        return serve_404().await;
+    };
+    *request.uri_mut() = uri;
+
+    match tunnel::proxy(&mux, peer, request).await {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("Failed to reach the session of tunnel {}: {}", id, e);
+            (StatusCode::BAD_GATEWAY, "The session can't be reached").into_response()
+        }
+    }
+}
+
+/// Compare tunnel secrets in constant time
+fn secrets_match(expected: &str, candidate: &str) -> bool {
+    let (expected, candidate) = (expected.as_bytes(), candidate.as_bytes());
+    expected.len() == candidate.len() && expected.iter().zip(candidate).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,297 @@
+use crate::history::{color_rgb, row_html};
+use crate::recording::{Event, Recording};
+use anyhow::{Context, bail};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Shortest time between two frames of a GIF, in seconds; browsers slow down faster GIFs
+const FRAME_INTERVAL: f64 = 1.0 / 25.0;
+
+/// How long the last frame is shown before the GIF starts over, in hundredths of a second
+const LAST_FRAME_DELAY: u16 = 300;
+
+/// Space around the screen, in pixels
+const PADDING: usize = 8;
+
+/// Colors of text and background without attributes
+const DEFAULT_FOREGROUND: [u8; 3] = [0xff, 0xff, 0xff];
+const DEFAULT_BACKGROUND: [u8; 3] = [0x00, 0x00, 0x00];
+
+/// Monospace fonts tried when none is given
+const FONT_CANDIDATES: [&str; 6] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+    "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+    "/usr/share/fonts/dejavu-sans-mono-fonts/DejaVuSansMono.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationMono-Regular.ttf",
+    "/usr/share/fonts/truetype/noto/NotoSansMono-Regular.ttf",
+    "/Library/Fonts/Courier New.ttf",
+];
+
+/// Draws terminal screens into images with the 256 colors of the xterm palette, as GIF frames use
+struct Painter {
+    font: fontdue::Font,
+    size: f32,
+    cell_width: usize,
+    cell_height: usize,
+    baseline: usize,
+    glyphs: HashMap<char, (fontdue::Metrics, Vec<u8>)>,
+    /// Palette index closest to each color used so far
+    palette_indexes: HashMap<[u8; 3], u8>,
+}
+
+impl Painter {
+    fn new(font_path: &Path, size: f32) -> anyhow::Result<Self> {
+        let data = std::fs::read(font_path).with_context(|| format!("Failed to read {}", font_path.display()))?;
+        let font = fontdue::Font::from_bytes(data, fontdue::FontSettings::default())
+            .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", font_path.display(), e))?;
+        let Some(line) = font.horizontal_line_metrics(size) else {
+            bail!("{} has no horizontal metrics", font_path.display());
+        };
+        let cell_width = font.metrics('M', size).advance_width.ceil() as usize;
+
+        Ok(Self {
+            size,
+            cell_width,
+            cell_height: line.new_line_size.ceil() as usize,
+            baseline: line.ascent.ceil() as usize,
+            font,
+            glyphs: HashMap::new(),
+            palette_indexes: HashMap::new(),
+        })
+    }
+
+    fn image_size(&self, (cols, rows): (u16, u16)) -> (usize, usize) {
+        (
+            cols as usize * self.cell_width + 2 * PADDING,
+            rows as usize * self.cell_height + 2 * PADDING,
+        )
+    }
+
+    /// Palette index of the palette color closest to `color`
+    fn index(&mut self, color: [u8; 3]) -> u8 {
+        *self.palette_indexes.entry(color).or_insert_with(|| {
+            let distance = |other: [u8; 3]| (0..3).map(|i| (color[i] as i32 - other[i] as i32).pow(2)).sum::<i32>();
+            (0..=255u8).min_by_key(|&idx| distance(palette_color(idx))).unwrap_or(0)
+        })
+    }
+
+    /// Draw row `row` of `screen` into `image`, `width` pixels wide, with the cursor if it is on the row
+    fn paint_row(&mut self, image: &mut [u8], width: usize, screen: &vt100::Screen, row: u16) {
+        let (_, cols) = screen.size();
+        let top = PADDING + row as usize * self.cell_height;
+        let cursor = (!screen.hide_cursor()).then(|| screen.cursor_position());
+
+        for col in 0..cols {
+            let Some(cell) = screen.cell(row, col) else {
+                continue;
+            };
+            if cell.is_wide_continuation() {
+                continue;
+            }
+            let (mut fg, mut bg) = cell_colors(cell);
+            if cursor == Some((row, col)) {
+                (fg, bg) = (bg, fg);
+            }
+            let cells = if cell.is_wide() { 2 } else { 1 };
+            let left = PADDING + col as usize * self.cell_width;
+            let right = (left + cells * self.cell_width).min(width - PADDING);
+
+            let bg_index = self.index(bg);
+            for y in top..top + self.cell_height {
+                image[y * width + left..y * width + right].fill(bg_index);
+            }
+
+            let Some(ch) = cell.contents().chars().next().filter(|ch| !ch.is_whitespace()) else {
+                continue;
+            };
+            let size = self.size;
+            let font = &self.font;
+            let (metrics, coverage) = self
+                .glyphs
+                .entry(ch)
+                .or_insert_with(|| font.rasterize(ch, size))
+                .clone();
+            let glyph_top = self.baseline as i32 - metrics.height as i32 - metrics.ymin;
+            for gy in 0..metrics.height {
+                let y = top as i32 + glyph_top + gy as i32;
+                if y < top as i32 || y >= (top + self.cell_height) as i32 {
+                    continue;
+                }
+                for gx in 0..metrics.width {
+                    let x = left as i32 + metrics.xmin + gx as i32;
+                    if x < left as i32 || x >= right as i32 {
+                        continue;
+                    }
+                    let alpha = coverage[gy * metrics.width + gx] as u32;
+                    if alpha == 0 {
+                        continue;
+                    }
+                    let blended =
+                        std::array::from_fn(|i| ((fg[i] as u32 * alpha + bg[i] as u32 * (255 - alpha)) / 255) as u8);
+                    image[y as usize * width + x as usize] = self.index(blended);
+                }
+            }
+        }
+    }
+}
+
+/// Color of palette index `idx`
+fn palette_color(idx: u8) -> [u8; 3] {
+    color_rgb(vt100::Color::Idx(idx)).unwrap_or(DEFAULT_BACKGROUND)
+}
+
+/// Foreground and background color of `cell`, after bold, dim and inverse
+fn cell_colors(cell: &vt100::Cell) -> ([u8; 3], [u8; 3]) {
+    let fg = match cell.fgcolor() {
+        // Bold text in one of the first 8 colors is shown in its bright variant, as most terminals do
+        vt100::Color::Idx(idx @ 0..=7) if cell.bold() => palette_color(idx + 8),
+        color => color_rgb(color).unwrap_or(DEFAULT_FOREGROUND),
+    };
+    let bg = color_rgb(cell.bgcolor()).unwrap_or(DEFAULT_BACKGROUND);
+    let fg = if cell.dim() {
+        std::array::from_fn(|i| ((fg[i] as u16 + bg[i] as u16) / 2) as u8)
+    } else {
+        fg
+    };
+    if cell.inverse() { (bg, fg) } else { (fg, bg) }
+}
+
+/// The font given, or the first monospace font found on the system
+fn find_font(font: Option<&Path>) -> anyhow::Result<PathBuf> {
+    if let Some(font) = font {
+        return Ok(font.to_path_buf());
+    }
+    FONT_CANDIDATES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+        .context("No monospace font found; give one with --font")
+}
+
+/// An image waiting for the time of the next one, which decides how long it is shown
+struct PendingFrame {
+    time: f64,
+    top: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+/// Render the recording at `cast` to an animated GIF at `out`, drawing text with `font` at `font_size`
+/// pixels
+pub fn gif(cast: &Path, out: &Path, font: Option<&Path>, font_size: f32) -> anyhow::Result<()> {
+    let recording = Recording::load(cast)?;
+    let mut painter = Painter::new(&find_font(font)?, font_size)?;
+
+    // A GIF can't change size, so it fits the largest screen of the recording
+    let (width, height) = painter.image_size(recording.max_size());
+    if width > u16::MAX as usize || height > u16::MAX as usize {
+        bail!("The recording is too large for a GIF at this font size");
+    }
+
+    let palette: Vec<u8> = (0..=255u8).flat_map(palette_color).collect();
+    let file = File::create(out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let mut encoder = gif::Encoder::new(BufWriter::new(file), width as u16, height as u16, &palette)?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+
+    let (mut cols, mut rows) = (recording.width, recording.height);
+    let mut parser = vt100::Parser::new(rows, cols, 0);
+    let mut image = vec![painter.index(DEFAULT_BACKGROUND); width * height];
+    let mut pending = PendingFrame {
+        time: 0.0,
+        top: 0,
+        height,
+        pixels: image.clone(),
+    };
+    // What each row showed in the last frame, so only rows that changed are drawn again
+    let mut shown: Vec<Option<String>> = vec![None; rows as usize];
+    let mut full = true;
+
+    let mut events = recording.events.iter().peekable();
+    while let Some((time, event)) = events.next() {
+        match event {
+            Event::Output(data) => parser.process(data.as_bytes()),
+            Event::Resize(new_cols, new_rows) => {
+                (cols, rows) = (*new_cols, *new_rows);
+                parser.screen_mut().set_size(rows, cols);
+                // Clear what the old size left outside the new one
+                image.fill(painter.index(DEFAULT_BACKGROUND));
+                shown = vec![None; rows as usize];
+                full = true;
+            }
+        }
+
+        // Let output arriving in quick succession make up a single frame
+        if events
+            .peek()
+            .is_some_and(|(next, _)| next - pending.time < FRAME_INTERVAL)
+        {
+            continue;
+        }
+
+        let screen = parser.screen();
+        let cursor_row = (!screen.hide_cursor()).then(|| screen.cursor_position());
+        let mut changed: Option<(u16, u16)> = None;
+        for row in 0..rows {
+            let mut content = row_html(screen, row, cols);
+            if let Some((cursor_row, cursor_col)) = cursor_row.filter(|(cursor_row, _)| *cursor_row == row) {
+                content.push_str(&format!("\0{cursor_row},{cursor_col}"));
+            }
+            if shown[row as usize].as_ref() == Some(&content) {
+                continue;
+            }
+            painter.paint_row(&mut image, width, screen, row);
+            shown[row as usize] = Some(content);
+            changed = Some(changed.map_or((row, row), |(first, _)| (first, row)));
+        }
+
+        // Only the band of rows that changed is stored in the frame
+        let (top, band_height) = match changed {
+            _ if full => (0, height),
+            Some((first, last)) => (
+                PADDING + first as usize * painter.cell_height,
+                (last - first + 1) as usize * painter.cell_height,
+            ),
+            None => continue,
+        };
+        full = false;
+
+        write_frame(&mut encoder, width, &pending, *time)?;
+        pending = PendingFrame {
+            time: *time,
+            top,
+            height: band_height,
+            pixels: image[top * width..(top + band_height) * width].to_vec(),
+        };
+    }
+
+    write_frame(
+        &mut encoder,
+        width,
+        &pending,
+        pending.time + LAST_FRAME_DELAY as f64 / 100.0,
+    )?;
+    Ok(())
+}
+
+/// Write `frame`, shown until `next_time`
+fn write_frame<W: std::io::Write>(
+    encoder: &mut gif::Encoder<W>,
+    width: usize,
+    frame: &PendingFrame,
+    next_time: f64,
+) -> anyhow::Result<()> {
+    let delay = ((next_time - frame.time) * 100.0).round().clamp(2.0, u16::MAX as f64) as u16;
+    encoder.write_frame(&gif::Frame {
+        delay,
+        dispose: gif::DisposalMethod::Keep,
+        top: frame.top as u16,
+        width: width as u16,
+        height: frame.height as u16,
+        buffer: Cow::Borrowed(&frame.pixels),
+        ..gif::Frame::default()
+    })?;
+    Ok(())
+}
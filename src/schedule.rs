@@ -0,0 +1,170 @@
+use crate::compression::Compression;
+use crate::server::{AppState, PtyEvent, encode_write_message};
+use bytes::Bytes;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// What starts the command of a session created ahead of time
+#[derive(Debug, Clone, Copy)]
+pub enum StartTrigger {
+    /// The time given with `--start-at`
+    At(SystemTime),
+    /// The first viewer connecting, with `--start-on-connect`
+    FirstViewer,
+}
+
+/// Holds back the command of the main session until its trigger fires, while viewers can already open
+/// the session URL
+pub struct StartSchedule {
+    trigger: StartTrigger,
+    started: watch::Sender<bool>,
+}
+
+impl StartSchedule {
+    pub fn new(trigger: StartTrigger) -> Self {
+        Self {
+            trigger,
+            started: watch::Sender::new(false),
+        }
+    }
+
+    /// Note that a viewer connected, which starts the command with `--start-on-connect`
+    pub fn viewer_connected(&self) {
+        if matches!(self.trigger, StartTrigger::FirstViewer) {
+            self.started.send_replace(true);
+        }
+    }
+
+    /// Wait until the command is due to start
+    pub async fn wait(&self) {
+        let mut started = self.started.subscribe();
+        if let StartTrigger::At(time) = self.trigger {
+            let delay = time.duration_since(SystemTime::now()).unwrap_or_default();
+            tokio::time::sleep(delay).await;
+            self.started.send_replace(true);
+        }
+        let _ = started.wait_for(|started| *started).await;
+    }
+
+    /// When the command starts, e.g. "at 2026-03-01 14:30"
+    pub fn describe(&self) -> String {
+        match self.trigger {
+            StartTrigger::At(time) => format!("at {}", local_time(time)),
+            StartTrigger::FirstViewer => "when the first viewer connects".to_string(),
+        }
+    }
+
+    /// What viewers see until the command starts
+    pub fn notice(&self) -> String {
+        format!("\r\n  Waiting for the session: it starts {}\r\n", self.describe())
+    }
+}
+
+/// Show `data` to viewers as if the command had written it
+pub async fn show(state: &AppState, data: &[u8]) {
+    let data = Bytes::copy_from_slice(data);
+    state.screen.feed(&data, *state.current_size.lock().await);
+    {
+        let mut history = state.history.lock().await;
+        history.extend_from_slice(&data);
+        if let Some(history_account) = &state.history_account {
+            history_account.account().add(data.len());
+        }
+    }
+    if let Ok(frame) = encode_write_message(&data) {
+        let _ = state.pty_tx.send(PtyEvent::Output {
+            data: data.clone(),
+            frame,
+            compressed: (state.compression != Compression::None).then(|| state.compression.frame(&data)),
+        });
+    }
+}
+
+/// Take the waiting notice off the viewers' screens once the command starts
+pub async fn clear(state: &AppState) {
+    {
+        let mut history = state.history.lock().await;
+        history.clear();
+        if let Some(history_account) = &state.history_account {
+            history_account.account().clear();
+        }
+    }
+    show(state, b"\x1b[H\x1b[2J").await;
+}
+
+/// Parse `--start-at`: a time of day such as "14:30" (the next one to come, in local time), an RFC 3339
+/// time such as "2026-03-01T14:30:00Z", or a delay such as "15m"
+pub fn parse_start_time(value: &str) -> Result<SystemTime, String> {
+    if let Some(time) = next_time_of_day(value) {
+        return Ok(time);
+    }
+    if let Ok(time) = humantime::parse_rfc3339(value) {
+        return Ok(time);
+    }
+    if let Ok(delay) = humantime::parse_duration(value) {
+        return Ok(SystemTime::now() + delay);
+    }
+    Err(format!(
+        "expected a time of day such as 14:30, a UTC time such as 2026-03-01T14:30:00Z or a delay such as 15m, not {value:?}"
+    ))
+}
+
+/// The next time the clock shows `HH:MM` or `HH:MM:SS`, today or tomorrow
+fn next_time_of_day(value: &str) -> Option<SystemTime> {
+    let mut parts = value.split(':').map(|part| part.parse::<i32>().ok());
+    let (hour, minute) = (parts.next()??, parts.next()??);
+    let second = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() || !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+        return None;
+    }
+
+    let now = SystemTime::now();
+    let mut tm = local_tm(now)?;
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+    tm.tm_isdst = -1;
+    // SAFETY: tm is a valid, initialized struct tm
+    let mut time = unsafe { libc::mktime(&mut tm) };
+    if time <= now.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t {
+        tm.tm_mday += 1;
+        tm.tm_hour = hour;
+        tm.tm_min = minute;
+        tm.tm_sec = second;
+        tm.tm_isdst = -1;
+        // SAFETY: as above; mktime normalizes the day past the end of the month
+        time = unsafe { libc::mktime(&mut tm) };
+    }
+    (time >= 0).then(|| UNIX_EPOCH + Duration::from_secs(time as u64))
+}
+
+/// `time` in the local time zone, e.g. "2026-03-01 14:30", with seconds unless they are 0
+fn local_time(time: SystemTime) -> String {
+    match local_tm(time) {
+        Some(tm) => {
+            let seconds = if tm.tm_sec == 0 {
+                String::new()
+            } else {
+                format!(":{:02}", tm.tm_sec)
+            };
+            format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}{seconds}",
+                tm.tm_year + 1900,
+                tm.tm_mon + 1,
+                tm.tm_mday,
+                tm.tm_hour,
+                tm.tm_min
+            )
+        }
+        None => humantime::format_rfc3339_seconds(time).to_string(),
+    }
+}
+
+fn local_tm(time: SystemTime) -> Option<libc::tm> {
+    let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+    // SAFETY: an all-zero struct tm is valid, and localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    let result = unsafe { libc::localtime_r(&seconds, &mut tm) };
+    (!result.is_null()).then_some(tm)
+}
@@ -0,0 +1,52 @@
+use crate::history;
+use std::sync::Mutex;
+
+/// The session's screen as viewers see it, kept up to date from the output sent to them so clients that
+/// join or fall behind can be shown the current screen instead of a tail of raw output
+pub struct TerminalScreen {
+    parser: Mutex<vt100::Parser>,
+}
+
+impl TerminalScreen {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            parser: Mutex::new(vt100::Parser::new(rows, cols, 0)),
+        }
+    }
+
+    /// Apply output sent to viewers while the terminal was `cols` x `rows`
+    pub fn feed(&self, data: &[u8], (cols, rows): (u16, u16)) {
+        let mut parser = self.parser.lock().unwrap_or_else(|e| e.into_inner());
+        if parser.screen().size() != (rows, cols) {
+            parser.screen_mut().set_size(rows, cols);
+        }
+        parser.process(data);
+    }
+
+    /// Output that redraws the screen at `cols` x `rows` on a terminal in any state: a reset, the lines
+    /// `history` left in the scrollback, the alternate screen if the program uses it, then the contents
+    /// with their colors, the cursor and the input modes
+    pub fn snapshot(&self, (cols, rows): (u16, u16), history: &[u8]) -> Vec<u8> {
+        let mut snapshot = b"\x1bc".to_vec();
+        let scrollback = history::scrollback_formatted(history, (cols, rows));
+        if !scrollback.is_empty() {
+            for line in scrollback {
+                snapshot.extend_from_slice(&line);
+                snapshot.extend_from_slice(b"\x1b[m\r\n");
+            }
+            // Scroll the last lines off the screen too, so the contents below don't draw over them
+            snapshot.extend_from_slice(&b"\r\n".repeat(usize::from(rows.saturating_sub(1))));
+        }
+
+        let mut parser = self.parser.lock().unwrap_or_else(|e| e.into_inner());
+        if parser.screen().size() != (rows, cols) {
+            parser.screen_mut().set_size(rows, cols);
+        }
+        let screen = parser.screen();
+        if screen.alternate_screen() {
+            snapshot.extend_from_slice(b"\x1b[?1049h");
+        }
+        snapshot.extend_from_slice(&screen.state_formatted());
+        snapshot
+    }
+}
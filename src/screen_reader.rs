@@ -0,0 +1,121 @@
+use crate::server::{AppState, PtyEvent, encode_message};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+/// Quiet period after output before changed lines are announced, so typing isn't read out per keystroke
+const SETTLE_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest time changes are held back while output keeps arriving
+const MAX_SETTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Lines of text that appeared or changed on the screen, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenTextMessage {
+    #[serde(rename = "Lines")]
+    pub lines: Vec<String>,
+}
+
+/// Follows the terminal screen and turns its changes into lines of plain text for screen readers
+#[derive(Clone)]
+pub struct ScreenReader {
+    parser: Arc<Mutex<vt100::Parser>>,
+    changed: Arc<Notify>,
+}
+
+impl ScreenReader {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            parser: Arc::new(Mutex::new(vt100::Parser::new(rows, cols, 0))),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Apply PTY output produced while the terminal was `cols` x `rows`
+    pub fn feed(&self, data: &[u8], (cols, rows): (u16, u16)) {
+        let mut parser = self.parser.lock().unwrap_or_else(|e| e.into_inner());
+        if parser.screen().size() != (rows, cols) {
+            parser.screen_mut().set_size(rows, cols);
+        }
+        parser.process(data);
+        drop(parser);
+        self.changed.notify_one();
+    }
+
+    /// Current contents of the screen, one line per row without trailing blanks
+    pub fn lines(&self) -> Vec<String> {
+        let parser = self.parser.lock().unwrap_or_else(|e| e.into_inner());
+        let (_, cols) = parser.screen().size();
+        parser
+            .screen()
+            .rows(0, cols)
+            .map(|row| row.trim_end().to_string())
+            .collect()
+    }
+
+    /// Broadcast the lines that changed once output settles, until `shutdown` is cancelled
+    pub fn start(&self, state: &AppState, shutdown: CancellationToken) {
+        let reader = self.clone();
+        let pty_tx = state.pty_tx.clone();
+        state.supervisor.spawn("screen-reader", async move {
+            let mut previous = reader.lines();
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = reader.changed.notified() => {}
+                }
+
+                // Wait for the output to settle
+                let deadline = tokio::time::Instant::now() + MAX_SETTLE_DELAY;
+                while let Ok(()) = tokio::time::timeout(SETTLE_DELAY, reader.changed.notified()).await {
+                    if tokio::time::Instant::now() >= deadline {
+                        break;
+                    }
+                }
+
+                let current = reader.lines();
+                let lines = changed_lines(&previous, &current);
+                previous = current;
+                if lines.is_empty() {
+                    continue;
+                }
+
+                match encode_message("ScreenText", &ScreenTextMessage { lines: lines.clone() }) {
+                    Ok(frame) => {
+                        let _ = pty_tx.send(PtyEvent::ScreenText {
+                            lines: Arc::new(lines),
+                            frame,
+                        });
+                    }
+                    Err(e) => error!("Failed to encode screen text: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Lines of `current` that are new compared to `previous`, taking scrolling into account
+fn changed_lines(previous: &[String], current: &[String]) -> Vec<String> {
+    // Find how far the screen scrolled: the shift under which most rows are unchanged
+    let shift = (0..previous.len())
+        .max_by_key(|&shift| {
+            let unchanged = previous[shift..]
+                .iter()
+                .zip(current)
+                .filter(|(before, after)| before == after && !after.is_empty())
+                .count();
+            // Prefer the smallest shift among equally good ones
+            (unchanged, std::cmp::Reverse(shift))
+        })
+        .unwrap_or(0);
+
+    current
+        .iter()
+        .enumerate()
+        .filter(|(row, line)| !line.is_empty() && previous.get(row + shift) != Some(line))
+        .map(|(_, line)| line.clone())
+        .collect()
+}
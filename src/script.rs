@@ -0,0 +1,253 @@
+use crate::host::HostMode;
+use crate::transform::{OutputStage, StripAnsi};
+use anyhow::Context;
+use mlua::{Function, HookTriggers, Lua, Value};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Longest output line passed to `on_output_line`; longer lines are split
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Most Lua instructions a hook call, or the script's top level, may run before it's stopped
+const INSTRUCTION_LIMIT: u32 = 10_000_000;
+
+/// Instructions between checks of the instruction limit
+const INSTRUCTION_STEP: u32 = 10_000;
+
+/// Checks of the instruction limit left to the running call
+struct InstructionBudget(u32);
+
+/// A viewer, as described to hooks
+pub struct ScriptClient {
+    pub id: String,
+    pub address: IpAddr,
+    pub readonly: bool,
+}
+
+/// What to do with input a viewer sent
+pub enum InputVerdict {
+    /// Write the input to the PTY unchanged
+    Allow,
+    /// Write this instead
+    Replace(Vec<u8>),
+    /// Discard the input
+    Drop,
+}
+
+struct ScriptState {
+    lua: Lua,
+    /// Turns output into plain text for `on_output_line`
+    strip: StripAnsi,
+    /// Text of the output line still being written
+    line: Vec<u8>,
+}
+
+impl ScriptState {
+    /// The hook named `name`, if the script defines one
+    fn hook(&self, name: &str) -> Option<Function<'_>> {
+        self.lua.globals().get::<_, Option<Function>>(name).ok().flatten()
+    }
+}
+
+/// Lua functions called on session events, loaded from `--script`.
+///
+/// The script defines any of these global functions:
+/// - `on_client_join(client)`: `client` has `id`, `address` and `readonly`; return `false` to refuse
+///   the viewer
+/// - `on_input(client, data)`: input a viewer typed; return `false` to drop it or a string to write
+///   instead
+/// - `on_output_line(line)`: each line of output, as plain text
+/// - `on_exit(code)`: the exit code of the shared program
+///
+/// Errors raised by a hook are logged and the event is handled as if the hook didn't exist. So are hooks
+/// that run more than ten million instructions, which are stopped.
+pub struct ScriptHooks {
+    name: String,
+    state: Mutex<ScriptState>,
+    has_output_line: bool,
+}
+
+impl ScriptHooks {
+    pub fn load(path: &Path, host: Arc<dyn HostMode>) -> anyhow::Result<Self> {
+        let source =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read script {}", path.display()))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+
+        let lua = Lua::new();
+        register_api(&lua, &name, host).context("Failed to set up the script API")?;
+        limit_instructions(&lua);
+        lua.load(source.as_str())
+            .set_name(name.as_str())
+            .exec()
+            .with_context(|| format!("Failed to run script {}", path.display()))?;
+
+        let state = ScriptState {
+            lua,
+            strip: StripAnsi::new(),
+            line: Vec::new(),
+        };
+        let has_output_line = state.hook("on_output_line").is_some();
+        Ok(Self {
+            name,
+            state: Mutex::new(state),
+            has_output_line,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ScriptState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn hook_failed(&self, hook: &str, e: mlua::Error) {
+        warn!("Script {}: {} failed: {}", self.name, hook, e);
+    }
+
+    /// Ask `on_client_join` whether a viewer may join
+    pub fn client_join(&self, client: &ScriptClient) -> bool {
+        let state = self.lock();
+        let Some(hook) = state.hook("on_client_join") else {
+            return true;
+        };
+        refuel(&state.lua);
+        let result = client_table(&state.lua, client).and_then(|client| hook.call::<_, Value>(client));
+        match result {
+            Ok(Value::Boolean(false)) => false,
+            Ok(_) => true,
+            Err(e) => {
+                self.hook_failed("on_client_join", e);
+                true
+            }
+        }
+    }
+
+    /// Run input from a viewer through `on_input`
+    pub fn input(&self, client: &ScriptClient, data: &[u8]) -> InputVerdict {
+        let state = self.lock();
+        let Some(hook) = state.hook("on_input") else {
+            return InputVerdict::Allow;
+        };
+        refuel(&state.lua);
+        let result = client_table(&state.lua, client).and_then(|client| {
+            let data = state.lua.create_string(data)?;
+            hook.call::<_, Value>((client, data))
+        });
+        match result {
+            Ok(Value::Boolean(false)) => InputVerdict::Drop,
+            Ok(Value::String(replacement)) => InputVerdict::Replace(replacement.as_bytes().to_vec()),
+            Ok(_) => InputVerdict::Allow,
+            Err(e) => {
+                self.hook_failed("on_input", e);
+                InputVerdict::Allow
+            }
+        }
+    }
+
+    /// Pass complete lines of `data` to `on_output_line`
+    pub fn output(&self, data: &[u8]) {
+        if !self.has_output_line {
+            return;
+        }
+
+        let mut state = self.lock();
+        let mut text = Vec::with_capacity(data.len());
+        state.strip.process(data, &mut text);
+
+        let mut lines = Vec::new();
+        for &byte in &text {
+            match byte {
+                b'\n' => lines.push(std::mem::take(&mut state.line)),
+                b'\r' => {}
+                _ => {
+                    state.line.push(byte);
+                    if state.line.len() >= MAX_LINE_LENGTH {
+                        lines.push(std::mem::take(&mut state.line));
+                    }
+                }
+            }
+        }
+
+        let Some(hook) = state.hook("on_output_line") else {
+            return;
+        };
+        for line in lines {
+            refuel(&state.lua);
+            let result = state.lua.create_string(&line).and_then(|line| hook.call::<_, ()>(line));
+            if let Err(e) = result {
+                self.hook_failed("on_output_line", e);
+            }
+        }
+    }
+
+    /// Tell `on_exit` how the shared program exited
+    pub fn exit(&self, code: u32) {
+        let state = self.lock();
+        let Some(hook) = state.hook("on_exit") else {
+            return;
+        };
+        refuel(&state.lua);
+        if let Err(e) = hook.call::<_, ()>(code) {
+            self.hook_failed("on_exit", e);
+        }
+    }
+}
+
+/// Stop any call into `lua` that runs more than `INSTRUCTION_LIMIT` instructions since it was last refueled
+fn limit_instructions(lua: &Lua) {
+    refuel(lua);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(INSTRUCTION_STEP),
+        |lua, _| match lua.app_data_mut::<InstructionBudget>() {
+            Some(mut budget) if budget.0 > 0 => {
+                budget.0 -= 1;
+                Ok(())
+            }
+            _ => Err(mlua::Error::RuntimeError(format!(
+                "ran more than {INSTRUCTION_LIMIT} instructions"
+            ))),
+        },
+    );
+}
+
+/// Grant the next call into `lua` the full instruction limit
+fn refuel(lua: &Lua) {
+    lua.set_app_data(InstructionBudget(INSTRUCTION_LIMIT / INSTRUCTION_STEP));
+}
+
+/// Describe a viewer to a hook
+fn client_table<'lua>(lua: &'lua Lua, client: &ScriptClient) -> mlua::Result<mlua::Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("id", client.id.as_str())?;
+    table.set("address", client.address.to_string())?;
+    table.set("readonly", client.readonly)?;
+    Ok(table)
+}
+
+/// The `rwshell` table offered to scripts: `rwshell.log(message)` and `rwshell.notify(message)`
+fn register_api(lua: &Lua, name: &str, host: Arc<dyn HostMode>) -> mlua::Result<()> {
+    let api = lua.create_table()?;
+
+    let script = name.to_string();
+    api.set(
+        "log",
+        lua.create_function(move |_, message: String| {
+            info!("Script {}: {}", script, message);
+            Ok(())
+        })?,
+    )?;
+
+    let script = name.to_string();
+    api.set(
+        "notify",
+        lua.create_function(move |_, message: String| {
+            host.notify(&format!("Script {script}: {message}"));
+            Ok(())
+        })?,
+    )?;
+
+    lua.globals().set("rwshell", api)
+}
@@ -0,0 +1,197 @@
+use crate::args::Args;
+use crate::tls::ClientCertificate;
+use axum::Router;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::debug;
+
+/// How long open connections get to finish once shutdown has been requested
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Connection level settings for the HTTP server
+#[derive(Clone)]
+pub struct ServeOptions {
+    /// Time allowed for a client to send complete request headers, including WebSocket upgrades, and
+    /// to complete the TLS handshake
+    pub header_read_timeout: Duration,
+    /// Terminates TLS on accepted connections, if serving HTTPS
+    pub tls: Option<TlsAcceptor>,
+    /// Send small writes such as echoed keystrokes right away instead of batching them (Nagle's algorithm)
+    pub tcp_nodelay: bool,
+    /// Probe connections silent for this long, see [`set_tcp_keepalive`]
+    pub tcp_keepalive: Duration,
+    /// Keep HTTP/1.1 connections open for further requests
+    pub keep_alive: bool,
+    /// Most concurrent streams per HTTP/2 connection, if not hyper's default
+    pub http2_max_streams: Option<u32>,
+    /// Time between pings on idle HTTP/2 connections, if they are pinged
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Time to wait for the answer to a ping before closing an HTTP/2 connection
+    pub http2_keep_alive_timeout: Duration,
+}
+
+impl ServeOptions {
+    /// The connection settings given with `args`, serving HTTPS with `tls` if given
+    pub fn new(args: &Args, tls: Option<TlsAcceptor>) -> Self {
+        Self {
+            header_read_timeout: args.handshake_timeout,
+            tls,
+            tcp_nodelay: args.tcp_nodelay,
+            tcp_keepalive: args.tcp_keepalive,
+            keep_alive: args.keep_alive,
+            http2_max_streams: args.http2_max_streams,
+            http2_keep_alive_interval: args.http2_keep_alive_interval,
+            http2_keep_alive_timeout: args.http2_keep_alive_timeout,
+        }
+    }
+}
+
+/// Unanswered keepalive probes after which a connection is considered dead
+const TCP_KEEPALIVE_RETRIES: u32 = 3;
+
+/// Have the kernel probe `stream` once it has been silent for `idle`, and drop it once a few probes in a
+/// row go unanswered, e.g. after a NAT forgot the connection. A dead peer is then noticed within about
+/// twice `idle` instead of only when something is next written, if ever. Zero leaves keepalive off.
+pub fn set_tcp_keepalive(stream: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    if idle.is_zero() {
+        return Ok(());
+    }
+    let interval = (idle / TCP_KEEPALIVE_RETRIES).max(Duration::from_secs(1));
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(idle)
+        .with_interval(interval)
+        .with_retries(TCP_KEEPALIVE_RETRIES);
+    socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Hyper's connection settings from `options`
+fn connection_builder(options: &ServeOptions) -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(options.header_read_timeout)
+        .keep_alive(options.keep_alive);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(options.http2_max_streams)
+        .keep_alive_interval(options.http2_keep_alive_interval)
+        .keep_alive_timeout(options.http2_keep_alive_timeout);
+    builder
+}
+
+/// Serve `app` on `listener` until `shutdown` completes.
+///
+/// This is equivalent to `axum::serve`, but exposes the hyper connection settings that
+/// `axum::serve` doesn't, so idle or half-sent requests are dropped instead of accumulating.
+pub async fn serve<F>(listener: TcpListener, app: Router, options: ServeOptions, shutdown: F) -> std::io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let builder = connection_builder(&options);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        debug!("Accepted connection from {}", remote_addr);
+        if let Err(e) = stream.set_nodelay(options.tcp_nodelay) {
+            debug!("Failed to set TCP_NODELAY for {}: {}", remote_addr, e);
+        }
+        if let Err(e) = set_tcp_keepalive(&stream, options.tcp_keepalive) {
+            debug!("Failed to enable TCP keepalive for {}: {}", remote_addr, e);
+        }
+
+        // Make the client address available to handlers and middleware, like `axum::serve` does, and the
+        // name on its certificate if it presented one
+        let app = app.clone();
+        let service = move |certificate: Option<ClientCertificate>| {
+            TowerToHyperService::new(app.map_request(move |mut request: Request<_>| {
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                if let Some(certificate) = &certificate {
+                    request.extensions_mut().insert(certificate.clone());
+                }
+                request
+            }))
+        };
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        let options = options.clone();
+
+        tokio::spawn(async move {
+            let result = match options.tls {
+                Some(acceptor) => {
+                    let stream = match tokio::time::timeout(options.header_read_timeout, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", remote_addr);
+                            return;
+                        }
+                    };
+                    let service = service(ClientCertificate::of(stream.get_ref().1));
+                    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                    watcher.watch(connection.into_owned()).await
+                }
+                None => {
+                    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service(None));
+                    watcher.watch(connection.into_owned()).await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Connection from {} ended with error: {}", remote_addr, e);
+            }
+        });
+    }
+
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        debug!("Timed out waiting for connections to close");
+    }
+
+    Ok(())
+}
+
+/// Serve `app` on a single connection from `remote_addr` that didn't come in on a listener, such as one
+/// carried over a `--public` tunnel, until either side closes it
+pub async fn serve_io<I>(io: I, remote_addr: SocketAddr, app: Router, options: &ServeOptions)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app.map_request(move |mut request: Request<_>| {
+        request.extensions_mut().insert(ConnectInfo(remote_addr));
+        request
+    }));
+    let builder = connection_builder(options);
+    if let Err(e) = builder.serve_connection_with_upgrades(TokioIo::new(io), service).await {
+        debug!("Connection from {} ended with error: {}", remote_addr, e);
+    }
+}
@@ -1,40 +1,209 @@
-use crate::args::Args;
-use crate::assets::Assets;
+use crate::admin::{ClientConnection, ClientInfo, require_admin_token};
+use crate::api;
+use crate::approval::ApprovalGate;
+use crate::args::{Args, Subcommand};
+use crate::assets::{self, Assets};
+use crate::auth::{AuthToken, require_token};
+use crate::budget::{HistoryAccount, MemoryBudget};
+use crate::capability::{self, AccessMode, Capability, Quota, QuotaConnection, TokenBindings, TokenQuotas, TokenUse};
+use crate::compression::Compression;
+use crate::confirm::{self, ConfirmGate, ConfirmationMessage, LineBuffer};
+use crate::control;
+use crate::fingerprint::ClientFingerprint;
+use crate::follow::{FollowBuffer, FollowMessage, Replayed};
+use crate::fork::{ForkedMessage, Forker};
+use crate::geoip::{GeoIp, Location};
+use crate::group::{self, GroupMember};
+use crate::history::{self, HistoryRequestMessage};
+use crate::host::{HostMode, SessionLink, host_mode};
+use crate::htpasswd::{Htpasswd, require_password};
+use crate::identity::{ServerIdentity, default_identity_path};
+use crate::invite::{Invites, redeem_invite};
+use crate::ip_filter::{IpFilter, filter_ip};
+use crate::limits::SessionLimits;
+use crate::line_log::LineLog;
+use crate::metrics::{self, CommandMetrics, ExitCounts};
+use crate::mirror;
+use crate::multicast;
+use crate::mux::{
+    CloseChannelMessage, MAX_MUX_MESSAGE, MUX_PATH, MUX_QUEUE, OpenMessage, channel_close_frame, forward_session,
+    open_refusal,
+};
+use crate::notes::{Notes, NotesMessage};
+use crate::notify::{Notifier, NotifyEvent, SmtpSettings};
+use crate::panes::{self, Backend};
+use crate::plugin::{PluginEvent, Plugins};
+use crate::pty::AsyncPty;
+use crate::qr;
+use crate::rate_limit::{ConnectionLimits, ConnectionSlot, RateLimiter, client_key, rate_limit};
+use crate::recording;
+use crate::schedule::{self, StartSchedule, StartTrigger};
+use crate::screen::TerminalScreen;
+use crate::screen_reader::ScreenReader;
+use crate::script::{InputVerdict, ScriptClient, ScriptHooks};
+use crate::serve::{ServeOptions, serve};
+use crate::session_env::SessionEnv;
+use crate::session_id::IdGenerator;
+use crate::showkeys::{KeysMessage, describe_keys};
+use crate::stats::HostStats;
+use crate::stderr::{StderrCapture, StderrMessage, StderrOutput};
+use crate::subscription::{Stream, SubscribeMessage, Subscription};
+use crate::supervisor::Supervisor;
+use crate::suspend::{self, SuspendMode, Suspender};
+use crate::systemd;
+use crate::term::{CapabilitiesMessage, ColorDepth, TermSettings};
+use crate::timeline::{NoteMessage, Timeline, TimelineMessage};
+use crate::tls;
+use crate::transform::{ColorDowngrade, LineFeeds, OutputColors, OutputPipeline, StripAnsi};
+use crate::tunnel;
+use crate::version::{VERSION_PATH, VersionInfo};
+use crate::websocket::{
+    BINARY_WRITE, ClientsMessage, CloseMessage, CloseReason, ErrorMessage, Heartbeat, WriteAccessMessage, binary_frame,
+    decode_binary_frame,
+};
+use anyhow::Context;
 use axum::{
     Router,
+    body::Body,
     extract::{
-        Path, State,
-        ws::{WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, Query, Request, State,
+        ws::{Utf8Bytes, WebSocket, WebSocketUpgrade},
     },
-    http::{StatusCode, header},
-    response::{Html, IntoResponse, Response},
-    routing::get,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware,
+    response::{
+        Html, IntoResponse, Json, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{delete, get, post},
 };
 use base64::{Engine as _, engine::general_purpose};
+use bytes::{Bytes, BytesMut};
 use futures_util::{SinkExt, StreamExt};
-use portable_pty::{CommandBuilder, MasterPty, PtySize, native_pty_system};
+use portable_pty::{Child, CommandBuilder, MasterPty, PtySize, SlavePty, native_pty_system};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use terminal_size::{Height, Width, terminal_size};
-use termios::{TCSANOW, Termios, tcsetattr};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::{Mutex, broadcast};
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot, watch};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
+use tower::ServiceExt;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct AppState {
     pub session_id: String,
-    pub pty_tx: broadcast::Sender<Vec<u8>>,
-    pub pty_writer: Arc<Mutex<Option<Box<dyn std::io::Write + Send>>>>,
+    pub base_path: Arc<str>, // Path the server is reached under, from --base-url
+    pub pty_tx: broadcast::Sender<PtyEvent>,
+    pub pty_writer: Arc<Mutex<AsyncPty>>,
     pub pty_master: Arc<Mutex<Box<dyn MasterPty + Send>>>, // Add PTY master for resizing
     pub current_size: Arc<Mutex<(u16, u16)>>,              // (cols, rows)
-    pub output_buffer: Arc<Mutex<Vec<u8>>>,                // Buffer for output before client connects
+    pub screen: Arc<TerminalScreen>,                       // Current screen, shown to joining clients
     pub readonly: bool,                                    // Whether session is read-only
-    pub headless: bool,                                    // Whether server is in headless mode
+    pub host: Arc<dyn HostMode>,                           // Interactive or headless host behaviour
     pub last_resize_time: Arc<Mutex<std::time::Instant>>,  // For rate limiting resize requests
     pub pending_resize: Arc<Mutex<Option<(u16, u16)>>>,    // Store pending resize request
+    pub identity: Arc<ServerIdentity>,                     // Server identity for challenge signing
+    pub history: Arc<Mutex<Vec<u8>>>,                      // Recent output, the scrollback of joining clients
+    pub buffer_size: usize,                                // Most output kept in the history, in bytes
+    pub compression: Compression,                          // Compression offered to binary protocol clients
+    pub broadcast_capacity: usize,                         // Capacity of the PTY output broadcast channel
+    pub clients: Arc<Mutex<HashMap<String, ClientStats>>>, // Per-connection statistics
+    pub max_input_frame: usize,                            // Largest accepted incoming message in bytes
+    pub first_message_timeout: Option<Duration>,           // Close sockets that stay silent this long
+    pub pause_buffer: usize,                               // Most output held back for a paused viewer
+    pub catch_up_speed: u32,                               // How much faster held back output is replayed
+    pub max_connection_lifetime: Option<Duration>,         // Close sockets open longer than this
+    pub ping_interval: Duration,                           // Ping clients this often, zero for never
+    pub ping_timeout: Duration,                            // Close sockets whose ping isn't answered in time
+    pub supervisor: Supervisor,                            // Runs session and client tasks
+    pub colors: ColorDepth,                                // Colors advertised to the program
+    pub viewer_colors: Arc<watch::Sender<ColorDepth>>,     // Least capable colors among participants
+    pub screen_reader: Option<ScreenReader>,               // Plain text view of the screen, if enabled
+    pub lines: Arc<LineLog>,                               // Output as numbered plain text lines
+    pub budget: Option<Arc<MemoryBudget>>,                 // Memory all sessions' buffers share, if limited
+    pub history_account: Option<Arc<HistoryAccount>>,      // Counts the history against the budget
+    pub show_keys: bool,                                   // Broadcast the host's keystrokes
+    pub announce_viewers: bool,                            // Tell the host when viewers join or leave
+    pub rate_limiter: Arc<RateLimiter>,                    // Throttles page loads and WebSocket upgrades
+    pub connection_limits: Arc<ConnectionLimits>,          // Caps the clients connected at once
+    pub robots_txt: Arc<str>,                              // Contents of /robots.txt
+    pub token_bindings: Arc<TokenBindings>,                // Addresses capability tokens were first used from
+    pub token_quotas: Arc<TokenQuotas>,                    // Use of capability tokens counted against their quotas
+    pub geoip: Option<Arc<GeoIp>>,                         // Locates client addresses, if a database is given
+    pub plugins: Arc<Plugins>,                             // WebAssembly output plugins
+    pub script: Option<Arc<ScriptHooks>>,                  // Lua hooks for session events, if a script is given
+    pub confirm: Option<Arc<ConfirmGate>>,                 // Holds viewer commands the host must allow
+    pub approval: Option<Arc<ApprovalGate>>,               // Viewers asking for write access, with --approve-writers
+    pub notifier: Option<Arc<Notifier>>,                   // Sends session events to the sinks given with --notify
+    pub schedule: Option<Arc<StartSchedule>>,              // Holds back the command until it is due to start
+    pub suspender: Option<Arc<Suspender>>,                 // Suspends the command while nobody watches
+    pub limits: Option<Arc<SessionLimits>>,                // Ends the session when idle or running too long
+    pub command: Arc<CommandBuilder>,                      // The command as given, to start copies of the session
+    pub child_pid: Arc<AtomicU32>,                         // Process ID of the running command, 0 before it starts
+    pub forker: Option<Arc<Forker>>,                       // Starts copies of the session, with --allow-fork
+    pub notes: Arc<Notes>,                                 // Markdown notes shared next to the terminal
+    pub timeline: Arc<Timeline>,                           // Timestamped entries noted during the session
+    pub metrics: Arc<CommandMetrics>,                      // Liveness of the command, for /metrics
+    pub exits: Arc<ExitCounts>,                            // Exit codes of all sessions' commands, for /metrics
+    pub stderr: Option<Arc<StderrOutput>>,                 // The program's stderr, with --separate-stderr
+}
+
+/// Event broadcast from the PTY side to every WebSocket connection
+#[derive(Clone, Debug)]
+pub enum PtyEvent {
+    /// Output read from the PTY, together with its `Write` message encoded once for all clients and, with
+    /// `--compression`, its binary message for clients that negotiated compression
+    Output {
+        data: Bytes,
+        frame: Utf8Bytes,
+        compressed: Option<Bytes>,
+    },
+    /// A serialized protocol message for all clients
+    Message(Utf8Bytes),
+    /// Lines that changed on the screen, together with their encoded `ScreenText` message
+    ScreenText { lines: Arc<Vec<String>>, frame: Utf8Bytes },
+    /// A serialized `Close` message, after which connections are closed with a close frame for `reason`
+    Close { reason: CloseReason, frame: Utf8Bytes },
+}
+
+/// Statistics tracked for each connected WebSocket client
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClientStats {
+    /// Broadcast messages this client missed because it fell behind
+    pub lagged_messages: u64,
+    /// Number of times the client was resynchronized after lagging
+    pub resyncs: u64,
+    /// TERM of the client's terminal, if it reported one
+    pub term: Option<String>,
+    /// Colors the client's terminal can display, if it reported them
+    pub colors: Option<ColorDepth>,
+    /// Where the client connects from, if a GeoIP database is configured. Only shown through the admin
+    /// API, since every viewer may read the stats
+    #[serde(skip)]
+    pub location: Option<Location>,
+    /// Input from the client that couldn't be written to the program, e.g. because it had exited
+    pub write_errors: u64,
+    /// How a WebSocket client connected, only shown through the admin API
+    #[serde(skip)]
+    pub connection: Option<ClientConnection>,
+}
+
+#[derive(Serialize)]
+struct SessionStats {
+    session_id: String,
+    broadcast_capacity: usize,
+    lagged_messages: u64,
+    task_panics: u64,
+    throttled_requests: u64,
+    rejected_connections: u64,
+    colors: ColorDepth,
+    least_capable_colors: ColorDepth,
+    clients: HashMap<String, ClientStats>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -62,9 +231,15 @@ struct WinSizeMessage {
 }
 
 #[derive(Serialize, Deserialize)]
-struct ReadOnlyMessage {
+pub(crate) struct ReadOnlyMessage {
     #[serde(rename = "ReadOnly")]
-    readonly: bool,
+    pub(crate) readonly: bool,
+    /// Whether a read-only viewer may ask the host for write access, with --approve-writers
+    #[serde(rename = "CanRequestWrite", default)]
+    pub(crate) can_request_write: bool,
+    /// Whether the viewer may fork the session, with --allow-fork
+    #[serde(rename = "CanFork", default)]
+    pub(crate) can_fork: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -73,8 +248,76 @@ struct HeadlessMessage {
     headless: bool,
 }
 
+#[derive(Serialize, Deserialize)]
+struct ChallengeMessage {
+    #[serde(rename = "Nonce")]
+    nonce: String, // base64 encoded
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityMessage {
+    #[serde(rename = "PublicKey")]
+    public_key: String, // base64 encoded
+    #[serde(rename = "Session")]
+    session: String, // Session answered for, empty for the whole server
+    #[serde(rename = "ServerNonce")]
+    server_nonce: String, // base64 encoded
+    #[serde(rename = "Signature")]
+    signature: String, // base64 encoded
+}
+
+#[derive(Serialize, Deserialize)]
+struct KnockMessage {
+    #[serde(rename = "Message", default)]
+    message: String,
+}
+
+/// Minimum time between two knocks from the same client address
+const KNOCK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Longest knock text shown to the host, in characters
+const MAX_KNOCK_LENGTH: usize = 200;
+
+/// Minimum time between two history pages rendered for the same client address
+const HISTORY_REQUEST_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Minimum time between two forks of the session by the same client
+const FORK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Replies a client may have waiting to be sent, such as answers to its challenges or history requests
+const REPLY_QUEUE: usize = 16;
+
+/// How long clients get to receive their close frame once the server shuts down
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Size of each read from the PTY master
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Amount of recent output a relay keeps for viewers that join late
+pub(crate) const MAX_HISTORY_SIZE: usize = 64 * 1024;
+
+/// Serialize a protocol message of type `msg_type` carrying `payload`
+pub(crate) fn encode_message<T: Serialize>(msg_type: &str, payload: &T) -> crate::error::Result<Utf8Bytes> {
+    let message = TtyMessage {
+        msg_type: msg_type.to_string(),
+        data: general_purpose::STANDARD.encode(serde_json::to_vec(payload)?),
+    };
+
+    Ok(serde_json::to_string(&message)?.into())
+}
+
+/// Encode terminal output as a serialized `Write` protocol message
+pub fn encode_write_message(data: &[u8]) -> crate::error::Result<Utf8Bytes> {
+    let write_msg = WriteMessage {
+        size: data.len(),
+        data: general_purpose::STANDARD.encode(data),
+    };
+
+    encode_message("Write", &write_msg)
+}
+
 /// Validates terminal size to prevent abuse or invalid values
-fn is_valid_terminal_size(cols: u16, rows: u16) -> bool {
+pub(crate) fn is_valid_terminal_size(cols: u16, rows: u16) -> bool {
     // Minimum reasonable terminal size
     const MIN_COLS: u16 = 10;
     const MIN_ROWS: u16 = 5;
@@ -100,7 +343,7 @@ async fn process_resize_request(
     pending_resize: &Arc<Mutex<Option<(u16, u16)>>>,
     pty_master: &Arc<Mutex<Box<dyn MasterPty + Send>>>,
     current_size: &Arc<Mutex<(u16, u16)>>,
-    pty_tx: &broadcast::Sender<Vec<u8>>,
+    pty_tx: &broadcast::Sender<PtyEvent>,
 ) -> bool {
     const MIN_RESIZE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
@@ -136,12 +379,12 @@ async fn process_resize_request(
 }
 
 /// Apply resize immediately without rate limiting
-async fn apply_resize(
+pub(crate) async fn apply_resize(
     cols: u16,
     rows: u16,
     pty_master: &Arc<Mutex<Box<dyn MasterPty + Send>>>,
     current_size: &Arc<Mutex<(u16, u16)>>,
-    pty_tx: &broadcast::Sender<Vec<u8>>,
+    pty_tx: &broadcast::Sender<PtyEvent>,
 ) {
     // Update stored size
     {
@@ -168,102 +411,611 @@ async fn apply_resize(
 
     // Broadcast size change to other WebSocket clients
     let winsize_msg = WinSizeMessage { cols, rows };
-    let tty_msg_broadcast = TtyMessage {
-        msg_type: "WinSize".to_string(),
-        data: general_purpose::STANDARD.encode(serde_json::to_vec(&winsize_msg).unwrap()),
-    };
+    match encode_message("WinSize", &winsize_msg) {
+        Ok(frame) => {
+            let _ = pty_tx.send(PtyEvent::Message(frame));
+        }
+        Err(e) => error!("Failed to encode terminal size: {}", e),
+    }
+}
+
+/// Messages that bring a client up to date: the current size, then output that redraws the current screen
+pub(crate) async fn resync_frames(state: &AppState) -> crate::error::Result<[Utf8Bytes; 2]> {
+    let size = *state.current_size.lock().await;
+    let (cols, rows) = size;
 
-    let json_str = serde_json::to_string(&tty_msg_broadcast).unwrap();
-    let _ = pty_tx.send(format!("WINSIZE:{json_str}").into_bytes());
+    Ok([
+        encode_message("WinSize", &WinSizeMessage { cols, rows })?,
+        encode_write_message(&state.screen.snapshot(size, &state.history.lock().await))?,
+    ])
 }
 
-/// Start a background task to process pending resize requests
-fn start_pending_resize_processor(
-    last_resize_time: Arc<Mutex<std::time::Instant>>,
-    pending_resize: Arc<Mutex<Option<(u16, u16)>>>,
-    pty_master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
-    current_size: Arc<Mutex<(u16, u16)>>,
-    pty_tx: broadcast::Sender<Vec<u8>>,
-    cancellation_token: tokio_util::sync::CancellationToken,
-) {
-    tokio::spawn(async move {
-        const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
-        const MIN_RESIZE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// Send a client that missed messages the terminal size and, with `screen`, the current screen. Returns
+/// false if the connection should end.
+async fn send_resync(
+    sender: &mut futures_util::stream::SplitSink<WebSocket, axum::extract::ws::Message>,
+    state: &AppState,
+    screen: bool,
+) -> bool {
+    let frames = match resync_frames(state).await {
+        Ok(frames) => frames,
+        Err(e) => {
+            error!("Failed to encode resync messages: {}", e);
+            let _ = sender.send(internal_error_frame()).await;
+            return false;
+        }
+    };
 
-        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let count = if screen { frames.len() } else { 1 };
+    for frame in frames.into_iter().take(count) {
+        if sender.send(axum::extract::ws::Message::Text(frame)).await.is_err() {
+            debug!("WebSocket connection closed while resyncing");
+            return false;
+        }
+    }
+    true
+}
 
-        loop {
-            tokio::select! {
-                _ = cancellation_token.cancelled() => {
-                    debug!("Pending resize processor cancelled");
-                    break;
-                }
-                _ = interval.tick() => {
-                    // Check if we have a pending resize and enough time has passed
-                    let pending = {
-                        let pending_lock = pending_resize.lock().await;
-                        *pending_lock
-                    };
-
-                    if let Some((cols, rows)) = pending {
-                        let now = std::time::Instant::now();
-                        let last_time = *last_resize_time.lock().await;
-
-                        if now.duration_since(last_time) >= MIN_RESIZE_INTERVAL {
-                            // Clear the pending resize
-                            {
-                                let mut pending_lock = pending_resize.lock().await;
-                                *pending_lock = None;
-                            }
+/// Tell all clients how many viewers the session has, after one joined or left, returning the number
+pub(crate) async fn broadcast_clients(state: &AppState) -> usize {
+    let count = state.clients.lock().await.len();
+    if let Some(suspender) = &state.suspender {
+        suspender.set_viewers(count);
+    }
+    match encode_message("Clients", &ClientsMessage { count }) {
+        Ok(frame) => {
+            let _ = state.pty_tx.send(PtyEvent::Message(frame));
+        }
+        Err(e) => error!("Failed to encode viewer count: {}", e),
+    }
+    count
+}
 
-                            // Update last resize time
-                            {
-                                let mut last_time_lock = last_resize_time.lock().await;
-                                *last_time_lock = now;
-                            }
+/// Tell all WebSocket clients whether they now decide the terminal size
+pub(crate) fn broadcast_headless(pty_tx: &broadcast::Sender<PtyEvent>, headless: bool) {
+    match encode_message("Headless", &HeadlessMessage { headless }) {
+        Ok(frame) => {
+            let _ = pty_tx.send(PtyEvent::Message(frame));
+        }
+        Err(e) => error!("Failed to encode headless state: {}", e),
+    }
+}
 
-                            debug!("Processing pending resize: {}x{}", cols, rows);
-                            apply_resize(cols, rows, &pty_master, &current_size, &pty_tx).await;
-                        }
-                    }
-                }
-            }
+/// Colors that every participant can display: the program's advertised colors, lowered by any client
+/// that reported a less capable terminal
+pub(crate) async fn least_capable_colors(state: &AppState) -> ColorDepth {
+    let clients = state.clients.lock().await;
+    clients
+        .values()
+        .filter_map(|client| client.colors)
+        .fold(state.colors, ColorDepth::min)
+}
+
+/// Recompute the least capable colors after a participant reported its terminal or left
+pub(crate) async fn refresh_viewer_colors(state: &AppState) {
+    let colors = least_capable_colors(state).await;
+    state.viewer_colors.send_if_modified(|current| {
+        if *current == colors {
+            return false;
         }
+        debug!("Least capable participant now displays {} colors", colors);
+        *current = colors;
+        true
     });
 }
 
+/// Show keys typed by the host to all clients, unless the program has turned off echo, e.g. for a password
+pub(crate) async fn broadcast_keys(state: &AppState, input: &[u8]) {
+    if !state.show_keys {
+        return;
+    }
+    match state.pty_writer.lock().await.echo_enabled() {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            debug!("Not showing keys, failed to read the terminal mode: {}", e);
+            return;
+        }
+    }
+
+    match encode_message(
+        "Keys",
+        &KeysMessage {
+            keys: describe_keys(input),
+        },
+    ) {
+        Ok(frame) => {
+            let _ = state.pty_tx.send(PtyEvent::Message(frame));
+        }
+        Err(e) => error!("Failed to encode keys: {}", e),
+    }
+}
+
+/// Broadcast a `Close` message to all WebSocket clients, after which their connections are closed
+pub(crate) fn broadcast_close(pty_tx: &broadcast::Sender<PtyEvent>, reason: CloseReason, message: &str) {
+    let close_msg = CloseMessage {
+        reason,
+        message: message.to_string(),
+    };
+    match encode_message("Close", &close_msg) {
+        Ok(frame) => {
+            let _ = pty_tx.send(PtyEvent::Close { reason, frame });
+        }
+        Err(e) => error!("Failed to encode close message: {}", e),
+    }
+}
+
+/// Whether the printed session links carry the token. A token generated for `--invites` stays secret, so
+/// links are only shared as invites and the printed ones don't let anyone in.
+pub(crate) fn token_in_links(args: &Args) -> bool {
+    !args.invites || args.auth_token.is_some() || args.generate_auth_token
+}
+
 pub struct RwShellServer {
-    args: Args,
-    session_id: String,
+    pub(crate) args: Args,
+    pub(crate) session_id: String,
+    identity: Arc<ServerIdentity>,
+    robots_txt: Arc<str>,
+    geoip: Option<Arc<GeoIp>>,
+    pub(crate) auth_token: Option<Arc<AuthToken>>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    admin_token: Option<Arc<AuthToken>>,
+    /// Single-use links to the main session, with `--invites`
+    invites: Option<Arc<Invites>>,
+    /// Tunnel through a relay viewers reach the server at, with `--public`
+    tunnel: Option<tunnel::Registration>,
+    /// Exit codes of the commands of every session, for `/metrics`
+    exits: Arc<ExitCounts>,
+    /// Memory the buffers of all sessions share, with `--memory-budget`
+    budget: Option<Arc<MemoryBudget>>,
+    rate_limiter: Arc<RateLimiter>,
+    ip_filter: Option<Arc<IpFilter>>,
+    token_bindings: Arc<TokenBindings>,
+    token_quotas: Arc<TokenQuotas>,
+    pub(crate) sessions: SessionRegistry,
+    /// Makes up the IDs of sessions started through the session API
+    pub(crate) ids: Box<dyn IdGenerator>,
+}
+
+/// A session started by [`RwShellServer::start_session`]
+pub(crate) struct Session {
+    pub(crate) state: AppState,
+    pub(crate) cancellation_token: CancellationToken,
+    /// Receives the exit code of the session's program once the session ends
+    pub(crate) ended: mpsc::UnboundedReceiver<u32>,
+}
+
+/// Sessions served by this process, by ID: the main one and those started through the session API
+#[derive(Clone, Default)]
+pub(crate) struct SessionRegistry(Arc<std::sync::RwLock<HashMap<String, RegisteredSession>>>);
+
+#[derive(Clone)]
+pub(crate) struct RegisteredSession {
+    pub(crate) command: String,
+    /// Routes under `/s/{id}/`
+    pub(crate) router: Router,
+    pub(crate) state: AppState,
+    pub(crate) cancellation_token: CancellationToken,
+}
+
+impl SessionRegistry {
+    pub(crate) fn get(&self, id: &str) -> Option<RegisteredSession> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).get(id).cloned()
+    }
+
+    pub(crate) fn insert(&self, id: String, session: RegisteredSession) {
+        self.0.write().unwrap_or_else(|e| e.into_inner()).insert(id, session);
+    }
+
+    pub(crate) fn remove(&self, id: &str) {
+        self.0.write().unwrap_or_else(|e| e.into_inner()).remove(id);
+    }
+
+    /// Every session, ordered by ID
+    pub(crate) fn all(&self) -> Vec<(String, RegisteredSession)> {
+        let mut sessions: Vec<_> = self
+            .0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(id, session)| (id.clone(), session.clone()))
+            .collect();
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+        sessions
+    }
 }
 
 impl RwShellServer {
-    pub async fn new(args: Args) -> anyhow::Result<Self> {
-        let session_id = if args.uuid {
-            Uuid::new_v4().to_string()
-        } else {
-            "local".to_string()
+    pub async fn new(mut args: Args) -> anyhow::Result<Self> {
+        let ids = args.session_ids.generator();
+        let session_id = match &args.session_id {
+            Some(id) => id.clone(),
+            None if args.uuid => ids.generate(),
+            None => "local".to_string(),
+        };
+        // A name picked for its URL must lead to this session, not to one another rwshell already serves
+        if args.session_id.is_some()
+            && std::os::unix::net::UnixStream::connect(control::control_socket_path(&session_id)).is_ok()
+        {
+            return Err(anyhow::anyhow!(
+                "another rwshell on this machine already serves session {session_id:?}; pick another --session-id"
+            ));
+        }
+
+        if args.broadcast_capacity == 0 {
+            return Err(anyhow::anyhow!("--broadcast-capacity must be at least 1"));
+        }
+        if args.catch_up_speed == 0 {
+            return Err(anyhow::anyhow!("--catch-up-speed must be at least 1"));
+        }
+
+        // Printed links and served pages use the relay's URL, like that of a reverse proxy
+        let tunnel = match &args.public {
+            Some(relay) => {
+                let registration = tunnel::register(relay).await?;
+                args.base_url = Some(registration.url.clone());
+                Some(registration)
+            }
+            None => None,
+        };
+
+        let identity_path = args.identity_file.clone().unwrap_or_else(default_identity_path);
+        let identity = Arc::new(ServerIdentity::load_or_generate(&identity_path)?);
+        debug!("Server identity: ed25519 {}", identity.public_key());
+
+        let robots_txt = match &args.robots_txt {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read robots.txt from {}", path.display()))?,
+            None if args.allow_indexing => "User-agent: *\nAllow: /\n".to_string(),
+            None => "User-agent: *\nDisallow: /\n".to_string(),
+        };
+
+        let geoip = match &args.geoip_db {
+            Some(path) => Some(Arc::new(GeoIp::open(path)?)),
+            None => None,
+        };
+
+        let auth_token = match &args.auth_token {
+            Some(token) => Some(AuthToken::new(token.clone())),
+            // With invites alone, the token is never shown and viewers only get it by redeeming an invite
+            None if args.generate_auth_token || args.invites => Some(AuthToken::generate()),
+            None => None,
+        }
+        .map(|token| Arc::new(token.scoped_to(&tls::page_url(&args, ""), tls::base_path(&args))));
+
+        let htpasswd = match &args.htpasswd {
+            Some(path) => Some(Arc::new(Htpasswd::load(path)?)),
+            None => None,
         };
 
-        Ok(Self { args, session_id })
+        // Shared by all sessions, so limits hold however many sessions there are
+        let rate_limiter = Arc::new(RateLimiter::new(args.rate_limit_per_ip, args.rate_limit_global));
+        let token_bindings = Arc::new(TokenBindings::new(args.strict_token_binding));
+        let ip_filter = IpFilter::new(args.allow_cidr.clone(), args.deny_cidr.clone()).map(Arc::new);
+
+        let budget = args.memory_budget.map(|limit| Arc::new(MemoryBudget::new(limit)));
+        let admin_token = args.admin_token.clone().map(|token| Arc::new(AuthToken::new(token)));
+        let invites = args.invites.then(|| {
+            Arc::new(Invites::new(
+                tls::page_url(&args, &format!("/s/{session_id}/")),
+                args.invite_ttl,
+            ))
+        });
+
+        Ok(Self {
+            args,
+            session_id,
+            identity,
+            robots_txt: robots_txt.into(),
+            geoip,
+            auth_token,
+            htpasswd,
+            admin_token,
+            invites,
+            tunnel,
+            exits: Arc::default(),
+            budget,
+            rate_limiter,
+            ip_filter,
+            token_bindings,
+            token_quotas: Arc::new(TokenQuotas::default()),
+            sessions: SessionRegistry::default(),
+            ids,
+        })
     }
 
-    pub async fn run(self) -> anyhow::Result<()> {
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        // Fail on an unusable certificate before the session starts
+        let tls_acceptor = tls::acceptor(&self.args)?;
+
         // Display session information
-        let url = if self.args.uuid {
-            format!("http://{}/s/{}/", self.args.listen, self.session_id)
+        let mut links = Vec::new();
+        if self.args.capabilities {
+            let ttl = self.args.capability_ttl;
+            let quota = Quota {
+                max_connects: self.args.capability_max_connects,
+                max_bytes: self.args.capability_max_bytes.map(|bytes| bytes as u64),
+                max_seconds: self.args.capability_max_time.map(|time| time.as_secs().max(1)),
+            };
+            let rw_token = capability::mint(
+                &self.identity,
+                &Capability {
+                    quota,
+                    ..Capability::new(&self.session_id, AccessMode::ReadWrite, ttl)
+                },
+            )?;
+            let ro_token = capability::mint(
+                &self.identity,
+                &Capability {
+                    quota,
+                    ..Capability::new(&self.session_id, AccessMode::ReadOnly, ttl)
+                },
+            )?;
+            if !self.args.readonly {
+                links.push(SessionLink {
+                    label: "read-write session",
+                    url: self.session_url(&rw_token),
+                });
+            }
+            links.push(SessionLink {
+                label: "read-only session",
+                url: self.session_url(&ro_token),
+            });
         } else {
-            format!("http://{}/s/local/", self.args.listen)
+            links.push(SessionLink {
+                label: "local session",
+                url: self.session_url(&self.session_id),
+            });
+        }
+        // Viewers need the token to get in; the page keeps it in a cookie once opened
+        let token_in_links = token_in_links(&self.args);
+        if let Some(token) = self.auth_token.as_ref().filter(|_| token_in_links) {
+            for link in &mut links {
+                link.url = token.add_to(&link.url);
+            }
+        }
+        for link in &links {
+            if self.args.show_url && !self.args.headless {
+                println!("{}", link.hyperlink());
+            } else {
+                println!("{link}");
+            }
+            // Printed before the host terminal goes raw; links that only invites get past aren't worth one
+            if self.args.qr && token_in_links {
+                match qr::render(&link.url, "\n") {
+                    Ok(code) => print!("{code}"),
+                    Err(e) => warn!("Failed to print the {} as a QR code: {:#}", link.label, e),
+                }
+            }
+        }
+
+        let host = host_mode(&self.args, &links, self.invites.clone());
+        let notes = match &self.args.notes_file {
+            Some(path) => Notes::load(path)?,
+            None => Notes::default(),
+        };
+        let timeline = match &self.args.timeline_file {
+            Some(path) => Timeline::load(path)?,
+            None => Timeline::default(),
+        };
+        let Session {
+            state: app_state,
+            cancellation_token,
+            mut ended,
+        } = self.start_session(
+            &self.session_id,
+            self.command()?,
+            Arc::clone(&host),
+            notes,
+            timeline,
+            self.start_trigger(),
+        )?;
+        // Before the first output reaches viewers
+        if let Some(path) = &self.args.record {
+            recording::start(&app_state, path, &self.args.command, cancellation_token.clone())?;
+        }
+
+        // Show the session on a group page; the read-only link is registered when there is one
+        if let (Some(group_url), Some(link)) = (&self.args.group, links.last()) {
+            let label = self
+                .args
+                .group_label
+                .clone()
+                .unwrap_or_else(|| default_group_label(&self.args.command));
+            let member = GroupMember {
+                // Sessions on different machines may share a session ID such as "local"
+                id: Uuid::new_v4().to_string(),
+                label,
+                url: link.url.clone(),
+            };
+            group::join(&app_state.supervisor, group_url, member, cancellation_token.clone());
+        }
+
+        self.sessions.insert(
+            self.session_id.clone(),
+            RegisteredSession {
+                command: self.args.command.clone(),
+                router: session_routes(app_state.clone()),
+                state: app_state.clone(),
+                cancellation_token: cancellation_token.clone(),
+            },
+        );
+        let app = self.create_app(app_state.clone()).await?;
+
+        // Set up the host terminal, e.g. raw mode for interactive sessions
+        host.prepare();
+
+        // Start the server
+        let listener = match systemd::activated_listener() {
+            Some(listener) => {
+                debug!("Using the listening socket passed by systemd");
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(&self.args.listen).await?,
+        };
+        debug!("Server listening on: {}", self.args.listen);
+        tls::spawn_http_redirect(&self.args).await?;
+
+        // Start the tasks of the host mode, e.g. stdin forwarding or deferred client resizes
+        host.start(&app_state, cancellation_token.clone());
+        if let Some(url) = &self.args.mirror {
+            mirror::start(&app_state, url.clone(), cancellation_token.clone());
+        }
+        if let Some(registration) = &self.tunnel {
+            // The relay terminates TLS for viewers
+            let options = ServeOptions::new(&self.args, None);
+            tunnel::start(
+                &app_state,
+                registration.clone(),
+                app.clone(),
+                options,
+                cancellation_token.clone(),
+            );
+        }
+        if let Some(group) = self.args.multicast {
+            multicast::start(&app_state, group, cancellation_token.clone())?;
+        }
+        // Nothing that needs privileges is left to set up
+        if self.args.harden {
+            crate::harden::apply().context("Failed to harden the server")?;
+        }
+
+        // Set up graceful shutdown
+        let pty_tx_shutdown = app_state.pty_tx.clone();
+        let host_shutdown = Arc::clone(&host);
+        let sessions = self.sessions.clone();
+        let (exit_code_tx, exit_code_rx) = oneshot::channel();
+        let shutdown_signal = async move {
+            let exit_code = tokio::select! {
+                exit_code = ended.recv() => {
+                    debug!("Shell process ended, shutting down server");
+                    exit_code
+                }
+                _ = host_shutdown.interrupted() => {
+                    broadcast_close(&pty_tx_shutdown, CloseReason::ServerShutdown, "Server is shutting down");
+                    Some(0)
+                }
+            };
+            // Sessions started at runtime end with the server
+            for (_, session) in sessions.all() {
+                broadcast_close(
+                    &session.state.pty_tx,
+                    CloseReason::ServerShutdown,
+                    "Server is shutting down",
+                );
+                session.cancellation_token.cancel();
+            }
+            cancellation_token.cancel();
+
+            // Restore terminal before exiting
+            host_shutdown.restore();
+            let _ = exit_code_tx.send(exit_code.unwrap_or(0));
+        };
+
+        // Start the server with graceful shutdown
+        let serve_options = ServeOptions::new(&self.args, tls_acceptor);
+        serve(listener, app, serve_options, shutdown_signal).await?;
+
+        // Exiting drops open WebSocket connections, so first let them deliver their `Close` message and
+        // close frame
+        self.wait_for_clients().await;
+        debug!("Exiting rwshell");
+        std::process::exit(exit_code_rx.await.unwrap_or(0) as i32);
+    }
+
+    /// Wait until the clients of every session have been sent their close frame and disconnected, or
+    /// for `CLOSE_GRACE_PERIOD` at most
+    async fn wait_for_clients(&self) {
+        let closed = async {
+            loop {
+                let mut connected = 0;
+                for (_, session) in self.sessions.all() {
+                    connected += session.state.clients.lock().await.len();
+                }
+                if connected == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_GRACE_PERIOD, closed).await.is_err() {
+            debug!("Timed out waiting for clients to close");
+        }
+    }
+
+    /// URL of the session page under `/s/{id}/`, where `id` is a session ID or a capability token
+    pub(crate) fn session_url(&self, id: &str) -> String {
+        tls::page_url(&self.args, &format!("/s/{id}/"))
+    }
+
+    /// What starts the command of the main session, if it doesn't start right away
+    fn start_trigger(&self) -> Option<StartTrigger> {
+        match (self.args.start_at, self.args.start_on_connect) {
+            (Some(time), _) => Some(StartTrigger::At(time)),
+            (None, true) => Some(StartTrigger::FirstViewer),
+            (None, false) => None,
+        }
+    }
+
+    /// Sends the events of session `id` to the sinks given with `--notify`
+    fn notifier(&self, id: &str) -> Notifier {
+        let hostname = hostname().unwrap_or_else(|| "localhost".to_string());
+        let smtp = SmtpSettings {
+            server: self.args.smtp_server.clone(),
+            from: self
+                .args
+                .smtp_from
+                .clone()
+                .unwrap_or_else(|| format!("rwshell@{hostname}")),
+        };
+        Notifier::new(self.args.notify.clone(), id, &self.session_url(id), &hostname, smtp)
+    }
+
+    /// The command of the main session
+    fn command(&self) -> anyhow::Result<CommandBuilder> {
+        let cmd = match (&self.args.subcommand, &self.args.compare) {
+            // A child rwshell logs into every host, with input going to all of them
+            (Some(Subcommand::Multi { hosts }), _) => panes::command_builder(hosts, &[], true, self.args.pane_key)?,
+            // A child rwshell runs the command on both backends and tiles their screens
+            (_, Some(backend)) => {
+                let command: Vec<String> = std::iter::once(self.args.command.clone())
+                    .chain(self.args.args.split_whitespace().map(str::to_string))
+                    .collect();
+                panes::command_builder(
+                    &[Backend::Local, backend.clone()],
+                    &command,
+                    self.args.sync_input,
+                    self.args.pane_key,
+                )?
+            }
+            _ => {
+                let mut cmd = CommandBuilder::new(&self.args.command);
+                if !self.args.args.is_empty() {
+                    for arg in self.args.args.split_whitespace() {
+                        cmd.arg(arg);
+                    }
+                }
+                cmd
+            }
         };
-        println!("local session: {url}");
+        Ok(cmd)
+    }
 
+    /// Run `cmd` in a new PTY as session `session_id`, sized and shown by `host`, and start the tasks
+    /// that read its output and wait for it to exit. The host's own tasks are left to the caller.
+    pub(crate) fn start_session(
+        self: &Arc<Self>,
+        session_id: &str,
+        mut cmd: CommandBuilder,
+        host: Arc<dyn HostMode>,
+        notes: Notes,
+        timeline: Timeline,
+        start: Option<StartTrigger>,
+    ) -> anyhow::Result<Session> {
         // Create PTY with actual terminal size
         let pty_system = native_pty_system();
-        let (cols, rows) = if self.args.headless {
-            (self.args.headless_cols, self.args.headless_rows)
-        } else {
-            get_terminal_size()
-        };
+        let (cols, rows) = host.initial_size();
 
         // Validate initial terminal size
         if !is_valid_terminal_size(cols, rows) {
@@ -285,393 +1037,823 @@ impl RwShellServer {
             pixel_height: 0,
         })?;
 
-        // Start command
-        let mut cmd = CommandBuilder::new(&self.args.command);
-        if !self.args.args.is_empty() {
-            for arg in self.args.args.split_whitespace() {
-                cmd.arg(arg);
-            }
+        let command = cmd
+            .get_argv()
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Forks start from the command as given, with an environment of their own
+        let original_command = Arc::new(cmd.clone());
+
+        // Tell the command it runs in rwshell, e.g. to keep from starting another session inside it
+        SessionEnv {
+            session_id,
+            url: &self.session_url(session_id),
+            headless: host.headless(),
+            readonly: self.args.readonly,
+        }
+        .apply(&mut cmd);
+        for (name, value) in &self.args.env {
+            cmd.env(name, value);
         }
 
-        // set RWSHELL environment variable to indicate we're in rwshell
-        cmd.env("RWSHELL", "1");
-        cmd.env("RWSHELL_SESSION", &self.session_id);
-
-        let mut child = pty_pair.slave.spawn_command(cmd)?;
-        let master = pty_pair.master;
+        // Advertise a terminal type the viewers can display
+        let term_settings = TermSettings::new(self.args.term.clone(), self.args.colors, self.args.headless);
+        term_settings.apply(&mut cmd);
+        debug!(
+            "Program terminal: TERM={}, {} colors",
+            term_settings.term.as_deref().unwrap_or("(inherited)"),
+            term_settings.colors
+        );
 
-        // Get writer for PTY input
-        let pty_writer = master.take_writer()?;
+        let stderr_capture = match self.args.separate_stderr {
+            true => Some(StderrCapture::new(&mut cmd).context("Failed to create a pipe for stderr")?),
+            false => None,
+        };
 
-        // Clone master for reading before moving it to AppState
-        let master_reader = master.try_clone_reader()?;
+        // Non-blocking handles for PTY input and output, serviced by the tokio reactor. Without a PTY they
+        // are the command's pipes, and the unused PTY only keeps the size viewers are told about; with a
+        // PTY helper, they are the helper's PTY.
+        let mut master = pty_pair.master;
+        let schedule = start.map(|trigger| Arc::new(StartSchedule::new(trigger)));
+        let suspender = self
+            .args
+            .suspend_after
+            .map(|after| Arc::new(Suspender::new(self.args.suspend_mode, after)));
+        let restarts = suspender
+            .as_ref()
+            .is_some_and(|suspender| suspender.mode == SuspendMode::Restart);
+        let limits = SessionLimits::new(self.args.idle_timeout, self.args.max_duration).map(Arc::new);
+        let (child, pty_writer, master_reader) = if self.args.no_pty {
+            let (child, pty_writer, master_reader) =
+                crate::pty::spawn_piped(&cmd).with_context(|| format!("Failed to run {}", self.args.command))?;
+            (SessionChild::Spawned(child), pty_writer, master_reader)
+        } else if let Some(socket) = &self.args.pty_socket {
+            let (helper_master, child) = crate::pty_helper::spawn(socket, (cols, rows), &cmd)?;
+            master = helper_master;
+            (
+                SessionChild::Spawned(child),
+                AsyncPty::new(master.as_ref())?,
+                AsyncPty::new(master.as_ref())?,
+            )
+        } else {
+            let child = if schedule.is_some() || restarts {
+                SessionChild::Deferred {
+                    slave: pty_pair.slave,
+                    cmd,
+                    schedule: schedule.clone(),
+                }
+            } else {
+                SessionChild::Spawned(pty_pair.slave.spawn_command(cmd)?)
+            };
+            (child, AsyncPty::new(master.as_ref())?, AsyncPty::new(master.as_ref())?)
+        };
 
         // Create broadcast channel for PTY output
-        let (pty_tx, _) = broadcast::channel(1024);
+        let (pty_tx, _) = broadcast::channel(self.args.broadcast_capacity);
+
+        // Cancelled when the session shuts down; session tasks run under a supervisor that cancels it on panic
+        let cancellation_token = CancellationToken::new();
+        let supervisor = Supervisor::new(session_id, cancellation_token.clone());
+
+        let plugins = Arc::new(Plugins::load(&self.args.plugins, &host)?);
+        let script = match &self.args.script {
+            Some(path) => Some(Arc::new(ScriptHooks::load(path, Arc::clone(&host))?)),
+            None => None,
+        };
+        let notifier = (!self.args.notify.is_empty()).then(|| Arc::new(self.notifier(session_id)));
 
         // Set up the HTTP server
+        let history = Arc::new(Mutex::new(Vec::new()));
         let app_state = AppState {
-            session_id: self.session_id.clone(),
+            session_id: session_id.to_string(),
+            base_path: tls::base_path(&self.args).into(),
             pty_tx: pty_tx.clone(),
-            pty_writer: Arc::new(Mutex::new(Some(pty_writer))),
+            pty_writer: Arc::new(Mutex::new(pty_writer)),
             pty_master: Arc::new(Mutex::new(master)),
             current_size: Arc::new(Mutex::new((cols, rows))),
-            output_buffer: Arc::new(Mutex::new(Vec::new())),
+            screen: Arc::new(TerminalScreen::new(cols, rows)),
             readonly: self.args.readonly,
-            headless: self.args.headless,
+            host: Arc::clone(&host),
             last_resize_time: Arc::new(Mutex::new(std::time::Instant::now())),
             pending_resize: Arc::new(Mutex::new(None)),
+            identity: Arc::clone(&self.identity),
+            history: Arc::clone(&history),
+            buffer_size: self.args.buffer_size,
+            compression: self.args.compression,
+            broadcast_capacity: self.args.broadcast_capacity,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            max_input_frame: self.args.max_input_frame,
+            first_message_timeout: self.args.first_message_timeout,
+            pause_buffer: self.args.pause_buffer,
+            catch_up_speed: self.args.catch_up_speed,
+            max_connection_lifetime: self.args.max_connection_lifetime,
+            ping_interval: self.args.ping_interval,
+            ping_timeout: self.args.ping_timeout,
+            supervisor: supervisor.clone(),
+            colors: term_settings.colors,
+            viewer_colors: Arc::new(watch::Sender::new(term_settings.colors)),
+            screen_reader: self.args.screen_reader.then(|| ScreenReader::new(cols, rows)),
+            lines: LineLog::new(self.budget.as_ref()),
+            budget: self.budget.clone(),
+            history_account: self
+                .budget
+                .as_ref()
+                .map(|budget| HistoryAccount::new(budget, Arc::clone(&history))),
+            show_keys: self.args.show_keys,
+            announce_viewers: self.args.announce_viewers,
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            connection_limits: Arc::new(ConnectionLimits::new(
+                self.args.max_clients,
+                self.args.max_clients_per_ip,
+            )),
+            robots_txt: Arc::clone(&self.robots_txt),
+            token_bindings: Arc::clone(&self.token_bindings),
+            token_quotas: Arc::clone(&self.token_quotas),
+            geoip: self.geoip.clone(),
+            plugins: Arc::clone(&plugins),
+            script: script.clone(),
+            confirm: (!self.args.confirm_patterns.is_empty() || self.args.training_wheels).then(|| {
+                Arc::new(ConfirmGate::new(
+                    self.args.confirm_patterns.clone(),
+                    self.args.training_wheels,
+                ))
+            }),
+            approval: self.args.approve_writers.then(|| Arc::new(ApprovalGate::default())),
+            notifier: notifier.clone(),
+            schedule,
+            suspender: suspender.clone(),
+            limits: limits.clone(),
+            command: original_command,
+            child_pid: Arc::new(AtomicU32::new(0)),
+            forker: self
+                .args
+                .allow_fork
+                .then(|| Arc::new(Forker::new(Arc::downgrade(self)))),
+            notes: Arc::new(notes),
+            timeline: Arc::new(timeline),
+            stderr: stderr_capture.is_some().then(|| Arc::new(StderrOutput::default())),
+            metrics: Arc::default(),
+            exits: Arc::clone(&self.exits),
         };
 
-        let app = self.create_app(app_state.clone()).await?;
-
-        // Set up raw terminal mode for interactive sessions
-        let original_termios = if !self.args.headless {
-            match setup_raw_terminal() {
-                Ok(termios) => Some(termios),
-                Err(e) => {
-                    debug!("Failed to set raw terminal mode: {}. Continuing anyway.", e);
-                    None
-                }
+        // Rewrite output for viewers; the host terminal keeps seeing the original
+        let mut output_pipeline = OutputPipeline::new();
+        if self.args.no_pty {
+            output_pipeline = output_pipeline.with_stage(LineFeeds::default());
+        }
+        for stage in plugins.stages() {
+            output_pipeline = output_pipeline.with_stage(stage);
+        }
+        match self.args.output_colors {
+            Some(OutputColors::Auto) => {
+                output_pipeline = output_pipeline.with_stage(ColorDowngrade::new(app_state.viewer_colors.subscribe()));
             }
-        } else {
-            None
-        };
-
-        // Start the server
-        let listener = TcpListener::bind(&self.args.listen).await?;
-        debug!("Server listening on: {}", self.args.listen);
-
-        // Start PTY output forwarding in background
-        let pty_tx_clone = pty_tx.clone();
-        let headless = self.args.headless;
+            Some(OutputColors::Fixed(colors)) => {
+                output_pipeline = output_pipeline.with_stage(ColorDowngrade::fixed(colors));
+            }
+            None => {}
+        }
+        if self.args.strip_ansi {
+            output_pipeline = output_pipeline.with_stage(StripAnsi::new());
+        }
 
-        // Create a shutdown signal for when PTY process ends
-        let cancellation_token = CancellationToken::new();
-        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
-        let (child_shutdown_tx, child_shutdown_rx) = tokio::sync::oneshot::channel();
-        let mut shutdown_tx = Some(shutdown_tx);
-
-        // Start pending resize processor for headless mode
-        if self.args.headless {
-            start_pending_resize_processor(
-                app_state.last_resize_time.clone(),
-                app_state.pending_resize.clone(),
-                app_state.pty_master.clone(),
-                app_state.current_size.clone(),
+        if let Some(screen_reader) = &app_state.screen_reader {
+            screen_reader.start(&app_state, cancellation_token.clone());
+        }
+        if let (Some(capture), Some(output)) = (stderr_capture, &app_state.stderr) {
+            capture.start(
+                Arc::clone(output),
                 pty_tx.clone(),
+                &supervisor,
                 cancellation_token.clone(),
             );
         }
 
-        // Monitor child process to prevent zombie processes
+        // Start PTY output forwarding in background
+        let pty_tx_clone = pty_tx.clone();
+
+        // Reports the exit code once the program exits or its output ends, whichever comes first
+        let (ended_tx, ended) = mpsc::unbounded_channel();
+
+        // Wait for the child on a blocking task so it is reaped as soon as it exits. Cancellation
+        // kills the child, which also releases the blocking thread.
         let token_child = cancellation_token.clone();
-        tokio::task::spawn_blocking(move || {
-            loop {
-                match child.try_wait() {
-                    Ok(Some(exit_status)) => {
-                        debug!("Child process exited with status: {:?}", exit_status);
-                        let _ = child_shutdown_tx.send(());
-                        token_child.cancel();
-                        break;
-                    }
-                    Ok(None) => {
-                        // Process is still running, check cancellation and continue
-                        if token_child.is_cancelled() {
-                            debug!("Child monitor task cancelled");
-                            break;
+        let pty_tx_child = pty_tx.clone();
+        let ended_child = ended_tx.clone();
+        let (exit_code_tx, exit_code_rx) = tokio::sync::watch::channel(None);
+        let stats = self.args.stats;
+        let supervisor_child = supervisor.clone();
+        let state_child = app_state.clone();
+        let notifier_child = notifier.clone();
+        supervisor.spawn("child-monitor", async move {
+            let fail_to_start = |e: anyhow::Error| {
+                error!("Failed to start the command: {:#}", e);
+                broadcast_close(&pty_tx_child, CloseReason::ChildExited, "The command failed to start");
+                exit_code_tx.send_replace(Some(1));
+                state_child.metrics.exited(1);
+                state_child.exits.record(1);
+                let _ = ended_child.send(1);
+                token_child.cancel();
+            };
+            let (mut child, respawn) = match child {
+                SessionChild::Spawned(child) => (child, None),
+                SessionChild::Deferred { slave, cmd, schedule } => {
+                    if let Some(schedule) = schedule {
+                        info!("The command starts {}", schedule.describe());
+                        schedule::show(&state_child, schedule.notice().as_bytes()).await;
+                        tokio::select! {
+                            _ = schedule.wait() => {}
+                            _ = token_child.cancelled() => return,
                         }
-                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        info!("Starting the command");
+                        schedule::clear(&state_child).await;
                     }
-                    Err(e) => {
-                        error!("Error checking child process status: {}", e);
-                        let _ = child_shutdown_tx.send(());
-                        token_child.cancel();
-                        break;
+                    match slave.spawn_command(cmd.clone()) {
+                        Ok(child) => (child, Some((slave, cmd))),
+                        Err(e) => return fail_to_start(e),
                     }
                 }
+            };
+            // The terminal side of the PTY is only kept to start the command again after a suspend;
+            // otherwise reading output ends once the command exits
+            let mut respawn = respawn.filter(|_| restarts);
+            if let Some(limits) = &limits {
+                limits.start(&state_child, token_child.clone());
             }
-        });
-
-        let token_clone = cancellation_token.clone();
-        let termios_clone = original_termios;
-        let app_state_buffer = app_state.clone();
-        tokio::task::spawn_blocking(move || {
-            use std::io::Read;
-            let mut reader = master_reader;
-            let mut buffer = [0u8; 1024];
 
-            loop {
-                match reader.read(&mut buffer) {
-                    Ok(n) if n > 0 => {
-                        let data = buffer[..n].to_vec();
-
-                        // Check if there are any subscribers
+            let mut cancelled = false;
+            let status = loop {
+                // Ends the tasks that follow this run of the command
+                let child_token = token_child.child_token();
+                state_child
+                    .child_pid
+                    .store(child.process_id().unwrap_or(0), Ordering::Relaxed);
+                state_child.metrics.started();
+                if stats {
+                    HostStats::new(child.process_id()).start(
+                        pty_tx_child.clone(),
+                        &supervisor_child,
+                        child_token.clone(),
+                    );
+                }
+                if let (Some(suspender), Some(pid), false) = (&suspender, child.process_id(), restarts) {
+                    supervisor_child.spawn(
+                        "suspend",
+                        suspend::stop_while_idle(Arc::clone(suspender), pid, child_token.clone()),
+                    );
+                }
+                let mut child_killer = child.clone_killer();
+                let mut child_wait = tokio::task::spawn_blocking(move || child.wait());
+                let can_restart = respawn.is_some();
+                let idle = async {
+                    match &suspender {
+                        Some(suspender) if can_restart => suspender.idle().await,
+                        _ => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    status = &mut child_wait => {
+                        child_token.cancel();
+                        break status;
+                    }
+                    _ = token_child.cancelled() => {
+                        debug!("Session cancelled, terminating child process");
+                        cancelled = true;
+                        let _ = child_killer.kill();
+                        break child_wait.await;
+                    }
+                    _ = idle => {}
+                }
+
+                // Nobody watched for a while with --suspend-mode restart
+                child_token.cancel();
+                let (Some(suspender), Some((slave, cmd))) = (&suspender, respawn.take()) else {
+                    unreachable!("only commands that can be restarted are ended while idle");
+                };
+                info!(
+                    "No viewers for {}, ending the command until one connects",
+                    suspender.describe_delay()
+                );
+                let _ = child_killer.kill();
+                let _ = child_wait.await;
+                state_child.metrics.stopped();
+                suspender.set_suspended(true);
+                tokio::select! {
+                    _ = suspender.viewer_connected() => {}
+                    _ = token_child.cancelled() => {
+                        cancelled = true;
+                        break Ok(Ok(portable_pty::ExitStatus::with_exit_code(0)));
+                    }
+                }
+                info!("A viewer connected, starting the command again");
+                suspender.set_suspended(false);
+                schedule::clear(&state_child).await;
+                child = match slave.spawn_command(cmd.clone()) {
+                    Ok(child) => child,
+                    Err(e) => return fail_to_start(e),
+                };
+                respawn = Some((slave, cmd));
+            };
+
+            let exit_code = match status {
+                Ok(Ok(exit_status)) => {
+                    debug!("Child process exited with status: {:?}", exit_status);
+                    broadcast_close(
+                        &pty_tx_child,
+                        CloseReason::ChildExited,
+                        &format!("Process exited with code {}", exit_status.exit_code()),
+                    );
+                    exit_status.exit_code()
+                }
+                Ok(Err(e)) => {
+                    error!("Error waiting for child process: {}", e);
+                    1
+                }
+                Err(e) => {
+                    error!("Child monitor task failed: {}", e);
+                    1
+                }
+            };
+
+            state_child.metrics.exited(exit_code);
+            state_child.exits.record(exit_code);
+            plugins.event(PluginEvent::Exit(exit_code));
+            if let Some(script) = &script {
+                script.exit(exit_code);
+            }
+            exit_code_tx.send_replace(Some(exit_code));
+            // Sent before the session ends, so the server doesn't exit halfway through; the host ending the
+            // session isn't the program exiting
+            if let Some(notifier) = notifier_child.filter(|_| !cancelled) {
+                let message = format!("`{command}` exited with code {exit_code}");
+                tokio::join!(
+                    notifier.deliver(NotifyEvent::Exit, message.clone(), Some(exit_code)),
+                    async {
+                        if exit_code != 0 {
+                            notifier.deliver(NotifyEvent::Failure, message, Some(exit_code)).await;
+                        }
+                    },
+                );
+            }
+            let _ = ended_child.send(exit_code);
+            token_child.cancel();
+        });
+
+        let token_clone = cancellation_token.clone();
+        let host_reader = Arc::clone(&host);
+        let app_state_buffer = app_state.clone();
+        let exit_code_reader = exit_code_rx;
+        supervisor.spawn("pty-reader", async move {
+            // Chunks are split off this buffer and shared with all subscribers without copying;
+            // its allocation is reclaimed once every subscriber has dropped them
+            let mut buffer = BytesMut::with_capacity(READ_CHUNK_SIZE * 4);
+
+            loop {
+                buffer.reserve(READ_CHUNK_SIZE);
+                buffer.resize(READ_CHUNK_SIZE, 0);
+
+                match master_reader.read(&mut buffer).await {
+                    Ok(n) if n > 0 => {
+                        buffer.truncate(n);
+                        let raw = buffer.split().freeze();
+                        let data = output_pipeline.process(raw.clone());
+                        if let Some(limits) = &app_state_buffer.limits {
+                            limits.touch();
+                        }
+                        app_state_buffer.metrics.output();
+
+                        if let Some(script) = &app_state_buffer.script {
+                            script.output(&raw);
+                        }
+
+                        if let Some(screen_reader) = &app_state_buffer.screen_reader {
+                            screen_reader.feed(&data, *app_state_buffer.current_size.lock().await);
+                        }
+
+                        app_state_buffer
+                            .screen
+                            .feed(&data, *app_state_buffer.current_size.lock().await);
+                        app_state_buffer.lines.feed(&data);
+
+                        // Keep recent output around for the scrollback of joining clients and the history pages
+                        {
+                            let mut history = app_state_buffer.history.lock().await;
+                            history.extend_from_slice(&data);
+                            let start = history.len().saturating_sub(app_state_buffer.buffer_size);
+                            history.drain(0..start);
+                            if let Some(history_account) = &app_state_buffer.history_account {
+                                let mut account = history_account.account();
+                                account.add(data.len());
+                                account.release(start);
+                            }
+                        }
+                        if let Some(budget) = &app_state_buffer.budget {
+                            budget.enforce();
+                        }
+
+                        // Check if there are any subscribers
                         let has_subscribers = pty_tx_clone.receiver_count() > 0;
 
-                        if has_subscribers {
+                        if has_subscribers && !data.is_empty() {
                             // Send to WebSocket clients
-                            match pty_tx_clone.send(data.clone()) {
-                                Ok(_) => {
-                                    // Successfully sent to subscribers
-                                }
-                                Err(tokio::sync::broadcast::error::SendError(_)) => {
-                                    // No subscribers, which shouldn't happen here but handle gracefully
-                                }
-                            }
-                        } else {
-                            // No subscribers, buffer the data (up to 1KB)
-                            let mut output_buffer = app_state_buffer.output_buffer.blocking_lock();
-                            output_buffer.extend_from_slice(&data);
-
-                            // Keep only the last 1KB of data
-                            const MAX_BUFFER_SIZE: usize = 1024;
-                            if output_buffer.len() > MAX_BUFFER_SIZE {
-                                let start = output_buffer.len() - MAX_BUFFER_SIZE;
-                                output_buffer.drain(0..start);
+                            match encode_write_message(&data) {
+                                Ok(frame) => match pty_tx_clone.send(PtyEvent::Output {
+                                    data: data.clone(),
+                                    frame,
+                                    compressed: (app_state_buffer.compression != Compression::None)
+                                        .then(|| app_state_buffer.compression.frame(&data)),
+                                }) {
+                                    Ok(_) => {
+                                        // Successfully sent to subscribers
+                                    }
+                                    Err(tokio::sync::broadcast::error::SendError(_)) => {
+                                        // No subscribers, which shouldn't happen here but handle gracefully
+                                    }
+                                },
+                                Err(e) => error!("Failed to encode PTY output: {}", e),
                             }
                         }
 
-                        // Write to stdout if not headless
-                        if !headless {
-                            print!("{}", String::from_utf8_lossy(&data));
-                            use std::io::Write;
-                            let _ = std::io::stdout().flush();
-                        }
+                        // Show the output on the host terminal, if there is one
+                        host_reader.echo(&raw).await;
                     }
                     Ok(_) => {
-                        debug!("Shell process ended - shutting down server");
-                        if let Some(tx) = shutdown_tx.take() {
-                            let _ = tx.send(());
-                        }
-                        token_clone.cancel();
+                        debug!("Shell process ended");
 
-                        // Restore terminal before exiting
-                        if let Some(ref termios) = termios_clone {
-                            restore_terminal(termios);
+                        // The child monitor reports how the process exited, and ends the session once its
+                        // notifications are sent; fall back to a generic reason
+                        let exit_code = wait_for_exit_code(exit_code_reader).await;
+                        if exit_code.is_none() {
+                            broadcast_close(&pty_tx_clone, CloseReason::ChildExited, "Shell process ended");
+                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                            let _ = ended_tx.send(0);
                         }
-
-                        // Force immediate exit
-                        std::process::exit(0);
+                        token_clone.cancel();
+                        break;
                     }
                     Err(e) => {
                         error!("Error reading from PTY: {}", e);
-                        if let Some(tx) = shutdown_tx.take() {
-                            let _ = tx.send(());
-                        }
+                        broadcast_close(&pty_tx_clone, CloseReason::ChildExited, "Shell process ended");
+                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                        let _ = ended_tx.send(1);
                         token_clone.cancel();
-
-                        // Restore terminal before exiting
-                        if let Some(ref termios) = termios_clone {
-                            restore_terminal(termios);
-                        }
-
-                        // Force immediate exit
-                        std::process::exit(1);
+                        break;
                     }
                 }
             }
         });
 
-        // Start terminal size monitoring (if not headless)
-        if !self.args.headless {
-            let app_state_resize = app_state.clone();
-            let pty_tx_resize = pty_tx.clone();
-            let token_size = cancellation_token.clone();
-            tokio::spawn(async move {
-                let mut last_size = (cols, rows);
-                let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(500));
-
-                loop {
-                    tokio::select! {
-                        _ = token_size.cancelled() => {
-                            debug!("Terminal size monitoring task cancelled");
-                            break;
-                        }
-                        _ = interval.tick() => {
-                            let current_size = get_terminal_size();
+        Ok(Session {
+            state: app_state,
+            cancellation_token,
+            ended,
+        })
+    }
 
-                            if current_size != last_size {
-                                debug!("Terminal size changed: {}x{} -> {}x{}",
-                                       last_size.0, last_size.1, current_size.0, current_size.1);
+    async fn create_app(self: &Arc<Self>, state: AppState) -> anyhow::Result<Router> {
+        if self.args.capabilities {
+            // Session routes are keyed by capability tokens verified on every request
+            let app = Router::new()
+                .route("/s/{token}/", get(serve_capability_page))
+                .route("/s/{token}/static/{*file}", get(serve_capability_static_file))
+                .route("/s/{token}/ws/", get(handle_capability_websocket))
+                .route("/s/{token}/stats", get(serve_capability_stats))
+                .route("/s/{token}/text", get(serve_capability_screen_text))
+                .route("/s/{token}/output", get(serve_capability_output))
+                .route("/s/{token}/stdin", post(write_capability_stdin))
+                .route("/s/{token}/history", get(serve_capability_history))
+                .route(
+                    "/s/{token}/notes",
+                    get(serve_capability_notes).put(write_capability_notes),
+                )
+                .route(
+                    "/s/{token}/timeline",
+                    get(serve_capability_timeline).post(append_capability_timeline),
+                )
+                .route(VERSION_PATH, get(serve_version))
+                .fallback(serve_404)
+                .layer(middleware::from_fn_with_state(self.auth_token.clone(), require_token))
+                .layer(middleware::from_fn_with_state(self.htpasswd.clone(), require_password))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&self.rate_limiter),
+                    rate_limit,
+                ))
+                .route("/robots.txt", get(serve_robots_txt))
+                .with_state(state);
+
+            return Ok(self.with_robots_tag(self.with_ip_filter(self.with_admin_api(app))));
+        }
 
-                                // Validate the new terminal size before applying it
-                                if !is_valid_terminal_size(current_size.0, current_size.1) {
-                                    debug!("Ignoring invalid terminal size from host terminal: {}x{}",
-                                           current_size.0, current_size.1);
-                                    continue;
-                                }
+        // Requests under /s/{id}/ go to the routes of the session with that ID
+        let mut app = Router::new()
+            .route(VERSION_PATH, get(serve_version))
+            .route("/api/sessions/{id}/lines", get(api::serve_lines))
+            .fallback(route_to_session)
+            .with_state(self.sessions.clone());
+        app = app.merge(
+            Router::new()
+                .route(MUX_PATH, get(handle_mux_websocket))
+                .with_state(Arc::clone(self)),
+        );
+        let app = app
+            .layer(middleware::from_fn_with_state(self.auth_token.clone(), require_token))
+            .layer(middleware::from_fn_with_state(self.invites.clone(), redeem_invite))
+            .layer(middleware::from_fn_with_state(self.htpasswd.clone(), require_password))
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&self.rate_limiter),
+                rate_limit,
+            ))
+            .merge(
+                Router::new()
+                    .route("/robots.txt", get(serve_robots_txt))
+                    .with_state(state),
+            );
 
-                                // Update stored size
-                                {
-                                    let mut stored_size = app_state_resize.current_size.lock().await;
-                                    *stored_size = current_size;
-                                }
+        Ok(self.with_robots_tag(self.with_ip_filter(self.with_admin_api(app))))
+    }
 
-                                // Resize the PTY to match new terminal size
-                                {
-                                    let pty_master = app_state_resize.pty_master.lock().await;
-                                    let new_size = PtySize {
-                                        rows: current_size.1,
-                                        cols: current_size.0,
-                                        pixel_width: 0,
-                                        pixel_height: 0,
-                                    };
+    /// Add the admin API if `--admin-token` is given, with the session API of `--session-api`. It takes that
+    /// token instead of the viewers' one, since the session API starts commands.
+    fn with_admin_api(self: &Arc<Self>, app: Router) -> Router {
+        let Some(admin_token) = &self.admin_token else {
+            return app;
+        };
+        let mut admin = Router::new()
+            .route("/api/clients", get(list_clients))
+            .route("/api/clients/{id}", delete(kick_client))
+            .route("/metrics", get(serve_metrics));
+        if self.invites.is_some() {
+            admin = admin.route("/api/invites", post(create_invite));
+        }
+        let mut admin = admin.with_state(Arc::clone(self));
+        if self.args.session_api {
+            admin = admin.merge(api::routes(Arc::clone(self)));
+        }
+        app.merge(
+            admin
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(admin_token),
+                    require_admin_token,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&self.rate_limiter),
+                    rate_limit,
+                )),
+        )
+    }
 
-                                    if let Err(e) = pty_master.resize(new_size) {
-                                        error!("Failed to resize PTY: {}", e);
-                                    } else {
-                                        debug!("Successfully resized PTY to {}x{}", current_size.0, current_size.1);
-                                    }
-                                }
+    /// Refuse clients outside `--allow-cidr` or inside `--deny-cidr`, on every route
+    fn with_ip_filter(&self, app: Router) -> Router {
+        match &self.ip_filter {
+            Some(filter) => app.layer(middleware::from_fn_with_state(Arc::clone(filter), filter_ip)),
+            None => app,
+        }
+    }
 
-                                // Send size change to all WebSocket clients
-                                let winsize_msg = WinSizeMessage {
-                                    cols: current_size.0,
-                                    rows: current_size.1,
-                                };
+    /// Ask search engines not to index or follow anything served, unless indexing is allowed
+    fn with_robots_tag(&self, app: Router) -> Router {
+        if self.args.allow_indexing {
+            return app;
+        }
 
-                                let tty_msg = TtyMessage {
-                                    msg_type: "WinSize".to_string(),
-                                    data: general_purpose::STANDARD.encode(serde_json::to_vec(&winsize_msg).unwrap()),
-                                };
+        app.layer(middleware::map_response(|mut response: Response| async move {
+            response.headers_mut().insert(
+                header::HeaderName::from_static("x-robots-tag"),
+                header::HeaderValue::from_static("noindex, nofollow"),
+            );
+            response
+        }))
+    }
+}
 
-                                let json_str = serde_json::to_string(&tty_msg).unwrap();
+async fn serve_robots_txt(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        state.robots_txt.to_string(),
+    )
+        .into_response()
+}
 
-                                // Broadcast to all WebSocket clients via PTY channel
-                                // We'll use a special marker to distinguish this from regular PTY output
-                                let _ = pty_tx_resize.send(format!("WINSIZE:{json_str}").into_bytes());
+/// Routes of one session, under `/s/{id}/`
+pub(crate) fn session_routes(state: AppState) -> Router {
+    let prefix = format!("/s/{}", state.session_id);
+    Router::new()
+        .route(&format!("{prefix}/"), get(serve_session_page))
+        .route(&format!("{prefix}/static/{{*file}}"), get(serve_static_file))
+        .route(&format!("{prefix}/ws/"), get(handle_websocket))
+        .route(&format!("{prefix}/stats"), get(serve_stats))
+        .route(&format!("{prefix}/text"), get(serve_screen_text))
+        .route(&format!("{prefix}/output"), get(serve_output))
+        .route(&format!("{prefix}/stdin"), post(write_stdin))
+        .route(&format!("{prefix}/history"), get(serve_history))
+        .route(&format!("{prefix}/notes"), get(serve_notes).put(write_notes))
+        .route(&format!("{prefix}/timeline"), get(serve_timeline).post(append_timeline))
+        .fallback(serve_404)
+        .with_state(state)
+}
 
-                                last_size = current_size;
-                            }
-                        }
-                    }
-                }
-            });
-        }
+/// Pass a request under `/s/{id}/` to the routes of session `id`
+async fn route_to_session(State(sessions): State<SessionRegistry>, request: Request) -> Response {
+    let id = request
+        .uri()
+        .path()
+        .strip_prefix("/s/")
+        .and_then(|rest| rest.split('/').next());
+    let Some(session) = id.and_then(|id| sessions.get(id)) else {
+        return serve_404().await;
+    };
+    match session.router.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
 
-        // Start stdin forwarding to PTY (if not headless)
-        if !self.args.headless {
-            let pty_writer_stdin = Arc::clone(&app_state.pty_writer);
-            tokio::task::spawn_blocking(move || {
-                use std::io::{Read, Write, stdin};
-                let mut stdin = stdin();
-                let mut buffer = [0u8; 1024];
-
-                loop {
-                    match stdin.read(&mut buffer) {
-                        Ok(n) if n > 0 => {
-                            let data = &buffer[..n];
-                            if let Some(writer) = pty_writer_stdin.blocking_lock().as_mut() {
-                                let _ = writer.write_all(data);
-                                let _ = writer.flush();
-                            }
-                        }
-                        Ok(_) => {
-                            eprintln!("Stdin reached EOF");
-                            break;
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading from stdin: {e}");
-                            break;
-                        }
-                    }
-                }
-                eprintln!("Stdin reader task ended");
-            });
+/// Follow several sessions of this server, read-only, over one WebSocket connection
+async fn handle_mux_websocket(
+    ws: WebSocketUpgrade,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    State(server): State<Arc<RwShellServer>>,
+) -> Response {
+    ws.max_message_size(MAX_MUX_MESSAGE)
+        .max_frame_size(MAX_MUX_MESSAGE)
+        .on_upgrade(move |socket| handle_mux_socket(socket, server, remote_addr.ip()))
+}
+
+/// Serve a multiplexed connection: each `Open` message starts sending a session's messages on a channel,
+/// until the session ends or a `CloseChannel` message stops it. Each channel counts against the session's
+/// connection limits like a connection of its own.
+async fn handle_mux_socket(socket: WebSocket, server: Arc<RwShellServer>, ip: IpAddr) {
+    let (mut sender, mut receiver) = socket.split();
+    // Bounded, so sessions that produce output faster than the client reads it fall behind and resync
+    let (out_tx, mut out_rx) = mpsc::channel::<axum::extract::ws::Message>(MUX_QUEUE);
+    let sender_task = tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            if sender.send(message).await.is_err() {
+                break;
+            }
         }
+    });
 
-        // Set up graceful shutdown
-        let token_shutdown = cancellation_token.clone();
-        let is_headless = self.args.headless;
-        let shutdown_signal = async move {
-            if is_headless {
-                // In headless mode, listen for Ctrl+C to shutdown the server
-                tokio::select! {
-                    _ = shutdown_rx => {
-                        debug!("Shell process ended, shutting down server");
-                        token_shutdown.cancel();
-                        tokio::spawn(async {
-                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                            debug!("Exiting rwshell");
-                            std::process::exit(0);
-                        });
-                    }
-                    _ = child_shutdown_rx => {
-                        debug!("Child process ended, shutting down server");
-                        token_shutdown.cancel();
-                        tokio::spawn(async {
-                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                            debug!("Exiting rwshell");
-                            std::process::exit(0);
-                        });
-                    }
-                    _ = tokio::signal::ctrl_c() => {
-                        debug!("Received Ctrl+C in headless mode, shutting down server");
-                        token_shutdown.cancel();
-                        std::process::exit(0);
+    let shutdown = CancellationToken::new();
+    let mut channels: HashMap<u32, CancellationToken> = HashMap::new();
+    while let Some(Ok(msg)) = receiver.next().await {
+        let axum::extract::ws::Message::Text(text) = msg else {
+            continue;
+        };
+        let Some((msg_type, data)) = serde_json::from_str::<TtyMessage>(&text)
+            .ok()
+            .and_then(|tty_msg| Some((tty_msg.msg_type, general_purpose::STANDARD.decode(&tty_msg.data).ok()?)))
+        else {
+            continue;
+        };
+
+        match msg_type.as_str() {
+            "Open" => {
+                let Ok(open) = serde_json::from_slice::<OpenMessage>(&data) else {
+                    continue;
+                };
+                channels.retain(|_, channel| !channel.is_cancelled());
+                let session = server.sessions.get(&open.session);
+                if let Some(reason) = open_refusal(session.is_some(), &channels, open.channel) {
+                    debug!("Refused to open channel {}: {}", open.channel, reason);
+                    if let Some(frame) = channel_close_frame(open.channel, CloseReason::SessionNotFound, reason) {
+                        let _ = out_tx.send(axum::extract::ws::Message::Text(frame)).await;
                     }
+                    continue;
                 }
-            } else {
-                // In interactive mode, listen for shell or child process termination
-                tokio::select! {
-                    _ = shutdown_rx => {
-                        debug!("Shell process ended, shutting down server");
+                let Some(session) = session else { continue };
+                let slot = match session.state.connection_limits.admit(ip) {
+                    Ok(slot) => slot,
+                    Err(rejected) => {
+                        let reason = CloseReason::TooManyClients;
+                        if let Some(frame) = channel_close_frame(open.channel, reason, rejected.message()) {
+                            let _ = out_tx.send(axum::extract::ws::Message::Text(frame)).await;
+                        }
+                        continue;
                     }
-                    _ = child_shutdown_rx => {
-                        debug!("Child process ended, shutting down server");
+                };
+
+                let channel_shutdown = shutdown.child_token();
+                channels.insert(open.channel, channel_shutdown.clone());
+                let out_tx = out_tx.clone();
+                tokio::spawn(async move {
+                    let _slot = slot;
+                    forward_session(open.channel, session.state, out_tx, channel_shutdown.clone()).await;
+                    channel_shutdown.cancel();
+                });
+            }
+            "CloseChannel" => {
+                if let Ok(close) = serde_json::from_slice::<CloseChannelMessage>(&data) {
+                    if let Some(channel) = channels.remove(&close.channel) {
+                        channel.cancel();
                     }
                 }
-                token_shutdown.cancel();
-
-                // Restore terminal before exiting
-                if let Some(ref termios) = original_termios {
-                    restore_terminal(termios);
+            }
+            "Challenge" => {
+                let Some(challenge) = serde_json::from_slice::<ChallengeMessage>(&data).ok() else {
+                    continue;
+                };
+                let Ok(nonce) = general_purpose::STANDARD.decode(&challenge.nonce) else {
+                    continue;
+                };
+                let (server_nonce, signature) = server.identity.sign_challenge("", &nonce);
+                let identity_msg = IdentityMessage {
+                    public_key: server.identity.public_key(),
+                    session: String::new(),
+                    server_nonce,
+                    signature,
+                };
+                if let Ok(frame) = encode_message("Identity", &identity_msg) {
+                    let _ = out_tx.send(axum::extract::ws::Message::Text(frame)).await;
                 }
-
-                tokio::spawn(async {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                    debug!("Exiting rwshell");
-                    std::process::exit(0);
-                });
             }
-        };
+            _ => {}
+        }
+    }
 
-        // Start the server with graceful shutdown
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal)
-            .await?;
+    shutdown.cancel();
+    drop(out_tx);
+    let _ = sender_task.await;
+    debug!("Multiplexed WebSocket connection closed");
+}
 
-        Ok(())
+/// List the WebSocket clients of every session of this server, oldest first
+async fn list_clients(State(server): State<Arc<RwShellServer>>) -> Json<Vec<ClientInfo>> {
+    let mut clients = Vec::new();
+    for (session_id, session) in server.sessions.all() {
+        for (id, stats) in session.state.clients.lock().await.iter() {
+            if let Some(connection) = &stats.connection {
+                clients.push(ClientInfo::new(id, &session_id, connection, stats.location.clone()));
+            }
+        }
     }
+    clients.sort_by(|a, b| a.connected_at.cmp(&b.connected_at).then_with(|| a.id.cmp(&b.id)));
+    Json(clients)
+}
 
-    async fn create_app(&self, state: AppState) -> anyhow::Result<Router> {
-        let (session_path, static_path, ws_path) = if self.args.uuid {
-            (
-                format!("/s/{}/", self.session_id),
-                format!("/s/{}/static/{{*file}}", self.session_id),
-                format!("/s/{}/ws/", self.session_id),
-            )
-        } else {
-            (
-                "/s/local/".to_string(),
-                "/s/local/static/{*file}".to_string(),
-                "/s/local/ws/".to_string(),
-            )
-        };
+/// Liveness of the commands of every session in the Prometheus text format
+async fn serve_metrics(State(server): State<Arc<RwShellServer>>) -> Response {
+    let sessions: Vec<_> = server
+        .sessions
+        .all()
+        .into_iter()
+        .map(|(id, session)| (id, session.state))
+        .collect();
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        metrics::render(&sessions, &server.exits, server.budget.as_deref()),
+    )
+        .into_response()
+}
 
-        let app = Router::new()
-            .route(&session_path, get(serve_session_page))
-            .route(&static_path, get(serve_static_file))
-            .route(&ws_path, get(handle_websocket))
-            .fallback(serve_404)
-            .with_state(state);
+/// Query of `POST /api/invites`
+#[derive(Deserialize)]
+struct InviteQuery {
+    /// Lifetime of the invite (e.g. "10m"), instead of `--invite-ttl`
+    ttl: Option<String>,
+}
+
+/// Mint a single-use invite link to the main session
+async fn create_invite(Query(query): Query<InviteQuery>, State(server): State<Arc<RwShellServer>>) -> Response {
+    let Some(invites) = &server.invites else {
+        return serve_404().await;
+    };
+    let ttl = match query.ttl.as_deref().map(humantime::parse_duration) {
+        Some(Ok(ttl)) => Some(ttl),
+        Some(Err(e)) => return (StatusCode::BAD_REQUEST, format!("Invalid ttl: {e}")).into_response(),
+        None => None,
+    };
+    let invite = invites.mint(ttl);
+    info!(
+        "Minted an invite link through the admin API, valid until {}",
+        invite.expires_at
+    );
+    (StatusCode::CREATED, Json(invite)).into_response()
+}
 
-        Ok(app)
+/// Disconnect the WebSocket client `id`, telling it that the host did
+async fn kick_client(Path(id): Path<String>, State(server): State<Arc<RwShellServer>>) -> Response {
+    for (_, session) in server.sessions.all() {
+        let kick = session
+            .state
+            .clients
+            .lock()
+            .await
+            .get(&id)
+            .and_then(|stats| stats.connection.as_ref())
+            .map(|connection| connection.kick.clone());
+        if let Some(kick) = kick {
+            info!("Disconnecting client {} through the admin API", id);
+            kick.cancel();
+            return StatusCode::NO_CONTENT.into_response();
+        }
     }
+    (StatusCode::NOT_FOUND, "No such client").into_response()
 }
 
-async fn serve_404() -> Response {
+pub(crate) async fn serve_404() -> Response {
     match Assets::get_file("404.html") {
         Some(content) => {
             let content_str = String::from_utf8_lossy(&content.data);
@@ -694,80 +1876,942 @@ async fn serve_404() -> Response {
     }
 }
 
-fn get_terminal_size() -> (u16, u16) {
-    if let Some((Width(w), Height(h))) = terminal_size() {
-        (w, h)
-    } else {
-        // Fallback to default size if unable to detect
-        (80, 25)
+/// Serve an embedded asset, precompressed if the browser accepts it. Under its hashed name, as pages link
+/// it, browsers may cache it for good; under its plain name they check back with its ETag.
+pub(crate) async fn serve_static_file(Path(file): Path<String>, headers: HeaderMap) -> Response {
+    match Assets::resolve(&file) {
+        Some((path, content, immutable)) => {
+            let etag = format!("\"{}\"", assets::content_hash(&content));
+            let cached = headers
+                .get(header::IF_NONE_MATCH)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.split(',').any(|tag| tag.trim() == "*" || tag.trim() == etag));
+            let mut response = if cached {
+                StatusCode::NOT_MODIFIED.into_response()
+            } else {
+                let mime_type = Assets::get_content_type(&path);
+                let accept_encoding = headers
+                    .get(header::ACCEPT_ENCODING)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+                match Assets::precompressed(&path, &content, accept_encoding) {
+                    Some((encoding, data)) => (
+                        [
+                            (header::CONTENT_TYPE, mime_type),
+                            (header::CONTENT_ENCODING, encoding.name().to_string()),
+                        ],
+                        data,
+                    )
+                        .into_response(),
+                    None => ([(header::CONTENT_TYPE, mime_type)], content.data).into_response(),
+                }
+            };
+            let cache_control = if immutable {
+                "public, max-age=31536000, immutable"
+            } else {
+                "no-cache"
+            };
+            let response_headers = response.headers_mut();
+            response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+            if let Ok(etag) = HeaderValue::from_str(&etag) {
+                response_headers.insert(header::ETAG, etag);
+            }
+            if Assets::has_variants(&path) {
+                response_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+            }
+            response
+        }
+        None => {
+            // Serve 404.html with 404 status code for missing static files
+            match Assets::get_file("404.html") {
+                Some(content) => {
+                    let content_str = String::from_utf8_lossy(&content.data);
+                    (
+                        StatusCode::NOT_FOUND,
+                        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+                        content_str.to_string(),
+                    )
+                        .into_response()
+                }
+                None => {
+                    // Fallback if 404.html is not found
+                    (
+                        StatusCode::NOT_FOUND,
+                        [(header::CONTENT_TYPE, "text/plain")],
+                        "404 - Static File Not Found".to_string(),
+                    )
+                        .into_response()
+                }
+            }
+        }
+    }
+}
+
+async fn serve_session_page(State(state): State<AppState>) -> Result<Html<String>, StatusCode> {
+    debug!("Serving session page for session: {}", state.session_id);
+    render_session_page(&format!("{}/s/{}", state.base_path, state.session_id))
+}
+
+pub(crate) fn render_session_page(path_prefix: &str) -> Result<Html<String>, StatusCode> {
+    match Assets::get_file("index.html") {
+        Some(template) => {
+            let template_str = Assets::with_hashed_urls(&String::from_utf8_lossy(&template.data));
+            let ws_path = format!("{path_prefix}/ws/");
+
+            // Simple template replacement
+            let rendered = template_str
+                .replace("__PathPrefix__", path_prefix)
+                .replace("__WSPath__", &format!("\"{ws_path}\""));
+
+            Ok(Html(rendered))
+        }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Verify a capability token from the request path against this session, refusing it once its quota
+/// is used up
+fn verify_capability(state: &AppState, token: &str) -> Option<Capability> {
+    let capability = verify_capability_signature(state, token)?;
+    match state.token_quotas.exceeded(token, &capability.quota) {
+        Some(reason) => {
+            debug!("Rejected capability token: {}", reason);
+            None
+        }
+        None => Some(capability),
+    }
+}
+
+/// Verify a capability token from the request path against this session, whatever is left of its quota
+fn verify_capability_signature(state: &AppState, token: &str) -> Option<Capability> {
+    if state.token_bindings.is_revoked(token) {
+        debug!("Rejected revoked capability token");
+        return None;
+    }
+
+    match capability::verify(&state.identity, &state.session_id, token) {
+        Ok(capability) => Some(capability),
+        Err(e) => {
+            debug!("Rejected capability token: {}", e);
+            None
+        }
+    }
+}
+
+async fn serve_capability_page(Path(token): Path<String>, State(state): State<AppState>) -> Response {
+    let Some(capability) = verify_capability_signature(&state, &token) else {
+        return serve_404().await;
+    };
+    // Tell the holder of a used-up link why it no longer opens
+    if let Some(reason) = state.token_quotas.exceeded(&token, &capability.quota) {
+        return (StatusCode::FORBIDDEN, reason).into_response();
+    }
+
+    debug!("Serving capability session page for session: {}", state.session_id);
+    render_session_page(&format!("{}/s/{token}", state.base_path)).into_response()
+}
+
+async fn serve_capability_static_file(
+    Path((token, file)): Path<(String, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if verify_capability(&state, &token).is_none() {
+        return serve_404().await;
+    }
+
+    serve_static_file(Path(file), headers).await
+}
+
+async fn serve_stats(State(state): State<AppState>) -> Json<SessionStats> {
+    let clients = state.clients.lock().await.clone();
+    Json(SessionStats {
+        session_id: state.session_id.clone(),
+        broadcast_capacity: state.broadcast_capacity,
+        lagged_messages: clients.values().map(|client| client.lagged_messages).sum(),
+        task_panics: state.supervisor.panics(),
+        throttled_requests: state.rate_limiter.throttled(),
+        rejected_connections: state.connection_limits.rejected(),
+        colors: state.colors,
+        least_capable_colors: *state.viewer_colors.borrow(),
+        clients,
+    })
+}
+
+/// The command of a session: spawned, or spawned by the child monitor with the terminal side of its PTY,
+/// once its schedule is due if it has one
+enum SessionChild {
+    Spawned(Box<dyn Child + Send + Sync>),
+    Deferred {
+        slave: Box<dyn SlavePty + Send>,
+        cmd: CommandBuilder,
+        schedule: Option<Arc<StartSchedule>>,
+    },
+}
+
+/// Name of a session on a group page: the command and the machine it runs on
+fn default_group_label(command: &str) -> String {
+    let command = std::path::Path::new(command)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| command.to_string());
+
+    match hostname() {
+        Some(hostname) => format!("{command}@{hostname}"),
+        None => command,
+    }
+}
+
+/// Name of this machine, if it has one
+pub(crate) fn hostname() -> Option<String> {
+    let mut hostname = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and the name is read up to its NUL
+    let ok = unsafe { libc::gethostname(hostname.as_mut_ptr().cast(), hostname.len()) } == 0;
+    let len = hostname.iter().position(|&byte| byte == 0).unwrap_or(hostname.len());
+    (ok && len > 0).then(|| String::from_utf8_lossy(&hostname[..len]).into_owned())
+}
+
+/// Versions of rwshell and its protocol, so clients can refuse to connect to a server they can't talk to
+async fn serve_version() -> Json<VersionInfo> {
+    Json(VersionInfo::current())
+}
+
+async fn serve_capability_stats(Path(token): Path<String>, State(state): State<AppState>) -> Response {
+    if verify_capability(&state, &token).is_none() {
+        return serve_404().await;
+    }
+
+    serve_stats(State(state)).await.into_response()
+}
+
+/// Stream the screen as plain text lines, starting with its current contents, for screen readers
+async fn serve_screen_text(State(state): State<AppState>) -> Response {
+    let Some(screen_reader) = &state.screen_reader else {
+        return serve_404().await;
+    };
+
+    let mut initial: String = screen_reader
+        .lines()
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line + "\n")
+        .collect();
+    initial.push('\n');
+
+    let pty_rx = state.pty_tx.subscribe();
+    let updates = futures_util::stream::unfold(pty_rx, |mut pty_rx| async move {
+        loop {
+            match pty_rx.recv().await {
+                Ok(PtyEvent::ScreenText { lines, .. }) => {
+                    let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+                    return Some((Ok::<_, std::convert::Infallible>(text), pty_rx));
+                }
+                Ok(PtyEvent::Close { .. }) | Err(broadcast::error::RecvError::Closed) => return None,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            }
+        }
+    });
+    let body = futures_util::stream::once(async move { Ok(initial) }).chain(updates);
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-cache"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        axum::body::Body::from_stream(body),
+    )
+        .into_response()
+}
+
+async fn serve_capability_screen_text(Path(token): Path<String>, State(state): State<AppState>) -> Response {
+    if verify_capability(&state, &token).is_none() {
+        return serve_404().await;
+    }
+
+    serve_screen_text(State(state)).await
+}
+
+/// Stream the session's output as server-sent events, for integrations that only watch: a `size` event
+/// with the terminal size whenever it changes, `output` events with base64 encoded output starting with
+/// the current screen, `marker` events with each new timeline entry as JSON, `stderr` events with base64
+/// encoded stderr output under `--separate-stderr` starting with its recent history, and a final `close`
+/// event
+async fn serve_output(ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, State(state): State<AppState>) -> Response {
+    let slot = match state.connection_limits.admit(remote_addr.ip()) {
+        Ok(slot) => slot,
+        Err(rejected) => return rejected.into_response(),
+    };
+    stream_output(state, slot, None).await
+}
+
+/// The output stream of [`serve_output`], counted against the connection limits with `slot` while it is
+/// open, and closed with the reason as the data of its `close` event once `quota` is used up
+async fn stream_output(state: AppState, slot: ConnectionSlot, quota: Option<QuotaConnection>) -> Response {
+    let pty_rx = state.pty_tx.subscribe();
+    let mut initial = match output_resync_events(&state).await {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to encode output events: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // Stderr isn't redrawn like the screen, so only its history is sent, and only once
+    let stderr = state.stderr.as_ref().map(|output| output.history()).unwrap_or_default();
+    if !stderr.is_empty() {
+        initial.push(
+            Event::default()
+                .event("stderr")
+                .data(general_purpose::STANDARD.encode(&stderr)),
+        );
+    }
+
+    let updates = futures_util::stream::unfold(Some((pty_rx, state, slot, quota)), |stream_state| async move {
+        let (mut pty_rx, state, slot, quota) = stream_state?;
+        let close = |reason: &str| {
+            let close = Event::default().event("close").data(reason);
+            Some((futures_util::stream::iter(vec![Ok(close)]), None))
+        };
+        loop {
+            let deadline = quota.as_ref().and_then(QuotaConnection::deadline);
+            let received = tokio::select! {
+                received = pty_rx.recv() => received,
+                _ = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    debug!("An output stream used up its time quota");
+                    let reason = quota.and_then(QuotaConnection::finish);
+                    return close(reason.as_deref().unwrap_or("This session link has expired"));
+                }
+            };
+            let events = match received {
+                Ok(PtyEvent::Output { data, .. }) => {
+                    if let Some(reason) = quota.as_ref().and_then(|quota| quota.add_bytes(data.len())) {
+                        debug!("An output stream used up its output quota");
+                        return close(&reason);
+                    }
+                    vec![
+                        Event::default()
+                            .event("output")
+                            .data(general_purpose::STANDARD.encode(&data)),
+                    ]
+                }
+                Ok(PtyEvent::Message(frame)) => match winsize_event(&frame)
+                    .map(|event| vec![event])
+                    .or_else(|| marker_events(&frame))
+                    .or_else(|| stderr_event(&frame).map(|event| vec![event]))
+                {
+                    Some(events) => events,
+                    None => continue,
+                },
+                Ok(PtyEvent::ScreenText { .. }) => continue,
+                Ok(PtyEvent::Close { .. }) | Err(broadcast::error::RecvError::Closed) => return close(""),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("Output stream missed {} messages, resynchronizing", missed);
+                    output_resync_events(&state).await.ok()?
+                }
+            };
+            let events: Vec<Result<Event, std::convert::Infallible>> = events.into_iter().map(Ok).collect();
+            return Some((futures_util::stream::iter(events), Some((pty_rx, state, slot, quota))));
+        }
+    });
+    let initial = initial.into_iter().map(Ok::<_, std::convert::Infallible>);
+    let events = futures_util::stream::iter(initial).chain(updates.flatten());
+
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Events that bring an output stream up to date: the size, then output that redraws the current screen
+async fn output_resync_events(state: &AppState) -> crate::error::Result<Vec<Event>> {
+    let size = *state.current_size.lock().await;
+    let (cols, rows) = size;
+    let snapshot = state.screen.snapshot(size, &state.history.lock().await);
+
+    Ok(vec![
+        Event::default()
+            .event("size")
+            .data(serde_json::to_string(&WinSizeMessage { cols, rows })?),
+        Event::default()
+            .event("output")
+            .data(general_purpose::STANDARD.encode(&snapshot)),
+    ])
+}
+
+/// The `size` event for a broadcast `WinSize` message, if `frame` is one
+fn winsize_event(frame: &str) -> Option<Event> {
+    let tty_msg = serde_json::from_str::<TtyMessage>(frame).ok()?;
+    if tty_msg.msg_type != "WinSize" {
+        return None;
+    }
+    let data = general_purpose::STANDARD.decode(&tty_msg.data).ok()?;
+    Some(Event::default().event("size").data(String::from_utf8(data).ok()?))
+}
+
+/// The size in a broadcast `WinSize` message, if `frame` is one
+pub(crate) fn decode_winsize(frame: &str) -> Option<(u16, u16)> {
+    let tty_msg = serde_json::from_str::<TtyMessage>(frame).ok()?;
+    if tty_msg.msg_type != "WinSize" {
+        return None;
+    }
+    let data = general_purpose::STANDARD.decode(&tty_msg.data).ok()?;
+    let winsize_msg = serde_json::from_slice::<WinSizeMessage>(&data).ok()?;
+    Some((winsize_msg.cols, winsize_msg.rows))
+}
+
+/// The `marker` events for a broadcast `Timeline` message, if `frame` is one
+fn marker_events(frame: &str) -> Option<Vec<Event>> {
+    let tty_msg = serde_json::from_str::<TtyMessage>(frame).ok()?;
+    if tty_msg.msg_type != "Timeline" {
+        return None;
+    }
+    let data = general_purpose::STANDARD.decode(&tty_msg.data).ok()?;
+    let message = serde_json::from_slice::<TimelineMessage>(&data).ok()?;
+    message
+        .entries
+        .iter()
+        .map(|entry| {
+            serde_json::to_string(entry)
+                .ok()
+                .map(|json| Event::default().event("marker").data(json))
+        })
+        .collect()
+}
+
+/// The `stderr` event for a broadcast `Stderr` message, if `frame` is one
+fn stderr_event(frame: &str) -> Option<Event> {
+    let tty_msg = serde_json::from_str::<TtyMessage>(frame).ok()?;
+    if tty_msg.msg_type != "Stderr" {
+        return None;
+    }
+    let data = general_purpose::STANDARD.decode(&tty_msg.data).ok()?;
+    let message = serde_json::from_slice::<StderrMessage>(&data).ok()?;
+    Some(Event::default().event("stderr").data(message.data))
+}
+
+async fn serve_capability_output(
+    Path(token): Path<String>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    fingerprint: ClientFingerprint,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(capability) = verify_capability_signature(&state, &token) else {
+        return serve_404().await;
+    };
+    let slot = match state.connection_limits.admit(remote_addr.ip()) {
+        Ok(slot) => slot,
+        Err(rejected) => return rejected.into_response(),
+    };
+    if let Some(refused) = record_token_use(&state, &token, &capability, remote_addr, &fingerprint) {
+        return refused;
+    }
+    let quota = if capability.quota.is_unlimited() {
+        None
+    } else {
+        match state.token_quotas.connect(&token, &capability.quota) {
+            Ok(quota) => Some(quota),
+            Err(reason) => return (StatusCode::FORBIDDEN, reason).into_response(),
+        }
+    };
+
+    stream_output(state, slot, quota).await
+}
+
+/// How `/history` renders the session's history
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryFormat {
+    /// The output as the program wrote it, escape sequences included
+    Raw,
+    /// Plain text lines
+    #[default]
+    Txt,
+    /// A page keeping colors and text attributes
+    Html,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    format: HistoryFormat,
+}
+
+/// The history kept for resynchronizing clients, so someone who joins late can read what happened
+async fn serve_history(Query(query): Query<HistoryQuery>, State(state): State<AppState>) -> Response {
+    let size = *state.current_size.lock().await;
+    let output = state.history.lock().await.clone();
+    match query.format {
+        HistoryFormat::Raw => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"rwshell-history.log\"",
+                ),
+            ],
+            output,
+        )
+            .into_response(),
+        HistoryFormat::Txt => {
+            let mut text = history::render_lines(&output, size).join("\n");
+            text.push('\n');
+            (
+                [
+                    (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+                    (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+                ],
+                text,
+            )
+                .into_response()
+        }
+        HistoryFormat::Html => Html(history::render_html(&output, size)).into_response(),
+    }
+}
+
+async fn serve_capability_history(
+    Path(token): Path<String>,
+    query: Query<HistoryQuery>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    fingerprint: ClientFingerprint,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(capability) = verify_capability_signature(&state, &token) else {
+        return serve_404().await;
+    };
+    if let Some(refused) = record_token_use(&state, &token, &capability, remote_addr, &fingerprint) {
+        return refused;
+    }
+    // A download counts as a connection, and the history it carries as output
+    if !capability.quota.is_unlimited() {
+        let quota = &capability.quota;
+        let bytes = state.history.lock().await.len();
+        let used_up = match state.token_quotas.connect(&token, quota) {
+            Ok(connection) => connection.add_bytes(bytes),
+            Err(reason) => Some(reason),
+        };
+        if let Some(reason) = used_up {
+            return (StatusCode::FORBIDDEN, reason).into_response();
+        }
+    }
+
+    serve_history(query, State(state)).await
+}
+
+/// The session's shared notes, as Markdown
+async fn serve_notes(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")],
+        state.notes.text(),
+    )
+        .into_response()
+}
+
+async fn serve_capability_notes(Path(token): Path<String>, State(state): State<AppState>) -> Response {
+    if verify_capability(&state, &token).is_none() {
+        return serve_404().await;
+    }
+
+    serve_notes(State(state)).await
+}
+
+/// Replace the session's shared notes with the request body, e.g. from a script
+async fn write_notes(State(state): State<AppState>, text: String) -> Response {
+    if let Some(refused) = refuse_http_writes(&state) {
+        return refused;
+    }
+    set_notes(&state, text)
+}
+
+async fn write_capability_notes(Path(token): Path<String>, State(state): State<AppState>, text: String) -> Response {
+    let Some(capability) = verify_capability(&state, &token) else {
+        return serve_404().await;
+    };
+    if capability.readonly() {
+        return (StatusCode::FORBIDDEN, "This session link is read-only").into_response();
+    }
+    if let Some(refused) = refuse_http_writes(&state) {
+        return refused;
+    }
+    set_notes(&state, text)
+}
+
+/// Refuse input, notes and timeline entries sent over HTTP to a read-only session, or with
+/// `--approve-writers`, where only viewers the host let type over their WebSocket may write
+fn refuse_http_writes(state: &AppState) -> Option<Response> {
+    if state.readonly {
+        return Some((StatusCode::FORBIDDEN, "The session is read-only").into_response());
+    }
+    if state.approval.is_some() {
+        return Some((StatusCode::FORBIDDEN, "Writers must be approved by the host").into_response());
+    }
+    None
+}
+
+fn set_notes(state: &AppState, text: String) -> Response {
+    match state.notes.set(text, &state.pty_tx) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(message) => (StatusCode::PAYLOAD_TOO_LARGE, message).into_response(),
+    }
+}
+
+/// The session's timeline, as JSON
+async fn serve_timeline(State(state): State<AppState>) -> Response {
+    Json(state.timeline.entries()).into_response()
+}
+
+async fn serve_capability_timeline(Path(token): Path<String>, State(state): State<AppState>) -> Response {
+    if verify_capability(&state, &token).is_none() {
+        return serve_404().await;
+    }
+
+    serve_timeline(State(state)).await
+}
+
+/// Add the request body to the session's timeline, e.g. from an alerting script, answering with the new
+/// entry
+async fn append_timeline(State(state): State<AppState>, text: String) -> Response {
+    if let Some(refused) = refuse_http_writes(&state) {
+        return refused;
+    }
+    add_to_timeline(&state, &text)
+}
+
+async fn append_capability_timeline(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+    text: String,
+) -> Response {
+    let Some(capability) = verify_capability(&state, &token) else {
+        return serve_404().await;
+    };
+    if capability.readonly() {
+        return (StatusCode::FORBIDDEN, "This session link is read-only").into_response();
+    }
+    if let Some(refused) = refuse_http_writes(&state) {
+        return refused;
+    }
+    add_to_timeline(&state, &text)
+}
+
+fn add_to_timeline(state: &AppState, text: &str) -> Response {
+    match state.timeline.append(text, "api", &state.pty_tx) {
+        Ok(entry) => (StatusCode::CREATED, Json(entry)).into_response(),
+        Err(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+    }
+}
+
+/// Write a streamed request body to the session as keyboard input, for integrations that only type
+async fn write_stdin(
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    body: Body,
+) -> Response {
+    if let Some(refused) = refuse_http_writes(&state) {
+        return refused;
+    }
+    stream_stdin(state, remote_addr, body).await
+}
+
+async fn write_capability_stdin(
+    Path(token): Path<String>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    body: Body,
+) -> Response {
+    let Some(capability) = verify_capability(&state, &token) else {
+        return serve_404().await;
+    };
+    if capability.readonly() {
+        return (StatusCode::FORBIDDEN, "This session link is read-only").into_response();
+    }
+    if let Some(refused) = refuse_http_writes(&state) {
+        return refused;
+    }
+    stream_stdin(state, remote_addr, body).await
+}
+
+async fn stream_stdin(state: AppState, remote_addr: SocketAddr, body: Body) -> Response {
+    let client_id = Uuid::new_v4().to_string();
+    let script_client = ScriptClient {
+        id: client_id.clone(),
+        address: remote_addr.ip(),
+        readonly: false,
+    };
+    if let Some(script) = &state.script {
+        if !script.client_join(&script_client) {
+            info!("Input client {} was refused by the session script", client_id);
+            return (StatusCode::FORBIDDEN, "Refused by the session script").into_response();
+        }
+    }
+
+    info!("Client {} is writing input from {}", client_id, remote_addr.ip());
+    let mut typed_line = LineBuffer::default();
+    let mut chunks = body.into_data_stream();
+    let mut written = 0;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                debug!("Input stream of client {} failed: {}", client_id, e);
+                break;
+            }
+        };
+        let input = match state.script.as_ref().map(|script| script.input(&script_client, &chunk)) {
+            Some(InputVerdict::Drop) => continue,
+            Some(InputVerdict::Replace(replacement)) => replacement,
+            Some(InputVerdict::Allow) | None => chunk.to_vec(),
+        };
+        state.host.remote_input(&client_id);
+        if let Err(e) = confirm::write_input(&state, &client_id, &mut typed_line, &input, |_| {}).await {
+            warn!("Failed to write input of client {} to the program: {}", client_id, e);
+            return (StatusCode::GONE, "The session has ended").into_response();
+        }
+        written += input.len();
+    }
+
+    if let Some(gate) = &state.confirm {
+        gate.forget(&client_id);
+    }
+    debug!("Client {} wrote {} bytes of input", client_id, written);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn handle_websocket(
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    fingerprint: ClientFingerprint,
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> Response {
+    let slot = match state.connection_limits.admit(remote_addr.ip()) {
+        Ok(slot) => slot,
+        Err(rejected) => return rejected.into_response(),
+    };
+    let readonly = state.readonly;
+    ws.max_message_size(state.max_input_frame)
+        .max_frame_size(state.max_input_frame)
+        .protocols(state.compression.protocols())
+        .on_upgrade(move |socket| async move {
+            // Counts against the limits until the connection ends
+            let _slot = slot;
+            handle_socket(socket, state, readonly, remote_addr, fingerprint, None).await
+        })
+}
+
+async fn handle_capability_websocket(
+    Path(token): Path<String>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    fingerprint: ClientFingerprint,
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> Response {
+    // A used-up link still connects, to be told why it is closed
+    let Some(capability) = verify_capability_signature(&state, &token) else {
+        return serve_404().await;
+    };
+    let slot = match state.connection_limits.admit(remote_addr.ip()) {
+        Ok(slot) => slot,
+        Err(rejected) => return rejected.into_response(),
+    };
+
+    if let Some(refused) = record_token_use(&state, &token, &capability, remote_addr, &fingerprint) {
+        return refused;
+    }
+
+    let readonly = state.readonly || capability.readonly();
+    let quota = (!capability.quota.is_unlimited()).then_some((token, capability.quota));
+    ws.max_message_size(state.max_input_frame)
+        .max_frame_size(state.max_input_frame)
+        .protocols(state.compression.protocols())
+        .on_upgrade(move |socket| async move {
+            let _slot = slot;
+            handle_socket(socket, state, readonly, remote_addr, fingerprint, quota).await
+        })
+}
+
+/// Note the use of a capability token from `remote_addr`, telling the host when the link turns up at a
+/// second address, since it may have leaked, and refusing it if that revoked it
+fn record_token_use(
+    state: &AppState,
+    token: &str,
+    capability: &Capability,
+    remote_addr: SocketAddr,
+    fingerprint: &ClientFingerprint,
+) -> Option<Response> {
+    let addr = client_key(remote_addr.ip());
+    let TokenUse::Moved { from } = state.token_bindings.record(token, addr) else {
+        return None;
+    };
+    let access = if capability.readonly() {
+        "read-only"
+    } else {
+        "read-write"
+    };
+    let client = fingerprint.describe();
+    if state.token_bindings.is_revoked(token) {
+        state.host.notify(&format!(
+            "The {access} session link was used by {client} from {addr} after {from}; it has been revoked"
+        ));
+        return Some((StatusCode::FORBIDDEN, "This session link has been revoked").into_response());
+    }
+    state.host.notify(&format!(
+        "The {access} session link was used by {client} from {addr} after {from}; it may have leaked"
+    ));
+    None
+}
+
+/// Whether a WebSocket receive failed because the message was larger than the connection accepts
+fn is_message_too_long(e: &axum::Error) -> bool {
+    use axum_tungstenite::error::{CapacityError, Error};
+    matches!(
+        std::error::Error::source(e).and_then(|source| source.downcast_ref::<Error>()),
+        Some(Error::Capacity(CapacityError::MessageTooLong { .. }))
+    )
+}
+
+/// Write input from a client to the PTY, once the session script and any confirmation gate have let it
+/// through. Returns false if the connection should be closed because the input was too large.
+async fn write_client_input(
+    state: &AppState,
+    client: &ScriptClient,
+    line: &mut LineBuffer,
+    data: Vec<u8>,
+    reply_tx: &mpsc::Sender<axum::extract::ws::Message>,
+) -> bool {
+    // Ignore input if session is read-only
+    if client.readonly {
+        debug!("Ignoring input in read-only mode");
+        return true;
+    }
+    if data.len() > state.max_input_frame {
+        debug!("Rejected oversized write of {} bytes", data.len());
+        let _ = reply_tx.send(message_too_big_frame()).await;
+        return false;
+    }
+
+    let data = match state.script.as_ref().map(|script| script.input(client, &data)) {
+        Some(InputVerdict::Drop) => {
+            debug!("Session script dropped input from client {}", client.id);
+            return true;
+        }
+        Some(InputVerdict::Replace(replacement)) => replacement,
+        Some(InputVerdict::Allow) | None => data,
+    };
+
+    debug!(
+        "Writing {} bytes to PTY: {:?}",
+        data.len(),
+        String::from_utf8_lossy(&data)
+    );
+    let confirmation_status = |message: ConfirmationMessage| {
+        if let Ok(frame) = encode_message("Confirmation", &message) {
+            let _ = reply_tx.try_send(axum::extract::ws::Message::Text(frame));
+        }
+    };
+    state.host.remote_input(&client.id);
+    if let Err(e) = confirm::write_input(state, &client.id, line, &data, confirmation_status).await {
+        record_write_error(state, &client.id, &e).await;
+        let error = ErrorMessage {
+            message: format!("Your input could not be written to the program: {e}"),
+        };
+        if let Ok(frame) = encode_message("Error", &error) {
+            let _ = reply_tx.send(axum::extract::ws::Message::Text(frame)).await;
+        }
+    }
+    true
+}
+
+/// Log and count input of client `client_id` that couldn't be written to the program
+pub(crate) async fn record_write_error(state: &AppState, client_id: &str, error: &std::io::Error) {
+    warn!(
+        "Failed to write input of client {} to the program: {}",
+        client_id, error
+    );
+    if let Some(stats) = state.clients.lock().await.get_mut(client_id) {
+        stats.write_errors += 1;
     }
 }
 
-async fn serve_static_file(Path(file): Path<String>) -> Response {
-    match Assets::get_file(&file) {
-        Some(content) => {
-            let mime_type = Assets::get_content_type(&file);
-            ([(header::CONTENT_TYPE, mime_type)], content.data).into_response()
-        }
-        None => {
-            // Serve 404.html with 404 status code for missing static files
-            match Assets::get_file("404.html") {
-                Some(content) => {
-                    let content_str = String::from_utf8_lossy(&content.data);
-                    (
-                        StatusCode::NOT_FOUND,
-                        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-                        content_str.to_string(),
-                    )
-                        .into_response()
-                }
-                None => {
-                    // Fallback if 404.html is not found
-                    (
-                        StatusCode::NOT_FOUND,
-                        [(header::CONTENT_TYPE, "text/plain")],
-                        "404 - Static File Not Found".to_string(),
-                    )
-                        .into_response()
-                }
-            }
-        }
+/// Serve a WebSocket client; `quota` is the capability token it connected with and the quota its
+/// connections are held to, if it has one
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    readonly: bool,
+    remote_addr: SocketAddr,
+    fingerprint: ClientFingerprint,
+    quota: Option<(String, Quota)>,
+) {
+    let client_id = Uuid::new_v4().to_string();
+    // Clients that asked for the binary protocol get output without the JSON envelope, compressed if they
+    // also asked for the server's compression
+    let compression = socket
+        .protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(Compression::from_protocol);
+    let binary = compression.is_some();
+    let compressed = compression.is_some_and(|compression| compression != Compression::None);
+    debug!(
+        "New WebSocket connection: {} (binary: {}, compressed: {})",
+        client_id, binary, compressed
+    );
+
+    // With a GeoIP database, viewers of internet-exposed sessions are logged with their location
+    let location = state.geoip.as_ref().map(|geoip| geoip.lookup(remote_addr.ip()));
+    let place = location.as_ref().map(|location| {
+        location
+            .as_ref()
+            .map_or("unknown location".to_string(), Location::to_string)
+    });
+    let client = fingerprint.describe();
+    match &place {
+        Some(place) => info!(
+            "Client {} ({}) joined from {} ({})",
+            client_id,
+            client,
+            remote_addr.ip(),
+            place
+        ),
+        None => debug!("Client {} ({}) joined from {}", client_id, client, remote_addr.ip()),
     }
-}
 
-async fn serve_session_page(State(state): State<AppState>) -> Result<Html<String>, StatusCode> {
-    debug!("Serving session page for session: {}", state.session_id);
-    match Assets::get_file("index.html") {
-        Some(template) => {
-            let template_str = String::from_utf8_lossy(&template.data);
-            let (path_prefix, ws_path) = if state.session_id == "local" {
-                ("/s/local".to_string(), "/s/local/ws/".to_string())
-            } else {
-                (
-                    format!("/s/{}", state.session_id),
-                    format!("/s/{}/ws/", state.session_id),
-                )
-            };
+    let (mut sender, mut receiver) = socket.split();
 
-            // Simple template replacement
-            let rendered = template_str
-                .replace("__PathPrefix__", &path_prefix)
-                .replace("__WSPath__", &format!("\"{ws_path}\""));
+    // With --approve-writers, clients that could type join read-only until the host allows them to
+    let approval = state.approval.clone().filter(|_| !readonly);
+    let readonly = readonly || approval.is_some();
 
-            Ok(Html(rendered))
+    // Let the session script refuse the viewer
+    let mut script_client = ScriptClient {
+        id: client_id.clone(),
+        address: remote_addr.ip(),
+        readonly,
+    };
+    if let Some(script) = &state.script {
+        if !script.client_join(&script_client) {
+            info!("Client {} was refused by the session script", client_id);
+            let _ = sender
+                .send(policy_violation_frame("Refused by the session script"))
+                .await;
+            return;
         }
-        None => Err(StatusCode::NOT_FOUND),
     }
-}
 
-async fn handle_websocket(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
-}
-
-async fn handle_socket(socket: WebSocket, state: AppState) {
-    debug!("New WebSocket connection");
-
-    let (mut sender, mut receiver) = socket.split();
+    // A link with a quota closes the connection once it is used up
+    let mut quota_connection = None;
+    if let Some((token, quota)) = &quota {
+        match state.token_quotas.connect(token, quota) {
+            Ok(connection) => quota_connection = Some(connection),
+            Err(reason) => {
+                info!("Client {} was refused: {}", client_id, reason);
+                for frame in quota_exceeded_frames(&reason) {
+                    let _ = sender.send(frame).await;
+                }
+                return;
+            }
+        }
+    }
 
     // Subscribe to PTY output
     let mut pty_rx = state.pty_tx.subscribe();
@@ -780,14 +2824,16 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             rows: current_size.1,
         };
 
-        let message = TtyMessage {
-            msg_type: "WinSize".to_string(),
-            data: general_purpose::STANDARD.encode(serde_json::to_vec(&winsize_msg).unwrap()),
+        let frame = match encode_message("WinSize", &winsize_msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to encode initial terminal size: {}", e);
+                let _ = sender.send(internal_error_frame()).await;
+                return;
+            }
         };
 
-        let json_str = serde_json::to_string(&message).unwrap();
-
-        if let Err(e) = sender.send(axum::extract::ws::Message::Text(json_str.into())).await {
+        if let Err(e) = sender.send(axum::extract::ws::Message::Text(frame)).await {
             let error_msg = e.to_string();
             if error_msg.contains("closed connection")
                 || error_msg.contains("Connection reset")
@@ -806,17 +2852,21 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     // Send readonly state to new client
     {
         let readonly_msg = ReadOnlyMessage {
-            readonly: state.readonly,
+            readonly,
+            can_request_write: approval.is_some(),
+            can_fork: !readonly && state.forker.is_some(),
         };
 
-        let message = TtyMessage {
-            msg_type: "ReadOnly".to_string(),
-            data: general_purpose::STANDARD.encode(serde_json::to_vec(&readonly_msg).unwrap()),
+        let frame = match encode_message("ReadOnly", &readonly_msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to encode readonly state: {}", e);
+                let _ = sender.send(internal_error_frame()).await;
+                return;
+            }
         };
 
-        let json_str = serde_json::to_string(&message).unwrap();
-
-        if let Err(e) = sender.send(axum::extract::ws::Message::Text(json_str.into())).await {
+        if let Err(e) = sender.send(axum::extract::ws::Message::Text(frame)).await {
             let error_msg = e.to_string();
             if error_msg.contains("closed connection")
                 || error_msg.contains("Connection reset")
@@ -829,23 +2879,25 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             return;
         }
 
-        debug!("Sent readonly state: {}", state.readonly);
+        debug!("Sent readonly state: {}", readonly);
     }
 
     // Send headless state to new client
     {
         let headless_msg = HeadlessMessage {
-            headless: state.headless,
+            headless: state.host.headless(),
         };
 
-        let message = TtyMessage {
-            msg_type: "Headless".to_string(),
-            data: general_purpose::STANDARD.encode(serde_json::to_vec(&headless_msg).unwrap()),
+        let frame = match encode_message("Headless", &headless_msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to encode headless state: {}", e);
+                let _ = sender.send(internal_error_frame()).await;
+                return;
+            }
         };
 
-        let json_str = serde_json::to_string(&message).unwrap();
-
-        if let Err(e) = sender.send(axum::extract::ws::Message::Text(json_str.into())).await {
+        if let Err(e) = sender.send(axum::extract::ws::Message::Text(frame)).await {
             let error_msg = e.to_string();
             if error_msg.contains("closed connection")
                 || error_msg.contains("Connection reset")
@@ -858,88 +2910,254 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             return;
         }
 
-        debug!("Sent headless state: {}", state.headless);
+        debug!("Sent headless state: {}", state.host.headless());
     }
 
-    // Send buffered output to new client
-    {
-        let mut output_buffer = state.output_buffer.lock().await;
-        if !output_buffer.is_empty() {
-            debug!("Sending {} bytes of buffered output to new client", output_buffer.len());
-
-            let write_msg = WriteMessage {
-                size: output_buffer.len(),
-                data: general_purpose::STANDARD.encode(&*output_buffer),
-            };
+    // Send the shared notes to new client
+    let notes = state.notes.text();
+    if !notes.is_empty() {
+        match encode_message("Notes", &NotesMessage { text: notes }) {
+            Ok(frame) => {
+                if let Err(e) = sender.send(axum::extract::ws::Message::Text(frame)).await {
+                    debug!("WebSocket connection closed while sending notes: {}", e);
+                    return;
+                }
+            }
+            Err(e) => error!("Failed to encode notes: {}", e),
+        }
+    }
 
-            let message = TtyMessage {
-                msg_type: "Write".to_string(),
-                data: general_purpose::STANDARD.encode(serde_json::to_vec(&write_msg).unwrap()),
-            };
+    // Send the timeline so far to new client
+    let entries = state.timeline.entries();
+    if !entries.is_empty() {
+        match encode_message("Timeline", &TimelineMessage { entries }) {
+            Ok(frame) => {
+                if let Err(e) = sender.send(axum::extract::ws::Message::Text(frame)).await {
+                    debug!("WebSocket connection closed while sending timeline: {}", e);
+                    return;
+                }
+            }
+            Err(e) => error!("Failed to encode timeline: {}", e),
+        }
+    }
 
-            let json_str = serde_json::to_string(&message).unwrap();
+    // Send the recent stderr output to new client
+    let stderr = state.stderr.as_ref().map(|output| output.history()).unwrap_or_default();
+    if !stderr.is_empty() {
+        let message = StderrMessage {
+            data: general_purpose::STANDARD.encode(&stderr),
+        };
+        match encode_message("Stderr", &message) {
+            Ok(frame) => {
+                if let Err(e) = sender.send(axum::extract::ws::Message::Text(frame)).await {
+                    debug!("WebSocket connection closed while sending stderr: {}", e);
+                    return;
+                }
+            }
+            Err(e) => error!("Failed to encode stderr: {}", e),
+        }
+    }
 
-            if let Err(e) = sender.send(axum::extract::ws::Message::Text(json_str.into())).await {
-                // 연결이 닫힌 경우는 정상적인 상황이므로 debug 레벨로 로깅
-                let error_msg = e.to_string();
-                if error_msg.contains("closed connection")
-                    || error_msg.contains("Connection reset")
-                    || error_msg.contains("Trying to work with closed connection")
-                {
-                    debug!("WebSocket connection closed while sending buffered output: {}", e);
-                } else {
-                    error!("Failed to send buffered output: {}", e);
+    // Send the current screen to new client
+    {
+        let size = *state.current_size.lock().await;
+        let snapshot = state.screen.snapshot(size, &state.history.lock().await);
+        let message = if binary {
+            let compression = compression.unwrap_or_default();
+            axum::extract::ws::Message::Binary(compression.frame(&snapshot))
+        } else {
+            match encode_write_message(&snapshot) {
+                Ok(frame) => axum::extract::ws::Message::Text(frame),
+                Err(e) => {
+                    error!("Failed to encode screen: {}", e);
+                    let _ = sender.send(internal_error_frame()).await;
+                    return;
                 }
-                return;
             }
+        };
 
-            // Clear the buffer after sending
-            output_buffer.clear();
+        if let Err(e) = sender.send(message).await {
+            // 연결이 닫힌 경우는 정상적인 상황이므로 debug 레벨로 로깅
+            let error_msg = e.to_string();
+            if error_msg.contains("closed connection")
+                || error_msg.contains("Connection reset")
+                || error_msg.contains("Trying to work with closed connection")
+            {
+                debug!("WebSocket connection closed while sending screen: {}", e);
+            } else {
+                error!("Failed to send screen: {}", e);
+            }
+            return;
         }
     }
 
+    // Cancelled when the client is disconnected through the admin API
+    let kick = CancellationToken::new();
+    state.clients.lock().await.insert(
+        client_id.clone(),
+        ClientStats {
+            location: location.flatten(),
+            connection: Some(ClientConnection {
+                address: remote_addr.ip(),
+                fingerprint,
+                connected_at: std::time::SystemTime::now(),
+                readonly,
+                kick: kick.clone(),
+            }),
+            ..Default::default()
+        },
+    );
+    state.plugins.event(PluginEvent::ClientJoined(&client_id));
+    if let Some(schedule) = &state.schedule {
+        schedule.viewer_connected();
+    }
+    let viewers = broadcast_clients(&state).await;
+    let from = place.as_ref().map_or(remote_addr.ip().to_string(), |place| {
+        format!("{} ({})", remote_addr.ip(), place)
+    });
+    if state.announce_viewers {
+        state
+            .host
+            .notify(&format!("Viewer connected: {client} from {from}, {viewers} total"));
+    }
+    if let Some(notifier) = &state.notifier {
+        notifier.notify(
+            NotifyEvent::ViewerJoin,
+            format!("Viewer connected: {client} from {from}, {viewers} total"),
+        );
+    }
+
+    // Per-connection channel for replies addressed only to this client. Once it's full, the client's
+    // messages wait until it reads its replies.
+    let (reply_tx, mut reply_rx) = mpsc::channel::<axum::extract::ws::Message>(REPLY_QUEUE);
+
     // Forward PTY output to WebSocket
-    let sender_task = tokio::spawn(async move {
-        while let Ok(data) = pty_rx.recv().await {
-            // Check if this is a WinSize message
-            if let Ok(data_str) = String::from_utf8(data.clone()) {
-                if let Some(winsize_json) = data_str.strip_prefix("WINSIZE:") {
-                    // Extract and send the WinSize message directly
-                    // Remove "WINSIZE:" prefix
-                    if let Err(e) = sender
-                        .send(axum::extract::ws::Message::Text(winsize_json.to_string().into()))
-                        .await
+    let clients_for_lag = Arc::clone(&state.clients);
+    let client_id_for_lag = client_id.clone();
+    let state_for_resync = state.clone();
+    let quota_for_sender = quota.clone();
+    let (subscription_tx, mut subscription_rx) = watch::channel(Subscription::default());
+    let (follow_tx, mut follow_rx) = watch::channel(true);
+    let supervisor = state.supervisor.clone();
+    let mut sender_task = supervisor.spawn_client("sender", &client_id, async move {
+        let mut subscription = Subscription::default();
+        let mut subscribing = true;
+        // Output held back while the viewer pauses, then replayed faster until it is live again
+        let mut follow = FollowBuffer::new(
+            state_for_resync.pause_buffer,
+            state_for_resync.catch_up_speed,
+            state_for_resync.budget.clone(),
+        );
+        let mut following = true;
+        loop {
+            let event = tokio::select! {
+                reply = reply_rx.recv() => {
+                    let Some(reply) = reply else { break };
+                    let is_close = matches!(reply, axum::extract::ws::Message::Close(_));
+                    if let Err(e) = sender.send(reply).await {
+                        debug!("Failed to send reply to WebSocket: {}", e);
+                        break;
+                    }
+                    if is_close {
+                        break;
+                    }
+                    continue;
+                }
+                changed = subscription_rx.changed(), if subscribing => {
+                    if changed.is_err() {
+                        subscribing = false;
+                        continue;
+                    }
+                    let previous = std::mem::replace(&mut subscription, *subscription_rx.borrow_and_update());
+                    // Output missed while unsubscribed is made up for by the current screen
+                    if subscription.includes(Stream::Output)
+                        && !previous.includes(Stream::Output)
+                        && !send_resync(&mut sender, &state_for_resync, true).await
                     {
-                        let error_msg = e.to_string();
-                        if error_msg.contains("closed connection")
-                            || error_msg.contains("Connection reset")
-                            || error_msg.contains("Trying to work with closed connection")
-                        {
-                            debug!("WebSocket connection closed while sending WinSize: {}", e);
-                        } else {
-                            error!("Failed to send WinSize message: {}", e);
-                        }
                         break;
                     }
                     continue;
                 }
-            }
-
-            debug!("Sending {} bytes to WebSocket", data.len());
-
-            let write_msg = WriteMessage {
-                size: data.len(),
-                data: general_purpose::STANDARD.encode(&data),
+                changed = follow_rx.changed(), if following => {
+                    if changed.is_err() {
+                        following = false;
+                        continue;
+                    }
+                    follow.set_follow(*follow_rx.borrow_and_update());
+                    continue;
+                }
+                _ = tokio::time::sleep_until(follow.next_due()), if follow.replaying() => match follow.pop() {
+                    Some(Replayed::Event(event)) => event,
+                    Some(Replayed::Resync) => {
+                        debug!("Client {} paused for longer than the buffer holds, resyncing", client_id_for_lag);
+                        let screen = subscription.includes(Stream::Output);
+                        if !send_resync(&mut sender, &state_for_resync, screen).await {
+                            break;
+                        }
+                        continue;
+                    }
+                    None => continue,
+                },
+                event = pty_rx.recv() => match event {
+                    Ok(event) if follow.holds_back(&event) => {
+                        if subscription.wants(&event) {
+                            follow.push(event);
+                        }
+                        continue;
+                    }
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("Client {} lagged behind by {} messages, resyncing", client_id_for_lag, skipped);
+                        if let Some(stats) = clients_for_lag.lock().await.get_mut(&client_id_for_lag) {
+                            stats.lagged_messages += skipped;
+                            stats.resyncs += 1;
+                        }
+                        let screen = subscription.includes(Stream::Output);
+                        if !send_resync(&mut sender, &state_for_resync, screen).await {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
             };
+            if !subscription.wants(&event) {
+                continue;
+            }
 
-            let message = TtyMessage {
-                msg_type: "Write".to_string(),
-                data: general_purpose::STANDARD.encode(serde_json::to_vec(&write_msg).unwrap()),
+            let (message, close) = match event {
+                PtyEvent::Output {
+                    data,
+                    frame,
+                    compressed: compressed_frame,
+                } => {
+                    debug!("Sending {} bytes to WebSocket", data.len());
+                    if let Some((token, quota)) = &quota_for_sender {
+                        if let Some(reason) = state_for_resync.token_quotas.add_bytes(token, quota, data.len()) {
+                            debug!("Client {} used up its output quota", client_id_for_lag);
+                            for frame in quota_exceeded_frames(&reason) {
+                                let _ = sender.send(frame).await;
+                            }
+                            break;
+                        }
+                    }
+                    if binary {
+                        let frame = match compressed_frame {
+                            Some(compressed_frame) if compressed => compressed_frame,
+                            _ => binary_frame(BINARY_WRITE, &data),
+                        };
+                        (axum::extract::ws::Message::Binary(frame), None)
+                    } else {
+                        (axum::extract::ws::Message::Text(frame), None)
+                    }
+                }
+                PtyEvent::Message(frame) | PtyEvent::ScreenText { frame, .. } => {
+                    (axum::extract::ws::Message::Text(frame), None)
+                }
+                PtyEvent::Close { reason, frame } => (axum::extract::ws::Message::Text(frame), Some(reason)),
             };
 
-            let json_str = serde_json::to_string(&message).unwrap();
-
-            if let Err(e) = sender.send(axum::extract::ws::Message::Text(json_str.into())).await {
+            if let Err(e) = sender.send(message).await {
                 let error_msg = e.to_string();
                 if error_msg.contains("closed connection")
                     || error_msg.contains("Connection reset")
@@ -951,48 +3169,398 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                 }
                 break;
             }
+
+            if let Some(reason) = close {
+                // The close reason has been delivered, now close the connection
+                let _ = sender.send(reason.close_frame()).await;
+                debug!("Sent Close message to WebSocket");
+                break;
+            }
         }
         debug!("PTY to WebSocket sender task ended");
     });
 
     // Handle WebSocket input
-    let pty_writer = state.pty_writer;
-    let readonly = state.readonly;
-    let headless = state.headless;
+    let state_for_input = state.clone();
+    let state_for_colors = state.clone();
+    let state_for_capabilities = state.clone();
+    let state_for_history = state.clone();
+    let clients = state.clients;
+    let host = state.host;
+    let rate_limiter = state.rate_limiter;
     let pty_master_for_resize = state.pty_master;
     let current_size_for_resize = state.current_size;
     let pty_tx_for_resize = state.pty_tx;
     let last_resize_time = state.last_resize_time;
     let pending_resize = state.pending_resize;
-    let receiver_task = tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
-            if let Ok(axum::extract::ws::Message::Text(text)) = msg {
+    let identity = state.identity;
+    let session_id = state.session_id;
+    let first_message_timeout = state.first_message_timeout;
+    let colors = state.colors;
+    let lifetime_reply_tx = reply_tx.clone();
+    let heartbeat = Heartbeat::new(state_for_colors.ping_interval, state_for_colors.ping_timeout);
+    let heartbeat_for_receiver = heartbeat.clone();
+    let client_id_for_receiver = client_id.clone();
+    let supervisor_for_approval = supervisor.clone();
+    let supervisor_for_fork = supervisor.clone();
+    let mut receiver_task = supervisor.spawn_client("receiver", &client_id, async move {
+        let mut readonly = readonly;
+        let write_granted = Arc::new(AtomicBool::new(false));
+        let asking = Arc::new(AtomicBool::new(false));
+        let mut last_fork: Option<std::time::Instant> = None;
+        let mut typed_line = LineBuffer::default();
+        let mut first_message_deadline = first_message_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        loop {
+            let next = match first_message_deadline.take() {
+                Some(deadline) => match tokio::time::timeout_at(deadline, receiver.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        debug!("Closing WebSocket connection that sent no message in time");
+                        let _ = reply_tx.send(policy_violation_frame("First message timeout")).await;
+                        break;
+                    }
+                },
+                None => receiver.next().await,
+            };
+            let Some(msg) = next else { break };
+            if let Some(heartbeat) = &heartbeat_for_receiver {
+                heartbeat.heard();
+            }
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if is_message_too_long(&e) {
+                        debug!("Rejected oversized WebSocket message: {}", e);
+                        let _ = reply_tx.send(message_too_big_frame()).await;
+                    } else {
+                        debug!("WebSocket receive error: {}", e);
+                    }
+                    break;
+                }
+            };
+
+            // The host allowed this viewer to type since its last message
+            if readonly && write_granted.load(Ordering::SeqCst) {
+                readonly = false;
+                script_client.readonly = false;
+                if let Some(stats) = state_for_input.clients.lock().await.get_mut(&client_id_for_receiver) {
+                    if let Some(connection) = &mut stats.connection {
+                        connection.readonly = false;
+                    }
+                }
+            }
+
+            // With the binary protocol, input arrives without the JSON envelope
+            if let axum::extract::ws::Message::Binary(frame) = &msg {
+                if let Some((BINARY_WRITE, data)) = decode_binary_frame(frame) {
+                    if !write_client_input(
+                        &state_for_input,
+                        &script_client,
+                        &mut typed_line,
+                        data.to_vec(),
+                        &reply_tx,
+                    )
+                    .await
+                    {
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            if let axum::extract::ws::Message::Text(text) = msg {
                 debug!("Received WebSocket message: {} chars", text.len());
                 if let Ok(tty_msg) = serde_json::from_str::<TtyMessage>(&text) {
                     if tty_msg.msg_type == "Write" {
-                        // Ignore input if session is read-only
+                        let Some(data) = general_purpose::STANDARD
+                            .decode(&tty_msg.data)
+                            .ok()
+                            .and_then(|data| serde_json::from_slice::<WriteMessage>(&data).ok())
+                            .and_then(|write_msg| general_purpose::STANDARD.decode(&write_msg.data).ok())
+                        else {
+                            continue;
+                        };
+                        if !write_client_input(&state_for_input, &script_client, &mut typed_line, data, &reply_tx).await
+                        {
+                            break;
+                        }
+                    } else if tty_msg.msg_type == "Challenge" {
+                        // Prove the server identity by signing the client nonce for this session
+                        let Ok(challenge_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(challenge) = serde_json::from_slice::<ChallengeMessage>(&challenge_data) else {
+                            continue;
+                        };
+                        let Ok(nonce) = general_purpose::STANDARD.decode(&challenge.nonce) else {
+                            debug!("Ignoring challenge with invalid nonce encoding");
+                            continue;
+                        };
+
+                        let (server_nonce, signature) = identity.sign_challenge(&session_id, &nonce);
+                        let identity_msg = IdentityMessage {
+                            public_key: identity.public_key(),
+                            session: session_id.clone(),
+                            server_nonce,
+                            signature,
+                        };
+                        let reply = match encode_message("Identity", &identity_msg) {
+                            Ok(frame) => axum::extract::ws::Message::Text(frame),
+                            Err(e) => {
+                                error!("Failed to encode identity: {}", e);
+                                let _ = reply_tx.send(internal_error_frame()).await;
+                                break;
+                            }
+                        };
+                        if reply_tx.send(reply).await.is_err() {
+                            break;
+                        }
+                        debug!("Answered identity challenge");
+                    } else if tty_msg.msg_type == "Knock" {
+                        // Any viewer, including read-only ones, may ask for the host's attention
+                        let Ok(knock_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(knock) = serde_json::from_slice::<KnockMessage>(&knock_data) else {
+                            continue;
+                        };
+
+                        if !rate_limiter.knock(remote_addr.ip(), KNOCK_INTERVAL) {
+                            debug!("Ignoring knock from client {}: too frequent", client_id_for_receiver);
+                            continue;
+                        }
+                        // Strip control characters so viewers can't inject escape sequences into the host terminal
+                        let message: String = knock
+                            .message
+                            .chars()
+                            .filter(|c| !c.is_control())
+                            .take(MAX_KNOCK_LENGTH)
+                            .collect();
+                        host.notify_knock(&client_id_for_receiver, &message);
+                    } else if tty_msg.msg_type == "RequestWrite" {
+                        // With --approve-writers, a viewer that joined read-only asks the host to let it type
+                        let Some(gate) = approval.clone().filter(|_| readonly) else {
+                            debug!("Ignoring write request from client {}", client_id_for_receiver);
+                            continue;
+                        };
+                        if asking.swap(true, Ordering::SeqCst) {
+                            continue;
+                        }
+                        let host = Arc::clone(&host);
+                        let client_id = client_id_for_receiver.clone();
+                        let reply_tx = reply_tx.clone();
+                        let write_granted = Arc::clone(&write_granted);
+                        let asking = Arc::clone(&asking);
+                        let can_fork = state_for_input.forker.is_some();
+                        if let Some(notifier) = &state_for_input.notifier {
+                            notifier.notify(NotifyEvent::WriteRequest, format!("Viewer {client_id} asks to type"));
+                        }
+                        supervisor_for_approval.spawn_client("approval", &client_id_for_receiver, async move {
+                            // Stop asking once the viewer is gone
+                            let granted = tokio::select! {
+                                granted = gate.ask(host.as_ref(), &client_id) => granted,
+                                _ = reply_tx.closed() => return,
+                            };
+                            write_granted.store(granted, Ordering::SeqCst);
+                            asking.store(false, Ordering::SeqCst);
+                            let readonly_msg = ReadOnlyMessage {
+                                readonly: !granted,
+                                can_request_write: !granted,
+                                can_fork: granted && can_fork,
+                            };
+                            for frame in [
+                                encode_message("ReadOnly", &readonly_msg),
+                                encode_message("WriteAccess", &WriteAccessMessage { granted }),
+                            ]
+                            .into_iter()
+                            .flatten()
+                            {
+                                let _ = reply_tx.send(axum::extract::ws::Message::Text(frame)).await;
+                            }
+                        });
+                    } else if tty_msg.msg_type == "Fork" {
+                        // With --allow-fork, a writer starts a copy of the session of its own to experiment in
+                        let Some(forker) = state_for_input.forker.clone().filter(|_| !readonly) else {
+                            debug!("Ignoring fork request from client {}", client_id_for_receiver);
+                            continue;
+                        };
+                        if last_fork.is_some_and(|forked| forked.elapsed() < FORK_INTERVAL) {
+                            debug!(
+                                "Ignoring fork request from client {}: too frequent",
+                                client_id_for_receiver
+                            );
+                            let error = ErrorMessage {
+                                message: "You just forked the session, try again in a few seconds".to_string(),
+                            };
+                            if let Ok(frame) = encode_message("Error", &error) {
+                                let _ = reply_tx.send(axum::extract::ws::Message::Text(frame)).await;
+                            }
+                            continue;
+                        }
+                        last_fork = Some(std::time::Instant::now());
+
+                        let state = state_for_input.clone();
+                        let reply_tx = reply_tx.clone();
+                        let client_id = client_id_for_receiver.clone();
+                        supervisor_for_fork.spawn_client("fork", &client_id_for_receiver, async move {
+                            let frame = match forker.fork(&state).await {
+                                Ok(fork) => {
+                                    info!("Client {} forked the session as {}", client_id, fork.id);
+                                    encode_message("Forked", &ForkedMessage { url: fork.url })
+                                }
+                                Err(e) => {
+                                    warn!("Failed to fork the session for client {}: {:#}", client_id, e);
+                                    let error = ErrorMessage {
+                                        message: format!("The session could not be forked: {e:#}"),
+                                    };
+                                    encode_message("Error", &error)
+                                }
+                            };
+                            if let Ok(frame) = frame {
+                                let _ = reply_tx.send(axum::extract::ws::Message::Text(frame)).await;
+                            }
+                        });
+                    } else if tty_msg.msg_type == "Notes" {
+                        // Writers edit the shared notes, which every viewer then receives
+                        if readonly {
+                            debug!("Ignoring notes from read-only client {}", client_id_for_receiver);
+                            continue;
+                        }
+                        let Ok(notes_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(notes) = serde_json::from_slice::<NotesMessage>(&notes_data) else {
+                            continue;
+                        };
+                        if let Err(message) = state_for_input.notes.set(notes.text, &state_for_input.pty_tx) {
+                            debug!("Ignoring notes from client {}: {}", client_id_for_receiver, message);
+                        }
+                    } else if tty_msg.msg_type == "Note" {
+                        // Writers add to the timeline, under their client id
                         if readonly {
-                            debug!("Ignoring input in read-only mode");
+                            debug!(
+                                "Ignoring timeline entry from read-only client {}",
+                                client_id_for_receiver
+                            );
+                            continue;
+                        }
+                        let Ok(note_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(note) = serde_json::from_slice::<NoteMessage>(&note_data) else {
+                            continue;
+                        };
+                        if let Err(message) = state_for_input.timeline.append(
+                            &note.text,
+                            &client_id_for_receiver,
+                            &state_for_input.pty_tx,
+                        ) {
+                            debug!(
+                                "Ignoring timeline entry from client {}: {}",
+                                client_id_for_receiver, message
+                            );
+                        }
+                    } else if tty_msg.msg_type == "Subscribe" {
+                        // Any viewer can choose the streams it receives, e.g. an embed that only shows stderr
+                        let Ok(subscribe_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(subscribe) = serde_json::from_slice::<SubscribeMessage>(&subscribe_data) else {
+                            debug!("Ignoring malformed subscription from client {}", client_id_for_receiver);
+                            continue;
+                        };
+                        debug!(
+                            "Client {} subscribed to {:?}",
+                            client_id_for_receiver, subscribe.streams
+                        );
+                        subscription_tx.send_replace(Subscription::new(&subscribe.streams));
+                    } else if tty_msg.msg_type == "Follow" {
+                        // Any viewer can pause the output to read it, and catch up when it follows it again
+                        let Ok(follow_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(message) = serde_json::from_slice::<FollowMessage>(&follow_data) else {
+                            debug!(
+                                "Ignoring malformed follow message from client {}",
+                                client_id_for_receiver
+                            );
+                            continue;
+                        };
+                        debug!(
+                            "Client {} {} the output",
+                            client_id_for_receiver,
+                            if message.follow { "follows" } else { "paused" }
+                        );
+                        follow_tx.send_replace(message.follow);
+                    } else if tty_msg.msg_type == "HistoryRequest" {
+                        // Any viewer can page back through the history without affecting the terminal
+                        let Ok(request_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(request) = serde_json::from_slice::<HistoryRequestMessage>(&request_data) else {
+                            debug!(
+                                "Ignoring malformed history request from client {}",
+                                client_id_for_receiver
+                            );
+                            continue;
+                        };
+
+                        if !rate_limiter.history_request(remote_addr.ip(), HISTORY_REQUEST_INTERVAL) {
+                            debug!(
+                                "Ignoring history request from client {}: too frequent",
+                                client_id_for_receiver
+                            );
                             continue;
                         }
 
-                        if let Ok(write_msg_data) = general_purpose::STANDARD.decode(&tty_msg.data) {
-                            if let Ok(write_msg) = serde_json::from_slice::<WriteMessage>(&write_msg_data) {
-                                if let Ok(decoded_data) = general_purpose::STANDARD.decode(&write_msg.data) {
-                                    debug!(
-                                        "Writing {} bytes to PTY: {:?}",
-                                        decoded_data.len(),
-                                        String::from_utf8_lossy(&decoded_data)
-                                    );
-                                    if let Some(writer) = pty_writer.lock().await.as_mut() {
-                                        use std::io::Write;
-                                        let _ = writer.write_all(&decoded_data);
-                                        let _ = writer.flush();
-                                    }
+                        let size = *state_for_history.current_size.lock().await;
+                        // Render a copy so PTY output isn't held up meanwhile, away from the async workers
+                        let output = state_for_history.history.lock().await.clone();
+                        let Ok(lines) = tokio::task::spawn_blocking(move || history::render_lines(&output, size)).await
+                        else {
+                            continue;
+                        };
+                        match encode_message("History", &history::page(&lines, &request)) {
+                            Ok(frame) => {
+                                if reply_tx.send(axum::extract::ws::Message::Text(frame)).await.is_err() {
+                                    break;
                                 }
                             }
+                            Err(e) => error!("Failed to encode history: {}", e),
+                        }
+                    } else if tty_msg.msg_type == "Capabilities" {
+                        // Clients describe their terminal so output can be matched to the least capable one
+                        let Ok(capabilities_data) = general_purpose::STANDARD.decode(&tty_msg.data) else {
+                            continue;
+                        };
+                        let Ok(capabilities) = serde_json::from_slice::<CapabilitiesMessage>(&capabilities_data) else {
+                            debug!("Ignoring malformed capabilities from client {}", client_id_for_receiver);
+                            continue;
+                        };
+
+                        let term = capabilities.sanitized_term();
+                        if capabilities.colors < colors {
+                            info!(
+                                "Client {} ({}) supports {} colors, less than the {} colors advertised to the program",
+                                client_id_for_receiver, term, capabilities.colors, colors
+                            );
+                        } else {
+                            debug!(
+                                "Client {} reported TERM={} with {} colors",
+                                client_id_for_receiver, term, capabilities.colors
+                            );
+                        }
+                        if let Some(stats) = state_for_capabilities
+                            .clients
+                            .lock()
+                            .await
+                            .get_mut(&client_id_for_receiver)
+                        {
+                            stats.term = Some(term);
+                            stats.colors = Some(capabilities.colors);
                         }
-                    } else if tty_msg.msg_type == "WinSize" && headless {
+                        refresh_viewer_colors(&state_for_capabilities).await;
+                    } else if tty_msg.msg_type == "WinSize" && host.headless() {
                         // Only process WinSize messages from clients in headless mode
                         if let Ok(winsize_data) = general_purpose::STANDARD.decode(&tty_msg.data) {
                             if let Ok(winsize_msg) = serde_json::from_slice::<WinSizeMessage>(&winsize_data) {
@@ -1036,34 +3604,153 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
         debug!("WebSocket receiver task ended");
     });
 
-    // Wait for either task to complete
+    // Wait for either task to complete, or for the connection to reach its maximum lifetime or the time
+    // left in its quota
+    let lifetime = async {
+        match state.max_connection_lifetime {
+            Some(lifetime) => tokio::time::sleep(lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+    let quota_deadline = quota_connection.as_ref().and_then(QuotaConnection::deadline);
+    let quota_time = async {
+        match quota_deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let pings = async {
+        match &heartbeat {
+            Some(heartbeat) => {
+                heartbeat
+                    .run(|| {
+                        // A client that doesn't read its replies misses pings, and stops answering them
+                        !matches!(
+                            lifetime_reply_tx.try_send(axum::extract::ws::Message::Ping(Bytes::new())),
+                            Err(mpsc::error::TrySendError::Closed(_))
+                        )
+                    })
+                    .await
+            }
+            None => std::future::pending().await,
+        }
+    };
     tokio::select! {
-        _ = sender_task => {},
-        _ = receiver_task => {},
+        _ = &mut sender_task => {},
+        _ = &mut receiver_task => {},
+        _ = pings => {
+            // The client is gone, so there is no one to send a close frame to
+            debug!("Client {} stopped answering pings", client_id);
+            sender_task.abort();
+            receiver_task.abort();
+        },
+        _ = lifetime => {
+            debug!("Client {} reached the maximum connection lifetime", client_id);
+            let _ = lifetime_reply_tx.try_send(policy_violation_frame("Connection lifetime exceeded"));
+            let _ = tokio::time::timeout(Duration::from_secs(1), &mut sender_task).await;
+            sender_task.abort();
+            receiver_task.abort();
+        },
+        _ = quota_time => {
+            debug!("Client {} used up its time quota", client_id);
+            let reason = quota_connection.take().and_then(QuotaConnection::finish);
+            for frame in quota_exceeded_frames(reason.as_deref().unwrap_or("This session link has expired")) {
+                let _ = lifetime_reply_tx.try_send(frame);
+            }
+            let _ = tokio::time::timeout(Duration::from_secs(1), &mut sender_task).await;
+            sender_task.abort();
+            receiver_task.abort();
+        },
+        _ = kick.cancelled() => {
+            for frame in close_frames(CloseReason::Kicked, "Disconnected by the host") {
+                let _ = lifetime_reply_tx.try_send(frame);
+            }
+            let _ = tokio::time::timeout(Duration::from_secs(1), &mut sender_task).await;
+            sender_task.abort();
+            receiver_task.abort();
+        },
     }
 
+    drop(quota_connection);
+    if let Some(stats) = clients.lock().await.remove(&client_id) {
+        if stats.lagged_messages > 0 {
+            debug!(
+                "Client {} missed {} messages over {} resyncs",
+                client_id, stats.lagged_messages, stats.resyncs
+            );
+        }
+    }
+    refresh_viewer_colors(&state_for_colors).await;
+    if let Some(gate) = &state_for_colors.confirm {
+        gate.forget(&client_id);
+    }
+    let viewers = broadcast_clients(&state_for_colors).await;
+    if state_for_colors.announce_viewers {
+        state_for_colors.host.notify(&format!(
+            "Viewer {client} from {} left, {viewers} total",
+            remote_addr.ip()
+        ));
+    }
+    if let Some(notifier) = &state_for_colors.notifier {
+        notifier.notify(
+            NotifyEvent::ViewerLeave,
+            format!("Viewer {client} from {} left, {viewers} total", remote_addr.ip()),
+        );
+    }
+
+    if let Some(place) = &place {
+        info!("Client {} from {} ({}) left", client_id, remote_addr.ip(), place);
+    }
+    state_for_colors.plugins.event(PluginEvent::ClientLeft(&client_id));
     debug!("WebSocket connection closed");
 }
 
-fn setup_raw_terminal() -> Result<Termios, std::io::Error> {
-    use std::os::unix::io::AsRawFd;
-
-    let stdin_fd = std::io::stdin().as_raw_fd();
-    let original_termios = Termios::from_fd(stdin_fd)?;
-    let mut raw_termios = original_termios;
+/// Wait briefly for the child monitor to report the exit code of the child process
+async fn wait_for_exit_code(mut exit_code_rx: tokio::sync::watch::Receiver<Option<u32>>) -> Option<u32> {
+    let exit_code = tokio::time::timeout(Duration::from_millis(500), exit_code_rx.wait_for(Option::is_some)).await;
+    exit_code.ok()?.ok().and_then(|exit_code| *exit_code)
+}
 
-    // Set raw mode
-    termios::cfmakeraw(&mut raw_termios);
+/// Close frame sent when the server fails to serve a client
+fn internal_error_frame() -> axum::extract::ws::Message {
+    axum::extract::ws::Message::Close(Some(axum::extract::ws::CloseFrame {
+        code: axum::extract::ws::close_code::ERROR,
+        reason: "Internal server error".into(),
+    }))
+}
 
-    // Apply the raw terminal settings
-    tcsetattr(stdin_fd, TCSANOW, &raw_termios)?;
+/// Close frame sent when a client breaks a connection policy such as a timeout
+fn policy_violation_frame(reason: &str) -> axum::extract::ws::Message {
+    axum::extract::ws::Message::Close(Some(axum::extract::ws::CloseFrame {
+        code: axum::extract::ws::close_code::POLICY,
+        reason: reason.into(),
+    }))
+}
 
-    Ok(original_termios)
+/// The `Close` message and close frame sent to a client whose capability token used up its quota
+fn quota_exceeded_frames(reason: &str) -> Vec<axum::extract::ws::Message> {
+    close_frames(CloseReason::QuotaExceeded, reason)
 }
 
-fn restore_terminal(original_termios: &Termios) {
-    use std::os::unix::io::AsRawFd;
+/// The `Close` message telling a client why it is disconnected, and the close frame with `message`
+fn close_frames(reason: CloseReason, message: &str) -> Vec<axum::extract::ws::Message> {
+    let close_msg = CloseMessage {
+        reason,
+        message: message.to_string(),
+    };
+    let mut frames = Vec::new();
+    match encode_message("Close", &close_msg) {
+        Ok(frame) => frames.push(axum::extract::ws::Message::Text(frame)),
+        Err(e) => error!("Failed to encode close message: {}", e),
+    }
+    frames.push(policy_violation_frame(message));
+    frames
+}
 
-    let stdin_fd = std::io::stdin().as_raw_fd();
-    let _ = tcsetattr(stdin_fd, TCSANOW, original_termios);
+/// Close frame sent when a client exceeds the incoming message size limit
+fn message_too_big_frame() -> axum::extract::ws::Message {
+    axum::extract::ws::Message::Close(Some(axum::extract::ws::CloseFrame {
+        code: axum::extract::ws::close_code::SIZE,
+        reason: "Message too big".into(),
+    }))
 }
@@ -0,0 +1,52 @@
+use portable_pty::CommandBuilder;
+
+/// "1" in every session, so programs and rwshell itself can tell they run inside one
+pub const RWSHELL: &str = "RWSHELL";
+/// ID of the session
+pub const RWSHELL_SESSION: &str = "RWSHELL_SESSION";
+/// Address viewers open the session at, without any token
+pub const RWSHELL_URL: &str = "RWSHELL_URL";
+/// "terminal" when the host shares its terminal, "headless" without one
+pub const RWSHELL_MODE: &str = "RWSHELL_MODE";
+/// "1" when viewers can't type, "0" otherwise
+pub const RWSHELL_READONLY: &str = "RWSHELL_READONLY";
+/// Number of sessions the command runs inside of, 1 unless sessions were nested with `--allow-nested`
+pub const RWSHELL_LEVEL: &str = "RWSHELL_LEVEL";
+
+/// What a session tells its command about itself through the RWSHELL_* environment variables
+pub struct SessionEnv<'a> {
+    pub session_id: &'a str,
+    pub url: &'a str,
+    pub headless: bool,
+    pub readonly: bool,
+}
+
+impl SessionEnv<'_> {
+    /// Set the variables on `cmd`; `--env` is applied afterwards and may override them
+    pub fn apply(&self, cmd: &mut CommandBuilder) {
+        cmd.env(RWSHELL, "1");
+        cmd.env(RWSHELL_SESSION, self.session_id);
+        cmd.env(RWSHELL_URL, self.url);
+        cmd.env(RWSHELL_MODE, if self.headless { "headless" } else { "terminal" });
+        cmd.env(RWSHELL_READONLY, if self.readonly { "1" } else { "0" });
+        cmd.env(RWSHELL_LEVEL, (level() + 1).to_string());
+    }
+}
+
+/// The session this process runs inside of, if any: its ID, or "local" when an older rwshell didn't
+/// tell it
+pub fn enclosing_session() -> Option<String> {
+    if std::env::var(RWSHELL).ok()? != "1" {
+        return None;
+    }
+    Some(std::env::var(RWSHELL_SESSION).unwrap_or_else(|_| "local".to_string()))
+}
+
+/// Number of sessions this process runs inside of
+fn level() -> u32 {
+    match std::env::var(RWSHELL_LEVEL).ok().and_then(|level| level.parse().ok()) {
+        Some(level) => level,
+        None if enclosing_session().is_some() => 1,
+        None => 0,
+    }
+}
@@ -0,0 +1,155 @@
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Longest session ID accepted, which ends up in URLs and control socket file names
+pub const MAX_SESSION_ID_LENGTH: usize = 64;
+
+/// Letters and digits of short IDs, leaving out those easily mistaken for each other when read aloud or
+/// written down: 0/o, 1/l/i
+const SHORT_ID_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+/// Length of short IDs, about 40 bits
+const SHORT_ID_LENGTH: usize = 8;
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "brisk", "calm", "clever", "cosy", "crisp", "eager", "fancy", "gentle", "glad", "golden",
+    "happy", "jolly", "keen", "kind", "lively", "lucky", "merry", "mighty", "nimble", "proud", "quick", "quiet",
+    "rapid", "shiny", "silver", "steady", "sunny", "swift", "witty",
+];
+
+const ANIMALS: &[&str] = &[
+    "badger", "beaver", "bison", "crane", "dingo", "dolphin", "eagle", "falcon", "ferret", "gecko", "heron", "ibex",
+    "jaguar", "koala", "lemur", "lynx", "marmot", "moose", "newt", "otter", "panda", "puffin", "quail", "raven",
+    "salmon", "seal", "tapir", "tiger", "walrus", "wombat", "yak", "zebra",
+];
+
+/// Makes up IDs for new sessions
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+/// Random UUIDs, e.g. "3f2b9c1e-8d4a-4f6b-9e2d-1c5a7b8e9f0a"
+pub struct UuidIds;
+
+impl IdGenerator for UuidIds {
+    fn generate(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// Short random IDs, e.g. "k7m2xq9d"
+pub struct ShortIds;
+
+impl IdGenerator for ShortIds {
+    fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (0..SHORT_ID_LENGTH)
+            .map(|_| SHORT_ID_ALPHABET[rng.gen_range(0..SHORT_ID_ALPHABET.len())] as char)
+            .collect()
+    }
+}
+
+/// Words that are easy to dictate, e.g. "brave-otter-42"
+pub struct WordIds;
+
+impl IdGenerator for WordIds {
+    fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let adjective = ADJECTIVES[rng.gen_range(0..ADJECTIVES.len())];
+        let animal = ANIMALS[rng.gen_range(0..ANIMALS.len())];
+        format!("{adjective}-{animal}-{}", rng.gen_range(0..100))
+    }
+}
+
+/// "1", "2" and so on, the same on every run, e.g. for integration tests
+#[derive(Default)]
+pub struct SequentialIds(AtomicU64);
+
+impl IdGenerator for SequentialIds {
+    fn generate(&self) -> String {
+        (self.0.fetch_add(1, Ordering::Relaxed) + 1).to_string()
+    }
+}
+
+/// Style of generated session IDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum IdStyle {
+    /// Random UUIDs
+    #[default]
+    Uuid,
+    /// Eight random letters and digits
+    Short,
+    /// An adjective, an animal and a number, e.g. "brave-otter-42"
+    Words,
+    /// Counting up from 1
+    Sequential,
+}
+
+impl IdStyle {
+    pub fn generator(self) -> Box<dyn IdGenerator> {
+        match self {
+            Self::Uuid => Box::new(UuidIds),
+            Self::Short => Box::new(ShortIds),
+            Self::Words => Box::new(WordIds),
+            Self::Sequential => Box::new(SequentialIds::default()),
+        }
+    }
+}
+
+/// Check that `id` can be used as a session ID: it's part of URLs and file names, so only letters,
+/// digits, '-', '_' and '.' are allowed
+pub fn parse_session_id(id: &str) -> Result<String, String> {
+    if id.is_empty() || id.len() > MAX_SESSION_ID_LENGTH {
+        return Err(format!("must be 1 to {MAX_SESSION_ID_LENGTH} characters long"));
+    }
+    if id.starts_with('.') {
+        return Err("must not start with '.'".to_string());
+    }
+    if let Some(c) = id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        return Err(format!("must not contain {c:?}: use letters, digits, '-', '_' or '.'"));
+    }
+    Ok(id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_ids_that_escape_urls_or_paths_are_refused() {
+        for id in [
+            "", ".", "..", ".hidden", "../etc", "a/b", "a\\b", "a b", "a?b", "a%2fb", "é", "a\0b",
+        ] {
+            assert!(parse_session_id(id).is_err(), "{id:?}");
+        }
+        assert!(parse_session_id(&"a".repeat(MAX_SESSION_ID_LENGTH + 1)).is_err());
+        for id in ["demo", "brave-otter-42", "v1.2_rc", "a..b"] {
+            assert_eq!(parse_session_id(id).as_deref(), Ok(id));
+        }
+    }
+
+    #[test]
+    fn generated_ids_are_valid_session_ids() {
+        for style in [IdStyle::Uuid, IdStyle::Short, IdStyle::Words, IdStyle::Sequential] {
+            let generator = style.generator();
+            for _ in 0..100 {
+                let id = generator.generate();
+                assert!(parse_session_id(&id).is_ok(), "{style:?}: {id:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn short_and_sequential_ids_look_as_documented() {
+        let id = ShortIds.generate();
+        assert_eq!(id.len(), SHORT_ID_LENGTH);
+        assert!(!id.contains(['0', 'o', '1', 'l', 'i']));
+
+        let sequential = SequentialIds::default();
+        assert_eq!([sequential.generate(), sequential.generate()], ["1", "2"]);
+    }
+}
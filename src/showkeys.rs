@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+/// Keys pressed by the host, for clients that render a key overlay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeysMessage {
+    #[serde(rename = "Keys")]
+    pub keys: Vec<String>,
+}
+
+/// Escape sequences sent by common keys
+const KEY_SEQUENCES: &[(&[u8], &str)] = &[
+    (b"\x1b[A", "Up"),
+    (b"\x1b[B", "Down"),
+    (b"\x1b[C", "Right"),
+    (b"\x1b[D", "Left"),
+    (b"\x1bOA", "Up"),
+    (b"\x1bOB", "Down"),
+    (b"\x1bOC", "Right"),
+    (b"\x1bOD", "Left"),
+    (b"\x1b[H", "Home"),
+    (b"\x1b[F", "End"),
+    (b"\x1bOH", "Home"),
+    (b"\x1bOF", "End"),
+    (b"\x1b[1~", "Home"),
+    (b"\x1b[2~", "Insert"),
+    (b"\x1b[3~", "Delete"),
+    (b"\x1b[4~", "End"),
+    (b"\x1b[5~", "PageUp"),
+    (b"\x1b[6~", "PageDown"),
+    (b"\x1b[Z", "Shift+Tab"),
+    (b"\x1bOP", "F1"),
+    (b"\x1bOQ", "F2"),
+    (b"\x1bOR", "F3"),
+    (b"\x1bOS", "F4"),
+    (b"\x1b[15~", "F5"),
+    (b"\x1b[17~", "F6"),
+    (b"\x1b[18~", "F7"),
+    (b"\x1b[19~", "F8"),
+    (b"\x1b[20~", "F9"),
+    (b"\x1b[21~", "F10"),
+    (b"\x1b[23~", "F11"),
+    (b"\x1b[24~", "F12"),
+];
+
+/// Describe terminal input as the keys that produced it, e.g. `["l", "s", "Enter"]`
+pub fn describe_keys(input: &[u8]) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+
+    while i < input.len() {
+        let rest = &input[i..];
+
+        if let Some((sequence, name)) = KEY_SEQUENCES.iter().find(|(sequence, _)| rest.starts_with(sequence)) {
+            keys.push(name.to_string());
+            i += sequence.len();
+            continue;
+        }
+
+        let (key, len) = match rest[0] {
+            b'\r' | b'\n' => ("Enter".to_string(), 1),
+            b'\t' => ("Tab".to_string(), 1),
+            0x7f | 0x08 => ("Backspace".to_string(), 1),
+            0x00 => ("Ctrl+Space".to_string(), 1),
+            0x1b => match rest.get(1) {
+                // Alt sends the key prefixed with ESC
+                Some(&next) if next.is_ascii_graphic() && next != b'[' && next != b'O' => {
+                    (format!("Alt+{}", next as char), 2)
+                }
+                _ => ("Esc".to_string(), 1),
+            },
+            byte @ 0x01..=0x1a => (format!("Ctrl+{}", (b'A' + byte - 1) as char), 1),
+            byte @ 0x1c..=0x1f => (format!("Ctrl+{}", (byte + 0x40) as char), 1),
+            b' ' => ("Space".to_string(), 1),
+            _ => {
+                // A printable character, which may take several bytes in UTF-8
+                let len = utf8_len(rest);
+                (String::from_utf8_lossy(&rest[..len]).into_owned(), len)
+            }
+        };
+        keys.push(key);
+        i += len;
+    }
+
+    keys
+}
+
+/// Length of the UTF-8 character starting `bytes`, or 1 for invalid input
+fn utf8_len(bytes: &[u8]) -> usize {
+    let len = match bytes[0] {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    };
+    if len <= bytes.len() && std::str::from_utf8(&bytes[..len]).is_ok() {
+        len
+    } else {
+        1
+    }
+}
@@ -0,0 +1,164 @@
+use crate::server::{PtyEvent, encode_message};
+use crate::supervisor::Supervisor;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// How often the host is sampled with `--stats`
+const STATS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Load and memory of the host and the CPU used by the session's command, sent to viewers with `--stats`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsMessage {
+    /// Load averages over 1, 5 and 15 minutes
+    #[serde(rename = "Load")]
+    pub load: [f64; 3],
+    /// Memory of the host, in bytes
+    #[serde(rename = "MemoryTotal")]
+    pub memory_total: u64,
+    /// Memory that can be used without swapping, in bytes
+    #[serde(rename = "MemoryAvailable")]
+    pub memory_available: u64,
+    #[serde(rename = "SwapTotal")]
+    pub swap_total: u64,
+    #[serde(rename = "SwapFree")]
+    pub swap_free: u64,
+    /// CPU used by the command and the processes it started since the last sample, in percent of one core
+    #[serde(rename = "Cpu")]
+    pub cpu: Option<f64>,
+}
+
+/// Samples the host from /proc, following the CPU time of the command's processes between samples
+pub struct HostStats {
+    pid: Option<u32>,
+    ticks_per_second: f64,
+    previous: Option<(u64, Instant)>,
+}
+
+impl HostStats {
+    /// Stats of the host, with the CPU used by `pid` and its descendants if the command has a pid
+    pub fn new(pid: Option<u32>) -> Self {
+        // SAFETY: sysconf only reads a system setting
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        Self {
+            pid,
+            ticks_per_second: if ticks_per_second > 0 {
+                ticks_per_second as f64
+            } else {
+                100.0
+            },
+            previous: None,
+        }
+    }
+
+    /// Sample the host now. Fails where there is no /proc, as on macOS.
+    pub fn sample(&mut self) -> std::io::Result<StatsMessage> {
+        let loadavg = std::fs::read_to_string("/proc/loadavg")?;
+        let mut load = [0.0; 3];
+        for (average, field) in load.iter_mut().zip(loadavg.split_whitespace()) {
+            *average = field.parse().unwrap_or_default();
+        }
+
+        let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+        let memory = |name: &str| {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map_or(0, |kib| kib * 1024)
+        };
+
+        let cpu = self.pid.and_then(|pid| {
+            let now = Instant::now();
+            let ticks = tree_cpu_ticks(pid)?;
+            let (previous_ticks, previous_time) = self.previous.replace((ticks, now))?;
+            let seconds = now.duration_since(previous_time).as_secs_f64();
+            (seconds > 0.0)
+                .then(|| ticks.saturating_sub(previous_ticks) as f64 / self.ticks_per_second / seconds * 100.0)
+        });
+
+        Ok(StatsMessage {
+            load,
+            memory_total: memory("MemTotal"),
+            memory_available: memory("MemAvailable"),
+            swap_total: memory("SwapTotal"),
+            swap_free: memory("SwapFree"),
+            cpu,
+        })
+    }
+
+    /// Send a sample to every viewer every few seconds until `shutdown` is cancelled, or stop at once if
+    /// the host can't be sampled
+    pub fn start(mut self, pty_tx: broadcast::Sender<PtyEvent>, supervisor: &Supervisor, shutdown: CancellationToken) {
+        supervisor.spawn("stats", async move {
+            // The first sample only starts measuring CPU time
+            if let Err(e) = self.sample() {
+                warn!("Host statistics are not available: {}", e);
+                return;
+            }
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + STATS_INTERVAL, STATS_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+
+                let stats = match self.sample() {
+                    Ok(stats) => stats,
+                    Err(e) => {
+                        debug!("Failed to sample host statistics: {}", e);
+                        continue;
+                    }
+                };
+                match encode_message("Stats", &stats) {
+                    Ok(frame) => {
+                        let _ = pty_tx.send(PtyEvent::Message(frame));
+                    }
+                    Err(e) => debug!("Failed to encode host statistics: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// CPU time used by `pid` and all its descendants, including children they waited for, in clock ticks
+fn tree_cpu_ticks(pid: u32) -> Option<u64> {
+    let mut processes: HashMap<u32, (u32, u64)> = HashMap::new();
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(process) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        // The process may have exited since the directory was listed
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // Fields after the command name, which may contain spaces: state, ppid, ..., utime, stime,
+        // cutime, cstime
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            continue;
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let field = |index: usize| fields.get(index).and_then(|value| value.parse::<u64>().ok());
+        let (Some(ppid), Some(utime), Some(stime), Some(cutime), Some(cstime)) =
+            (field(1), field(11), field(12), field(13), field(14))
+        else {
+            continue;
+        };
+        processes.insert(process, (ppid as u32, utime + stime + cutime + cstime));
+    }
+
+    let (_, mut ticks) = *processes.get(&pid)?;
+    let mut parents = vec![pid];
+    while let Some(parent) = parents.pop() {
+        for (&process, &(ppid, process_ticks)) in &processes {
+            if ppid == parent {
+                ticks += process_ticks;
+                parents.push(process);
+            }
+        }
+    }
+    Some(ticks)
+}
@@ -0,0 +1,126 @@
+use crate::server::{PtyEvent, encode_message};
+use crate::supervisor::Supervisor;
+use base64::{Engine as _, engine::general_purpose};
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Most recent stderr output kept for viewers who join later, in bytes
+const MAX_STDERR_HISTORY: usize = 64 * 1024;
+
+/// Output the program wrote to stderr with `--separate-stderr`, base64 encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StderrMessage {
+    #[serde(rename = "Data")]
+    pub data: String,
+}
+
+/// Recent stderr output of a session whose program writes stderr apart from the PTY
+#[derive(Default)]
+pub struct StderrOutput {
+    history: std::sync::Mutex<Vec<u8>>,
+}
+
+impl StderrOutput {
+    pub fn history(&self) -> Vec<u8> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Keep `data` and send it to every viewer
+    fn push(&self, data: &[u8], pty_tx: &broadcast::Sender<PtyEvent>) {
+        {
+            let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+            history.extend_from_slice(data);
+            if history.len() > MAX_STDERR_HISTORY {
+                let start = history.len() - MAX_STDERR_HISTORY;
+                history.drain(..start);
+            }
+        }
+
+        let message = StderrMessage {
+            data: general_purpose::STANDARD.encode(data),
+        };
+        match encode_message("Stderr", &message) {
+            Ok(frame) => {
+                let _ = pty_tx.send(PtyEvent::Message(frame));
+            }
+            Err(e) => warn!("Failed to encode stderr output: {}", e),
+        }
+    }
+}
+
+/// A named pipe that a program's stderr is redirected to, read by the server. The PTY only lets a
+/// program inherit the terminal, so the program is started by a shell that first points its stderr at
+/// the pipe and removes it, leaving nothing behind however the server ends.
+pub struct StderrCapture {
+    path: PathBuf,
+    receiver: pipe::Receiver,
+}
+
+impl StderrCapture {
+    /// Create the pipe and change `cmd` to write its stderr to it
+    pub fn new(cmd: &mut CommandBuilder) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("rwshell-stderr-{}", Uuid::new_v4()));
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: c_path is a valid NUL-terminated string
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Also open for writing, so reads wait for the program instead of ending before it opens the pipe
+        let receiver = match pipe::OpenOptions::new().read_write(true).open_receiver(&path) {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(e);
+            }
+        };
+
+        let argv = cmd.get_argv_mut();
+        let program = std::mem::take(argv);
+        argv.extend(
+            ["/bin/sh", "-c", "exec 2>\"$0\" && rm -f \"$0\" && exec \"$@\""]
+                .into_iter()
+                .map(Into::into)
+                .chain(std::iter::once(path.clone().into_os_string()))
+                .chain(program),
+        );
+        Ok(Self { path, receiver })
+    }
+
+    /// Read the program's stderr into `output` until `shutdown` is cancelled, then remove the pipe if the
+    /// program never started
+    pub fn start(
+        mut self,
+        output: Arc<StderrOutput>,
+        pty_tx: broadcast::Sender<PtyEvent>,
+        supervisor: &Supervisor,
+        shutdown: CancellationToken,
+    ) {
+        supervisor.spawn("stderr-reader", async move {
+            let mut buffer = [0u8; 8192];
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    read = self.receiver.read(&mut buffer) => match read {
+                        Ok(0) => break,
+                        Ok(n) => output.push(&buffer[..n], &pty_tx),
+                        Err(e) => {
+                            debug!("Failed to read stderr of the program: {}", e);
+                            break;
+                        }
+                    },
+                }
+            }
+            let _ = std::fs::remove_file(&self.path);
+        });
+    }
+}
@@ -0,0 +1,115 @@
+use crate::server::PtyEvent;
+use crate::websocket::TtyMessage;
+use serde::{Deserialize, Serialize};
+
+/// A stream of a session that viewers can stop receiving
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stream {
+    /// The terminal output
+    Output,
+    /// Output of the program's stderr, with `--separate-stderr`
+    Stderr,
+    /// Changed lines of the screen as text, with `--screen-reader`
+    ScreenText,
+}
+
+/// Sent by a viewer to receive only the listed streams, until it sends another one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeMessage {
+    #[serde(rename = "Streams")]
+    pub streams: Vec<Stream>,
+}
+
+/// The streams a connection receives: all of them, until the viewer subscribes to some. Messages that
+/// aren't part of a stream, such as size changes and the timeline, are always sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription {
+    output: bool,
+    stderr: bool,
+    screen_text: bool,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            output: true,
+            stderr: true,
+            screen_text: true,
+        }
+    }
+}
+
+impl Subscription {
+    pub fn new(streams: &[Stream]) -> Self {
+        Self {
+            output: streams.contains(&Stream::Output),
+            stderr: streams.contains(&Stream::Stderr),
+            screen_text: streams.contains(&Stream::ScreenText),
+        }
+    }
+
+    pub fn includes(&self, stream: Stream) -> bool {
+        match stream {
+            Stream::Output => self.output,
+            Stream::Stderr => self.stderr,
+            Stream::ScreenText => self.screen_text,
+        }
+    }
+
+    /// Whether `event` goes to the viewer. Broadcast messages are only decoded when stderr is left out,
+    /// to tell its messages apart.
+    pub fn wants(&self, event: &PtyEvent) -> bool {
+        match event {
+            PtyEvent::Output { .. } => self.output,
+            PtyEvent::ScreenText { .. } => self.screen_text,
+            PtyEvent::Message(frame) if !self.stderr => {
+                serde_json::from_str::<TtyMessage>(frame).map_or(true, |message| message.msg_type != "Stderr")
+            }
+            PtyEvent::Message(_) | PtyEvent::Close { .. } => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::encode_message;
+
+    fn message(msg_type: &str) -> PtyEvent {
+        PtyEvent::Message(encode_message(msg_type, &serde_json::json!({})).unwrap())
+    }
+
+    fn screen_text() -> PtyEvent {
+        PtyEvent::ScreenText {
+            lines: std::sync::Arc::new(vec!["line".to_string()]),
+            frame: "{}".into(),
+        }
+    }
+
+    #[test]
+    fn viewers_get_every_stream_until_they_subscribe() {
+        let subscription = Subscription::default();
+        assert!(subscription.wants(&message("Stderr")));
+        assert!(subscription.wants(&screen_text()));
+    }
+
+    #[test]
+    fn subscriptions_leave_out_the_other_streams() {
+        let subscribe: SubscribeMessage = serde_json::from_str(r#"{"Streams":["screen-text"]}"#).unwrap();
+        let subscription = Subscription::new(&subscribe.streams);
+        assert!(subscription.includes(Stream::ScreenText));
+        assert!(!subscription.includes(Stream::Output));
+        assert!(subscription.wants(&screen_text()));
+        assert!(!subscription.wants(&message("Stderr")));
+        // Messages outside the streams still arrive, even malformed ones
+        assert!(subscription.wants(&message("WinSize")));
+        assert!(subscription.wants(&PtyEvent::Message("not json".into())));
+
+        let subscription = Subscription::new(&[Stream::Stderr]);
+        assert!(subscription.wants(&message("Stderr")));
+        assert!(!subscription.wants(&screen_text()));
+
+        assert!(serde_json::from_str::<SubscribeMessage>(r#"{"Streams":["keystrokes"]}"#).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use futures_util::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+/// Runs the tasks of a session, containing panics so they are reported instead of lost
+#[derive(Clone)]
+pub struct Supervisor {
+    session_id: String,
+    shutdown: CancellationToken,
+    panics: Arc<AtomicU64>,
+}
+
+impl Supervisor {
+    pub fn new(session_id: &str, shutdown: CancellationToken) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            shutdown,
+            panics: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Number of supervised tasks that have panicked
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Spawn a task the session depends on; if it panics the whole session is torn down
+    pub fn spawn<F>(&self, name: &'static str, future: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+                error!(
+                    "Task {} of session {} panicked: {}; shutting down the session",
+                    name,
+                    supervisor.session_id,
+                    panic_message(&panic)
+                );
+                supervisor.panics.fetch_add(1, Ordering::Relaxed);
+                supervisor.shutdown.cancel();
+            }
+        })
+    }
+
+    /// Spawn a task serving a single client; if it panics only that client is affected
+    pub fn spawn_client<F>(&self, name: &'static str, client_id: &str, future: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let client_id = client_id.to_string();
+        tokio::spawn(async move {
+            if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+                error!(
+                    "Task {} of client {} in session {} panicked: {}",
+                    name,
+                    client_id,
+                    supervisor.session_id,
+                    panic_message(&panic)
+                );
+                supervisor.panics.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
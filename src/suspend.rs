@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// What happens to the command of a session nobody watches, with `--suspend-after`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SuspendMode {
+    /// Stop the command with SIGSTOP and let it continue when a viewer connects
+    Stop,
+    /// End the command and start it again, from scratch, when a viewer connects
+    Restart,
+}
+
+/// Tracks the viewers of a session to suspend its command once none have been connected for a while
+pub struct Suspender {
+    pub mode: SuspendMode,
+    after: Duration,
+    viewers: watch::Sender<usize>,
+    /// The command is stopped or ended because nobody watches
+    suspended: AtomicBool,
+}
+
+impl Suspender {
+    pub fn new(mode: SuspendMode, after: Duration) -> Self {
+        Self {
+            mode,
+            after,
+            viewers: watch::Sender::new(0),
+            suspended: AtomicBool::new(false),
+        }
+    }
+
+    /// Note how many viewers are connected now
+    pub fn set_viewers(&self, count: usize) {
+        self.viewers.send_replace(count);
+    }
+
+    /// Wait until no viewer has been connected for the suspend delay
+    pub async fn idle(&self) {
+        let mut viewers = self.viewers.subscribe();
+        loop {
+            if viewers.wait_for(|count| *count == 0).await.is_err() {
+                return std::future::pending().await;
+            }
+            if tokio::time::timeout(self.after, viewers.wait_for(|count| *count > 0))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Wait until a viewer connects
+    pub async fn viewer_connected(&self) {
+        let _ = self.viewers.subscribe().wait_for(|count| *count > 0).await;
+    }
+
+    /// Note whether the command is suspended now
+    pub fn set_suspended(&self, suspended: bool) {
+        self.suspended.store(suspended, Ordering::Relaxed);
+    }
+
+    /// Whether the command is stopped or ended because nobody watches
+    pub fn suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed)
+    }
+
+    /// The delay as given, e.g. "10m"
+    pub fn describe_delay(&self) -> String {
+        humantime::format_duration(self.after).to_string()
+    }
+}
+
+/// Send `signal` to the process group of the command, which leads its own group in the PTY
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill only sends a signal; a stale group ID at worst fails with ESRCH
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+}
+
+/// Stop the process group of the command with SIGSTOP whenever no viewer has been connected for a while,
+/// and let it continue once one connects, until `shutdown` is cancelled
+pub async fn stop_while_idle(suspender: Arc<Suspender>, pid: u32, shutdown: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = suspender.idle() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        info!(
+            "No viewers for {}, stopping the command until one connects",
+            suspender.describe_delay()
+        );
+        signal_group(pid, libc::SIGSTOP);
+        suspender.set_suspended(true);
+        let connected = tokio::select! {
+            _ = suspender.viewer_connected() => true,
+            _ = shutdown.cancelled() => false,
+        };
+        // Also when the session ends, so the command can be terminated
+        signal_group(pid, libc::SIGCONT);
+        suspender.set_suspended(false);
+        if !connected {
+            return;
+        }
+        info!("A viewer connected, continuing the command");
+    }
+}
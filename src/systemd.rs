@@ -0,0 +1,155 @@
+use anyhow::{Context, Result, bail};
+use std::net::SocketAddr;
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// First file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Directory for systemd units of the current user
+pub fn user_unit_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("systemd")
+        .join("user")
+}
+
+/// Directory for system-wide systemd units
+pub fn system_unit_dir() -> PathBuf {
+    PathBuf::from("/etc/systemd/system")
+}
+
+/// Name of the systemd service for the session `name`
+pub fn service_name(name: &str) -> String {
+    format!("rwshell-{name}.service")
+}
+
+/// Name of the systemd socket for the session `name`
+pub fn socket_name(name: &str) -> String {
+    format!("rwshell-{name}.socket")
+}
+
+/// A systemd service running rwshell as a persistent headless session
+pub struct ServiceUnit<'a> {
+    pub description: &'a str,
+    pub exe: &'a Path,
+    pub args: &'a [String],
+    /// Directory relative paths in `args` are resolved against
+    pub working_directory: Option<&'a Path>,
+    /// Installed for the whole system instead of the current user
+    pub system: bool,
+    /// Started by a socket unit that passes the listening socket
+    pub socket_activated: bool,
+}
+
+impl ServiceUnit<'_> {
+    pub fn render(&self) -> String {
+        let mut exec_start = quote(&self.exe.to_string_lossy());
+        for arg in self.args {
+            exec_start.push(' ');
+            exec_start.push_str(&quote(arg));
+        }
+
+        let mut unit = format!(
+            "[Unit]
+Description=rwshell: {}
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+ExecStart={exec_start}
+",
+            self.description
+        );
+        if let Some(dir) = self.working_directory {
+            unit.push_str(&format!("WorkingDirectory={}\n", dir.display()));
+        }
+        unit.push_str("Restart=on-failure\n");
+
+        // A socket-activated service is started by its socket instead
+        if !self.socket_activated {
+            unit.push_str(&format!("\n[Install]\nWantedBy={}\n", install_target(self.system)));
+        }
+        unit
+    }
+}
+
+/// A systemd socket listening on `listen` and starting the service of the same name
+pub fn socket_unit(description: &str, listen: SocketAddr) -> String {
+    format!(
+        "[Unit]
+Description=rwshell socket: {description}
+
+[Socket]
+ListenStream={listen}
+
+[Install]
+WantedBy=sockets.target
+"
+    )
+}
+
+fn install_target(system: bool) -> &'static str {
+    if system { "multi-user.target" } else { "default.target" }
+}
+
+/// Run `systemctl` for the user or system manager
+pub fn systemctl(system: bool, args: &[&str]) -> Result<()> {
+    let mut command = Command::new("systemctl");
+    if !system {
+        command.arg("--user");
+    }
+    command.args(args);
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run systemctl {}", args.join(" ")))?;
+    if !status.success() {
+        bail!("systemctl {} failed with {}", args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// The listening socket passed by systemd socket activation, if this process was started that way
+pub fn activated_listener() -> Option<std::net::TcpListener> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+
+    // SAFETY: systemd passes an open listening socket as the first file descriptor after stderr. It
+    // is closed on exec so the shared command doesn't inherit it; LISTEN_PID keeps the command from
+    // mistaking the variables for its own.
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+        Some(std::net::TcpListener::from_raw_fd(LISTEN_FDS_START))
+    }
+}
+
+/// Quote a word of a command line for systemd, which expands `%` specifiers and `$` variables
+fn quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%").replace('$', "$$");
+    let plain = !escaped.is_empty()
+        && escaped
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:@%$+=,".contains(c));
+    if plain {
+        return escaped;
+    }
+
+    let mut quoted = String::with_capacity(escaped.len() + 2);
+    quoted.push('"');
+    for c in escaped.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
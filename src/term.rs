@@ -0,0 +1,144 @@
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+
+/// Longest TERM name accepted from a client
+const MAX_TERM_LENGTH: usize = 64;
+
+/// Colors a terminal can display, ordered from least to most capable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ColorDepth {
+    /// No colors, only attributes like bold and reverse video
+    #[serde(rename = "none")]
+    #[value(name = "none")]
+    Monochrome,
+    /// The 16 standard ANSI colors
+    #[serde(rename = "16")]
+    #[value(name = "16")]
+    Ansi16,
+    /// The xterm 256-color palette
+    #[serde(rename = "256")]
+    #[value(name = "256")]
+    Ansi256,
+    /// 24-bit RGB colors
+    #[serde(rename = "truecolor")]
+    #[value(name = "truecolor")]
+    TrueColor,
+}
+
+impl ColorDepth {
+    /// Guess the color depth of a terminal from its TERM and COLORTERM variables
+    pub fn detect(term: Option<&str>, colorterm: Option<&str>) -> Self {
+        if matches!(colorterm, Some("truecolor" | "24bit")) {
+            return Self::TrueColor;
+        }
+
+        match term {
+            None | Some("" | "dumb" | "vt100" | "vt102" | "vt220") => Self::Monochrome,
+            Some(term) if term.ends_with("-mono") => Self::Monochrome,
+            Some(term) if term.ends_with("-direct") => Self::TrueColor,
+            Some(term) if term.contains("256color") => Self::Ansi256,
+            Some(_) => Self::Ansi16,
+        }
+    }
+
+    /// Color depth of the terminal rwshell runs in
+    pub fn from_env() -> Self {
+        Self::detect(
+            std::env::var("TERM").ok().as_deref(),
+            std::env::var("COLORTERM").ok().as_deref(),
+        )
+    }
+
+    /// TERM value advertising this color depth
+    pub fn term(self) -> &'static str {
+        match self {
+            Self::Monochrome => "vt100",
+            Self::Ansi16 => "xterm",
+            Self::Ansi256 | Self::TrueColor => "xterm-256color",
+        }
+    }
+}
+
+impl std::fmt::Display for ColorDepth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Monochrome => "no",
+            Self::Ansi16 => "16",
+            Self::Ansi256 => "256",
+            Self::TrueColor => "truecolor",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Terminal type and color depth advertised to the shared program
+#[derive(Debug, Clone)]
+pub struct TermSettings {
+    /// TERM exported to the program; inherited from the environment if unset
+    pub term: Option<String>,
+    /// Colors the program is told it may use
+    pub colors: ColorDepth,
+}
+
+impl TermSettings {
+    /// Settings from the `--term` and `--colors` options.
+    ///
+    /// Interactive sessions follow the host terminal unless overridden. Headless sessions are only
+    /// viewed through browsers, whose terminal supports truecolor.
+    pub fn new(term: Option<String>, colors: Option<ColorDepth>, headless: bool) -> Self {
+        let colors = colors.unwrap_or(if headless {
+            ColorDepth::TrueColor
+        } else {
+            ColorDepth::from_env()
+        });
+
+        // Only replace the inherited TERM when it would no longer match the advertised colors
+        let term = match term {
+            Some(term) => Some(term),
+            None if headless || colors != ColorDepth::from_env() => Some(colors.term().to_string()),
+            None => None,
+        };
+
+        Self { term, colors }
+    }
+
+    /// Export the settings to the environment of `cmd`
+    pub fn apply(&self, cmd: &mut CommandBuilder) {
+        if let Some(term) = &self.term {
+            cmd.env("TERM", term);
+        }
+        if self.colors == ColorDepth::TrueColor {
+            cmd.env("COLORTERM", "truecolor");
+        } else {
+            cmd.env_remove("COLORTERM");
+        }
+    }
+}
+
+/// Capabilities a client reports about the terminal it renders the session in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapabilitiesMessage {
+    #[serde(rename = "Term", default)]
+    pub term: String,
+    #[serde(rename = "Colors")]
+    pub colors: ColorDepth,
+}
+
+impl CapabilitiesMessage {
+    /// Capabilities of the terminal this process runs in
+    pub fn from_env() -> Self {
+        Self {
+            term: std::env::var("TERM").unwrap_or_default(),
+            colors: ColorDepth::from_env(),
+        }
+    }
+
+    /// The reported TERM with control characters removed and its length capped, safe for logs and stats
+    pub fn sanitized_term(&self) -> String {
+        self.term
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_TERM_LENGTH)
+            .collect()
+    }
+}
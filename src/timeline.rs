@@ -0,0 +1,166 @@
+use crate::server::{PtyEvent, encode_message};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Longest text of a timeline entry, in characters
+pub const MAX_ENTRY_LENGTH: usize = 500;
+
+/// Most entries a session's timeline keeps; later ones are refused
+const MAX_ENTRIES: usize = 10_000;
+
+/// Something noted during a session, at the time it was noted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    /// When the entry was added, in RFC 3339 (e.g. "2024-05-01T09:30:00Z")
+    #[serde(rename = "Time")]
+    pub time: String,
+    #[serde(rename = "Text")]
+    pub text: String,
+    /// Who added it: a viewer's client id, "host" or "api"
+    #[serde(rename = "Author")]
+    pub author: String,
+}
+
+impl TimelineEntry {
+    /// When the entry was added, if its time can be read
+    pub fn system_time(&self) -> Option<SystemTime> {
+        humantime::parse_rfc3339(&self.time).ok()
+    }
+}
+
+/// Entries of the timeline: all of them when a viewer connects, then each one as it is added
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineMessage {
+    #[serde(rename = "Entries")]
+    pub entries: Vec<TimelineEntry>,
+}
+
+/// A new entry for the timeline, sent by writers and by `rwshell ctl note`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoteMessage {
+    #[serde(rename = "Text")]
+    pub text: String,
+}
+
+/// Reply of the control socket to a [`NoteMessage`]: the entry that was added, or why it wasn't
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotedMessage {
+    #[serde(rename = "Entry")]
+    pub entry: Option<TimelineEntry>,
+    #[serde(rename = "Error", default)]
+    pub error: Option<String>,
+}
+
+/// Timestamped entries added during a session, such as the steps of an incident. Entries are only ever
+/// appended; with `--timeline-file` they start from the file and each new one is added to it as a line
+/// of JSON.
+#[derive(Default)]
+pub struct Timeline {
+    entries: std::sync::Mutex<Vec<TimelineEntry>>,
+    file: Option<PathBuf>,
+}
+
+impl Timeline {
+    /// Timeline kept in `file`, starting from its entries if it exists
+    pub fn load(file: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            entries: std::sync::Mutex::new(read_entries(file)?),
+            file: Some(file.to_path_buf()),
+        })
+    }
+
+    pub fn entries(&self) -> Vec<TimelineEntry> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Add `text` by `author` at the current time, save it and send it to every viewer. Fails if `text`
+    /// is empty or longer than [`MAX_ENTRY_LENGTH`], or the timeline is full.
+    pub fn append(
+        &self,
+        text: &str,
+        author: &str,
+        pty_tx: &broadcast::Sender<PtyEvent>,
+    ) -> Result<TimelineEntry, String> {
+        // One line of plain text, so entries can't inject escape sequences wherever they are shown
+        let text: String = text
+            .trim()
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        if text.is_empty() {
+            return Err("A timeline entry needs some text".to_string());
+        }
+        if text.chars().count() > MAX_ENTRY_LENGTH {
+            return Err(format!("Timeline entries are limited to {MAX_ENTRY_LENGTH} characters"));
+        }
+
+        let entry = TimelineEntry {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            text,
+            author: author.to_string(),
+        };
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            if entries.len() >= MAX_ENTRIES {
+                return Err(format!("The timeline is limited to {MAX_ENTRIES} entries"));
+            }
+            entries.push(entry.clone());
+            // Saved while locked, so the file keeps the entries in order
+            if let Some(file) = &self.file {
+                if let Err(e) = append_entry(file, &entry) {
+                    warn!("Failed to save timeline entry to {}: {}", file.display(), e);
+                }
+            }
+        }
+        debug!("Timeline entry from {}: {}", entry.author, entry.text);
+
+        let message = TimelineMessage {
+            entries: vec![entry.clone()],
+        };
+        match encode_message("Timeline", &message) {
+            Ok(frame) => {
+                let _ = pty_tx.send(PtyEvent::Message(frame));
+            }
+            Err(e) => warn!("Failed to encode timeline entry: {}", e),
+        }
+        Ok(entry)
+    }
+}
+
+fn append_entry(file: &Path, entry: &TimelineEntry) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?
+        .write_all(line.as_bytes())
+}
+
+/// Entries of the timeline file at `path`, or none if there is no such file
+pub fn read_entries(path: &Path) -> anyhow::Result<Vec<TimelineEntry>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read timeline from {}", path.display())),
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid timeline entry on line {} of {}", number + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Timeline file that `rwshell export` shows as markers of the recording at `cast`: "incident.cast" goes
+/// with "incident.timeline.jsonl"
+pub fn file_for_recording(cast: &Path) -> PathBuf {
+    cast.with_extension("timeline.jsonl")
+}
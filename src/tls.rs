@@ -0,0 +1,202 @@
+use crate::args::Args;
+use crate::serve::{ServeOptions, serve};
+use anyhow::{Context, bail};
+use axum::Router;
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig, ServerConnection};
+use tracing::{debug, error};
+
+/// Accepts TLS connections with the certificate and key given with `--tls-cert` and `--tls-key`, if
+/// they were given
+pub fn acceptor(args: &Args) -> anyhow::Result<Option<TlsAcceptor>> {
+    let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read a private key from {}", key_path.display()))?;
+    let builder = ServerConfig::builder();
+    let builder = match &args.tls_client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(ca_path)? {
+                roots
+                    .add(ca)
+                    .with_context(|| format!("Invalid CA certificate in {}", ca_path.display()))?;
+            }
+            // Only names viewers: those without a certificate still connect
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .with_context(|| format!("Failed to use the CAs in {}", ca_path.display()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .with_context(|| format!("{} doesn't match {}", key_path.display(), cert_path.display()))?;
+    // WebSocket upgrades need HTTP/1.1, which browsers fall back to for them when offered HTTP/2
+    config.alpn_protocols = if args.http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Name on the verified certificate a client presented with `--tls-client-ca`, added to its requests
+#[derive(Clone, Debug)]
+pub struct ClientCertificate(pub String);
+
+impl ClientCertificate {
+    /// The certificate the client of `connection` presented, named by the common name of its subject, or
+    /// the whole subject without one
+    pub fn of(connection: &ServerConnection) -> Option<Self> {
+        let der = connection.peer_certificates()?.first()?;
+        let (_, certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = certificate.subject();
+        let name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map_or_else(|| subject.to_string(), str::to_string);
+        Some(Self(name))
+    }
+}
+
+/// The certificate chain in the PEM file at `path`, leaf first
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Scheme of the page URLs served with `args`
+pub fn http_scheme(args: &Args) -> &'static str {
+    if args.tls_cert.is_some() { "https" } else { "http" }
+}
+
+/// Scheme of the WebSocket URLs served with `args`
+pub fn ws_scheme(args: &Args) -> &'static str {
+    if args.tls_cert.is_some() { "wss" } else { "ws" }
+}
+
+/// Path the server is reached under, without a trailing slash: that of `--base-url`, or empty
+pub fn base_path(args: &Args) -> &str {
+    args.base_url
+        .as_ref()
+        .map_or("", |url| url.path().trim_end_matches('/'))
+}
+
+/// URL viewers open `path` of the server at, e.g. "/s/local/": under `--base-url` if given, otherwise at
+/// the listen address
+pub fn page_url(args: &Args, path: &str) -> String {
+    match &args.base_url {
+        Some(url) => format!("{}://{}{}{path}", url.scheme(), host_port(url), base_path(args)),
+        None => format!("{}://{}{path}", http_scheme(args), args.listen),
+    }
+}
+
+/// WebSocket URL of `path` of the server, like `page_url`
+pub fn websocket_url(args: &Args, path: &str) -> String {
+    match &args.base_url {
+        Some(url) => {
+            let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+            format!("{scheme}://{}{}{path}", host_port(url), base_path(args))
+        }
+        None => format!("{}://{}{path}", ws_scheme(args), args.listen),
+    }
+}
+
+/// Host of `url`, with its port unless it is the scheme's default
+fn host_port(url: &url::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+/// With `--http-redirect-port`, listen on that port of the `--listen` address too and answer plain HTTP
+/// requests there with a permanent redirect to the same path over HTTPS, e.g. for links pasted with
+/// http:// after TLS was enabled
+pub async fn spawn_http_redirect(args: &Args) -> anyhow::Result<()> {
+    let Some(port) = args.http_redirect_port else {
+        return Ok(());
+    };
+    let (host, https_port) = args.listen.rsplit_once(':').unwrap_or((args.listen.as_str(), "443"));
+    let address = format!("{host}:{port}");
+    let listener = TcpListener::bind(&address)
+        .await
+        .with_context(|| format!("Failed to listen on {address} for --http-redirect-port"))?;
+
+    let target = Arc::new(RedirectTarget {
+        origin: args
+            .base_url
+            .as_ref()
+            .map(|url| format!("https://{}{}", host_port(url), base_path(args))),
+        default_host: host.to_string(),
+        port: https_port.parse().ok().filter(|&port| port != 443),
+    });
+    let app = Router::new().fallback(redirect_to_https).with_state(target);
+    let options = ServeOptions::new(args, None);
+    debug!("Redirecting plain HTTP on {} to HTTPS", address);
+    tokio::spawn(async move {
+        if let Err(e) = serve(listener, app, options, std::future::pending()).await {
+            error!("Failed to serve HTTPS redirects: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Where plain HTTP requests are redirected to
+struct RedirectTarget {
+    /// Scheme, host and path prefix from `--base-url`, if given
+    origin: Option<String>,
+    /// Host of `--listen`, for requests without a `Host` header
+    default_host: String,
+    /// HTTPS port, unless it is the default one
+    port: Option<u16>,
+}
+
+async fn redirect_to_https(State(target): State<Arc<RedirectTarget>>, request: Request) -> Response {
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    let location = match &target.origin {
+        Some(origin) => format!("{origin}{path}"),
+        None => {
+            let host = request
+                .headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map_or(target.default_host.as_str(), strip_port);
+            match target.port {
+                Some(port) => format!("https://{host}:{port}{path}"),
+                None => format!("https://{host}{path}"),
+            }
+        }
+    };
+    (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response()
+}
+
+/// `host` without its port, e.g. "example.com" for "example.com:80" and "[::1]" for "[::1]:80"
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
@@ -0,0 +1,591 @@
+use crate::term::ColorDepth;
+use bytes::Bytes;
+use tokio::sync::watch;
+
+/// Longest CSI sequence buffered while waiting for its final byte; longer ones are passed on unparsed
+const MAX_CSI_LENGTH: usize = 256;
+
+/// Colors of the xterm 16-color palette
+const ANSI_PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// Intensities of the 6x6x6 color cube in the xterm 256-color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Color depth output is converted to, from the `--output-colors` option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColors {
+    /// Follow the least capable participant
+    Auto,
+    Fixed(ColorDepth),
+}
+
+impl std::str::FromStr for OutputColors {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            "none" => Ok(Self::Fixed(ColorDepth::Monochrome)),
+            "16" => Ok(Self::Fixed(ColorDepth::Ansi16)),
+            "256" => Ok(Self::Fixed(ColorDepth::Ansi256)),
+            _ => Err(format!(
+                "invalid output colors: {value} (expected auto, none, 16 or 256)"
+            )),
+        }
+    }
+}
+
+/// A step of the output pipeline that rewrites PTY output before it reaches viewers.
+///
+/// Output arrives in arbitrary chunks, so stages keep whatever state they need to handle escape
+/// sequences split across calls.
+pub trait OutputStage: Send {
+    /// Append the transformed form of `input` to `output`
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>);
+
+    /// Whether the stage would currently leave output untouched and can be skipped
+    fn is_passthrough(&self) -> bool {
+        false
+    }
+}
+
+/// The stages applied, in order, to PTY output sent to viewers
+#[derive(Default)]
+pub struct OutputPipeline {
+    stages: Vec<Box<dyn OutputStage>>,
+}
+
+impl OutputPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `stage` to the pipeline
+    pub fn with_stage(mut self, stage: impl OutputStage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Run `data` through every active stage
+    pub fn process(&mut self, data: Bytes) -> Bytes {
+        let mut data = data;
+        for stage in &mut self.stages {
+            if stage.is_passthrough() {
+                continue;
+            }
+            let mut output = Vec::with_capacity(data.len());
+            stage.process(&data, &mut output);
+            data = Bytes::from(output);
+        }
+        data
+    }
+}
+
+/// A piece of terminal output, as split by `Tokenizer`
+enum Token<'a> {
+    /// Printable text and C0 controls
+    Text(&'a [u8]),
+    /// A complete CSI sequence, including the leading `ESC [`
+    Csi(&'a [u8]),
+    /// Part of any other escape sequence or control string; long strings arrive in several parts
+    Escape(&'a [u8]),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// After ESC, collecting intermediate bytes
+    Escape,
+    Csi,
+    /// Inside OSC, DCS, SOS, PM or APC, until BEL or ST
+    ControlString,
+    /// ESC seen inside a control string, which is either ST or the start of a new sequence
+    ControlStringEscape,
+}
+
+/// Splits terminal output into text and escape sequences, keeping state across chunks
+struct Tokenizer {
+    state: State,
+    pending: Vec<u8>,
+}
+
+impl Tokenizer {
+    fn new() -> Self {
+        Self {
+            state: State::Ground,
+            pending: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, input: &[u8], mut emit: impl FnMut(Token<'_>)) {
+        let mut i = 0;
+        while i < input.len() {
+            if self.state == State::Ground {
+                let end = input[i..]
+                    .iter()
+                    .position(|&b| b == 0x1b)
+                    .map_or(input.len(), |p| i + p);
+                if end > i {
+                    emit(Token::Text(&input[i..end]));
+                }
+                if end < input.len() {
+                    self.pending.push(0x1b);
+                    self.state = State::Escape;
+                }
+                i = end + 1;
+                continue;
+            }
+
+            if self.state == State::ControlString {
+                // Stream control strings through instead of buffering them; they can be large (e.g. images)
+                let end = input[i..]
+                    .iter()
+                    .position(|&b| b == 0x07 || b == 0x1b)
+                    .map_or(input.len(), |p| i + p);
+                match input.get(end) {
+                    Some(0x07) => {
+                        emit(Token::Escape(&input[i..=end]));
+                        self.state = State::Ground;
+                    }
+                    Some(_) => {
+                        if end > i {
+                            emit(Token::Escape(&input[i..end]));
+                        }
+                        self.state = State::ControlStringEscape;
+                    }
+                    None => emit(Token::Escape(&input[i..end])),
+                }
+                i = end + 1;
+                continue;
+            }
+
+            let byte = input[i];
+            i += 1;
+            match self.state {
+                State::Escape if byte == 0x1b => {
+                    // A new escape sequence interrupts the unfinished one
+                    emit(Token::Escape(&self.pending));
+                    self.pending.clear();
+                    self.pending.push(0x1b);
+                }
+                State::Escape => {
+                    self.pending.push(byte);
+                    match byte {
+                        b'[' if self.pending.len() == 2 => self.state = State::Csi,
+                        b']' | b'P' | b'X' | b'^' | b'_' if self.pending.len() == 2 => {
+                            emit(Token::Escape(&self.pending));
+                            self.pending.clear();
+                            self.state = State::ControlString;
+                        }
+                        // Intermediate bytes
+                        0x20..=0x2f => {}
+                        _ => self.finish_escape(&mut emit),
+                    }
+                }
+                State::Csi => match byte {
+                    0x1b => {
+                        // An interrupted sequence is passed on as is
+                        emit(Token::Escape(&self.pending));
+                        self.pending.clear();
+                        self.pending.push(0x1b);
+                        self.state = State::Escape;
+                    }
+                    0x40..=0x7e => {
+                        self.pending.push(byte);
+                        emit(Token::Csi(&self.pending));
+                        self.pending.clear();
+                        self.state = State::Ground;
+                    }
+                    // CAN and SUB cancel the sequence
+                    0x18 | 0x1a => {
+                        self.pending.push(byte);
+                        self.finish_escape(&mut emit);
+                    }
+                    _ => {
+                        self.pending.push(byte);
+                        if self.pending.len() > MAX_CSI_LENGTH {
+                            self.finish_escape(&mut emit);
+                        }
+                    }
+                },
+                State::ControlStringEscape => {
+                    if byte == b'\\' {
+                        emit(Token::Escape(b"\x1b\\"));
+                        self.state = State::Ground;
+                    } else {
+                        // The control string was cut short by a new escape sequence
+                        self.pending.push(0x1b);
+                        self.state = State::Escape;
+                        i -= 1;
+                    }
+                }
+                State::Ground | State::ControlString => unreachable!(),
+            }
+        }
+    }
+
+    fn finish_escape(&mut self, emit: &mut impl FnMut(Token<'_>)) {
+        emit(Token::Escape(&self.pending));
+        self.pending.clear();
+        self.state = State::Ground;
+    }
+}
+
+/// What a color parameter of an SGR sequence selects
+#[derive(Clone, Copy)]
+enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// Layer an SGR color parameter applies to
+#[derive(Clone, Copy)]
+enum Layer {
+    Foreground,
+    Background,
+    Underline,
+}
+
+/// Converts SGR colors to at most a given color depth, e.g. truecolor to the 16 ANSI colors
+pub struct ColorDowngrade {
+    target: watch::Receiver<ColorDepth>,
+    tokenizer: Tokenizer,
+}
+
+impl ColorDowngrade {
+    /// Downgrade to whatever `target` currently holds, so the depth can follow the viewers
+    pub fn new(target: watch::Receiver<ColorDepth>) -> Self {
+        Self {
+            target,
+            tokenizer: Tokenizer::new(),
+        }
+    }
+
+    /// Downgrade to a fixed color depth
+    pub fn fixed(target: ColorDepth) -> Self {
+        let (_, target) = watch::channel(target);
+        Self::new(target)
+    }
+}
+
+impl OutputStage for ColorDowngrade {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        let target = *self.target.borrow();
+        self.tokenizer.feed(input, |token| match token {
+            Token::Text(bytes) | Token::Escape(bytes) => output.extend_from_slice(bytes),
+            Token::Csi(sequence) => match downgrade_sgr(sequence, target) {
+                Some(sequence) => output.extend_from_slice(&sequence),
+                None => output.extend_from_slice(sequence),
+            },
+        });
+    }
+
+    fn is_passthrough(&self) -> bool {
+        // Keep parsing while a sequence is pending so it isn't misread once downgrading resumes
+        *self.target.borrow() == ColorDepth::TrueColor && self.tokenizer.state == State::Ground
+    }
+}
+
+/// Removes all escape sequences and control characters other than line breaks and tabs, leaving plain text
+pub struct StripAnsi {
+    tokenizer: Tokenizer,
+}
+
+impl StripAnsi {
+    pub fn new() -> Self {
+        Self {
+            tokenizer: Tokenizer::new(),
+        }
+    }
+}
+
+impl Default for StripAnsi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputStage for StripAnsi {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        self.tokenizer.feed(input, |token| {
+            if let Token::Text(bytes) = token {
+                output.extend(
+                    bytes
+                        .iter()
+                        .filter(|&&b| (b >= 0x20 && b != 0x7f) || matches!(b, b'\n' | b'\r' | b'\t' | 0x08)),
+                );
+            }
+        });
+    }
+}
+
+/// Turns bare line feeds into carriage return and line feed, as a terminal's output processing does, for
+/// commands run without a PTY
+#[derive(Default)]
+pub struct LineFeeds {
+    after_cr: bool,
+}
+
+impl OutputStage for LineFeeds {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for &byte in input {
+            if byte == b'\n' && !self.after_cr {
+                output.push(b'\r');
+            }
+            output.push(byte);
+            self.after_cr = byte == b'\r';
+        }
+    }
+}
+
+/// Rewrite an SGR sequence for `target`, or `None` if it needs no change
+fn downgrade_sgr(sequence: &[u8], target: ColorDepth) -> Option<Vec<u8>> {
+    let params = sequence.strip_prefix(b"\x1b[")?.strip_suffix(b"m")?;
+    // Leave private sequences such as `CSI > 4 ; 2 m` alone
+    if !params.iter().all(|b| b.is_ascii_digit() || *b == b';' || *b == b':') {
+        return None;
+    }
+    if params.is_empty() || target == ColorDepth::TrueColor {
+        return None;
+    }
+
+    let params = std::str::from_utf8(params).ok()?;
+    let items: Vec<&str> = params.split(';').collect();
+    let mut kept: Vec<String> = Vec::with_capacity(items.len());
+    let mut changed = false;
+
+    let mut i = 0;
+    while i < items.len() {
+        let item = items[i];
+        let code: u16 = item.split(':').next().unwrap_or("").parse().unwrap_or(0);
+        let layer = match code {
+            38 => Some(Layer::Foreground),
+            48 => Some(Layer::Background),
+            58 => Some(Layer::Underline),
+            _ => None,
+        };
+
+        let Some(layer) = layer else {
+            let is_color = matches!(code, 30..=37 | 39 | 40..=47 | 49 | 59 | 90..=97 | 100..=107);
+            if is_color && target == ColorDepth::Monochrome {
+                changed = true;
+            } else {
+                kept.push(item.to_string());
+            }
+            i += 1;
+            continue;
+        };
+
+        let Some((color, consumed)) = parse_extended_color(&items[i..]) else {
+            // Malformed; pass the rest through untouched
+            kept.extend(items[i..].iter().map(|item| item.to_string()));
+            break;
+        };
+        i += consumed;
+
+        let replacement = convert_color(color, layer, target);
+        let original = items[i - consumed..i].join(";");
+        if replacement.as_deref() != Some(original.as_str()) {
+            changed = true;
+        }
+        kept.extend(replacement);
+    }
+
+    if !changed {
+        return None;
+    }
+    // Dropping every parameter must not turn the sequence into a reset
+    if kept.is_empty() {
+        return Some(Vec::new());
+    }
+    Some(format!("\x1b[{}m", kept.join(";")).into_bytes())
+}
+
+/// Parse `38;5;n`, `38;2;r;g;b` or their colon forms, returning the color and the number of items used
+fn parse_extended_color(items: &[&str]) -> Option<(Color, usize)> {
+    let number = |s: &str| s.parse::<u8>().ok();
+
+    if items[0].contains(':') {
+        let sub: Vec<&str> = items[0].split(':').collect();
+        return match sub.get(1).copied() {
+            Some("5") => Some((Color::Indexed(number(sub.get(2)?)?), 1)),
+            // `38:2::r:g:b` carries a color space id before the components
+            Some("2") if sub.len() >= 5 => {
+                let rgb = &sub[sub.len() - 3..];
+                Some((Color::Rgb(number(rgb[0])?, number(rgb[1])?, number(rgb[2])?), 1))
+            }
+            _ => None,
+        };
+    }
+
+    match items.get(1).copied() {
+        Some("5") => Some((Color::Indexed(number(items.get(2)?)?), 3)),
+        Some("2") => Some((
+            Color::Rgb(number(items.get(2)?)?, number(items.get(3)?)?, number(items.get(4)?)?),
+            5,
+        )),
+        _ => None,
+    }
+}
+
+/// SGR parameters selecting `color` on `layer` at `target` depth, or `None` if the layer can't be colored
+fn convert_color(color: Color, layer: Layer, target: ColorDepth) -> Option<String> {
+    let prefix = match layer {
+        Layer::Foreground => 38,
+        Layer::Background => 48,
+        Layer::Underline => 58,
+    };
+
+    match target {
+        ColorDepth::TrueColor => Some(match color {
+            Color::Indexed(index) => format!("{prefix};5;{index}"),
+            Color::Rgb(r, g, b) => format!("{prefix};2;{r};{g};{b}"),
+        }),
+        ColorDepth::Ansi256 => {
+            let index = match color {
+                Color::Indexed(index) => index,
+                Color::Rgb(r, g, b) => nearest_256(r, g, b),
+            };
+            Some(format!("{prefix};5;{index}"))
+        }
+        ColorDepth::Ansi16 => {
+            let index = match color {
+                Color::Indexed(index) if index < 16 => index,
+                Color::Indexed(index) => nearest_16(palette_256(index)),
+                Color::Rgb(r, g, b) => nearest_16((r, g, b)),
+            };
+            let (normal, bright) = match layer {
+                Layer::Foreground => (30, 90),
+                Layer::Background => (40, 100),
+                // There is no 16-color underline color
+                Layer::Underline => return None,
+            };
+            Some(if index < 8 {
+                format!("{}", normal + index as u16)
+            } else {
+                format!("{}", bright + index as u16 - 8)
+            })
+        }
+        ColorDepth::Monochrome => None,
+    }
+}
+
+/// RGB value of a color in the xterm 256-color palette
+fn palette_256(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI_PALETTE[index as usize],
+        16..=231 => {
+            let index = index - 16;
+            (
+                CUBE_LEVELS[(index / 36) as usize],
+                CUBE_LEVELS[(index / 6 % 6) as usize],
+                CUBE_LEVELS[(index % 6) as usize],
+            )
+        }
+        232..=255 => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// Closest color of the 256-color palette outside the configurable first 16
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    (16..=255)
+        .min_by_key(|&index| distance(palette_256(index), (r, g, b)))
+        .unwrap_or(16)
+}
+
+/// Closest color of the 16-color palette
+fn nearest_16(rgb: (u8, u8, u8)) -> u8 {
+    (0..16u8)
+        .min_by_key(|&index| distance(ANSI_PALETTE[index as usize], rgb))
+        .unwrap_or(7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `chunks` through `stage` one after another, as output arrives from the PTY
+    fn process(stage: &mut impl OutputStage, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for chunk in chunks {
+            stage.process(chunk, &mut output);
+        }
+        output
+    }
+
+    /// Output with every kind of sequence the tokenizer knows: CSI, OSC ended by BEL and by ST, a
+    /// charset designation with an intermediate byte and DCS
+    const MIXED: &[u8] =
+        b"a\x1b[1;38;2;255;0;0mred\x1b[0m \x1b]0;title\x07b\x1b]8;;http://x\x1b\\link\x1b(Bc\x1bPdcs\x1b\\d";
+
+    #[test]
+    fn strip_ansi_removes_sequences_split_across_chunks() {
+        assert_eq!(process(&mut StripAnsi::new(), &[b"a\x1b", b"[3", b"1mb"]), b"ab");
+        assert_eq!(process(&mut StripAnsi::new(), &[b"x\x1b]0;ti", b"tle\x07y"]), b"xy");
+        assert_eq!(process(&mut StripAnsi::new(), &[b"x\x1b]0;t\x1b", b"\\z"]), b"xz");
+        assert_eq!(process(&mut StripAnsi::new(), &[MIXED]), b"ared blinkcd");
+    }
+
+    #[test]
+    fn strip_ansi_output_does_not_depend_on_chunk_boundaries() {
+        for split in 0..=MIXED.len() {
+            let (first, second) = MIXED.split_at(split);
+            assert_eq!(
+                process(&mut StripAnsi::new(), &[first, second]),
+                b"ared blinkcd",
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn color_downgrade_rewrites_sequences_split_across_chunks() {
+        let mut stage = ColorDowngrade::fixed(ColorDepth::Ansi16);
+        assert_eq!(process(&mut stage, &[b"\x1b[38;2;255", b";0;0mX"]), b"\x1b[91mX");
+
+        let whole = process(&mut ColorDowngrade::fixed(ColorDepth::Ansi256), &[MIXED]);
+        for split in 0..=MIXED.len() {
+            let (first, second) = MIXED.split_at(split);
+            let mut stage = ColorDowngrade::fixed(ColorDepth::Ansi256);
+            assert_eq!(process(&mut stage, &[first, second]), whole, "split at {split}");
+        }
+    }
+
+    #[test]
+    fn color_downgrade_leaves_other_sequences_alone() {
+        let mut stage = ColorDowngrade::fixed(ColorDepth::Monochrome);
+        assert_eq!(process(&mut stage, &[b"\x1b[1;31mX\x1b[>4;2m"]), b"\x1b[1mX\x1b[>4;2m");
+        // A sequence cut short by another is passed on as it is, and the color that follows is dropped
+        assert_eq!(process(&mut stage, &[b"\x1b[3", b"\x1b[32m"]), b"\x1b[3");
+    }
+
+    #[test]
+    fn line_feeds_keep_carriage_returns_split_from_their_line_feed() {
+        assert_eq!(process(&mut LineFeeds::default(), &[b"a\nb\r", b"\nc"]), b"a\r\nb\r\nc");
+    }
+}
@@ -0,0 +1,475 @@
+use crate::serve::{ServeOptions, serve_io};
+use crate::server::AppState;
+use anyhow::Context;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{HeaderValue, StatusCode, Version, header};
+use axum::response::Response;
+use base64::{Engine as _, engine::general_purpose};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use url::Url;
+
+/// Path the relay serves tunneled sessions under, as `/t/{id}/`
+pub const TUNNEL_PATH: &str = "/t";
+
+/// Query parameter a session reconnecting to the relay asks for its tunnel again with
+pub const TUNNEL_ID_PARAM: &str = "id";
+
+/// Query parameter carrying the secret a session proves the tunnel is its own with
+pub const TUNNEL_SECRET_PARAM: &str = "secret";
+
+/// Longest tunnel ID the relay accepts from a session
+const MAX_TUNNEL_ID_LEN: usize = 64;
+
+/// Frame kinds: a stream is opened by the relay for a connection, carries data both ways, and is closed
+/// by either side
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const CLOSE: u8 = 2;
+
+/// Most data of a stream sent in one frame
+const MAX_CHUNK: usize = 16 * 1024;
+
+/// Frames buffered for a stream whose reader is behind. A stream that falls further behind is closed,
+/// rather than holding up the others.
+const STREAM_BUFFER: usize = 64;
+
+/// Frames waiting to be sent over the tunnel
+const TUNNEL_BUFFER: usize = 256;
+
+/// Time between pings, which keep NATs and proxies on the way from dropping a quiet tunnel
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay before the first reconnection attempt, doubled after every failure
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+type RelaySocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// First message of the relay on a tunnel: the tunnel's ID, the URL viewers reach the session at, and the
+/// secret the session asks for the ID again with when it reconnects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelOpened {
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+}
+
+/// The ID the relay gives a tunnel: the one the session asks for, if it could be one, or a new random one
+pub fn tunnel_id(requested: Option<String>) -> String {
+    requested
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_TUNNEL_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+        .unwrap_or_else(|| general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()))
+}
+
+/// A new secret for a tunnel, which the relay hands out once
+pub fn tunnel_secret() -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Connections multiplexed over the WebSocket between a session and the relay. Each is a numbered
+/// stream, sent in binary frames of the stream number (big-endian u32), the frame kind and its data.
+pub struct Mux {
+    outgoing: mpsc::Sender<Bytes>,
+    /// Streams open at this end, by number, to pass the data coming in for them to
+    streams: Mutex<HashMap<u32, mpsc::Sender<Bytes>>>,
+    next_id: AtomicU32,
+}
+
+impl Mux {
+    /// A mux, and the frames it wants sent over the tunnel
+    pub fn new() -> (Arc<Self>, mpsc::Receiver<Bytes>) {
+        let (outgoing, outgoing_rx) = mpsc::channel(TUNNEL_BUFFER);
+        let mux = Self {
+            outgoing,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+        };
+        (Arc::new(mux), outgoing_rx)
+    }
+
+    fn streams(&self) -> MutexGuard<'_, HashMap<u32, mpsc::Sender<Bytes>>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Open a stream to the session for a connection from `peer`, at the relay
+    pub async fn open(self: &Arc<Self>, peer: SocketAddr) -> anyhow::Result<DuplexStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.outgoing
+            .send(frame(id, OPEN, peer.to_string().as_bytes()))
+            .await
+            .context("The tunnel is closed")?;
+        Ok(self.attach(id))
+    }
+
+    /// Handle a frame from the other end. Returns the stream it opened, if it did, with the address of
+    /// the connection the stream carries.
+    pub fn dispatch(self: &Arc<Self>, mut frame: Bytes) -> Option<(DuplexStream, SocketAddr)> {
+        if frame.len() < 5 {
+            return None;
+        }
+        let id = frame.get_u32();
+        match frame.get_u8() {
+            OPEN => {
+                let peer = std::str::from_utf8(&frame).ok()?.parse().ok()?;
+                Some((self.attach(id), peer))
+            }
+            DATA => {
+                let mut streams = self.streams();
+                if let Some(stream) = streams.get(&id) {
+                    if stream.try_send(frame).is_err() {
+                        debug!("Closing tunneled stream {} that fell behind", id);
+                        streams.remove(&id);
+                    }
+                }
+                None
+            }
+            _ => {
+                self.streams().remove(&id);
+                None
+            }
+        }
+    }
+
+    /// Close every stream, once the tunnel is gone
+    pub fn close(&self) {
+        self.streams().clear();
+    }
+
+    /// Register stream `id`, and pass data between it and the returned end until either side closes it
+    fn attach(self: &Arc<Self>, id: u32) -> DuplexStream {
+        let (local, remote) = tokio::io::duplex(4 * MAX_CHUNK);
+        let (incoming_tx, mut incoming_rx) = mpsc::channel::<Bytes>(STREAM_BUFFER);
+        self.streams().insert(id, incoming_tx);
+        let (mut reader, mut writer) = tokio::io::split(remote);
+        let closed = CancellationToken::new();
+
+        // Reading and writing go on separately, so a WebSocket sending while it receives can't block itself
+        let closed_incoming = closed.clone();
+        tokio::spawn(async move {
+            while let Some(data) = incoming_rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            closed_incoming.cancel();
+        });
+
+        let mux = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_CHUNK];
+            loop {
+                let read = tokio::select! {
+                    _ = closed.cancelled() => break,
+                    read = reader.read(&mut buffer) => read,
+                };
+                match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if mux.outgoing.send(frame(id, DATA, &buffer[..n])).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            mux.streams().remove(&id);
+            let _ = mux.outgoing.send(frame(id, CLOSE, &[])).await;
+        });
+
+        local
+    }
+}
+
+fn frame(id: u32, kind: u8, data: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(5 + data.len());
+    frame.put_u32(id);
+    frame.put_u8(kind);
+    frame.put_slice(data);
+    frame.freeze()
+}
+
+/// Pass `request` from `peer` on to the session over a new stream of `mux`, and its response back. A
+/// WebSocket upgrade is passed on too, and the upgraded connections are joined.
+pub async fn proxy(mux: &Arc<Mux>, peer: SocketAddr, mut request: Request) -> anyhow::Result<Response> {
+    let io = mux.open(peer).await?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.with_upgrades().await {
+            debug!("Tunneled connection from {} ended with error: {}", peer, e);
+        }
+    });
+
+    // Viewers may speak HTTP/2 to the relay, while the tunnel carries HTTP/1.1
+    if request.version() != Version::HTTP_11 {
+        if let Some(host) = request
+            .uri()
+            .authority()
+            .and_then(|a| HeaderValue::from_str(a.as_str()).ok())
+        {
+            request.headers_mut().entry(header::HOST).or_insert(host);
+        }
+        *request.version_mut() = Version::HTTP_11;
+    }
+    let client_upgrade = hyper::upgrade::on(&mut request);
+    let mut response = sender.send_request(request).await?;
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let session_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            match tokio::try_join!(client_upgrade, session_upgrade) {
+                Ok((client, session)) => {
+                    let _ = tokio::io::copy_bidirectional(&mut TokioIo::new(client), &mut TokioIo::new(session)).await;
+                }
+                Err(e) => debug!("Failed to upgrade the tunneled connection from {}: {}", peer, e),
+            }
+        });
+    }
+    Ok(response.map(Body::new))
+}
+
+/// A tunnel the relay set up for the session, with `--public`
+#[derive(Debug, Clone)]
+pub struct Registration {
+    /// Tunnel URL printed by `rwshell relay`
+    relay: Url,
+    id: String,
+    /// Proves to the relay that the tunnel is the session's, when it reconnects
+    secret: String,
+    /// Where viewers reach the session through the relay
+    pub url: Url,
+}
+
+/// Set up a tunnel with the relay at `relay`, to learn where viewers will reach the session before it
+/// starts
+pub async fn register(relay: &Url) -> anyhow::Result<Registration> {
+    let (mut socket, opened) = connect(relay, None)
+        .await
+        .with_context(|| format!("Failed to open a tunnel at {}", relay.host_str().unwrap_or("the relay")))?;
+    let _ = socket.close(None).await;
+    Ok(Registration {
+        relay: relay.clone(),
+        url: Url::parse(&opened.url).context("The relay sent an invalid URL")?,
+        id: opened.id,
+        secret: opened.secret,
+    })
+}
+
+/// Connect to the relay, asking for the tunnel of `registration` if given
+async fn connect(relay: &Url, registration: Option<&Registration>) -> anyhow::Result<(RelaySocket, TunnelOpened)> {
+    let mut url = relay.clone();
+    if let Some(registration) = registration {
+        url.query_pairs_mut()
+            .append_pair(TUNNEL_ID_PARAM, &registration.id)
+            .append_pair(TUNNEL_SECRET_PARAM, &registration.secret);
+    }
+    let (mut socket, _) = connect_async(url.as_str()).await?;
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => return Ok((socket, serde_json::from_str(&text)?)),
+            Some(Ok(Message::Close(_))) | None => anyhow::bail!("The relay closed the connection"),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Serve `app` to viewers reaching the session through the relay's tunnel, reconnecting until `shutdown`
+/// is cancelled
+pub fn start(
+    state: &AppState,
+    mut registration: Registration,
+    app: Router,
+    options: ServeOptions,
+    shutdown: CancellationToken,
+) {
+    state.supervisor.spawn("tunnel", async move {
+        let host = registration.relay.host_str().unwrap_or("relay").to_string();
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            let result = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                result = tunnel(&mut registration, &app, &options) => result,
+            };
+            match result {
+                Ok(()) => {
+                    warn!("The relay closed the tunnel");
+                    delay = MIN_RECONNECT_DELAY;
+                }
+                // The URL carries the relay's token, so only its host is logged
+                Err(e) => warn!("Failed to keep the tunnel to {} open: {}", host, e),
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+/// Serve connections passed over one connection to the relay, until it ends
+async fn tunnel(registration: &mut Registration, app: &Router, options: &ServeOptions) -> anyhow::Result<()> {
+    let (socket, opened) = connect(&registration.relay, Some(registration)).await?;
+    if opened.id != registration.id {
+        warn!("The relay moved the session to {}", opened.url);
+    }
+    // A relay that restarted since hands out a new secret
+    registration.id = opened.id;
+    registration.secret = opened.secret;
+    info!("Sharing the session at {}", opened.url);
+
+    let (mut sender, mut receiver) = socket.split();
+    let (mux, mut outgoing_rx) = Mux::new();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let result = loop {
+        let message = tokio::select! {
+            Some(frame) = outgoing_rx.recv() => Message::Binary(frame.to_vec()),
+            _ = ping.tick() => Message::Ping(Vec::new()),
+            message = receiver.next() => {
+                match message {
+                    Some(Ok(Message::Binary(frame))) => {
+                        if let Some((io, peer)) = mux.dispatch(frame.into()) {
+                            debug!("Tunneled connection from {}", peer);
+                            let app = app.clone();
+                            let options = options.clone();
+                            tokio::spawn(async move { serve_io(io, peer, app, &options).await });
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => break Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => break Err(e.into()),
+                }
+                continue;
+            }
+        };
+        if let Err(e) = sender.send(message).await {
+            break Err(e.into());
+        }
+    };
+    mux.close();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "203.0.113.7:51234";
+
+    fn peer() -> SocketAddr {
+        PEER.parse().unwrap()
+    }
+
+    #[test]
+    fn frames_carry_the_stream_number_kind_and_data() {
+        assert_eq!(&frame(0x0102_0304, DATA, b"hi")[..], b"\x01\x02\x03\x04\x01hi");
+        assert_eq!(&frame(7, CLOSE, &[])[..], b"\x00\x00\x00\x07\x02");
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_ignored() {
+        let (mux, _outgoing) = Mux::new();
+        for len in 0..5 {
+            assert!(mux.dispatch(Bytes::from(vec![0; len])).is_none(), "{len} bytes");
+        }
+        // An OPEN frame cut off in its address, or without one
+        let open = frame(1, OPEN, PEER.as_bytes());
+        assert!(mux.dispatch(open.slice(..open.len() - 6)).is_none());
+        assert!(mux.dispatch(open.slice(..5)).is_none());
+        assert!(mux.dispatch(frame(1, OPEN, b"\xff\xfe")).is_none());
+        assert!(mux.streams().is_empty());
+        // Data and closes for streams that aren't open, and unknown kinds
+        assert!(mux.dispatch(frame(9, DATA, b"lost")).is_none());
+        assert!(mux.dispatch(frame(9, CLOSE, &[])).is_none());
+        assert!(mux.dispatch(frame(9, 0x7f, b"?")).is_none());
+        assert!(mux.streams().is_empty());
+    }
+
+    #[tokio::test]
+    async fn opened_streams_receive_their_data_until_closed() {
+        let (mux, _outgoing) = Mux::new();
+        let (mut stream, from) = mux.dispatch(frame(3, OPEN, PEER.as_bytes())).expect("stream opened");
+        assert_eq!(from, peer());
+
+        assert!(mux.dispatch(frame(3, DATA, b"GET / HTTP/1.1\r\n")).is_none());
+        // An empty DATA frame is as long as a header and carries nothing
+        assert!(mux.dispatch(frame(3, DATA, &[])).is_none());
+        let mut received = vec![0; 16];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, b"GET / HTTP/1.1\r\n");
+
+        assert!(mux.dispatch(frame(3, CLOSE, &[])).is_none());
+        assert!(mux.streams().is_empty());
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn both_ends_of_a_tunnel_exchange_data() {
+        let (relay, mut relay_outgoing) = Mux::new();
+        let (session, mut session_outgoing) = Mux::new();
+
+        let mut viewer = relay.open(peer()).await.unwrap();
+        let open = relay_outgoing.recv().await.unwrap();
+        let (mut served, from) = session.dispatch(open).expect("stream opened");
+        assert_eq!(from, peer());
+
+        viewer.write_all(b"request").await.unwrap();
+        session.dispatch(relay_outgoing.recv().await.unwrap());
+        let mut request = [0; 7];
+        served.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"request");
+
+        served.write_all(b"response").await.unwrap();
+        drop(served);
+        // The data, then the close
+        while let Some(frame) = session_outgoing.recv().await {
+            let closing = frame[4] == CLOSE;
+            relay.dispatch(frame);
+            if closing {
+                break;
+            }
+        }
+        let mut response = Vec::new();
+        viewer.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+    }
+
+    #[test]
+    fn tunnel_ids_are_kept_only_if_they_fit_in_a_url() {
+        assert_eq!(tunnel_id(Some("demo_1-a".to_string())), "demo_1-a");
+        for requested in [
+            None,
+            Some(String::new()),
+            Some("../x".to_string()),
+            Some("a".repeat(65)),
+        ] {
+            let id = tunnel_id(requested.clone());
+            assert_ne!(Some(&id), requested.as_ref());
+            assert_eq!(id.len(), 22);
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use crate::error::{Result, RwShellError};
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{debug, error};
+use url::Url;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TtyMessage {
@@ -11,6 +15,182 @@ pub struct TtyMessage {
     pub data: String, // base64 encoded
 }
 
+/// WebSocket subprotocol of the binary framing. Clients that ask for it get output as binary messages of
+/// a type byte followed by the raw bytes, and may send input the same way; every other message is still
+/// JSON text. Clients that don't ask for it get the JSON protocol only.
+pub const BINARY_PROTOCOL: &str = "rwshell.v2";
+
+/// Type byte of a binary message carrying terminal output, or input from a client
+pub const BINARY_WRITE: u8 = 1;
+
+/// Type byte of a binary message carrying terminal output compressed as the subprotocol says, e.g.
+/// "rwshell.v2+zstd"
+pub const BINARY_WRITE_COMPRESSED: u8 = 2;
+
+/// A binary message of type `frame_type` carrying `data`
+pub fn binary_frame(frame_type: u8, data: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(frame_type);
+    frame.extend_from_slice(data);
+    frame.into()
+}
+
+/// The type byte and payload of a binary message, unless it is empty
+pub fn decode_binary_frame(frame: &[u8]) -> Option<(u8, &[u8])> {
+    frame.split_first().map(|(frame_type, data)| (*frame_type, data))
+}
+
+/// Machine-readable reason carried by a `Close` message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CloseReason {
+    /// The shared command exited
+    ChildExited,
+    /// The session was idle for too long
+    IdleTimeout,
+    /// The session ran for as long as it may
+    TimeLimit,
+    /// The client was disconnected by the host
+    Kicked,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The client attempted something its read-only access doesn't allow
+    ReadonlyEnforced,
+    /// Another local terminal is already attached to the session
+    AlreadyAttached,
+    /// The client's capability token used up its quota of connections, output or time
+    QuotaExceeded,
+    /// The session asked for over a multiplexed connection doesn't exist, or has ended
+    SessionNotFound,
+    /// The session has `--max-clients` clients, or the client's address `--max-clients-per-ip`
+    TooManyClients,
+}
+
+impl CloseReason {
+    /// Whether a client should try to reconnect after being closed for this reason
+    pub fn should_reconnect(self) -> bool {
+        matches!(self, CloseReason::ServerShutdown)
+    }
+
+    /// The reason as named in `Close` messages, e.g. "child-exited"
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::ChildExited => "child-exited",
+            CloseReason::IdleTimeout => "idle-timeout",
+            CloseReason::TimeLimit => "time-limit",
+            CloseReason::Kicked => "kicked",
+            CloseReason::ServerShutdown => "server-shutdown",
+            CloseReason::ReadonlyEnforced => "readonly-enforced",
+            CloseReason::AlreadyAttached => "already-attached",
+            CloseReason::QuotaExceeded => "quota-exceeded",
+            CloseReason::SessionNotFound => "session-not-found",
+            CloseReason::TooManyClients => "too-many-clients",
+        }
+    }
+
+    /// WebSocket close code for this reason: a normal closure when the session ended, "going away" when
+    /// the server did, and a policy violation otherwise
+    pub fn close_code(self) -> u16 {
+        match self {
+            CloseReason::ChildExited | CloseReason::SessionNotFound => close_code::NORMAL,
+            CloseReason::ServerShutdown => close_code::AWAY,
+            CloseReason::IdleTimeout
+            | CloseReason::TimeLimit
+            | CloseReason::Kicked
+            | CloseReason::ReadonlyEnforced
+            | CloseReason::AlreadyAttached
+            | CloseReason::QuotaExceeded
+            | CloseReason::TooManyClients => close_code::POLICY,
+        }
+    }
+
+    /// The close frame ending a connection for this reason, after its `Close` message
+    pub fn close_frame(self) -> Message {
+        Message::Close(Some(CloseFrame {
+            code: self.close_code(),
+            reason: self.name().into(),
+        }))
+    }
+}
+
+/// Sent right before the server closes a WebSocket connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseMessage {
+    #[serde(rename = "Reason")]
+    pub reason: CloseReason,
+    #[serde(rename = "Message")]
+    pub message: String,
+}
+
+/// Sent to a client when something it sent failed, e.g. its input couldn't be written to the program
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    #[serde(rename = "Message")]
+    pub message: String,
+}
+
+/// Sent to all clients of a session that will soon end, e.g. as it is about to reach its `--idle-timeout`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WarningMessage {
+    #[serde(rename = "Message")]
+    pub message: String,
+}
+
+/// Sent to all clients of a session whenever a viewer joins or leaves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientsMessage {
+    #[serde(rename = "Count")]
+    pub count: usize,
+}
+
+/// Sent to a viewer of a session with `--approve-writers` once the host answered its `RequestWrite`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteAccessMessage {
+    #[serde(rename = "Granted")]
+    pub granted: bool,
+}
+
+/// Pings the peer of a WebSocket connection, to notice when it is gone without closing the connection, e.g.
+/// behind a NAT that forgot it
+#[derive(Clone)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    last_heard: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl Heartbeat {
+    /// Ping every `interval`, expecting an answer within `timeout`; `None` if `interval` is zero
+    pub fn new(interval: Duration, timeout: Duration) -> Option<Self> {
+        (!interval.is_zero()).then(|| Self {
+            interval,
+            timeout,
+            last_heard: Arc::new(std::sync::Mutex::new(Instant::now())),
+        })
+    }
+
+    /// Note that a message arrived from the peer; any message answers a ping, not only its Pong
+    pub fn heard(&self) {
+        *self.last_heard.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Call `ping` to send a Ping every interval, and return once the peer didn't answer one in time or
+    /// `ping` returns false
+    pub async fn run(&self, mut ping: impl FnMut() -> bool) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let sent = Instant::now();
+            if !ping() {
+                return;
+            }
+            tokio::time::sleep(self.timeout).await;
+            if *self.last_heard.lock().unwrap_or_else(|e| e.into_inner()) < sent {
+                return;
+            }
+        }
+    }
+}
+
 pub struct TtyWebSocket {
     socket: WebSocket,
 }
@@ -56,3 +236,30 @@ impl TtyWebSocket {
         Ok(())
     }
 }
+
+/// Path of `path` on the server of the session page at `url`, under the base path a reverse proxy may
+/// serve it at, e.g. "/terminals/api/version" for "https://example.com/terminals/s/local/"
+pub fn server_path(url: &Url, path: &str) -> String {
+    let base = url.path().rfind("/s/").map_or("", |end| &url.path()[..end]);
+    format!("{base}{path}")
+}
+
+/// The WebSocket URL of the session page at `url`
+pub fn session_websocket_url(url: &Url) -> anyhow::Result<Url> {
+    let mut ws_url = url.clone();
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    ws_url
+        .set_scheme(scheme)
+        .map_err(|_| anyhow::anyhow!("Unsupported session URL {url}"))?;
+
+    let mut path = url.path().trim_end_matches('/').to_string();
+    if !path.ends_with("/ws") {
+        path.push_str("/ws");
+    }
+    path.push('/');
+    ws_url.set_path(&path);
+    ws_url.set_query(None);
+    ws_url.set_fragment(None);
+    crate::auth::carry_token(url, &mut ws_url);
+    Ok(ws_url)
+}